@@ -0,0 +1,6 @@
+fn main() {
+    // Reported by `shellm --version`
+    let target = std::env::var("TARGET").unwrap_or_default();
+    println!("cargo:rustc-env=SHELLM_TARGET={target}");
+    println!("cargo:rerun-if-changed=build.rs");
+}
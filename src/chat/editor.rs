@@ -0,0 +1,185 @@
+/// Single-line input buffer with a movable cursor.
+#[derive(Default)]
+pub(super) struct LineEditor {
+    buf: String,
+    /// Byte offset of the cursor in `buf`, always on a char boundary
+    cursor: usize,
+}
+
+impl LineEditor {
+    pub(super) fn text(&self) -> &str {
+        &self.buf
+    }
+
+    pub(super) fn before_cursor(&self) -> &str {
+        &self.buf[..self.cursor]
+    }
+
+    pub(super) fn after_cursor(&self) -> &str {
+        &self.buf[self.cursor..]
+    }
+
+    /// Replace the whole line, leaving the cursor at the end.
+    pub(super) fn set(&mut self, text: String) {
+        self.buf = text;
+        self.cursor = self.buf.len();
+    }
+
+    pub(super) fn clear(&mut self) {
+        self.buf.clear();
+        self.cursor = 0;
+    }
+
+    pub(super) fn insert_char(&mut self, c: char) {
+        self.buf.insert(self.cursor, c);
+        self.cursor += c.len_utf8();
+    }
+
+    pub(super) fn insert_str(&mut self, s: &str) {
+        self.buf.insert_str(self.cursor, s);
+        self.cursor += s.len();
+    }
+
+    /// Delete the char before the cursor. Returns false when there is nothing to delete.
+    pub(super) fn backspace(&mut self) -> bool {
+        let Some(c) = self.before_cursor().chars().next_back() else {
+            return false;
+        };
+        self.cursor -= c.len_utf8();
+        self.buf.remove(self.cursor);
+        true
+    }
+
+    /// Delete the char under the cursor. Returns false at the end of the line.
+    pub(super) fn delete(&mut self) -> bool {
+        if self.cursor == self.buf.len() {
+            return false;
+        }
+        self.buf.remove(self.cursor);
+        true
+    }
+
+    pub(super) fn move_left(&mut self) -> bool {
+        let Some(c) = self.before_cursor().chars().next_back() else {
+            return false;
+        };
+        self.cursor -= c.len_utf8();
+        true
+    }
+
+    pub(super) fn move_right(&mut self) -> bool {
+        let Some(c) = self.after_cursor().chars().next() else {
+            return false;
+        };
+        self.cursor += c.len_utf8();
+        true
+    }
+
+    pub(super) fn move_home(&mut self) -> bool {
+        let moved = self.cursor != 0;
+        self.cursor = 0;
+        moved
+    }
+
+    pub(super) fn move_end(&mut self) -> bool {
+        let moved = self.cursor != self.buf.len();
+        self.cursor = self.buf.len();
+        moved
+    }
+}
+
+/// Previously submitted questions, recalled with Up/Down like a shell prompt.
+#[derive(Default)]
+pub(super) struct InputHistory {
+    entries: Vec<String>,
+    /// Entry currently shown in the input line; `None` while editing a fresh line
+    index: Option<usize>,
+    /// The in-progress line, restored when walking forward past the newest entry
+    draft: String,
+}
+
+impl InputHistory {
+    pub(super) fn push(&mut self, line: String) {
+        self.entries.push(line);
+        self.index = None;
+        self.draft.clear();
+    }
+
+    /// Step back to an older entry, stashing `current` when leaving the fresh line.
+    pub(super) fn prev(&mut self, current: &str) -> Option<&str> {
+        let index = match self.index {
+            None if self.entries.is_empty() => return None,
+            None => {
+                self.draft = current.to_string();
+                self.entries.len() - 1
+            }
+            Some(i) => i.saturating_sub(1),
+        };
+        self.index = Some(index);
+        Some(&self.entries[index])
+    }
+
+    /// Step forward to a newer entry, or back to the stashed draft.
+    pub(super) fn next(&mut self) -> Option<String> {
+        let index = self.index?;
+        if index + 1 < self.entries.len() {
+            self.index = Some(index + 1);
+            Some(self.entries[index + 1].clone())
+        } else {
+            self.index = None;
+            Some(std::mem::take(&mut self.draft))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_insert_and_delete_at_cursor() {
+        let mut editor = LineEditor::default();
+        editor.insert_str("lsla");
+        editor.move_left();
+        editor.move_left();
+        editor.insert_str(" -");
+        assert_eq!(editor.text(), "ls -la");
+        assert_eq!(editor.before_cursor(), "ls -");
+
+        assert!(editor.backspace());
+        assert!(editor.delete());
+        assert_eq!(editor.text(), "ls a");
+        assert!(editor.move_home());
+        assert!(!editor.backspace());
+        assert!(editor.move_end());
+        assert!(!editor.delete());
+    }
+
+    #[test]
+    fn test_cursor_moves_by_char() {
+        let mut editor = LineEditor::default();
+        editor.set("查看文件".to_string());
+        assert!(editor.move_left());
+        assert_eq!(editor.after_cursor(), "件");
+        assert!(editor.backspace());
+        assert_eq!(editor.text(), "查看件");
+        editor.insert_char('a');
+        assert_eq!(editor.text(), "查看a件");
+        assert!(editor.move_right());
+        assert!(!editor.move_right());
+    }
+
+    #[test]
+    fn test_input_history_restores_draft() {
+        let mut history = InputHistory::default();
+        history.push("first".to_string());
+        history.push("second".to_string());
+
+        assert_eq!(history.prev("draft"), Some("second"));
+        assert_eq!(history.prev("second"), Some("first"));
+        assert_eq!(history.prev("first"), Some("first"));
+        assert_eq!(history.next().as_deref(), Some("second"));
+        assert_eq!(history.next().as_deref(), Some("draft"));
+        assert_eq!(history.next(), None);
+    }
+}
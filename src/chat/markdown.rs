@@ -0,0 +1,78 @@
+use super::{char_width, display_width};
+use crate::style::{ColorMode, Style, style};
+
+/// Style `` `code` `` and `**bold**` spans with ANSI escapes.
+/// Returns the styled string and its display width, which excludes the escapes and markers.
+/// Unmatched markers are kept as literal text, and so is everything when colors are off.
+pub(super) fn render_inline_markdown(s: &str, color: ColorMode) -> (String, usize) {
+    if !color.enabled() {
+        return (s.to_string(), display_width(s));
+    }
+
+    let mut out = String::with_capacity(s.len());
+    let mut width = 0usize;
+    let mut rest = s;
+
+    while let Some(ch) = rest.chars().next() {
+        if let Some((inner, tail)) = span(rest, "`") {
+            out.push_str(&style(inner, Style::Reverse, color));
+            width += display_width(inner);
+            rest = tail;
+        } else if let Some((inner, tail)) = span(rest, "**") {
+            out.push_str(&style(inner, Style::Bold, color));
+            width += display_width(inner);
+            rest = tail;
+        } else {
+            out.push(ch);
+            width += char_width(ch);
+            rest = &rest[ch.len_utf8()..];
+        }
+    }
+
+    (out, width)
+}
+
+/// If `s` starts with a non-empty span delimited by `marker`, return its content and the rest.
+fn span<'a>(s: &'a str, marker: &str) -> Option<(&'a str, &'a str)> {
+    let after = s.strip_prefix(marker)?;
+    let end = after.find(marker)?;
+    if end == 0 {
+        return None;
+    }
+    Some((&after[..end], &after[end + marker.len()..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_code_and_bold_spans() {
+        let (styled, width) = render_inline_markdown("run `ls -la` **now**", ColorMode::Always);
+        assert_eq!(styled, "run \x1b[7mls -la\x1b[27m \x1b[1mnow\x1b[0m");
+        assert_eq!(width, "run ls -la now".len());
+    }
+
+    #[test]
+    fn test_unmatched_markers_are_literal() {
+        let (styled, width) = render_inline_markdown("a `b and **c", ColorMode::Always);
+        assert_eq!(styled, "a `b and **c");
+        assert_eq!(width, 12);
+
+        let (styled, _) = render_inline_markdown("empty `` span", ColorMode::Always);
+        assert_eq!(styled, "empty `` span");
+    }
+
+    #[test]
+    fn test_width_counts_wide_chars() {
+        let (_, width) = render_inline_markdown("用 `du -sh` 查看", ColorMode::Always);
+        assert_eq!(width, 2 + 1 + 6 + 1 + 4);
+    }
+
+    #[test]
+    fn test_no_styling_without_color() {
+        let (styled, width) = render_inline_markdown("run `ls`", ColorMode::Never);
+        assert_eq!(styled, "run `ls`");
+        assert_eq!(width, 8);
+    }
+}
@@ -0,0 +1,2037 @@
+use std::borrow::Cow;
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{Result, bail};
+use crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use crossterm::terminal::{self, Clear, ClearType};
+use crossterm::{cursor, execute};
+use regex::Regex;
+
+use crate::config::{ReasoningDisplay, SystemInfo, system_prompt};
+use crate::history::HistoryStore;
+use crate::i18n::{Language, MessageKey, t};
+use crate::keys::KeyBindings;
+use crate::llm::{ChatMessage, ChatReply, ChatSink, LLMClient, LlmError, Role, Usage};
+use crate::pty::{InjectMode, PtySession, inject_command};
+use crate::safety::redact;
+use crate::style::{ColorMode, Style, style};
+
+mod editor;
+mod markdown;
+mod state;
+
+use editor::LineEditor;
+use markdown::render_inline_markdown;
+use state::{ChatAction, ChatState};
+
+/// Chat mode settings derived from the config.
+pub struct ChatOptions {
+    /// Prefix questions with the shell output captured before entering chat mode
+    pub include_terminal_context: bool,
+    /// Where the chat history is saved after each reply, when persistence is enabled
+    pub history_store: Option<HistoryStore>,
+    pub color: ColorMode,
+    pub prompt_template: String,
+    pub system_info: SystemInfo,
+    pub keys: KeyBindings,
+    pub reasoning: ReasoningDisplay,
+    pub reasoning_wrap: bool,
+    /// Show how long each request took under the answer
+    pub show_timing: bool,
+    /// Print a banner when chat mode starts
+    pub show_welcome: bool,
+    /// The banner, when not the built-in overview of the keys
+    pub welcome_text: Option<String>,
+    /// Secrets masked in shell output before it is sent along with a question
+    pub redact_patterns: Vec<Regex>,
+}
+
+impl ChatOptions {
+    /// The banner printed on entering chat mode, if any.
+    fn welcome(&self, lang: &Language) -> Option<String> {
+        if !self.show_welcome {
+            return None;
+        }
+        Some(match &self.welcome_text {
+            Some(text) => text.clone(),
+            None => self.keys.relabel(t(lang, MessageKey::WelcomeMessage)),
+        })
+    }
+
+    /// The system prompt, with `{cwd}` set to the shell's current directory.
+    fn system_prompt(&self, cwd: &Path) -> String {
+        let cwd = cwd.display().to_string();
+        let mut vars = self.system_info.to_vars();
+        vars.insert("cwd", &cwd);
+        system_prompt(&self.prompt_template, &vars)
+    }
+}
+
+/// Get terminal width, default 80
+fn get_terminal_width() -> usize {
+    terminal::size().map(|(w, _)| w as usize).unwrap_or(80)
+}
+
+/// Rough width used without the `unicode-width` feature: anything past ASCII counts
+/// as wide.
+fn approx_char_width(c: char) -> usize {
+    match c {
+        '\u{0000}'..='\u{001F}' | '\u{007F}' => 0,
+        _ if c.is_ascii() => 1,
+        _ => 2,
+    }
+}
+
+/// Terminal columns taken by `c`, from the Unicode width tables when built with the
+/// `unicode-width` feature.
+#[cfg(feature = "unicode-width")]
+fn char_width(c: char) -> usize {
+    // The tables give control characters no width; the estimate says 0 for them too
+    unicode_width::UnicodeWidthChar::width(c).unwrap_or_else(|| approx_char_width(c))
+}
+
+#[cfg(not(feature = "unicode-width"))]
+fn char_width(c: char) -> usize {
+    approx_char_width(c)
+}
+
+fn display_width(s: &str) -> usize {
+    s.chars().map(char_width).sum()
+}
+
+fn wrap_rows(visible: &str, cols: usize) -> usize {
+    wrap_width_rows(display_width(visible), cols)
+}
+
+/// Rows taken by a line of the given display width
+fn wrap_width_rows(width: usize, cols: usize) -> usize {
+    if cols == 0 {
+        return 1;
+    }
+    width.max(1).div_ceil(cols)
+}
+
+fn truncate_tail_by_width(s: &str, max_width: usize) -> &str {
+    if max_width == 0 {
+        return "";
+    }
+    if display_width(s) <= max_width {
+        return s;
+    }
+
+    let mut width = 0usize;
+    let mut start = s.len();
+    for (idx, ch) in s.char_indices().rev() {
+        let w = char_width(ch);
+        if width + w > max_width {
+            break;
+        }
+        width += w;
+        start = idx;
+    }
+    &s[start..]
+}
+
+/// How far `truncate_tail_by_width_word_aware` looks for a space before it gives up
+/// and cuts the word, in characters
+const WORD_SNAP_CHARS: usize = 16;
+
+/// Combining marks, joiners, variation selectors and skin-tone modifiers, which
+/// belong to the character before them.
+fn is_cluster_continuation(c: char) -> bool {
+    matches!(
+        c,
+        '\u{0300}'..='\u{036F}'
+            | '\u{1AB0}'..='\u{1AFF}'
+            | '\u{1DC0}'..='\u{1DFF}'
+            | '\u{200C}'..='\u{200D}'
+            | '\u{20D0}'..='\u{20FF}'
+            | '\u{FE00}'..='\u{FE0F}'
+            | '\u{FE20}'..='\u{FE2F}'
+            | '\u{1F3FB}'..='\u{1F3FF}'
+            | '\u{E0020}'..='\u{E007F}'
+    )
+}
+
+/// Letters and digits of scripts that separate words with spaces; CJK text can break
+/// between any two characters.
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() && c < '\u{2E80}'
+}
+
+/// The tail of `s` that fits in `max_width`, like `truncate_tail_by_width`, but never
+/// starting inside a character cluster, and starting after a space rather than
+/// inside a word when one is close.
+fn truncate_tail_by_width_word_aware(s: &str, max_width: usize) -> &str {
+    let tail = truncate_tail_by_width(s, max_width);
+    if tail.len() == s.len() {
+        return tail;
+    }
+    let mut start = s.len() - tail.len();
+
+    // Drop the rest of a cluster whose first character was cut off
+    while let Some(c) = s[start..].chars().next() {
+        let after_joiner = s[..start].ends_with('\u{200D}');
+        if !is_cluster_continuation(c) && !after_joiner {
+            break;
+        }
+        start += c.len_utf8();
+    }
+
+    let inside_word = s[..start].chars().next_back().is_some_and(is_word_char)
+        && s[start..].chars().next().is_some_and(is_word_char);
+    if inside_word
+        && let Some((idx, space)) = s[start..]
+            .char_indices()
+            .take(WORD_SNAP_CHARS)
+            .find(|(_, c)| c.is_whitespace())
+    {
+        start += idx + space.len_utf8();
+    }
+    &s[start..]
+}
+
+/// How wide a paragraph made by `soft_wrap_reasoning` may grow, in terminal rows
+const REASONING_PARAGRAPH_ROWS: usize = 3;
+
+/// Sentence ends that need no space after them
+const FULL_WIDTH_STOPS: [char; 3] = ['。', '！', '？'];
+
+/// The reasoning as the expanded view shows it, soft-wrapped when `wrap` is set.
+fn expanded_reasoning(reasoning: &str, wrap: bool, cols: usize) -> Cow<'_, str> {
+    if wrap {
+        Cow::Owned(soft_wrap_reasoning(reasoning, cols))
+    } else {
+        Cow::Borrowed(reasoning)
+    }
+}
+
+/// Split lines wider than the terminal into paragraphs at sentence ends, for models
+/// that send their reasoning as one long line. A paragraph gathers whole sentences
+/// up to a few rows wide; a single longer sentence stays whole. Shorter lines and
+/// the model's own line breaks are kept as they are.
+fn soft_wrap_reasoning(reasoning: &str, cols: usize) -> String {
+    let max_width = cols.max(1) * REASONING_PARAGRAPH_ROWS;
+    let mut lines = Vec::new();
+    for line in reasoning.lines() {
+        if display_width(line) <= cols {
+            lines.push(line.to_string());
+            continue;
+        }
+        let mut paragraph = String::new();
+        for sentence in split_sentences(line) {
+            if !paragraph.is_empty()
+                && display_width(&paragraph) + 1 + display_width(sentence) > max_width
+            {
+                lines.push(std::mem::take(&mut paragraph));
+            }
+            if !paragraph.is_empty() && !paragraph.ends_with(FULL_WIDTH_STOPS) {
+                paragraph.push(' ');
+            }
+            paragraph.push_str(sentence);
+        }
+        lines.push(paragraph);
+    }
+    lines.join("\n")
+}
+
+/// Sentences of `line` with surrounding spaces trimmed. A sentence ends at `.`, `!`
+/// or `?` followed by a space, or at a full-width `。`, `！` or `？`.
+fn split_sentences(line: &str) -> Vec<&str> {
+    let mut sentences = Vec::new();
+    let mut start = 0;
+    let mut chars = line.char_indices().peekable();
+    while let Some((idx, ch)) = chars.next() {
+        let end = match ch {
+            _ if FULL_WIDTH_STOPS.contains(&ch) => true,
+            '.' | '!' | '?' => chars.peek().is_some_and(|(_, next)| next.is_whitespace()),
+            _ => false,
+        };
+        if end {
+            let sentence = line[start..idx + ch.len_utf8()].trim();
+            if !sentence.is_empty() {
+                sentences.push(sentence);
+            }
+            start = idx + ch.len_utf8();
+        }
+    }
+    let rest = line[start..].trim();
+    if !rest.is_empty() {
+        sentences.push(rest);
+    }
+    sentences
+}
+
+/// Break `text` into lines of at most `cols` display columns, at spaces where possible.
+/// Wide (CJK) characters may break anywhere, and newlines in `text` are kept.
+fn wrap_text(text: &str, cols: usize) -> Vec<String> {
+    let cols = cols.max(1);
+    let mut lines = Vec::new();
+    for paragraph in text.split('\n') {
+        let mut line = String::new();
+        let mut width = 0usize;
+        for (word, spaced) in wrap_tokens(paragraph) {
+            let word_width = display_width(word);
+            if !line.is_empty() {
+                let gap = usize::from(spaced);
+                if width + gap + word_width > cols {
+                    lines.push(std::mem::take(&mut line));
+                    width = 0;
+                } else if spaced {
+                    line.push(' ');
+                    width += 1;
+                }
+            }
+            // A word longer than a whole line is split wherever it overflows
+            for ch in word.chars() {
+                let w = char_width(ch);
+                if width + w > cols && !line.is_empty() {
+                    lines.push(std::mem::take(&mut line));
+                    width = 0;
+                }
+                line.push(ch);
+                width += w;
+            }
+        }
+        lines.push(line);
+    }
+    lines
+}
+
+/// Split a paragraph into words and single wide characters, each flagged if whitespace
+/// came before it.
+fn wrap_tokens(paragraph: &str) -> Vec<(&str, bool)> {
+    let mut tokens = Vec::new();
+    let mut start = None;
+    let mut spaced = false;
+    for (idx, ch) in paragraph.char_indices() {
+        if ch.is_whitespace() {
+            if let Some(s) = start.take() {
+                tokens.push((&paragraph[s..idx], spaced));
+            }
+            spaced = true;
+        } else if char_width(ch) > 1 {
+            if let Some(s) = start.take() {
+                tokens.push((&paragraph[s..idx], spaced));
+                spaced = false;
+            }
+            tokens.push((&paragraph[idx..idx + ch.len_utf8()], spaced));
+            spaced = false;
+        } else if start.is_none() {
+            start = Some(idx);
+        }
+    }
+    if let Some(s) = start {
+        tokens.push((&paragraph[s..], spaced));
+    }
+    tokens
+}
+
+/// What Enter does with the input line.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum InputMode {
+    /// Typing a new question for the LLM
+    Question,
+    /// Editing the suggested command before injecting it
+    EditCommand,
+}
+
+/// Where the last `prompt` draw left the input, so the next draw can erase it.
+#[derive(Default)]
+struct PromptArea {
+    /// Row of the cursor, counted from the first row of the input
+    cursor_row: usize,
+    rows: usize,
+}
+
+impl PromptArea {
+    /// Move below the drawn input so following output starts on a fresh row.
+    fn leave(&mut self) {
+        let below = self.rows.saturating_sub(self.cursor_row + 1);
+        if below > 0 {
+            let _ = execute!(io::stdout(), cursor::MoveDown(below as u16));
+        }
+        print!("\r\n");
+        io::stdout().flush().ok();
+        *self = Self::default();
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct InputLayout {
+    rows: usize,
+    cursor_row: usize,
+    cursor_col: usize,
+    /// The last row is exactly full, so the terminal is waiting to wrap
+    wrap_pending: bool,
+}
+
+/// Lay out a possibly multi-line input after a prompt of `prompt_width` columns.
+/// Continuation lines are indented to line up with the first one.
+fn layout_input(prompt_width: usize, text: &str, cursor: usize, cols: usize) -> InputLayout {
+    let cols = cols.max(1);
+    let mut layout = InputLayout {
+        rows: 0,
+        cursor_row: 0,
+        cursor_col: 0,
+        wrap_pending: false,
+    };
+    let mut offset = 0;
+    let mut lines = text.split('\n').peekable();
+    while let Some(line) = lines.next() {
+        let line_end = offset + line.len();
+        if (offset..=line_end).contains(&cursor) {
+            let width = prompt_width + display_width(&line[..cursor - offset]);
+            layout.cursor_row = layout.rows + width / cols;
+            layout.cursor_col = width % cols;
+        }
+        let width = prompt_width + display_width(line);
+        layout.rows += wrap_width_rows(width, cols);
+        if lines.peek().is_none() && width > 0 && width.is_multiple_of(cols) {
+            layout.wrap_pending = true;
+            layout.rows += 1;
+        }
+        offset = line_end + 1;
+    }
+    layout
+}
+
+/// The text of each row `layout_input` counts: the prompt and the lines of `text`,
+/// continuation lines indented, cut where the terminal wraps them.
+fn input_rows(prompt_text: &str, text: &str, cols: usize) -> Vec<String> {
+    let cols = cols.max(1);
+    let indent = " ".repeat(display_width(prompt_text));
+    let mut rows = Vec::new();
+    let mut lines = text.split('\n').enumerate().peekable();
+    while let Some((i, line)) = lines.next() {
+        let lead = if i == 0 { prompt_text } else { indent.as_str() };
+        let mut row = String::new();
+        let mut width = 0;
+        for c in lead.chars().chain(line.chars()) {
+            let w = char_width(c);
+            if width > 0 && width + w > cols {
+                rows.push(std::mem::take(&mut row));
+                width = 0;
+            }
+            row.push(c);
+            width += w;
+        }
+        rows.push(row);
+        if lines.peek().is_none() && width == cols {
+            // The cursor waits on the next row
+            rows.push(String::new());
+        }
+    }
+    rows
+}
+
+/// The rows of an input taller than the terminal that are shown, as the range
+/// `top..bottom`. Rows hidden above or below are marked on a row of their own.
+#[derive(Debug, PartialEq, Eq)]
+struct InputWindow {
+    top: usize,
+    bottom: usize,
+}
+
+impl InputWindow {
+    /// The rows of `layout` shown in `max_rows` terminal rows, keeping the cursor in view.
+    fn new(layout: &InputLayout, max_rows: usize) -> Self {
+        let rows = layout.rows;
+        let max_rows = max_rows.max(3);
+        if rows <= max_rows {
+            return Self {
+                top: 0,
+                bottom: rows,
+            };
+        }
+        // Leave room for both markers; a marker that is not needed gives its row back
+        let shown = max_rows - 2;
+        let top = layout
+            .cursor_row
+            .saturating_sub(shown - 1)
+            .min(rows - shown);
+        let bottom = top + shown;
+        if top == 0 {
+            Self {
+                top,
+                bottom: bottom + 1,
+            }
+        } else if bottom == rows {
+            Self {
+                top: top - 1,
+                bottom,
+            }
+        } else {
+            Self { top, bottom }
+        }
+    }
+}
+
+fn prompt(
+    area: &mut PromptArea,
+    editor: &LineEditor,
+    mode: InputMode,
+    lang: &Language,
+    color: ColorMode,
+) {
+    let prompt_text = match mode {
+        InputMode::Question => t(lang, MessageKey::PromptUser),
+        InputMode::EditCommand => t(lang, MessageKey::PromptEditCommand),
+    };
+    let (cols, term_rows) = terminal::size().map_or((80, 24), |(w, h)| (w as usize, h as usize));
+    let layout = layout_input(
+        display_width(prompt_text),
+        editor.text(),
+        editor.before_cursor().len(),
+        cols,
+    );
+    let window = InputWindow::new(&layout, term_rows);
+    let rows = input_rows(prompt_text, editor.text(), cols);
+
+    let marker = style("…", Style::Dim, color);
+    let mut shown: Vec<&str> = Vec::new();
+    if window.top > 0 {
+        shown.push(&marker);
+    }
+    let visible = rows
+        .get(window.top..window.bottom.min(rows.len()))
+        .unwrap_or_default();
+    shown.extend(visible.iter().map(String::as_str));
+    if window.bottom < layout.rows {
+        shown.push(&marker);
+    }
+    let cursor_row = layout.cursor_row - window.top + usize::from(window.top > 0);
+
+    let mut stdout = io::stdout();
+    if area.cursor_row > 0 {
+        let _ = execute!(stdout, cursor::MoveUp(area.cursor_row as u16));
+    }
+    print!("\r\x1b[J{}", shown.join("\r\n"));
+
+    let up = shown.len().saturating_sub(cursor_row + 1);
+    if up > 0 {
+        let _ = execute!(stdout, cursor::MoveUp(up as u16));
+    }
+    let cursor_col = layout.cursor_col.min(u16::MAX as usize) as u16;
+    let _ = execute!(stdout, cursor::MoveToColumn(cursor_col));
+    stdout.flush().ok();
+
+    area.cursor_row = cursor_row;
+    area.rows = shown.len();
+}
+
+fn normalize_to_single_line(s: &str) -> String {
+    s.chars()
+        .map(|c| if c == '\n' || c == '\r' { ' ' } else { c })
+        .collect::<String>()
+        .trim()
+        .to_string()
+}
+
+/// Append streamed text with newlines flattened to spaces.
+fn push_single_line(buf: &mut String, chunk: &str) {
+    buf.extend(
+        chunk
+            .chars()
+            .map(|c| if c == '\n' || c == '\r' { ' ' } else { c }),
+    );
+}
+
+/// `prefix` followed by the tail of `text` that fits in the current terminal row.
+fn live_line(prefix: &str, text: &str) -> String {
+    let max_display_width = get_terminal_width()
+        .saturating_sub(display_width(prefix))
+        .saturating_sub(1);
+    format!(
+        "{prefix}{}",
+        truncate_tail_by_width_word_aware(text, max_display_width)
+    )
+}
+
+/// The single line shown while a reply streams in: a spinner with the elapsed seconds
+/// until text arrives, then the tail of the reasoning or the answer so far.
+struct LivePreview<'a> {
+    color: ColorMode,
+    reasoning_display: ReasoningDisplay,
+    thinking_text: &'a str,
+    assistant_prompt: &'a str,
+    /// Whether anything was drawn that `clear` has to remove
+    drawn: bool,
+    /// Set once streamed text has replaced the spinner
+    content_arrived: bool,
+    last_frame: Option<usize>,
+    /// Streamed text with newlines flattened, for the sliding window
+    reasoning: String,
+    answer: String,
+}
+
+impl<'a> LivePreview<'a> {
+    fn new(lang: &'a Language, options: &ChatOptions) -> Self {
+        Self {
+            color: options.color,
+            reasoning_display: options.reasoning,
+            thinking_text: t(lang, MessageKey::ThinkingProcess),
+            assistant_prompt: t(lang, MessageKey::PromptAssistant),
+            drawn: false,
+            content_arrived: false,
+            last_frame: None,
+            reasoning: String::new(),
+            answer: String::new(),
+        }
+    }
+
+    /// Until something streams in, animate a spinner with the elapsed seconds.
+    fn tick(&mut self, elapsed: Duration) {
+        if self.content_arrived {
+            return;
+        }
+        let frame = (elapsed.as_millis() / 100) as usize;
+        if self.last_frame == Some(frame) {
+            return;
+        }
+        self.last_frame = Some(frame);
+        let spinner = ['|', '/', '-', '\\'][frame % 4];
+        let status = format!("{spinner} {}s", elapsed.as_secs());
+        let line = live_line(self.thinking_text, &status);
+        self.draw(&style(&line, Style::Dim, self.color));
+    }
+
+    fn draw(&mut self, line: &str) {
+        self.drawn = true;
+        print!("\r\x1b[2K{line}");
+        io::stdout().flush().ok();
+    }
+
+    /// Remove the line before the reply block or an error takes its place.
+    fn clear(&self) {
+        if self.drawn {
+            print!("\r\x1b[2K");
+            io::stdout().flush().ok();
+        }
+    }
+}
+
+impl ChatSink for LivePreview<'_> {
+    fn on_reasoning(&mut self, text: &str) {
+        // Hidden reasoning leaves the spinner running until the answer starts
+        if self.reasoning_display == ReasoningDisplay::Hidden {
+            return;
+        }
+        self.content_arrived = true;
+        push_single_line(&mut self.reasoning, text);
+        let line = live_line(self.thinking_text, &self.reasoning);
+        self.draw(&style(&line, Style::Dim, self.color));
+    }
+
+    fn on_answer(&mut self, text: &str) {
+        self.content_arrived = true;
+        push_single_line(&mut self.answer, text);
+        let line = live_line(self.assistant_prompt, &self.answer);
+        self.draw(&line);
+    }
+
+    /// Drop the abandoned attempt's text and go back to the spinner.
+    fn on_restart(&mut self) {
+        self.clear();
+        self.drawn = false;
+        self.content_arrived = false;
+        self.last_frame = None;
+        self.reasoning.clear();
+        self.answer.clear();
+    }
+}
+
+/// The last reply as shown below the prompt.
+#[derive(Clone, Copy)]
+struct ReplyView<'a> {
+    reasoning: Option<&'a str>,
+    reasoning_expanded: bool,
+    /// Rows the expanded reasoning is scrolled up from its end, when it does not fit
+    reasoning_scroll: usize,
+    /// Break long expanded reasoning lines into paragraphs at sentence ends
+    reasoning_wrap: bool,
+    answer: &'a str,
+    /// Suggested commands, best first
+    candidates: &'a [String],
+    /// Index of the candidate that Ctrl+L would inject
+    selected: usize,
+    /// How to expand the collapsed reasoning, with the configured key
+    toggle_hint: &'a str,
+    /// Token counts shown under the answer, when the backend reported them
+    usage: Option<Usage>,
+    /// How long the request took, when `preference.show_timing` is on
+    timing: Option<Duration>,
+    explanation: Option<&'a str>,
+    explanation_expanded: bool,
+    /// How to show the collapsed explanation, with the configured key
+    explanation_hint: &'a str,
+}
+
+/// A reply of this chat session, kept so earlier ones can be brought back.
+#[derive(Clone, Debug, Default, PartialEq)]
+struct PastReply {
+    reasoning: Option<String>,
+    answer: String,
+    candidates: Vec<String>,
+    usage: Option<Usage>,
+    timing: Option<Duration>,
+    explanation: Option<String>,
+}
+
+/// The replies of this session, oldest first, and which one is on screen.
+#[derive(Default)]
+struct ReplyHistory {
+    replies: Vec<PastReply>,
+    current: usize,
+}
+
+impl ReplyHistory {
+    /// Add a new reply, or replace the newest one when it was regenerated, and show it.
+    fn push(&mut self, reply: PastReply, replace_last: bool) {
+        if replace_last {
+            self.replies.pop();
+        }
+        self.replies.push(reply);
+        self.current = self.replies.len() - 1;
+    }
+
+    fn prev(&mut self) -> Option<&PastReply> {
+        self.current = self.current.checked_sub(1)?;
+        self.replies.get(self.current)
+    }
+
+    fn next(&mut self) -> Option<&PastReply> {
+        if self.current + 1 >= self.replies.len() {
+            return None;
+        }
+        self.current += 1;
+        self.replies.get(self.current)
+    }
+
+    fn is_latest(&self) -> bool {
+        self.current + 1 >= self.replies.len()
+    }
+}
+
+/// The explanation block below the candidates, each line flagged if it is the dim hint.
+fn explanation_lines<'a>(view: &ReplyView<'a>) -> Vec<(&'a str, bool)> {
+    match view.explanation {
+        None => Vec::new(),
+        Some(explanation) if view.explanation_expanded => {
+            explanation.lines().map(|line| (line, false)).collect()
+        }
+        Some(_) => vec![(view.explanation_hint, true)],
+    }
+}
+
+/// The dim lines under the answer: token counts and how long the request took.
+fn footer_lines(lang: &Language, usage: Option<Usage>, timing: Option<Duration>) -> Vec<String> {
+    let usage = usage.map(|usage| {
+        t(lang, MessageKey::TokenUsage)
+            .replace("{prompt}", &usage.prompt_tokens.to_string())
+            .replace("{completion}", &usage.completion_tokens.to_string())
+    });
+    let timing = timing.map(|timing| {
+        t(lang, MessageKey::ReplyTiming).replace("{secs}", &format!("{:.1}", timing.as_secs_f64()))
+    });
+    usage.into_iter().chain(timing).collect()
+}
+
+/// The candidate lines below the answer, unstyled, each flagged if it is the selected one.
+/// A single command keeps the one-line form; several are numbered for picking with 1-9.
+fn candidate_lines(lang: &Language, candidates: &[String], selected: usize) -> Vec<(String, bool)> {
+    let candidate_prompt = t(lang, MessageKey::PromptCandidate);
+    match candidates {
+        [] => Vec::new(),
+        [cmd] => vec![(
+            format!("{candidate_prompt}{}", normalize_to_single_line(cmd)),
+            false,
+        )],
+        _ => {
+            let hint = t(lang, MessageKey::HintChooseCandidate)
+                .replace("{n}", &candidates.len().to_string());
+            let mut lines = vec![(format!("{candidate_prompt}{hint}"), false)];
+            lines.extend(candidates.iter().enumerate().map(|(i, cmd)| {
+                let marker = if i == selected { '>' } else { ' ' };
+                let line = format!("{marker} {}. {}", i + 1, normalize_to_single_line(cmd));
+                (line, i == selected)
+            }));
+            lines
+        }
+    }
+}
+
+/// Rows of the reply block below the reasoning: the answer, its footer, the candidates
+/// and the explanation.
+fn answer_rows(lang: &Language, view: &ReplyView, term_cols: usize) -> usize {
+    let assistant_prompt = t(lang, MessageKey::PromptAssistant);
+    let assistant_rows = wrap_text(&format!("{assistant_prompt}{}", view.answer), term_cols).len();
+
+    let candidate_rows: usize = candidate_lines(lang, view.candidates, view.selected)
+        .iter()
+        .map(|(line, _)| wrap_rows(line, term_cols))
+        .sum();
+
+    let footer_rows: usize = footer_lines(lang, view.usage, view.timing)
+        .iter()
+        .map(|line| wrap_rows(line, term_cols))
+        .sum();
+
+    let explanation_rows: usize = explanation_lines(view)
+        .iter()
+        .map(|(line, _)| wrap_rows(line, term_cols))
+        .sum();
+
+    assistant_rows + footer_rows + candidate_rows + explanation_rows
+}
+
+/// The expanded reasoning of a block at most `max_rows` high: its wrapped rows and how
+/// many of them fit. None when not even its markers fit, so the toggle hint stands in.
+fn reasoning_window(
+    lang: &Language,
+    view: &ReplyView,
+    reasoning: &str,
+    term_cols: usize,
+    max_rows: usize,
+) -> Option<(Vec<String>, usize)> {
+    let start_rows = wrap_rows(t(lang, MessageKey::ReasoningStart), term_cols);
+    let end_rows = wrap_rows(t(lang, MessageKey::ReasoningEnd), term_cols);
+    let reserved = answer_rows(lang, view, term_cols) + start_rows + end_rows;
+    if reserved >= max_rows {
+        return None;
+    }
+    let budget = max_rows - reserved;
+
+    let reasoning = expanded_reasoning(reasoning, view.reasoning_wrap, term_cols);
+    let rows: Vec<String> = reasoning
+        .lines()
+        .flat_map(|line| wrap_text(line, term_cols))
+        .collect();
+    if rows.len() <= budget {
+        let visible = rows.len();
+        return Some((rows, visible));
+    }
+    // The hint that part is hidden takes rows of its own
+    let truncated_rows = wrap_rows(t(lang, MessageKey::ReasoningTruncated), term_cols);
+    Some((rows, budget.saturating_sub(truncated_rows)))
+}
+
+/// How far the expanded reasoning can scroll up in a terminal of this size, and the rows
+/// a page moves; None when it fits or is not expanded.
+fn reasoning_scroll_range(
+    lang: &Language,
+    view: &ReplyView,
+    cols: usize,
+    rows: usize,
+) -> Option<(usize, usize)> {
+    let reasoning = view.reasoning.filter(|_| view.reasoning_expanded)?;
+    let (lines, visible) = reasoning_window(lang, view, reasoning, cols, rows)?;
+    let max_scroll = lines.len() - visible;
+    (max_scroll > 0).then_some((max_scroll, visible.max(1)))
+}
+
+/// Pre-compute the number of rows needed to render the reply block (without truncation)
+fn calculate_reply_rows(lang: &Language, view: &ReplyView, term_cols: usize) -> usize {
+    let ReplyView {
+        reasoning,
+        reasoning_expanded,
+        reasoning_wrap,
+        toggle_hint,
+        ..
+    } = *view;
+
+    let reasoning_rows = if let Some(reasoning) = reasoning {
+        if reasoning_expanded {
+            let reasoning_start = t(lang, MessageKey::ReasoningStart);
+            let reasoning_end = t(lang, MessageKey::ReasoningEnd);
+            let start_rows = wrap_rows(reasoning_start, term_cols);
+            let end_rows = wrap_rows(reasoning_end, term_cols);
+
+            let reasoning = expanded_reasoning(reasoning, reasoning_wrap, term_cols);
+            // Number of rows for reasoning content
+            let content_rows: usize = reasoning.lines().map(|l| wrap_rows(l, term_cols)).sum();
+
+            // Possible truncation hint
+            let truncated_hint = t(lang, MessageKey::ReasoningTruncated);
+            let truncated_rows = wrap_rows(truncated_hint, term_cols);
+
+            start_rows + content_rows + truncated_rows + end_rows
+        } else {
+            let hint = toggle_hint;
+            wrap_rows(hint, term_cols)
+        }
+    } else {
+        0
+    };
+
+    reasoning_rows + answer_rows(lang, view, term_cols)
+}
+
+/// Ensure there is enough space to render content, scrolling the terminal when needed.
+/// Returns the actual number of lines scrolled.
+fn ensure_scroll_space(stdout: &mut io::Stdout, needed_rows: usize) -> Result<usize> {
+    let (_, term_rows) = terminal::size().unwrap_or((80, 24));
+    let (_, cur_row) = cursor::position().unwrap_or((0, 0));
+
+    // Available rows below the cursor (minus one line reserved for the input prompt)
+    let available_below = (term_rows.saturating_sub(cur_row + 1)) as usize;
+
+    if needed_rows > available_below {
+        // Need to scroll: compute how many lines must be freed
+        let scroll_lines = needed_rows - available_below;
+
+        // Print blank lines so the terminal scrolls upward automatically
+        for _ in 0..scroll_lines {
+            print!("\r\n");
+        }
+        stdout.flush()?;
+
+        // Move the cursor back to the rendering start position
+        execute!(stdout, cursor::MoveUp(scroll_lines as u16))?;
+
+        Ok(scroll_lines)
+    } else {
+        Ok(0)
+    }
+}
+
+/// Clear the reply block and the input below it, then render `view` in its place.
+/// Returns the rows the new block uses.
+fn redraw_reply(
+    area: &mut PromptArea,
+    reply_rows: usize,
+    lang: &Language,
+    color: ColorMode,
+    view: &ReplyView,
+) -> Result<usize> {
+    let (cols, rows) = terminal::size().unwrap_or((80, 24));
+    let mut stdout = io::stdout();
+
+    // Step 1: clear the previous reply block and the input below it
+    let up = reply_rows + area.cursor_row;
+    execute!(stdout, cursor::MoveToColumn(0))?;
+    execute!(
+        stdout,
+        cursor::MoveUp(up.min(u16::MAX as usize) as u16),
+        Clear(ClearType::FromCursorDown)
+    )?;
+    *area = PromptArea::default();
+
+    // Step 2: pre-compute how many rows are needed
+    let needed_rows = calculate_reply_rows(lang, view, cols as usize);
+
+    // Step 3: ensure there is enough space
+    ensure_scroll_space(&mut stdout, needed_rows)?;
+
+    // Step 4: render the reply block (using full terminal height as max_rows)
+    let used_rows = render_reply_block(lang, color, view, cols as usize, rows as usize);
+    io::stdout().flush().ok();
+    Ok(used_rows)
+}
+
+fn render_reply_block(
+    lang: &Language,
+    color: ColorMode,
+    view: &ReplyView,
+    term_cols: usize,
+    max_rows: usize,
+) -> usize {
+    let ReplyView {
+        reasoning,
+        reasoning_expanded,
+        reasoning_scroll,
+        answer,
+        candidates,
+        selected,
+        toggle_hint,
+        usage,
+        timing,
+        ..
+    } = *view;
+    // Wrapped before the markdown is rendered, which only ever makes a line narrower
+    let assistant_prompt = t(lang, MessageKey::PromptAssistant);
+    let assistant_lines = wrap_text(&format!("{assistant_prompt}{answer}"), term_cols);
+    let assistant_rows = assistant_lines.len();
+
+    let candidates = candidate_lines(lang, candidates, selected);
+    let candidate_rows: usize = candidates
+        .iter()
+        .map(|(line, _)| wrap_rows(line, term_cols))
+        .sum();
+
+    let footer = footer_lines(lang, usage, timing);
+    let footer_rows: usize = footer.iter().map(|line| wrap_rows(line, term_cols)).sum();
+
+    let explanation = explanation_lines(view);
+    let explanation_rows: usize = explanation
+        .iter()
+        .map(|(line, _)| wrap_rows(line, term_cols))
+        .sum();
+
+    let mut used_rows = 0usize;
+
+    if let Some(reasoning) = reasoning {
+        let window = reasoning_expanded
+            .then(|| reasoning_window(lang, view, reasoning, term_cols, max_rows))
+            .flatten();
+        if let Some((lines, visible)) = window {
+            let reasoning_start = t(lang, MessageKey::ReasoningStart);
+            let reasoning_end = t(lang, MessageKey::ReasoningEnd);
+
+            print!("{}\r\n", style(reasoning_start, Style::Dim, color));
+            used_rows += wrap_rows(reasoning_start, term_cols);
+            if visible < lines.len() {
+                let truncated_hint = t(lang, MessageKey::ReasoningTruncated);
+                print!("{}\r\n", style(truncated_hint, Style::Dim, color));
+                used_rows += wrap_rows(truncated_hint, term_cols);
+            }
+
+            // The end shows unless scrolled up; the scroll never goes past the start
+            let end = lines.len() - reasoning_scroll.min(lines.len() - visible);
+            for line in &lines[end - visible..end] {
+                print!("{}\r\n", style(line, Style::Dim, color));
+            }
+            used_rows += visible;
+
+            print!("{}\r\n", style(reasoning_end, Style::Dim, color));
+            used_rows += wrap_rows(reasoning_end, term_cols);
+        } else {
+            let hint = toggle_hint;
+            print!("{}\r\n", style(hint, Style::Dim, color));
+            used_rows += wrap_rows(hint, term_cols);
+        }
+    }
+
+    for line in &assistant_lines {
+        let (line, _) = render_inline_markdown(line, color);
+        print!("{line}\r\n");
+    }
+    used_rows += assistant_rows;
+
+    for line in &footer {
+        print!("{}\r\n", style(line, Style::Dim, color));
+    }
+    used_rows += footer_rows;
+
+    for (line, is_selected) in candidates {
+        if is_selected {
+            print!("\x1b[2K{}\r\n", style(&line, Style::Bold, color));
+        } else {
+            print!("\x1b[2K{line}\r\n");
+        }
+    }
+    used_rows += candidate_rows;
+
+    for (line, is_hint) in explanation {
+        if is_hint {
+            print!("{}\r\n", style(line, Style::Dim, color));
+        } else {
+            print!("{line}\r\n");
+        }
+    }
+    used_rows += explanation_rows;
+
+    used_rows
+}
+
+/// Prefix a question with recent shell output so the model can see what just happened.
+fn with_terminal_context(question: &str, output: &str) -> String {
+    format!(
+        "Recent terminal output:\n```\n{}\n```\n\n{question}",
+        output.trim_end()
+    )
+}
+
+/// The question the agentic loop asks after running `cmd`, carrying its `output`.
+pub fn agentic_follow_up(cmd: &str, output: &str) -> String {
+    format!(
+        "I ran `{cmd}`. Its output:\n```\n{}\n```\n\nWhat is the next step? If the task is \
+         done, say so and suggest no command.",
+        output.trim_end()
+    )
+}
+
+/// Ask before injecting a command that matched one of the dangerous patterns.
+pub fn confirm_dangerous(cmd: &str, lang: &Language, color: ColorMode) -> Result<bool> {
+    let warning = style(t(lang, MessageKey::DangerousCommand), Style::Yellow, color);
+    confirm(
+        &format!("{warning}{cmd}"),
+        t(lang, MessageKey::ConfirmInject),
+    )
+}
+
+/// Ask before the agentic loop runs a command, with the dangerous-command warning when
+/// it matched one of the patterns.
+pub fn confirm_run(cmd: &str, dangerous: bool, lang: &Language, color: ColorMode) -> Result<bool> {
+    let label = if dangerous {
+        style(t(lang, MessageKey::DangerousCommand), Style::Yellow, color)
+    } else {
+        style(t(lang, MessageKey::AgenticRun), Style::Bold, color)
+    };
+    confirm(&format!("{label}{cmd}"), t(lang, MessageKey::ConfirmRun))
+}
+
+/// Show `line` and `question`, then wait for a key; only y accepts.
+fn confirm(line: &str, question: &str) -> Result<bool> {
+    print!("\r\x1b[2K{line}\r\n{question}");
+    io::stdout().flush().ok();
+
+    loop {
+        if let Event::Key(key) = event::read()?
+            && key.kind == KeyEventKind::Press
+        {
+            let accepted = matches!(key.code, KeyCode::Char('y' | 'Y'));
+            print!("\r\n");
+            io::stdout().flush().ok();
+            return Ok(accepted);
+        }
+    }
+}
+
+/// Progress from the worker thread running `LLMClient::chat`.
+enum ChatEvent {
+    Reasoning(String),
+    Answer(String),
+    Usage(Usage),
+    Restart,
+    Compacted(Vec<ChatMessage>),
+    Done(Result<ChatReply, LlmError>),
+}
+
+/// Passes what the worker thread's request streams on to the UI thread.
+struct ChannelSink(mpsc::Sender<ChatEvent>);
+
+impl ChatSink for ChannelSink {
+    fn on_reasoning(&mut self, text: &str) {
+        let _ = self.0.send(ChatEvent::Reasoning(text.to_string()));
+    }
+
+    fn on_answer(&mut self, text: &str) {
+        let _ = self.0.send(ChatEvent::Answer(text.to_string()));
+    }
+
+    fn on_usage(&mut self, usage: Usage) {
+        let _ = self.0.send(ChatEvent::Usage(usage));
+    }
+
+    fn on_restart(&mut self) {
+        let _ = self.0.send(ChatEvent::Restart);
+    }
+}
+
+/// Added to the system prompt for a question asking only for an explanation.
+const EXPLAIN_ONLY_PROMPT: &str = "This question asks for an explanation only: \
+suggest no command and give the explanation as your answer.";
+
+/// A question starting with `?` asks for an explanation instead of a command; this
+/// returns it without the `?`.
+fn explain_only(line: &str) -> Option<&str> {
+    line.strip_prefix('?')
+        .map(str::trim_start)
+        .filter(|question| !question.is_empty())
+}
+
+/// How much of `history` precedes the turn that asked `question`, when that turn is
+/// the last one; regenerating sends only this part and then replaces the turn.
+fn regenerate_context(history: &[ChatMessage], question: &str) -> Option<usize> {
+    match history {
+        [.., user, assistant]
+            if user.role == Role::User
+                && user.content == question
+                && assistant.role == Role::Assistant =>
+        {
+            Some(history.len() - 2)
+        }
+        _ => None,
+    }
+}
+
+/// Run the request on a worker thread so Esc/Ctrl+C can abort it, drawing what
+/// streams in on `preview`; its spinner ticks whenever no event arrived for a
+/// polling interval. On abort the worker is told to stop and left to finish in the
+/// background; its remaining output is discarded and `LlmError::Cancelled` is returned.
+///
+/// The history is compacted first when the client is set up to; a failed summary
+/// is only logged and the full history sent. The compacted history, if any, is
+/// returned with the reply so the caller can keep it.
+fn request_reply(
+    llm: &Arc<dyn LLMClient>,
+    system_prompt: String,
+    history: &[ChatMessage],
+    request: String,
+    preview: &mut LivePreview,
+) -> Result<(ChatReply, Option<Vec<ChatMessage>>)> {
+    let started = Instant::now();
+    let (tx, rx) = mpsc::channel();
+    let cancel = Arc::new(AtomicBool::new(false));
+    {
+        let llm = Arc::clone(llm);
+        let history = history.to_vec();
+        let cancel = Arc::clone(&cancel);
+        thread::spawn(move || {
+            let history = match llm.compact_history(&history, &cancel) {
+                Ok(compacted) if compacted.len() < history.len() => {
+                    let _ = tx.send(ChatEvent::Compacted(compacted.clone()));
+                    compacted
+                }
+                Ok(_) => history,
+                Err(e) => {
+                    crate::log::record("compaction failed", &format!("{e:#}"));
+                    history
+                }
+            };
+            let mut sink = ChannelSink(tx.clone());
+            let result = llm.chat(&system_prompt, &history, &request, &mut sink, &cancel);
+            let _ = tx.send(ChatEvent::Done(result));
+        });
+    }
+
+    let mut compacted = None;
+    loop {
+        match rx.recv_timeout(Duration::from_millis(20)) {
+            Ok(ChatEvent::Reasoning(text)) => preview.on_reasoning(&text),
+            Ok(ChatEvent::Answer(text)) => preview.on_answer(&text),
+            Ok(ChatEvent::Usage(usage)) => preview.on_usage(usage),
+            Ok(ChatEvent::Restart) => preview.on_restart(),
+            Ok(ChatEvent::Compacted(history)) => compacted = Some(history),
+            Ok(ChatEvent::Done(result)) => return Ok((result?, compacted)),
+            Err(RecvTimeoutError::Timeout) => preview.tick(started.elapsed()),
+            Err(RecvTimeoutError::Disconnected) => bail!("LLM worker thread exited"),
+        }
+
+        // Keys typed while waiting are dropped, except the ones that abort
+        while event::poll(Duration::ZERO)? {
+            if let Event::Key(key) = event::read()?
+                && key.kind == KeyEventKind::Press
+                && (key.code == KeyCode::Esc
+                    || (key.code == KeyCode::Char('c')
+                        && key.modifiers.contains(KeyModifiers::CONTROL)))
+            {
+                cancel.store(true, Ordering::Relaxed);
+                return Err(LlmError::Cancelled.into());
+            }
+        }
+    }
+}
+
+/// How chat mode ended.
+#[derive(Debug, PartialEq, Eq)]
+pub enum ChatOutcome {
+    /// Inject this command as `preference.inject_mode` says
+    Accept(String),
+    /// This command was staged on the shell's line and stays there
+    Staged(String),
+    /// Nothing to inject
+    Exit,
+}
+
+impl ChatOutcome {
+    /// What is typed into the shell once chat has ended this way. Each starts with
+    /// Ctrl+U, so Enter only ever runs the accepted command or an empty line.
+    pub fn shell_input(&self, inject_mode: InjectMode) -> Vec<u8> {
+        match self {
+            ChatOutcome::Accept(cmd) => inject_command(cmd, inject_mode == InjectMode::Execute),
+            // Typed again so the shell redraws the line whose echo was muted
+            ChatOutcome::Staged(cmd) => inject_command(cmd, false),
+            // An empty line brings back a fresh prompt; Enter alone would run whatever
+            // was left on the line, a staged command included
+            ChatOutcome::Exit => inject_command("", true),
+        }
+    }
+}
+
+pub fn chat_mode(
+    llm: &Arc<dyn LLMClient>,
+    session: &mut PtySession,
+    options: &ChatOptions,
+    history: &mut Vec<ChatMessage>,
+    lang: &Language,
+    follow_up: Option<String>,
+) -> Result<ChatOutcome> {
+    let mut staged = None;
+    let accepted = run_chat(llm, session, options, history, lang, follow_up, &mut staged);
+    session.mute_output(false);
+    Ok(match accepted? {
+        Some(command) => ChatOutcome::Accept(command),
+        None => staged.map_or(ChatOutcome::Exit, ChatOutcome::Staged),
+    })
+}
+
+/// The chat loop; returns the accepted command, leaving the last staged one in `staged`.
+/// A `follow_up` question is sent right away, without being typed.
+fn run_chat(
+    llm: &Arc<dyn LLMClient>,
+    session: &mut PtySession,
+    options: &ChatOptions,
+    history: &mut Vec<ChatMessage>,
+    lang: &Language,
+    mut follow_up: Option<String>,
+    staged: &mut Option<String>,
+) -> Result<Option<String>> {
+    match options.welcome(lang) {
+        _ if follow_up.is_some() => {
+            let note = style(
+                t(lang, MessageKey::AgenticFollowUp),
+                Style::Dim,
+                options.color,
+            );
+            print!("\r\n\x1b[2K{note}");
+        }
+        Some(welcome) => print!("\r\n\x1b[2K{welcome}\r\n"),
+        None => print!("\r\n\x1b[2K"),
+    }
+
+    // A follow-up already carries the output that matters
+    let terminal_context = options
+        .include_terminal_context
+        .then(|| session.recent_output())
+        .filter(|output| follow_up.is_none() && !output.trim().is_empty())
+        .map(|output| redact(&output, &options.redact_patterns));
+    let mut state = ChatState::new(options, lang);
+    let mut area = PromptArea::default();
+
+    if follow_up.is_none() {
+        prompt(&mut area, &state.editor, state.mode, lang, options.color);
+    }
+
+    loop {
+        let action = if let Some(line) = follow_up.take() {
+            ChatAction::Submit(Some(line))
+        } else {
+            // Poll rather than block, so a shell that exits meanwhile ends chat too
+            if !event::poll(Duration::from_millis(100))? {
+                if session.child_exited() {
+                    area.leave();
+                    return Ok(None);
+                }
+                continue;
+            }
+            match event::read()? {
+                Event::Key(key)
+                    if matches!(key.kind, KeyEventKind::Press | KeyEventKind::Repeat) =>
+                {
+                    state.apply_key(&key, history)
+                }
+                Event::Paste(pasted) => state.apply_paste(&pasted),
+                // The block was laid out for the old width; clear it with the cached row
+                // count and lay it out again, scrolling if it now needs more rows
+                Event::Resize(..) if state.reply_rows > 0 => ChatAction::Redraw,
+                Event::Resize(..) => ChatAction::Prompt,
+                _ => ChatAction::None,
+            }
+        };
+
+        // A question to send: typed and submitted, or the last one again
+        let (line, mut turn_start) = match action {
+            ChatAction::None => continue,
+            ChatAction::Prompt => {
+                prompt(&mut area, &state.editor, state.mode, lang, options.color);
+                continue;
+            }
+            ChatAction::Redraw => {
+                if state.reply_rows > 0 {
+                    state.reply_rows = redraw_reply(
+                        &mut area,
+                        state.reply_rows,
+                        lang,
+                        options.color,
+                        &state.view(),
+                    )?;
+                }
+                prompt(&mut area, &state.editor, state.mode, lang, options.color);
+                continue;
+            }
+            ChatAction::Submit(line) => {
+                area.leave();
+                let Some(line) = line else {
+                    prompt(&mut area, &state.editor, state.mode, lang, options.color);
+                    continue;
+                };
+                // History entries before this turn; a regenerated turn replaces the last pair
+                (line, history.len())
+            }
+            ChatAction::Regenerate {
+                question,
+                context_len,
+            } => {
+                // Clear the reply block and the input below it; the new reply takes its place
+                let up = state.reply_rows + area.cursor_row;
+                execute!(
+                    io::stdout(),
+                    cursor::MoveToColumn(0),
+                    cursor::MoveUp(up.min(u16::MAX as usize) as u16),
+                    Clear(ClearType::FromCursorDown)
+                )?;
+                area = PromptArea::default();
+                state.reply_rows = 0;
+                (question, context_len)
+            }
+            ChatAction::Accept { command, newline } => {
+                if newline {
+                    area.leave();
+                }
+                return Ok(Some(command));
+            }
+            ChatAction::Stage(command) => {
+                // The shell echoes the line wherever our cursor is, so its output stays
+                // off the screen until chat ends and the line is typed again
+                session.mute_output(true);
+                session.inject(&command, InjectMode::Prefill)?;
+                *staged = Some(command);
+
+                // A note replaces the input; it counts as part of the reply block
+                // so redrawing the reply clears it
+                let mut stdout = io::stdout();
+                if area.cursor_row > 0 {
+                    execute!(stdout, cursor::MoveUp(area.cursor_row as u16))?;
+                }
+                execute!(
+                    stdout,
+                    cursor::MoveToColumn(0),
+                    Clear(ClearType::FromCursorDown)
+                )?;
+                let note = style(
+                    t(lang, MessageKey::CommandStaged),
+                    Style::Dim,
+                    options.color,
+                );
+                print!("{note}\r\n");
+                if state.reply_rows > 0 {
+                    state.reply_rows += 1;
+                }
+                area = PromptArea::default();
+                prompt(&mut area, &state.editor, state.mode, lang, options.color);
+                continue;
+            }
+            ChatAction::Exit => return Ok(None),
+            ChatAction::Interrupt => {
+                // Ctrl+C also discards whatever is on the shell's line
+                session.interrupt()?;
+                *staged = None;
+                return Ok(None);
+            }
+            ChatAction::ScrollReasoning { up } => {
+                let (cols, rows) = terminal::size().unwrap_or((80, 24));
+                let range =
+                    reasoning_scroll_range(lang, &state.view(), cols as usize, rows as usize);
+                let Some((max_scroll, page)) = range else {
+                    continue;
+                };
+                let scroll = if up {
+                    (state.reasoning_scroll + page).min(max_scroll)
+                } else {
+                    state.reasoning_scroll.min(max_scroll).saturating_sub(page)
+                };
+                if scroll != state.reasoning_scroll {
+                    state.reasoning_scroll = scroll;
+                    state.reply_rows = redraw_reply(
+                        &mut area,
+                        state.reply_rows,
+                        lang,
+                        options.color,
+                        &state.view(),
+                    )?;
+                    prompt(&mut area, &state.editor, state.mode, lang, options.color);
+                }
+                continue;
+            }
+            ChatAction::ClearScreen => {
+                // The reply stays cached, so accepting still works; only the
+                // on-screen block goes away
+                execute!(io::stdout(), Clear(ClearType::All), cursor::MoveTo(0, 0))?;
+                state.reply_rows = 0;
+                area = PromptArea::default();
+                prompt(&mut area, &state.editor, state.mode, lang, options.color);
+                continue;
+            }
+        };
+
+        let explain = explain_only(&line);
+        let question = explain.unwrap_or(&line).to_string();
+
+        let request = match &terminal_context {
+            Some(output) => with_terminal_context(&question, output),
+            None => question.clone(),
+        };
+        let mut system = options.system_prompt(&session.current_dir());
+        if explain.is_some() {
+            system.push('\n');
+            system.push_str(EXPLAIN_ONLY_PROMPT);
+        }
+        // A spinner, then the streamed reasoning or answer, until the reply block replaces it
+        let mut preview = LivePreview::new(lang, options);
+        let started = Instant::now();
+        let result = request_reply(llm, system, &history[..turn_start], request, &mut preview);
+        preview.clear();
+
+        let response: ChatReply = match result {
+            Ok((response, compacted)) => {
+                // The summary stands in for the older turns from now on
+                if let Some(compacted) = compacted {
+                    let compacted_len = compacted.len();
+                    history.splice(..turn_start, compacted);
+                    turn_start = compacted_len;
+                }
+                response
+            }
+            Err(e) if matches!(e.downcast_ref(), Some(LlmError::Cancelled)) => {
+                // Drop whatever was streamed and let the question be edited or resent
+                prompt(&mut area, &state.editor, state.mode, lang, options.color);
+                continue;
+            }
+            Err(e) => {
+                // Report the failure and keep the question in the buffer for a retry
+                let error_prefix = t(lang, MessageKey::RequestFailed);
+                let message = match e.downcast_ref::<LlmError>() {
+                    Some(error) => format!("{error_prefix}{}", error.localized(lang)),
+                    None => format!("{error_prefix}{e:#}"),
+                };
+                print!("{}\r\n", style(&message, Style::Red, options.color));
+                prompt(&mut area, &state.editor, state.mode, lang, options.color);
+                continue;
+            }
+        };
+
+        crate::log::record("reply", &format!("{response:#?}"));
+
+        // Regenerating replaces the previous answer rather than adding a turn
+        let regenerated = turn_start < history.len();
+        state.push_reply(
+            PastReply {
+                // Save full reasoning so it can be expanded later
+                reasoning: response
+                    .reasoning
+                    .clone()
+                    .filter(|_| options.reasoning != ReasoningDisplay::Hidden),
+                answer: response.text.clone(),
+                // Number keys pick among at most nine candidates
+                candidates: if explain.is_some() {
+                    Vec::new()
+                } else {
+                    response
+                        .suggested_commands
+                        .iter()
+                        .take(9)
+                        .cloned()
+                        .collect()
+                },
+                usage: response.usage,
+                timing: options.show_timing.then(|| started.elapsed()),
+                explanation: response.explanation.clone(),
+            },
+            regenerated,
+        );
+
+        let mut stdout = io::stdout();
+        execute!(
+            stdout,
+            cursor::MoveToColumn(0),
+            Clear(ClearType::FromCursorDown)
+        )?;
+
+        let (cols, rows) = terminal::size().unwrap_or((80, 24));
+
+        let view = state.view();
+        // Pre-compute how many rows are needed
+        let needed_rows = calculate_reply_rows(lang, &view, cols as usize);
+
+        // Ensure there is enough space
+        ensure_scroll_space(&mut stdout, needed_rows)?;
+
+        // Use full terminal height as max_rows (space has been ensured)
+        let max_rows = rows as usize;
+
+        state.reply_rows = render_reply_block(lang, options.color, &view, cols as usize, max_rows);
+        io::stdout().flush().ok();
+
+        history.truncate(turn_start);
+        history.push(ChatMessage {
+            role: Role::User,
+            content: question,
+        });
+        state.last_question = Some(line);
+        history.push(ChatMessage {
+            role: Role::Assistant,
+            content: response.text,
+        });
+        if let Some(store) = &options.history_store
+            && let Err(e) = store.save(history)
+        {
+            let error_prefix = t(lang, MessageKey::HistorySaveFailed);
+            let message = format!("{error_prefix}{e:#}");
+            print!("{}\r\n", style(&message, Style::Red, options.color));
+        }
+
+        if !regenerated {
+            state.editor.clear();
+        }
+        prompt(&mut area, &state.editor, state.mode, lang, options.color);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options() -> ChatOptions {
+        ChatOptions {
+            include_terminal_context: false,
+            history_store: None,
+            color: ColorMode::Never,
+            prompt_template: "{shell} in {cwd}".to_string(),
+            keys: KeyBindings::default(),
+            reasoning: ReasoningDisplay::Collapsed,
+            reasoning_wrap: false,
+            show_timing: false,
+            show_welcome: true,
+            welcome_text: None,
+            redact_patterns: Vec::new(),
+            system_info: SystemInfo {
+                os: "Linux".to_string(),
+                distro: "debian 12".to_string(),
+                arch: "x86_64".to_string(),
+                shell: "zsh".to_string(),
+                shell_version: "5.9".to_string(),
+                lang: "en-US".to_string(),
+                cwd: "/home/me".to_string(),
+                user: "me".to_string(),
+                hostname: "box".to_string(),
+                datetime: "2024-01-31T09:30:00+00:00".to_string(),
+                date: "2024-01-31".to_string(),
+            },
+        }
+    }
+
+    #[test]
+    fn test_system_prompt_uses_shell_cwd() {
+        assert_eq!(
+            options().system_prompt(Path::new("/tmp/project")),
+            "zsh in /tmp/project"
+        );
+    }
+
+    #[test]
+    fn test_welcome() {
+        let mut options = options();
+        let welcome = options.welcome(&Language::En).unwrap();
+        assert!(welcome.starts_with("[LLM chat]"));
+
+        options.welcome_text = Some("Ask away".to_string());
+        assert_eq!(options.welcome(&Language::En).as_deref(), Some("Ask away"));
+        options.show_welcome = false;
+        assert_eq!(options.welcome(&Language::En), None);
+    }
+
+    #[test]
+    fn test_reply_history() {
+        let reply = |answer: &str| PastReply {
+            answer: answer.to_string(),
+            ..Default::default()
+        };
+        let mut replies = ReplyHistory::default();
+        assert_eq!(replies.prev(), None);
+        replies.push(reply("one"), false);
+        replies.push(reply("two"), false);
+        replies.push(reply("two again"), true);
+        assert!(replies.is_latest());
+        assert_eq!(replies.prev(), Some(&reply("one")));
+        assert_eq!(replies.prev(), None);
+        assert!(!replies.is_latest());
+        assert_eq!(replies.next(), Some(&reply("two again")));
+        assert_eq!(replies.next(), None);
+
+        // A new reply shows up as the newest, wherever the user had scrolled to
+        replies.prev();
+        replies.push(reply("three"), false);
+        assert!(replies.is_latest());
+        assert_eq!(replies.prev(), Some(&reply("two again")));
+    }
+
+    #[test]
+    fn test_explain_only() {
+        assert_eq!(
+            explain_only("? what does tar -xzf do"),
+            Some("what does tar -xzf do")
+        );
+        assert_eq!(explain_only("?why"), Some("why"));
+        assert_eq!(explain_only("list files?"), None);
+        assert_eq!(explain_only("?  "), None);
+    }
+
+    #[test]
+    fn test_regenerate_context() {
+        let message = |role, content: &str| ChatMessage {
+            role,
+            content: content.to_string(),
+        };
+        let mut history = vec![
+            message(Role::User, "list files"),
+            message(Role::Assistant, "ls"),
+            message(Role::User, "only hidden ones"),
+            message(Role::Assistant, "ls -a"),
+        ];
+        assert_eq!(regenerate_context(&history, "only hidden ones"), Some(2));
+        assert_eq!(regenerate_context(&history, "list files"), None);
+        history.pop();
+        assert_eq!(regenerate_context(&history, "only hidden ones"), None);
+        assert_eq!(regenerate_context(&[], "list files"), None);
+    }
+
+    #[test]
+    fn test_candidate_lines() {
+        let one = vec!["ls -la".to_string()];
+        assert_eq!(
+            candidate_lines(&Language::En, &one, 0),
+            vec![("candidate: ls -la".to_string(), false)]
+        );
+
+        let two = vec!["ls -la".to_string(), "ls\n-lah".to_string()];
+        assert_eq!(
+            candidate_lines(&Language::En, &two, 1),
+            vec![
+                ("candidate: (press 1-2 to choose)".to_string(), false),
+                ("  1. ls -la".to_string(), false),
+                ("> 2. ls -lah".to_string(), true),
+            ]
+        );
+        assert!(candidate_lines(&Language::En, &[], 0).is_empty());
+    }
+
+    #[test]
+    fn test_char_width() {
+        for (c, width) in [('a', 1), ('\t', 0), ('\u{7F}', 0), ('中', 2), ('あ', 2)] {
+            assert_eq!(char_width(c), width, "{c:?}");
+        }
+        // Combining mark, zero-width joiner, arrow, box drawing, emoji, full-width A
+        let tricky = ['\u{301}', '\u{200D}', '→', '─', '\u{1F600}', 'Ａ'];
+        let widths: Vec<usize> = tricky.iter().map(|&c| char_width(c)).collect();
+        if cfg!(feature = "unicode-width") {
+            assert_eq!(widths, [0, 0, 1, 1, 2, 2]);
+            assert_eq!(display_width("cafe\u{301} → 中文"), 11);
+        } else {
+            assert_eq!(widths, [2, 2, 2, 2, 2, 2]);
+            assert_eq!(display_width("cafe\u{301} → 中文"), 14);
+        }
+    }
+
+    #[test]
+    fn test_shell_input() {
+        let accept = ChatOutcome::Accept("ls -la".to_string());
+        assert_eq!(accept.shell_input(InjectMode::Prefill), b"\x15ls -la");
+        assert_eq!(accept.shell_input(InjectMode::Execute), b"\x15ls -la\r");
+        let staged = ChatOutcome::Staged("make".to_string());
+        assert_eq!(staged.shell_input(InjectMode::Execute), b"\x15make");
+        assert_eq!(
+            ChatOutcome::Exit.shell_input(InjectMode::Prefill),
+            b"\x15\r"
+        );
+        assert_eq!(
+            ChatOutcome::Exit.shell_input(InjectMode::Execute),
+            b"\x15\r"
+        );
+    }
+
+    /// The lines a shell would run for `input`: Ctrl+U clears the line, Enter runs it.
+    fn submitted_lines(input: &[u8]) -> Vec<String> {
+        let mut lines = Vec::new();
+        let mut line = Vec::new();
+        for &byte in input {
+            match byte {
+                0x15 => line.clear(),
+                b'\r' => lines.push(String::from_utf8(std::mem::take(&mut line)).unwrap()),
+                _ => line.push(byte),
+            }
+        }
+        lines
+    }
+
+    #[test]
+    fn test_exit_after_staging_runs_nothing() {
+        let command = "rm -rf build".to_string();
+        // Staged in one visit (typed while in chat, then again on leaving), and the
+        // next visit is left without accepting anything
+        let mut input = inject_command(&command, false);
+        input.extend(ChatOutcome::Staged(command.clone()).shell_input(InjectMode::Execute));
+        input.extend(ChatOutcome::Exit.shell_input(InjectMode::Execute));
+        assert_eq!(submitted_lines(&input), [""]);
+
+        // Only an accepted command reaches Enter
+        input.extend(ChatOutcome::Accept(command.clone()).shell_input(InjectMode::Execute));
+        assert_eq!(submitted_lines(&input), ["", command.as_str()]);
+    }
+
+    #[test]
+    fn test_agentic_follow_up() {
+        assert_eq!(
+            agentic_follow_up("df -h", "Filesystem  Size\n/dev/sda1   50G\n\n"),
+            "I ran `df -h`. Its output:\n```\nFilesystem  Size\n/dev/sda1   50G\n```\n\n\
+             What is the next step? If the task is done, say so and suggest no command."
+        );
+    }
+
+    #[test]
+    fn test_truncate_tail_word_aware() {
+        // A cut word is dropped up to the next space
+        assert_eq!(truncate_tail_by_width_word_aware("hello world", 8), "world");
+        assert_eq!(
+            truncate_tail_by_width_word_aware("hello world", 20),
+            "hello world"
+        );
+        // Too long a word to skip is cut after all
+        let long = "x".repeat(40);
+        assert_eq!(truncate_tail_by_width_word_aware(&long, 10), &long[30..]);
+        // CJK breaks between any two characters
+        assert_eq!(
+            truncate_tail_by_width_word_aware("我们先看看磁盘", 5),
+            "磁盘"
+        );
+        assert_eq!(
+            truncate_tail_by_width_word_aware("检查 disk 使用量", 11),
+            "disk 使用量"
+        );
+        assert_eq!(
+            truncate_tail_by_width_word_aware("检查 disk 使用量", 10),
+            "使用量"
+        );
+        // Combining marks and joined emoji are not split from their base
+        assert_eq!(truncate_tail_by_width_word_aware("x\u{301}yz", 2), "yz");
+        assert_eq!(
+            truncate_tail_by_width_word_aware(
+                "ok \u{1F468}\u{200D}\u{1F469}\u{200D}\u{1F467} hi",
+                6
+            ),
+            " hi"
+        );
+        assert_eq!(
+            truncate_tail_by_width_word_aware("a \u{1F44D}\u{1F3FD} ok", 5),
+            " ok"
+        );
+    }
+
+    #[test]
+    fn test_soft_wrap_reasoning() {
+        // Short lines and existing breaks are left alone
+        assert_eq!(
+            soft_wrap_reasoning("First. Second.\nThird.", 80),
+            "First. Second.\nThird."
+        );
+
+        let long = "The user wants to list files. ls does that. Adding -la shows hidden ones too.";
+        assert_eq!(
+            soft_wrap_reasoning(long, 20),
+            "The user wants to list files. ls does that.\nAdding -la shows hidden ones too."
+        );
+        // A sentence longer than a paragraph is kept whole; abbreviations like 1.5 stay joined
+        assert_eq!(
+            soft_wrap_reasoning("Version 1.5 is fine. Ok.", 4),
+            "Version 1.5 is fine.\nOk."
+        );
+        assert_eq!(
+            soft_wrap_reasoning("用户想列出文件。ls 可以。", 6),
+            "用户想列出文件。\nls 可以。"
+        );
+        assert_eq!(expanded_reasoning("a. b.", false, 1), "a. b.");
+    }
+
+    #[test]
+    fn test_explanation_lines() {
+        let mut view = ReplyView {
+            reasoning: None,
+            reasoning_expanded: false,
+            reasoning_scroll: 0,
+            reasoning_wrap: false,
+            answer: "",
+            candidates: &[],
+            selected: 0,
+            toggle_hint: "",
+            usage: None,
+            timing: None,
+            explanation: Some("-s: summary\n-h: human-readable"),
+            explanation_expanded: false,
+            explanation_hint: "(Ctrl+X)",
+        };
+        assert_eq!(explanation_lines(&view), vec![("(Ctrl+X)", true)]);
+
+        view.explanation_expanded = true;
+        assert_eq!(
+            explanation_lines(&view),
+            vec![("-s: summary", false), ("-h: human-readable", false)]
+        );
+
+        view.explanation = None;
+        assert!(explanation_lines(&view).is_empty());
+    }
+
+    #[test]
+    fn test_reasoning_window() {
+        let reasoning = (1..=20)
+            .map(|n| format!("step {n}"))
+            .collect::<Vec<_>>()
+            .join("\n");
+        let mut view = ReplyView {
+            reasoning: Some(&reasoning),
+            reasoning_expanded: true,
+            reasoning_scroll: 0,
+            reasoning_wrap: false,
+            answer: "done",
+            candidates: &[],
+            selected: 0,
+            toggle_hint: "",
+            usage: None,
+            timing: None,
+            explanation: None,
+            explanation_expanded: false,
+            explanation_hint: "",
+        };
+        // Start and end markers, the truncation hint and the answer leave 6 of 10 rows
+        let (lines, visible) = reasoning_window(&Language::En, &view, &reasoning, 80, 10).unwrap();
+        assert_eq!((lines.len(), visible), (20, 6));
+        assert_eq!(
+            reasoning_scroll_range(&Language::En, &view, 80, 10),
+            Some((14, 6))
+        );
+
+        // Everything fits, so there is nothing to scroll
+        assert_eq!(reasoning_scroll_range(&Language::En, &view, 80, 40), None);
+        assert!(reasoning_window(&Language::En, &view, &reasoning, 80, 3).is_none());
+
+        view.reasoning_expanded = false;
+        assert_eq!(reasoning_scroll_range(&Language::En, &view, 80, 10), None);
+    }
+
+    #[test]
+    fn test_footer_lines() {
+        let usage = Usage {
+            prompt_tokens: 12,
+            completion_tokens: 3,
+        };
+        let timing = Duration::from_millis(2340);
+        assert!(footer_lines(&Language::En, None, None).is_empty());
+        assert_eq!(
+            footer_lines(&Language::En, None, Some(timing)),
+            ["took 2.3s"]
+        );
+        assert_eq!(
+            footer_lines(&Language::En, Some(usage), Some(timing)).len(),
+            2
+        );
+
+        // The timing line takes a row of its own
+        let mut view = ReplyView {
+            reasoning: None,
+            reasoning_expanded: false,
+            reasoning_scroll: 0,
+            reasoning_wrap: false,
+            answer: "done",
+            candidates: &[],
+            selected: 0,
+            toggle_hint: "",
+            usage: None,
+            timing: None,
+            explanation: None,
+            explanation_expanded: false,
+            explanation_hint: "",
+        };
+        let rows = calculate_reply_rows(&Language::En, &view, 80);
+        view.timing = Some(timing);
+        assert_eq!(calculate_reply_rows(&Language::En, &view, 80), rows + 1);
+    }
+
+    #[test]
+    fn test_wrap_text() {
+        assert_eq!(
+            wrap_text("list all files in the current directory", 16),
+            vec!["list all files", "in the current", "directory"]
+        );
+        assert_eq!(
+            wrap_text("first\n\nsecond", 80),
+            vec!["first", "", "second"]
+        );
+        assert_eq!(wrap_text("abcdefghij", 4), vec!["abcd", "efgh", "ij"]);
+        assert_eq!(wrap_text("", 10), vec![""]);
+    }
+
+    #[test]
+    fn test_wrap_text_wide_chars() {
+        // Two columns per character, so three fit in seven columns
+        assert_eq!(
+            wrap_text("查找大文件并删除", 7),
+            vec!["查找大", "文件并", "删除"]
+        );
+        assert_eq!(wrap_text("use find 查找", 10), vec!["use find", "查找"]);
+    }
+
+    #[test]
+    fn test_layout_single_line() {
+        let layout = layout_input(5, "ls -la", 6, 80);
+        assert_eq!(
+            layout,
+            InputLayout {
+                rows: 1,
+                cursor_row: 0,
+                cursor_col: 11,
+                wrap_pending: false,
+            }
+        );
+    }
+
+    #[test]
+    fn test_layout_multi_line_and_wrapping() {
+        // "you> " + "abc", then an indented 12-char line that wraps in 10 columns
+        let text = "abc\nabcdefghijkl";
+        let layout = layout_input(5, text, 4 + 7, 10);
+        assert_eq!(layout.rows, 1 + 2);
+        assert_eq!((layout.cursor_row, layout.cursor_col), (2, 2));
+
+        let layout = layout_input(5, "abc\n", 3, 10);
+        assert_eq!(layout.rows, 2);
+        assert_eq!((layout.cursor_row, layout.cursor_col), (0, 8));
+    }
+
+    #[test]
+    fn test_input_rows_match_layout() {
+        let text = "abc\nabcdefghijkl";
+        let rows = input_rows("you> ", text, 10);
+        assert_eq!(rows, ["you> abc", "     abcde", "fghijkl"]);
+        assert_eq!(rows.len(), layout_input(5, text, 0, 10).rows);
+
+        // An exactly full last row leaves the cursor on an empty one
+        let rows = input_rows("you> ", "abcde", 10);
+        assert_eq!(rows, ["you> abcde", ""]);
+        assert_eq!(rows.len(), layout_input(5, "abcde", 5, 10).rows);
+    }
+
+    #[test]
+    fn test_layout_taller_than_terminal() {
+        // Ten one-row lines in a terminal of five rows
+        let text = ["a"; 10].join("\n");
+        let at = |cursor| InputWindow::new(&layout_input(5, &text, cursor, 80), 5);
+        // Cursor on the last row: a marker above and the last four rows
+        assert_eq!(at(text.len()), InputWindow { top: 6, bottom: 10 });
+        // On the first row: the first four rows and a marker below
+        assert_eq!(at(0), InputWindow { top: 0, bottom: 4 });
+        // In the middle: markers on both sides of three rows, the cursor among them
+        assert_eq!(at(10), InputWindow { top: 3, bottom: 6 });
+
+        // An input that fits is shown whole
+        let layout = layout_input(5, &text, 0, 80);
+        assert_eq!(
+            InputWindow::new(&layout, 10),
+            InputWindow { top: 0, bottom: 10 }
+        );
+    }
+
+    #[test]
+    fn test_layout_exactly_full_row() {
+        let layout = layout_input(5, "abcde", 5, 10);
+        assert!(layout.wrap_pending);
+        assert_eq!(layout.rows, 2);
+        assert_eq!((layout.cursor_row, layout.cursor_col), (1, 0));
+    }
+}
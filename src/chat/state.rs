@@ -0,0 +1,571 @@
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+
+use super::editor::{InputHistory, LineEditor};
+use super::{
+    ChatOptions, InputMode, PastReply, ReplyHistory, ReplyView, explain_only, regenerate_context,
+};
+use crate::config::ReasoningDisplay;
+use crate::i18n::{Language, MessageKey, t};
+use crate::keys::KeyBindings;
+use crate::llm::ChatMessage;
+
+/// What a key asks chat mode to do once `ChatState` has been updated.
+#[derive(Debug, PartialEq, Eq)]
+pub(super) enum ChatAction {
+    /// Nothing changed
+    None,
+    /// The input changed; draw the prompt again
+    Prompt,
+    /// The reply changed; draw the reply block and the prompt again
+    Redraw,
+    /// Enter finished the question; `None` when it was blank
+    Submit(Option<String>),
+    /// Ask the question of the reply on screen again, sending `history[..context_len]`
+    Regenerate {
+        question: String,
+        context_len: usize,
+    },
+    /// Leave chat with this command. Enter moves below the input first, as for a question
+    Accept { command: String, newline: bool },
+    /// Put this command on the shell's line and keep chatting
+    Stage(String),
+    /// Leave chat without a command
+    Exit,
+    /// Leave chat and interrupt the shell's foreground process
+    Interrupt,
+    /// Clear the screen; the reply stays for accepting
+    ClearScreen,
+    /// Scroll the expanded reasoning a page toward its start, or back toward its end
+    ScrollReasoning { up: bool },
+}
+
+/// Everything chat mode keeps between keys except what is on screen, so key handling
+/// can be tested without a terminal.
+pub(super) struct ChatState {
+    keys: KeyBindings,
+    pub(super) editor: LineEditor,
+    pub(super) mode: InputMode,
+    /// The unfinished question while the suggested command is being edited
+    question_draft: String,
+    input_history: InputHistory,
+    /// The reply on screen
+    pub(super) reply: PastReply,
+    /// Index of the candidate that the accept key injects
+    selected: usize,
+    reasoning_expanded: bool,
+    /// Rows the expanded reasoning is scrolled up from its end
+    pub(super) reasoning_scroll: usize,
+    explanation_expanded: bool,
+    /// Whether each new reply starts with its reasoning expanded
+    expand_reasoning: bool,
+    reasoning_wrap: bool,
+    toggle_hint: String,
+    explanation_hint: String,
+    /// The question behind the reply on screen, sent again by the regenerate key
+    pub(super) last_question: Option<String>,
+    reply_history: ReplyHistory,
+    /// Rows of the reply block on screen, 0 when there is none
+    pub(super) reply_rows: usize,
+}
+
+impl ChatState {
+    pub(super) fn new(options: &ChatOptions, lang: &Language) -> Self {
+        let expand_reasoning = options.reasoning == ReasoningDisplay::Expanded;
+        Self {
+            keys: options.keys,
+            editor: LineEditor::default(),
+            mode: InputMode::Question,
+            question_draft: String::new(),
+            input_history: InputHistory::default(),
+            reply: PastReply::default(),
+            selected: 0,
+            reasoning_expanded: expand_reasoning,
+            reasoning_scroll: 0,
+            explanation_expanded: false,
+            expand_reasoning,
+            reasoning_wrap: options.reasoning_wrap,
+            toggle_hint: options
+                .keys
+                .relabel(t(lang, MessageKey::HintToggleReasoning)),
+            explanation_hint: options
+                .keys
+                .relabel(t(lang, MessageKey::HintToggleExplanation)),
+            last_question: None,
+            reply_history: ReplyHistory::default(),
+            reply_rows: 0,
+        }
+    }
+
+    /// The candidate the accept key would inject.
+    pub(super) fn command(&self) -> Option<&str> {
+        self.reply.candidates.get(self.selected).map(String::as_str)
+    }
+
+    /// The reply on screen, laid out for `render_reply_block`.
+    pub(super) fn view(&self) -> ReplyView<'_> {
+        ReplyView {
+            reasoning: self.reply.reasoning.as_deref(),
+            reasoning_expanded: self.reasoning_expanded,
+            reasoning_scroll: self.reasoning_scroll,
+            reasoning_wrap: self.reasoning_wrap,
+            answer: &self.reply.answer,
+            candidates: &self.reply.candidates,
+            selected: self.selected,
+            toggle_hint: &self.toggle_hint,
+            usage: self.reply.usage,
+            timing: self.reply.timing,
+            explanation: self.reply.explanation.as_deref(),
+            explanation_expanded: self.explanation_expanded,
+            explanation_hint: &self.explanation_hint,
+        }
+    }
+
+    /// Show a new reply, replacing the newest one when it was regenerated.
+    pub(super) fn push_reply(&mut self, reply: PastReply, replace_last: bool) {
+        self.reply_history.push(reply.clone(), replace_last);
+        self.show(reply);
+    }
+
+    /// Put `reply` on screen as it first appeared.
+    fn show(&mut self, reply: PastReply) {
+        self.reply = reply;
+        self.selected = 0;
+        self.reasoning_expanded = self.expand_reasoning;
+        self.reasoning_scroll = 0;
+        self.explanation_expanded = false;
+    }
+
+    /// Pasted text goes into the input as one line.
+    pub(super) fn apply_paste(&mut self, text: &str) -> ChatAction {
+        self.editor.insert_str(&text.replace(['\r', '\n'], " "));
+        ChatAction::Prompt
+    }
+
+    /// Update the state for a pressed key; `history` is the conversation so far.
+    pub(super) fn apply_key(&mut self, key: &KeyEvent, history: &[ChatMessage]) -> ChatAction {
+        let keys = self.keys;
+        let question_mode = self.mode == InputMode::Question;
+        let reply_shown = self.reply_rows > 0;
+        match key.code {
+            KeyCode::Enter if !question_mode => {
+                let edited = self.editor.text().trim();
+                if edited.is_empty() {
+                    return ChatAction::None;
+                }
+                ChatAction::Accept {
+                    command: edited.to_string(),
+                    newline: true,
+                }
+            }
+            KeyCode::Enter
+                if key
+                    .modifiers
+                    .intersects(KeyModifiers::ALT | KeyModifiers::SHIFT) =>
+            {
+                // Alt+Enter (or Shift+Enter where reported) starts a new line
+                self.editor.insert_char('\n');
+                ChatAction::Prompt
+            }
+            KeyCode::Enter => {
+                let line = self.editor.text().trim_end().to_string();
+                if line.is_empty() {
+                    self.editor.clear();
+                    return ChatAction::Submit(None);
+                }
+                self.input_history.push(line.clone());
+                ChatAction::Submit(Some(line))
+            }
+            _ if keys.regenerate.matches(key)
+                && question_mode
+                && self.reply_history.is_latest()
+                && reply_shown =>
+            {
+                let Some(line) = &self.last_question else {
+                    return ChatAction::None;
+                };
+                let question = explain_only(line).unwrap_or(line);
+                match regenerate_context(history, question) {
+                    Some(context_len) => ChatAction::Regenerate {
+                        question: line.clone(),
+                        context_len,
+                    },
+                    None => ChatAction::None,
+                }
+            }
+            _ if keys.toggle_reasoning.matches(key)
+                && self.reply.reasoning.is_some()
+                && reply_shown =>
+            {
+                self.reasoning_expanded = !self.reasoning_expanded;
+                self.reasoning_scroll = 0;
+                ChatAction::Redraw
+            }
+            KeyCode::PageUp | KeyCode::PageDown
+                if self.reasoning_expanded && self.reply.reasoning.is_some() && reply_shown =>
+            {
+                // How far a page goes depends on the terminal, so chat mode does the scrolling
+                ChatAction::ScrollReasoning {
+                    up: key.code == KeyCode::PageUp,
+                }
+            }
+            _ if keys.toggle_explanation.matches(key)
+                && self.reply.explanation.is_some()
+                && reply_shown =>
+            {
+                self.explanation_expanded = !self.explanation_expanded;
+                ChatAction::Redraw
+            }
+            KeyCode::Char(c @ '1'..='9')
+                if question_mode
+                    && self.editor.text().is_empty()
+                    && self.reply.candidates.len() > 1
+                    && (c as usize - '1' as usize) < self.reply.candidates.len()
+                    && reply_shown =>
+            {
+                // Pick which candidate the accept key injects
+                self.selected = c as usize - '1' as usize;
+                ChatAction::Redraw
+            }
+            _ if keys.stage_command.matches(key) => {
+                if question_mode {
+                    return match self.command() {
+                        Some(command) => ChatAction::Stage(command.to_string()),
+                        None => ChatAction::None,
+                    };
+                }
+                let edited = self.editor.text().trim().to_string();
+                if edited.is_empty() {
+                    return ChatAction::None;
+                }
+                // Staging ends the edit; the question typed before it comes back
+                self.editor.set(std::mem::take(&mut self.question_draft));
+                self.mode = InputMode::Question;
+                ChatAction::Stage(edited)
+            }
+            _ if keys.accept_command.matches(key) => {
+                let command = if question_mode {
+                    self.command()
+                } else {
+                    Some(self.editor.text().trim()).filter(|edited| !edited.is_empty())
+                };
+                match command {
+                    Some(command) => ChatAction::Accept {
+                        command: command.to_string(),
+                        newline: false,
+                    },
+                    None => ChatAction::None,
+                }
+            }
+            _ if keys.edit.matches(key) => {
+                // Toggle between editing the suggested command and the question
+                if !question_mode {
+                    self.editor.set(std::mem::take(&mut self.question_draft));
+                    self.mode = InputMode::Question;
+                } else if let Some(command) = self.command() {
+                    let command = command.to_string();
+                    self.question_draft = self.editor.text().to_string();
+                    self.editor.set(command);
+                    self.mode = InputMode::EditCommand;
+                } else {
+                    return ChatAction::None;
+                }
+                ChatAction::Prompt
+            }
+            KeyCode::Esc if !question_mode => {
+                self.editor.set(std::mem::take(&mut self.question_draft));
+                self.mode = InputMode::Question;
+                ChatAction::Prompt
+            }
+            _ if keys.exit_chat.matches(key) => ChatAction::Exit,
+            _ if keys.interrupt.matches(key) => ChatAction::Interrupt,
+            _ if keys.clear_screen.matches(key) => ChatAction::ClearScreen,
+            KeyCode::Backspace
+            | KeyCode::Delete
+            | KeyCode::Left
+            | KeyCode::Right
+            | KeyCode::Home
+            | KeyCode::End => {
+                let changed = match key.code {
+                    KeyCode::Backspace => self.editor.backspace(),
+                    KeyCode::Delete => self.editor.delete(),
+                    KeyCode::Left => self.editor.move_left(),
+                    KeyCode::Right => self.editor.move_right(),
+                    KeyCode::Home => self.editor.move_home(),
+                    _ => self.editor.move_end(),
+                };
+                if changed {
+                    ChatAction::Prompt
+                } else {
+                    ChatAction::None
+                }
+            }
+            _ if (keys.previous_reply.matches(key) || keys.next_reply.matches(key))
+                && question_mode
+                && reply_shown =>
+            {
+                let reply = if keys.previous_reply.matches(key) {
+                    self.reply_history.prev()
+                } else {
+                    self.reply_history.next()
+                };
+                let Some(reply) = reply.cloned() else {
+                    return ChatAction::None;
+                };
+                // Bring the reply back as it first appeared; accepting then injects its command
+                self.show(reply);
+                ChatAction::Redraw
+            }
+            KeyCode::Up if question_mode => match self.input_history.prev(self.editor.text()) {
+                Some(entry) => {
+                    self.editor.set(entry.to_string());
+                    ChatAction::Prompt
+                }
+                None => ChatAction::None,
+            },
+            KeyCode::Down if question_mode => match self.input_history.next() {
+                Some(entry) => {
+                    self.editor.set(entry);
+                    ChatAction::Prompt
+                }
+                None => ChatAction::None,
+            },
+            KeyCode::Char(c) => {
+                self.editor.insert_char(c);
+                ChatAction::Prompt
+            }
+            _ => ChatAction::None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::SystemInfo;
+    use crate::llm::Role;
+    use crate::style::ColorMode;
+
+    fn state() -> ChatState {
+        let options = ChatOptions {
+            include_terminal_context: false,
+            history_store: None,
+            color: ColorMode::Never,
+            prompt_template: String::new(),
+            system_info: SystemInfo {
+                os: "Linux".to_string(),
+                distro: String::new(),
+                arch: "x86_64".to_string(),
+                shell: "bash".to_string(),
+                shell_version: "5.2".to_string(),
+                lang: "en-US".to_string(),
+                cwd: "/home/me".to_string(),
+                user: "me".to_string(),
+                hostname: "box".to_string(),
+                datetime: "2024-01-31T09:30:00+00:00".to_string(),
+                date: "2024-01-31".to_string(),
+            },
+            keys: KeyBindings::default(),
+            reasoning: ReasoningDisplay::Collapsed,
+            reasoning_wrap: false,
+            show_timing: false,
+            show_welcome: true,
+            welcome_text: None,
+            redact_patterns: Vec::new(),
+        };
+        ChatState::new(&options, &Language::En)
+    }
+
+    fn key(code: KeyCode) -> KeyEvent {
+        KeyEvent::new(code, KeyModifiers::NONE)
+    }
+
+    fn ctrl(c: char) -> KeyEvent {
+        KeyEvent::new(KeyCode::Char(c), KeyModifiers::CONTROL)
+    }
+
+    fn reply(candidates: &[&str]) -> PastReply {
+        PastReply {
+            answer: "answer".to_string(),
+            candidates: candidates.iter().map(|c| c.to_string()).collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_accept_pending_command() {
+        let mut state = state();
+        assert_eq!(state.apply_key(&ctrl('l'), &[]), ChatAction::None);
+
+        state.push_reply(reply(&["ls -la"]), false);
+        assert_eq!(
+            state.apply_key(&ctrl('l'), &[]),
+            ChatAction::Accept {
+                command: "ls -la".to_string(),
+                newline: false
+            }
+        );
+    }
+
+    #[test]
+    fn test_stage_command() {
+        let mut state = state();
+        let alt_l = KeyEvent::new(KeyCode::Char('l'), KeyModifiers::ALT);
+        assert_eq!(state.apply_key(&alt_l, &[]), ChatAction::None);
+
+        state.push_reply(reply(&["ls"]), false);
+        assert_eq!(
+            state.apply_key(&alt_l, &[]),
+            ChatAction::Stage("ls".to_string())
+        );
+
+        // An edited command is staged as edited, and the question comes back
+        state.editor.set("half a question".to_string());
+        state.apply_key(&ctrl('e'), &[]);
+        state.apply_key(&key(KeyCode::Char('/')), &[]);
+        assert_eq!(
+            state.apply_key(&alt_l, &[]),
+            ChatAction::Stage("ls/".to_string())
+        );
+        assert_eq!(state.mode, InputMode::Question);
+        assert_eq!(state.editor.text(), "half a question");
+    }
+
+    #[test]
+    fn test_pick_candidate() {
+        let mut state = state();
+        state.push_reply(reply(&["ls", "ls -a"]), false);
+        // Only while the reply is on screen
+        assert_eq!(
+            state.apply_key(&key(KeyCode::Char('2')), &[]),
+            ChatAction::Prompt
+        );
+        state.editor.clear();
+        state.reply_rows = 3;
+        assert_eq!(
+            state.apply_key(&key(KeyCode::Char('2')), &[]),
+            ChatAction::Redraw
+        );
+        assert_eq!(state.command(), Some("ls -a"));
+        assert_eq!(
+            state.apply_key(&key(KeyCode::Char('3')), &[]),
+            ChatAction::Prompt
+        );
+    }
+
+    #[test]
+    fn test_edit_command() {
+        let mut state = state();
+        state.push_reply(reply(&["ls"]), false);
+        state.editor.set("half a question".to_string());
+
+        assert_eq!(state.apply_key(&ctrl('e'), &[]), ChatAction::Prompt);
+        assert_eq!(state.mode, InputMode::EditCommand);
+        assert_eq!(state.editor.text(), "ls");
+        state.apply_key(&key(KeyCode::Char(' ')), &[]);
+        state.apply_key(&key(KeyCode::Char('/')), &[]);
+        assert_eq!(
+            state.apply_key(&key(KeyCode::Enter), &[]),
+            ChatAction::Accept {
+                command: "ls /".to_string(),
+                newline: true
+            }
+        );
+
+        // Esc goes back to the question as it was
+        assert_eq!(state.apply_key(&key(KeyCode::Esc), &[]), ChatAction::Prompt);
+        assert_eq!(state.mode, InputMode::Question);
+        assert_eq!(state.editor.text(), "half a question");
+
+        // Once remapped, Ctrl+E no longer switches
+        state.keys.edit = crate::keys::KeyBinding {
+            code: KeyCode::F(2),
+            modifiers: KeyModifiers::NONE,
+        };
+        state.apply_key(&ctrl('e'), &[]);
+        assert_eq!(state.mode, InputMode::Question);
+        assert_eq!(
+            state.apply_key(&key(KeyCode::F(2)), &[]),
+            ChatAction::Prompt
+        );
+        assert_eq!(state.mode, InputMode::EditCommand);
+    }
+
+    #[test]
+    fn test_submit_question() {
+        let mut state = state();
+        assert_eq!(
+            state.apply_key(&key(KeyCode::Enter), &[]),
+            ChatAction::Submit(None)
+        );
+        for c in "df".chars() {
+            state.apply_key(&key(KeyCode::Char(c)), &[]);
+        }
+        assert_eq!(
+            state.apply_key(&key(KeyCode::Enter), &[]),
+            ChatAction::Submit(Some("df".to_string()))
+        );
+        // The submitted question is in the input history
+        state.editor.clear();
+        assert_eq!(state.apply_key(&key(KeyCode::Up), &[]), ChatAction::Prompt);
+        assert_eq!(state.editor.text(), "df");
+    }
+
+    #[test]
+    fn test_regenerate() {
+        let history = [
+            ChatMessage {
+                role: Role::User,
+                content: "disk usage".to_string(),
+            },
+            ChatMessage {
+                role: Role::Assistant,
+                content: "{}".to_string(),
+            },
+        ];
+        let mut state = state();
+        state.push_reply(reply(&["du -sh"]), false);
+        state.last_question = Some("disk usage".to_string());
+        // Not while the reply is off screen
+        assert!(!matches!(
+            state.apply_key(&ctrl('g'), &history),
+            ChatAction::Regenerate { .. }
+        ));
+
+        state.editor.clear();
+        state.reply_rows = 2;
+        assert_eq!(
+            state.apply_key(&ctrl('g'), &history),
+            ChatAction::Regenerate {
+                question: "disk usage".to_string(),
+                context_len: 0
+            }
+        );
+    }
+
+    #[test]
+    fn test_toggle_reasoning() {
+        let mut state = state();
+        state.push_reply(
+            PastReply {
+                reasoning: Some("thinking".to_string()),
+                ..reply(&[])
+            },
+            false,
+        );
+        state.reply_rows = 2;
+        assert!(!state.view().reasoning_expanded);
+        let page_up = key(KeyCode::PageUp);
+        assert_eq!(state.apply_key(&page_up, &[]), ChatAction::None);
+        assert_eq!(state.apply_key(&ctrl('r'), &[]), ChatAction::Redraw);
+        assert!(state.view().reasoning_expanded);
+        assert_eq!(
+            state.apply_key(&page_up, &[]),
+            ChatAction::ScrollReasoning { up: true }
+        );
+
+        // Collapsing forgets the scroll
+        state.reasoning_scroll = 3;
+        state.apply_key(&ctrl('r'), &[]);
+        assert_eq!(state.view().reasoning_scroll, 0);
+    }
+}
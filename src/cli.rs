@@ -0,0 +1,251 @@
+use anyhow::{Context, Result, bail};
+
+use crate::config::Overrides;
+
+pub const USAGE: &str = "\
+Usage:
+  shellm [options]                   Start the shell with the LLM copilot (Ctrl+L to chat)
+  shellm [options] ask <question>    Print a suggested command for <question> and exit
+  shellm [options] models            List the models the configured endpoint offers
+  shellm [options] doctor            Send a test request and report whether the config works
+  shellm config init [--force]       Write a commented config file to ~/.config/shellm/config.toml
+  shellm --version                   Show the version, build target and default shell
+  shellm --help                      Show this message
+
+Options:
+  --profile <name>    Use the [profiles.<name>] section of the config (or set SHELLM_PROFILE)
+  --model <name>      Override the model from the config and environment
+  --language <code>   Override the answer and UI language (e.g. en-US, zh-CN)
+  --cwd <dir>         Start the shell in <dir> instead of the current directory
+  --no-pty            Chat without wrapping a shell: questions from stdin, commands to stdout";
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Command {
+    /// Wrap the shell in a PTY (the default)
+    Run,
+    /// Ask a single question and print the suggested command
+    Ask(String),
+    /// List the models of the configured provider
+    Models,
+    /// Check the provider settings with a real request
+    Doctor,
+    /// Write a starting config file; `force` replaces an existing one
+    ConfigInit {
+        force: bool,
+    },
+    Version,
+    Help,
+}
+
+#[derive(Debug)]
+pub struct Cli {
+    pub command: Command,
+    /// Config profile selected with `--profile`
+    pub profile: Option<String>,
+    /// Settings that beat both the config file and the environment
+    pub overrides: Overrides,
+    /// `--no-pty`: chat over stdin/stdout instead of wrapping a shell
+    pub no_pty: bool,
+}
+
+pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Cli> {
+    let mut args = args.into_iter();
+    let mut profile = None;
+    let mut overrides = Overrides::default();
+    let mut no_pty = false;
+    let mut next = args.next();
+    // Global options come before the subcommand, as `--name value` or `--name=value`
+    while let Some(arg) = next.as_deref() {
+        let (name, inline_value) = match arg.split_once('=') {
+            Some((name, value)) => (name, Some(value.to_string())),
+            None => (arg, None),
+        };
+        let slot = match name {
+            "--profile" => &mut profile,
+            "--model" => &mut overrides.model,
+            "--language" => &mut overrides.language,
+            "--cwd" => &mut overrides.cwd,
+            "--no-pty" if inline_value.is_none() => {
+                no_pty = true;
+                next = args.next();
+                continue;
+            }
+            _ => break,
+        };
+        let value = match inline_value {
+            Some(value) => value,
+            None => args
+                .next()
+                .with_context(|| format!("missing value for {name}"))?,
+        };
+        *slot = Some(value);
+        next = args.next();
+    }
+
+    let command = match next.as_deref() {
+        None => Command::Run,
+        Some("-h" | "--help") => Command::Help,
+        Some("-V" | "--version") => Command::Version,
+        Some("ask") => {
+            let question = args.collect::<Vec<_>>().join(" ");
+            if question.trim().is_empty() {
+                bail!("missing question for `ask`\n\n{USAGE}");
+            }
+            Command::Ask(question)
+        }
+        Some("models") => {
+            if let Some(other) = args.next() {
+                bail!("unknown argument: {other}\n\n{USAGE}");
+            }
+            Command::Models
+        }
+        Some("doctor") => {
+            if let Some(other) = args.next() {
+                bail!("unknown argument: {other}\n\n{USAGE}");
+            }
+            Command::Doctor
+        }
+        Some("config") => match args.next().as_deref() {
+            Some("init") => {
+                let mut force = false;
+                for arg in args {
+                    match arg.as_str() {
+                        "-f" | "--force" => force = true,
+                        other => bail!("unknown argument: {other}\n\n{USAGE}"),
+                    }
+                }
+                Command::ConfigInit { force }
+            }
+            _ => bail!("expected `config init`\n\n{USAGE}"),
+        },
+        Some(other) => bail!("unknown argument: {other}\n\n{USAGE}"),
+    };
+    Ok(Cli {
+        command,
+        profile,
+        overrides,
+        no_pty,
+    })
+}
+
+/// What `--version` prints; `shell` is the shell that would be spawned by default.
+pub fn version_info(shell: &str) -> String {
+    format!(
+        "shellm {}\ntarget: {}\nshell: {shell}",
+        env!("CARGO_PKG_VERSION"),
+        env!("SHELLM_TARGET"),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_args(args: &[&str]) -> Result<Cli> {
+        parse(args.iter().map(|s| s.to_string()))
+    }
+
+    #[test]
+    fn test_no_args_runs_shell() {
+        assert_eq!(parse_args(&[]).unwrap().command, Command::Run);
+    }
+
+    #[test]
+    fn test_ask_joins_words() {
+        let cli = parse_args(&["ask", "find", "large files"]).unwrap();
+        assert_eq!(cli.command, Command::Ask("find large files".to_string()));
+    }
+
+    #[test]
+    fn test_ask_requires_question() {
+        assert!(parse_args(&["ask"]).is_err());
+        assert!(parse_args(&["ask", "  "]).is_err());
+    }
+
+    #[test]
+    fn test_version() {
+        assert_eq!(
+            parse_args(&["--version"]).unwrap().command,
+            Command::Version
+        );
+        assert_eq!(parse_args(&["-V"]).unwrap().command, Command::Version);
+        let info = version_info("/bin/zsh");
+        assert!(info.starts_with(&format!("shellm {}\n", env!("CARGO_PKG_VERSION"))));
+        assert!(info.ends_with("shell: /bin/zsh"));
+    }
+
+    #[test]
+    fn test_config_init() {
+        assert_eq!(
+            parse_args(&["config", "init"]).unwrap().command,
+            Command::ConfigInit { force: false }
+        );
+        assert_eq!(
+            parse_args(&["config", "init", "--force"]).unwrap().command,
+            Command::ConfigInit { force: true }
+        );
+        assert!(parse_args(&["config"]).is_err());
+        assert!(parse_args(&["config", "init", "--yes"]).is_err());
+    }
+
+    #[test]
+    fn test_models() {
+        let cli = parse_args(&["--profile", "home", "models"]).unwrap();
+        assert_eq!(cli.command, Command::Models);
+        assert!(parse_args(&["models", "gpt"]).is_err());
+    }
+
+    #[test]
+    fn test_doctor() {
+        let cli = parse_args(&["--model", "gpt-4o-mini", "doctor"]).unwrap();
+        assert_eq!(cli.command, Command::Doctor);
+        assert_eq!(cli.overrides.model.as_deref(), Some("gpt-4o-mini"));
+        assert!(parse_args(&["doctor", "now"]).is_err());
+    }
+
+    #[test]
+    fn test_unknown_argument() {
+        assert!(parse_args(&["--bogus"]).is_err());
+    }
+
+    #[test]
+    fn test_profile_option() {
+        let cli = parse_args(&["--profile", "work", "ask", "uptime"]).unwrap();
+        assert_eq!(cli.profile.as_deref(), Some("work"));
+        assert_eq!(cli.command, Command::Ask("uptime".to_string()));
+
+        let cli = parse_args(&["--profile=home"]).unwrap();
+        assert_eq!(cli.profile.as_deref(), Some("home"));
+        assert_eq!(cli.command, Command::Run);
+
+        assert!(parse_args(&["--profile"]).is_err());
+    }
+
+    #[test]
+    fn test_override_options() {
+        let cli = parse_args(&[
+            "--model",
+            "gpt-4o",
+            "--language=ja-JP",
+            "--cwd",
+            "/tmp",
+            "ask",
+            "a=b",
+        ])
+        .unwrap();
+        assert_eq!(cli.overrides.model.as_deref(), Some("gpt-4o"));
+        assert_eq!(cli.overrides.language.as_deref(), Some("ja-JP"));
+        assert_eq!(cli.overrides.cwd.as_deref(), Some("/tmp"));
+        assert_eq!(cli.command, Command::Ask("a=b".to_string()));
+        assert!(parse_args(&["--model"]).is_err());
+        assert!(!cli.no_pty);
+    }
+
+    #[test]
+    fn test_no_pty() {
+        let cli = parse_args(&["--no-pty", "--model", "gpt-4o"]).unwrap();
+        assert!(cli.no_pty);
+        assert_eq!(cli.command, Command::Run);
+        assert!(parse_args(&["--no-pty=1"]).is_err());
+    }
+}
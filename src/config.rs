@@ -1,18 +1,46 @@
 use std::collections::HashMap;
 use std::env;
-use std::path::PathBuf;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
 
-use anyhow::{Context, Result};
+use anyhow::{Context, Result, bail};
+use chrono::Local;
 use serde::Deserialize;
 
+use crate::i18n::{Language, MessageKey, t};
+use crate::keys::KeyBindings;
+use crate::llm::{Compaction, JsonMode, PromptMode, ReasoningEffort, Sampling};
+use crate::pty::InjectMode;
+use crate::safety::{DEFAULT_DANGEROUS_PATTERNS, DEFAULT_REDACT_PATTERNS};
+use crate::style::ColorMode;
+
+// Also available to custom templates: {distro}, {shell_version}, {user}, {hostname}, {date}, {datetime}
+// and {shell_guidance} (appended automatically when a template does not place it)
 const DEFAULT_PROMPT_TEMPLATE: &str = r#"You are a focused shell copilot on {os} ({arch}) running {shell}.
+The current directory is {cwd}.
 Please answer in {lang}.
 Always respond with a markdown code block containing a JSON object:
 ```json
-{"command": "<shell command>", "answer": "brief human-readable note"}
+{"commands": ["<shell command>"], "answer": "brief human-readable note", "explanation": "what each part of the command does"}
 ```
+If the request is ambiguous, add up to 3 alternatives after the best command.
 Prefer safe defaults; if unsure ask via answer."#;
 
+/// The default template for `prompt.mode = "plain"`.
+const DEFAULT_PLAIN_PROMPT_TEMPLATE: &str = r#"You are a focused shell copilot on {os} ({arch}) running {shell}.
+The current directory is {cwd}.
+Please answer in {lang}.
+Reply with the shell command in a fenced code block, then one short sentence on what it does:
+```{shell}
+<shell command>
+```
+Write no other code blocks.
+Prefer safe defaults; if unsure, ask a question instead of giving a command."#;
+
 #[derive(Debug, Deserialize, Default)]
 pub struct Config {
     #[serde(default)]
@@ -23,59 +51,565 @@ pub struct Config {
     pub shell: ShellConfig,
     #[serde(default)]
     pub preference: PreferenceConfig,
+    #[serde(default)]
+    pub keys: KeysConfig,
 }
 
 #[derive(Debug, Deserialize, Default)]
 pub struct LlmConfig {
+    #[serde(default)]
+    pub provider: Provider,
     pub api_key: Option<String>,
+    /// File holding the API key; beats `api_key`
+    pub api_key_file: Option<String>,
+    /// Command printing the API key, e.g. `pass show openai`; beats `api_key_file`
+    pub api_key_command: Option<String>,
     pub model: Option<String>,
     pub base_url: Option<String>,
+    /// Whole-request timeout in seconds (default 120)
+    pub timeout_secs: Option<u64>,
+    /// Connection timeout in seconds (default 10)
+    pub connect_timeout_secs: Option<u64>,
+    /// Seconds a streamed reply may go without data before it is abandoned (default 30)
+    pub stream_idle_timeout_secs: Option<u64>,
+    /// Retries for 429/500/502/503 responses (default 3)
+    pub max_retries: Option<u32>,
+    /// Sampling parameters; omitted from the request unless set
+    pub temperature: Option<f32>,
+    pub top_p: Option<f32>,
+    pub max_tokens: Option<u32>,
+    /// `reasoning_effort` for reasoning models: low, medium or high (OpenAI-compatible only)
+    pub reasoning_effort: Option<ReasoningEffort>,
+    /// Ask for the model's reasoning (default true); see config.example.toml for what
+    /// each provider is sent when it is off
+    pub request_reasoning: Option<bool>,
+    /// `reasoning_effort` sent while `request_reasoning` is off (OpenAI-compatible only)
+    pub reasoning_effort_when_off: Option<ReasoningEffort>,
+    /// Azure OpenAI deployment name (provider = "azure")
+    pub deployment: Option<String>,
+    /// Azure OpenAI `api-version` query parameter (default 2024-10-21)
+    pub api_version: Option<String>,
+    /// Send `response_format: json_object` (OpenAI-compatible providers only)
+    #[serde(default)]
+    pub json_mode: JsonMode,
+    /// Proxy URL for all LLM requests (http, https, socks5 or socks5h); unset falls back
+    /// to HTTP(S)_PROXY
+    pub proxy: Option<String>,
+    /// Comma-separated hosts that bypass `proxy`, in NO_PROXY syntax
+    pub no_proxy: Option<String>,
+    /// Extra headers sent with every request, e.g. `HTTP-Referer` for OpenRouter
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    /// Sent as `OpenAI-Organization` (e.g. "org-..."), for billing separation
+    pub organization: Option<String>,
+    /// Sent as `OpenAI-Project` (e.g. "proj_...")
+    pub project: Option<String>,
+    /// Ask for token counts and show them under each answer (OpenAI-compatible providers only)
+    #[serde(default)]
+    pub show_usage: bool,
+    /// Model to retry a turn with once when the primary model times out, is rate limited or
+    /// gets a server error (for Azure, a deployment)
+    pub fallback_model: Option<String>,
+    /// Reasoning kept per reply, in characters; older text is dropped (default 100000)
+    pub max_reasoning_chars: Option<usize>,
+    /// Times a reply that is not valid JSON is sent back to be fixed (default 1)
+    pub json_repair_attempts: Option<u32>,
+    /// Earlier question/answer pairs sent with each request (default: all of them)
+    pub max_history_turns: Option<usize>,
+    /// Summarize the oldest turns once the conversation gets long
+    #[serde(default)]
+    pub auto_compact: bool,
+    /// Question/answer pairs kept before compacting (default 20)
+    pub compact_after_turns: Option<usize>,
+    /// Model that writes the summary (default: `model`)
+    pub compact_model: Option<String>,
+}
+
+/// Which LLM backend to talk to.
+#[derive(Debug, Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Provider {
+    #[default]
+    OpenAI,
+    Anthropic,
+    /// Local Ollama server through its OpenAI-compatible API
+    Ollama,
+    /// Azure OpenAI deployment
+    Azure,
+}
+
+/// Environment variables that override the config file for a provider.
+struct ProviderEnv {
+    api_key: &'static str,
+    model: Option<&'static str>,
+    base_url: Option<&'static str>,
+}
+
+impl Provider {
+    fn env_vars(self) -> ProviderEnv {
+        match self {
+            Provider::OpenAI => ProviderEnv {
+                api_key: "OPENAI_API_KEY",
+                model: Some("OPENAI_MODEL"),
+                base_url: Some("OPENAI_BASE_URL"),
+            },
+            // Shares the OpenAI variables, except the URL which points at the local server
+            Provider::Ollama => ProviderEnv {
+                api_key: "OPENAI_API_KEY",
+                model: Some("OPENAI_MODEL"),
+                base_url: None,
+            },
+            Provider::Anthropic => ProviderEnv {
+                api_key: "ANTHROPIC_API_KEY",
+                model: Some("ANTHROPIC_MODEL"),
+                base_url: Some("ANTHROPIC_BASE_URL"),
+            },
+            Provider::Azure => ProviderEnv {
+                api_key: "AZURE_OPENAI_API_KEY",
+                model: None,
+                base_url: Some("AZURE_OPENAI_ENDPOINT"),
+            },
+        }
+    }
+
+    fn default_model(self) -> Option<&'static str> {
+        match self {
+            Provider::OpenAI => Some("gpt-4o-mini"),
+            Provider::Ollama => Some("llama3"),
+            Provider::Anthropic => Some("claude-sonnet-4-5"),
+            Provider::Azure => None,
+        }
+    }
+
+    fn default_base_url(self) -> Option<&'static str> {
+        match self {
+            Provider::OpenAI => Some("https://api.openai.com/v1"),
+            Provider::Ollama => Some("http://localhost:11434/v1"),
+            Provider::Anthropic => Some("https://api.anthropic.com/v1"),
+            // The resource endpoint has no sensible default
+            Provider::Azure => None,
+        }
+    }
+}
+
+/// Settings given as command-line flags, the topmost layer.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Overrides {
+    pub model: Option<String>,
+    pub language: Option<String>,
+    /// Start directory for the shell, beating `shell.cwd`
+    pub cwd: Option<String>,
+}
+
+/// The config with every layer applied and defaults filled in.
+#[derive(Debug)]
+pub struct ResolvedConfig {
+    pub llm: ResolvedLlm,
+    pub prompt: PromptConfig,
+    pub shell: ShellConfig,
+    pub preference: PreferenceConfig,
+    pub keys: KeysConfig,
+}
+
+#[derive(Debug)]
+pub struct ResolvedLlm {
+    pub provider: Provider,
+    /// None when neither the config nor the environment has one
+    pub api_key: Option<String>,
+    pub model: String,
+    /// Empty for Azure until an endpoint is configured
+    pub base_url: String,
+    pub deployment: Option<String>,
+    pub api_version: String,
+    pub timeout: Duration,
+    pub connect_timeout: Duration,
+    pub stream_idle_timeout: Duration,
+    pub max_retries: u32,
+    pub sampling: Sampling,
+    pub json_mode: JsonMode,
+    pub proxy: Option<String>,
+    pub no_proxy: Option<String>,
+    pub headers: HashMap<String, String>,
+    pub organization: Option<String>,
+    pub project: Option<String>,
+    pub show_usage: bool,
+    pub fallback_model: Option<String>,
+    pub max_reasoning_chars: usize,
+    pub json_repair_attempts: u32,
+    pub max_history_turns: Option<usize>,
+    pub compaction: Option<Compaction>,
+    /// From `prompt.mode`; the client parses replies accordingly
+    pub prompt_mode: PromptMode,
+    pub request_reasoning: bool,
+    pub reasoning_effort_when_off: Option<ReasoningEffort>,
+}
+
+impl ResolvedConfig {
+    /// Catch settings that would otherwise only fail once a request is made.
+    /// Messages are in `lang` and name the offending key.
+    pub fn validate(&self, lang: &Language) -> Result<()> {
+        let llm = &self.llm;
+        if !llm.base_url.is_empty() {
+            let valid = reqwest::Url::parse(&llm.base_url)
+                .is_ok_and(|url| matches!(url.scheme(), "http" | "https") && url.has_host());
+            if !valid {
+                bail!(fill(
+                    t(lang, MessageKey::InvalidBaseUrl),
+                    &[("value", &llm.base_url)]
+                ));
+            }
+        }
+        if let Some(proxy) = &llm.proxy {
+            // socks5h resolves host names through the proxy as well
+            let valid = reqwest::Url::parse(proxy).is_ok_and(|url| {
+                matches!(url.scheme(), "http" | "https" | "socks5" | "socks5h") && url.has_host()
+            });
+            if !valid {
+                bail!(fill(t(lang, MessageKey::InvalidProxy), &[("value", proxy)]));
+            }
+        }
+        for (name, value) in &llm.headers {
+            let valid = reqwest::header::HeaderName::from_bytes(name.as_bytes()).is_ok()
+                && reqwest::header::HeaderValue::from_str(value).is_ok();
+            if !valid {
+                bail!(fill(t(lang, MessageKey::InvalidHeader), &[("value", name)]));
+            }
+        }
+        for (field, value, prefix) in [
+            ("llm.organization", &llm.organization, "org-"),
+            ("llm.project", &llm.project, "proj_"),
+        ] {
+            if let Some(value) = value
+                && !is_openai_id(value, prefix)
+            {
+                bail!(fill(
+                    t(lang, MessageKey::InvalidOpenAIId),
+                    &[("field", field), ("value", value), ("prefix", prefix)]
+                ));
+            }
+        }
+        if let Some(language) = &self.preference.language
+            && !is_language_code(language)
+        {
+            bail!(fill(
+                t(lang, MessageKey::InvalidLanguage),
+                &[("value", language)]
+            ));
+        }
+        if let Some(cwd) = self.shell.cwd.as_deref().filter(|dir| !dir.is_empty())
+            && !Path::new(cwd).is_dir()
+        {
+            bail!(fill(t(lang, MessageKey::InvalidCwd), &[("value", cwd)]));
+        }
+
+        let ranges: [(&str, Option<f64>, f64, f64); 9] = [
+            (
+                "llm.temperature",
+                llm.sampling.temperature.map(f64::from),
+                0.0,
+                2.0,
+            ),
+            ("llm.top_p", llm.sampling.top_p.map(f64::from), 0.0, 1.0),
+            (
+                "llm.max_tokens",
+                llm.sampling.max_tokens.map(f64::from),
+                1.0,
+                f64::MAX,
+            ),
+            (
+                "llm.timeout_secs",
+                Some(llm.timeout.as_secs_f64()),
+                1.0,
+                f64::MAX,
+            ),
+            (
+                "llm.connect_timeout_secs",
+                Some(llm.connect_timeout.as_secs_f64()),
+                1.0,
+                f64::MAX,
+            ),
+            (
+                "llm.stream_idle_timeout_secs",
+                Some(llm.stream_idle_timeout.as_secs_f64()),
+                1.0,
+                f64::MAX,
+            ),
+            (
+                "llm.compact_after_turns",
+                llm.compaction.as_ref().map(|c| c.after_turns as f64),
+                2.0,
+                f64::MAX,
+            ),
+            (
+                "llm.max_reasoning_chars",
+                Some(llm.max_reasoning_chars as f64),
+                1.0,
+                f64::MAX,
+            ),
+            (
+                "preference.agentic_max_steps",
+                self.preference.agentic_max_steps.map(f64::from),
+                1.0,
+                f64::MAX,
+            ),
+        ];
+        for (field, value, min, max) in ranges {
+            let Some(value) = value else {
+                continue;
+            };
+            if !(min..=max).contains(&value) {
+                let range = if max == f64::MAX {
+                    format!(">= {min}")
+                } else {
+                    format!("{min}-{max}")
+                };
+                bail!(fill(
+                    t(lang, MessageKey::ValueOutOfRange),
+                    &[
+                        ("field", field),
+                        ("value", &value.to_string()),
+                        ("range", &range),
+                    ]
+                ));
+            }
+        }
+        Ok(())
+    }
 }
 
 #[derive(Debug, Deserialize)]
 pub struct PromptConfig {
     #[serde(default = "default_prompt_template")]
     pub template: String,
+    /// File to read the template from, relative to the config file; beats `template`
+    pub template_file: Option<String>,
+    /// Send the recent terminal output along with each question
+    #[serde(default)]
+    pub include_terminal_context: bool,
+    /// Ask for a JSON reply or for plain text with a fenced command block
+    #[serde(default)]
+    pub mode: PromptMode,
 }
 
 impl Default for PromptConfig {
     fn default() -> Self {
         Self {
             template: DEFAULT_PROMPT_TEMPLATE.to_string(),
+            template_file: None,
+            include_terminal_context: false,
+            mode: PromptMode::Json,
         }
     }
 }
 
+impl PromptConfig {
+    /// Replace `template` with the contents of `template_file`, if set; a relative
+    /// path is taken from `config_dir`, the directory of the config file.
+    fn load_template_file(&mut self, config_dir: &Path) -> Result<()> {
+        let Some(file) = &self.template_file else {
+            return Ok(());
+        };
+        let path = config_dir.join(file);
+        self.template = std::fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read prompt.template_file: {}", path.display()))?;
+        Ok(())
+    }
+}
+
 fn default_prompt_template() -> String {
     DEFAULT_PROMPT_TEMPLATE.to_string()
 }
 
-#[derive(Debug, Deserialize, Default)]
+#[derive(Debug, Deserialize)]
 pub struct ShellConfig {
     /// Shell executable path. If not set, auto-detect based on OS.
     pub path: Option<String>,
+    /// Start the shell as a login shell (`-l`) so profile files are read. Defaults to
+    /// true on macOS, where terminals do this, and false elsewhere.
+    pub login: Option<bool>,
+    /// Arguments for the shell; replaces the `-l` chosen through `login`
+    pub args: Option<Vec<String>>,
+    /// Environment variables set for the shell, on top of the inherited ones
+    #[serde(default)]
+    pub env: HashMap<String, String>,
+    /// Directory the shell starts in instead of the one shellm was started in
+    pub cwd: Option<String>,
+    /// Regex patterns; accepting a matching command asks for confirmation first
+    #[serde(default = "default_dangerous_patterns")]
+    pub dangerous_patterns: Vec<String>,
+}
+
+impl Default for ShellConfig {
+    fn default() -> Self {
+        Self {
+            path: None,
+            login: None,
+            args: None,
+            env: HashMap::new(),
+            cwd: None,
+            dangerous_patterns: default_dangerous_patterns(),
+        }
+    }
+}
+
+impl ShellConfig {
+    /// Where the shell starts: `cwd` when set, else the current directory.
+    pub fn start_dir(&self) -> Result<PathBuf> {
+        match self.cwd.as_deref().filter(|dir| !dir.is_empty()) {
+            Some(dir) => Ok(PathBuf::from(dir)),
+            None => env::current_dir().context("failed to get current directory"),
+        }
+    }
+}
+
+fn default_dangerous_patterns() -> Vec<String> {
+    DEFAULT_DANGEROUS_PATTERNS
+        .iter()
+        .map(|p| p.to_string())
+        .collect()
 }
 
 #[derive(Debug, Deserialize, Default)]
 pub struct PreferenceConfig {
     pub language: Option<String>,
+    /// Save the chat history to disk and reload it on the next run
+    #[serde(default)]
+    pub persist_history: bool,
+    /// Maximum number of messages kept in the saved history (default 200)
+    pub history_max_entries: Option<usize>,
+    /// Colored output: auto (honors NO_COLOR), always or never
+    #[serde(default)]
+    pub color: ColorMode,
+    /// Append raw requests and replies here for troubleshooting (also SHELLM_LOG)
+    pub log_file: Option<String>,
+    /// Whether an accepted command is only typed onto the prompt or also run
+    #[serde(default)]
+    pub inject_mode: InjectMode,
+    /// How the model's reasoning is shown
+    #[serde(default)]
+    pub reasoning: ReasoningDisplay,
+    /// Break reasoning sent as one long line into paragraphs when it is expanded
+    #[serde(default)]
+    pub reasoning_wrap: bool,
+    /// Show how long each request took under the answer
+    #[serde(default)]
+    pub show_timing: bool,
+    /// Print the banner when entering chat mode (default true)
+    pub show_welcome: Option<bool>,
+    /// Banner printed instead of the built-in one
+    pub welcome_text: Option<String>,
+    /// Exit after this many seconds without a keystroke; 0 or unset never does
+    pub idle_timeout_secs: Option<u64>,
+    /// Window title set on start; the previous one is restored on exit
+    pub title: Option<String>,
+    /// Run an accepted command after confirmation and send its output back for the
+    /// next suggestion
+    #[serde(default)]
+    pub agentic: bool,
+    /// Commands one agentic run may execute before it stops (default 5)
+    pub agentic_max_steps: Option<u32>,
+    /// Regex patterns for secrets masked in shell output before it reaches the model;
+    /// unset uses the built-in list, an empty list turns masking off
+    pub redact_patterns: Option<Vec<String>>,
+}
+
+impl PreferenceConfig {
+    /// How long shellm waits for input before exiting, if it ever does.
+    pub fn idle_timeout(&self) -> Option<Duration> {
+        self.idle_timeout_secs
+            .filter(|secs| *secs > 0)
+            .map(Duration::from_secs)
+    }
+
+    /// Messages kept in the saved history.
+    pub fn history_max_entries(&self) -> usize {
+        self.history_max_entries.unwrap_or(200)
+    }
+
+    /// Commands one agentic run may execute.
+    pub fn agentic_max_steps(&self) -> u32 {
+        self.agentic_max_steps.unwrap_or(5)
+    }
+
+    /// The configured redact patterns, or the built-in ones.
+    pub fn redact_patterns(&self) -> Vec<String> {
+        match &self.redact_patterns {
+            Some(patterns) => patterns.clone(),
+            None => DEFAULT_REDACT_PATTERNS
+                .iter()
+                .map(|p| p.to_string())
+                .collect(),
+        }
+    }
+}
+
+/// How reasoning is shown in chat mode. The request is the same either way.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReasoningDisplay {
+    /// Neither streamed live nor kept for expanding
+    Hidden,
+    /// Streamed live, then collapsed to a line that expands on request
+    #[default]
+    Collapsed,
+    /// Streamed live, then shown in full
+    Expanded,
+}
+
+/// Key specifications such as "ctrl+g" for the remappable actions; unset keeps the default.
+#[derive(Debug, Deserialize, Default)]
+pub struct KeysConfig {
+    pub enter_chat: Option<String>,
+    pub accept_command: Option<String>,
+    pub stage_command: Option<String>,
+    pub edit: Option<String>,
+    pub toggle_reasoning: Option<String>,
+    pub toggle_explanation: Option<String>,
+    pub exit_chat: Option<String>,
+    pub interrupt: Option<String>,
+    pub clear_screen: Option<String>,
+    pub regenerate: Option<String>,
+    pub previous_reply: Option<String>,
+    pub next_reply: Option<String>,
 }
 
 #[derive(Debug)]
 pub struct SystemInfo {
     pub os: String,
+    /// Linux distribution from /etc/os-release, e.g. "debian 12"; empty elsewhere
+    pub distro: String,
     pub arch: String,
     pub shell: String,
+    /// e.g. "5.2.15"; empty when it could not be detected
+    pub shell_version: String,
     pub lang: String,
+    /// Directory shellm was started in; chat mode substitutes the shell's current one
+    pub cwd: String,
+    pub user: String,
+    pub hostname: String,
+    /// Local time at startup, ISO-8601 with UTC offset
+    pub datetime: String,
+    pub date: String,
 }
 
 impl SystemInfo {
-    pub fn collect(preference_lang: Option<&str>) -> Self {
+    /// `shell` is the path of the shell that is (or would be) spawned.
+    pub fn collect(preference_lang: Option<&str>, shell: &str) -> Self {
+        let now = Local::now();
         Self {
             os: Self::detect_os(),
+            distro: Self::detect_distro(),
             arch: Self::detect_arch(),
-            shell: Self::detect_shell(),
+            shell: Self::shell_name(shell),
+            shell_version: Self::detect_shell_version(shell),
             lang: Self::detect_lang(preference_lang),
+            cwd: env::current_dir()
+                .map(|dir| dir.display().to_string())
+                .unwrap_or_default(),
+            user: Self::detect_user(|key| env::var(key).ok()),
+            hostname: Self::detect_hostname(),
+            datetime: now.format("%Y-%m-%dT%H:%M:%S%:z").to_string(),
+            date: now.format("%Y-%m-%d").to_string(),
         }
     }
 
@@ -95,21 +629,41 @@ impl SystemInfo {
         env::consts::ARCH.to_string()
     }
 
-    fn detect_shell() -> String {
-        // Prefer SHELL environment variable
-        if let Ok(shell_path) = env::var("SHELL") {
-            if let Some(name) = shell_path.rsplit('/').next() {
-                return name.to_string();
-            }
+    /// The shell's name, e.g. "bash" for /bin/bash or "powershell" for powershell.exe.
+    fn shell_name(shell_path: &str) -> String {
+        std::path::Path::new(shell_path)
+            .file_stem()
+            .and_then(|name| name.to_str())
+            .unwrap_or_default()
+            .to_ascii_lowercase()
+    }
+
+    fn detect_distro() -> String {
+        if !cfg!(target_os = "linux") {
+            return String::new();
         }
-        // Special handling for Windows
-        if cfg!(target_os = "windows") {
-            if env::var("PSModulePath").is_ok() {
-                return "powershell".to_string();
-            }
-            return "cmd".to_string();
+        std::fs::read_to_string("/etc/os-release")
+            .or_else(|_| std::fs::read_to_string("/usr/lib/os-release"))
+            .map(|content| parse_os_release(&content))
+            .unwrap_or_default()
+    }
+
+    fn detect_shell_version(shell: &str) -> String {
+        // Set when shellm itself was started from these shells and they were exported
+        let version_var = match Self::shell_name(shell).as_str() {
+            "bash" => Some("BASH_VERSION"),
+            "zsh" => Some("ZSH_VERSION"),
+            _ => None,
+        };
+        if let Some(version) = version_var.and_then(|key| env::var(key).ok()) {
+            return parse_shell_version(&version).unwrap_or(version);
         }
-        "unknown".to_string()
+        if shell.is_empty() {
+            return String::new();
+        }
+        output_with_timeout(shell, &["--version"])
+            .and_then(|out| parse_shell_version(&out))
+            .unwrap_or_default()
     }
 
     fn detect_lang(preference: Option<&str>) -> String {
@@ -119,62 +673,626 @@ impl SystemInfo {
         }
         // Infer from LANG environment variable
         if let Ok(lang) = env::var("LANG") {
-            // Extract language code, e.g. "zh_CN.UTF-8" -> "zh-CN"
-            let lang_code = lang.split('.').next().unwrap_or(&lang);
-            return lang_code.replace('_', "-");
+            return Self::locale_to_lang(&lang);
         }
         "en-US".to_string()
     }
 
+    /// USER on Unix, USERNAME on Windows
+    fn detect_user(var: impl Fn(&str) -> Option<String>) -> String {
+        var("USER")
+            .or_else(|| var("USERNAME"))
+            .unwrap_or_else(|| "unknown".to_string())
+    }
+
+    fn detect_hostname() -> String {
+        // HOSTNAME is rarely exported, COMPUTERNAME is always set on Windows
+        if let Some(name) = ["HOSTNAME", "COMPUTERNAME"]
+            .iter()
+            .find_map(|key| env::var(key).ok().filter(|v| !v.is_empty()))
+        {
+            return name;
+        }
+        output_with_timeout("hostname", &[])
+            .map(|name| name.trim().to_string())
+            .filter(|name| !name.is_empty())
+            .unwrap_or_else(|| "unknown".to_string())
+    }
+
+    /// Extract the language code from a locale, e.g. "ja_JP.UTF-8" -> "ja-JP"
+    fn locale_to_lang(locale: &str) -> String {
+        let lang_code = locale.split('.').next().unwrap_or(locale);
+        lang_code.replace('_', "-")
+    }
+
     pub fn to_vars(&self) -> HashMap<&str, &str> {
         let mut vars = HashMap::new();
         vars.insert("os", self.os.as_str());
+        vars.insert("distro", self.distro.as_str());
         vars.insert("arch", self.arch.as_str());
         vars.insert("shell", self.shell.as_str());
+        // Left unsubstituted when unknown
+        if !self.shell_version.is_empty() {
+            vars.insert("shell_version", self.shell_version.as_str());
+        }
         vars.insert("lang", self.lang.as_str());
+        vars.insert("cwd", self.cwd.as_str());
+        vars.insert("user", self.user.as_str());
+        vars.insert("hostname", self.hostname.as_str());
+        vars.insert("datetime", self.datetime.as_str());
+        vars.insert("date", self.date.as_str());
         vars
     }
 }
 
+/// Sections a `[profiles.<name>]` table may override.
+const PROFILE_SECTIONS: &[&str] = &["llm", "prompt", "preference", "keys"];
+
+/// The places a config file is looked for, so the search order can be tested.
+struct ConfigCandidates {
+    env: Option<PathBuf>,
+    cwd: Option<PathBuf>,
+    exe_dir: Option<PathBuf>,
+    config_dir: Option<PathBuf>,
+}
+
+impl ConfigCandidates {
+    /// The first existing file, in order:
+    /// 1. `$SHELLM_CONFIG`
+    /// 2. `./shellm.toml`, for project-local overrides
+    /// 3. `shellm.toml` next to the binary, for portable installs
+    /// 4. `<XDG config dir>/shellm/config.toml`
+    fn find(self) -> Option<PathBuf> {
+        [
+            self.env,
+            self.cwd.map(|dir| dir.join("shellm.toml")),
+            self.exe_dir.map(|dir| dir.join("shellm.toml")),
+            self.config_dir
+                .map(|dir| dir.join("shellm").join("config.toml")),
+        ]
+        .into_iter()
+        .flatten()
+        .find(|path| path.is_file())
+    }
+}
+
+/// The starting config written by `shellm config init`: every supported key, commented
+/// out, with its default or an example value.
+pub fn default_config_toml() -> String {
+    let commented = |text: &str| {
+        text.lines()
+            .map(|line| format!("# {line}").trim_end().to_string())
+            .collect::<Vec<_>>()
+            .join("\n")
+    };
+    let patterns = DEFAULT_DANGEROUS_PATTERNS
+        .iter()
+        .map(|pattern| format!("#   {},", toml::Value::from(*pattern)))
+        .collect::<Vec<_>>()
+        .join("\n");
+    let redact_patterns = DEFAULT_REDACT_PATTERNS
+        .iter()
+        .map(|pattern| format!("#   {},", toml::Value::from(*pattern)))
+        .collect::<Vec<_>>()
+        .join("\n");
+    let template = commented(&format!("template = '''\n{DEFAULT_PROMPT_TEMPLATE}'''"));
+    let keys = KeyBindings::default();
+
+    format!(
+        r#"# shellm configuration. Environment variables and command-line flags override
+# these settings; see config.example.toml for a longer description of each key.
+
+[llm]
+# provider = "openai"  # "openai", "anthropic", "ollama" or "azure"
+# api_key = "sk-..."  # or OPENAI_API_KEY / ANTHROPIC_API_KEY / AZURE_OPENAI_API_KEY
+# api_key_file = "/run/secrets/openai_api_key"
+# api_key_command = "pass show openai"
+# model = "{model}"
+# base_url = "{base_url}"
+# timeout_secs = 120
+# connect_timeout_secs = 10
+# stream_idle_timeout_secs = 30  # give up on a reply that stops streaming this long
+# max_retries = 3
+# temperature = 1.0
+# top_p = 1.0
+# max_tokens = 1024
+# reasoning_effort = "medium"  # "minimal", "low", "medium" or "high", for reasoning models
+# request_reasoning = true  # false asks Anthropic and Ollama not to reason
+# reasoning_effort_when_off = "minimal"  # sent while request_reasoning is false (OpenAI-compatible)
+# deployment = "..."  # Azure only
+# api_version = "2024-10-21"  # Azure only
+# json_mode = "auto"  # "auto", "on" or "off"
+# proxy = "http://127.0.0.1:8080"  # or "socks5h://127.0.0.1:1080" for an ssh -D tunnel
+# no_proxy = "localhost,127.0.0.1"
+# organization = "org-..."
+# project = "proj_..."
+# show_usage = false
+# fallback_model = "..."
+# max_reasoning_chars = 100000  # only the tail of longer reasoning is kept
+# json_repair_attempts = 1  # ask again this often when a reply is not valid JSON
+# max_history_turns = 10  # earlier question/answer pairs sent along; unset sends all
+# auto_compact = false  # summarize the oldest turns once there are compact_after_turns
+# compact_after_turns = 20
+# compact_model = "..."  # model that writes the summary; defaults to model
+# headers = {{ "X-Title" = "shellm" }}
+
+[prompt]
+{template}
+# template_file = "prompt.txt"  # read the template from this file instead
+# include_terminal_context = false
+# mode = "json"  # "plain" asks for a fenced command block, for models that struggle with JSON
+
+[shell]
+# path = "/bin/bash"  # auto-detected from SHELL when unset
+# login = false  # start as a login shell (-l); true by default on macOS
+# args = ["-l"]  # replaces the arguments chosen through login
+# env = {{ TERM = "xterm-256color" }}
+# cwd = "/home/me/project"  # start directory instead of the current one
+# dangerous_patterns = [
+{patterns}
+# ]
+
+[preference]
+# language = "en-US"  # auto-detected from LANG when unset
+# persist_history = false
+# history_max_entries = 200
+# color = "auto"  # "auto", "always" or "never"
+# inject_mode = "prefill"  # "prefill" leaves an accepted command on the prompt, "execute" runs it
+# reasoning = "collapsed"  # "hidden", "collapsed" or "expanded"
+# reasoning_wrap = false  # split long reasoning lines into paragraphs when expanded
+# show_timing = false  # show how long each request took under the answer
+# show_welcome = true  # print the key overview when entering chat mode
+# welcome_text = "..."  # printed instead of the built-in key overview
+# idle_timeout_secs = 0  # exit after this long without a keystroke; 0 disables
+# title = "shellm"  # window title while shellm runs
+# agentic = false  # run accepted commands after confirmation and send their output back
+# agentic_max_steps = 5
+# redact_patterns = [  # secrets masked in shell output sent to the model; [] disables
+{redact_patterns}
+# ]
+# log_file = "/tmp/shellm.log"
+
+[keys]
+# enter_chat = "{enter_chat}"
+# accept_command = "{accept_command}"
+# stage_command = "{stage_command}"
+# edit = "{edit}"
+# toggle_reasoning = "{toggle_reasoning}"
+# toggle_explanation = "{toggle_explanation}"
+# exit_chat = "{exit_chat}"
+# interrupt = "{interrupt}"
+# clear_screen = "{clear_screen}"
+# regenerate = "{regenerate}"
+# previous_reply = "{previous_reply}"
+# next_reply = "{next_reply}"
+"#,
+        model = Provider::OpenAI.default_model().unwrap_or_default(),
+        base_url = Provider::OpenAI.default_base_url().unwrap_or_default(),
+        enter_chat = keys.enter_chat.label().to_ascii_lowercase(),
+        accept_command = keys.accept_command.label().to_ascii_lowercase(),
+        stage_command = keys.stage_command.label().to_ascii_lowercase(),
+        edit = keys.edit.label().to_ascii_lowercase(),
+        toggle_reasoning = keys.toggle_reasoning.label().to_ascii_lowercase(),
+        toggle_explanation = keys.toggle_explanation.label().to_ascii_lowercase(),
+        exit_chat = keys.exit_chat.label().to_ascii_lowercase(),
+        interrupt = keys.interrupt.label().to_ascii_lowercase(),
+        clear_screen = keys.clear_screen.label().to_ascii_lowercase(),
+        regenerate = keys.regenerate.label().to_ascii_lowercase(),
+        previous_reply = keys.previous_reply.label().to_ascii_lowercase(),
+        next_reply = keys.next_reply.label().to_ascii_lowercase(),
+    )
+}
+
+/// Write `default_config_toml` to `path`, creating its directory. An existing file is
+/// only replaced when `force` is set.
+pub fn write_default_config(path: &Path, force: bool, lang: &Language) -> Result<()> {
+    if path.exists() && !force {
+        let path = path.display().to_string();
+        bail!(
+            "{}",
+            t(lang, MessageKey::ConfigExists).replace("{path}", &path)
+        );
+    }
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("Failed to create {}", dir.display()))?;
+    }
+    std::fs::write(path, default_config_toml())
+        .with_context(|| format!("Failed to write {}", path.display()))
+}
+
+/// The UI language before any config file is read: SHELLM_LANGUAGE, else LANG.
+pub fn startup_language() -> Language {
+    let preference = env::var("SHELLM_LANGUAGE")
+        .ok()
+        .filter(|lang| !lang.is_empty());
+    Language::from_code(&SystemInfo::detect_lang(preference.as_deref()))
+}
+
+/// Where `shellm config init` writes: the XDG config file, the last place searched.
+pub fn default_config_path() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("shellm").join("config.toml"))
+}
+
 impl Config {
-    pub fn load() -> Result<Self> {
-        // 1. Check path specified by environment variable
-        if let Ok(path) = env::var("SHELLM_CONFIG") {
-            let path = PathBuf::from(path);
-            if path.exists() {
-                return Self::load_from_file(&path);
-            }
+    /// Load the config file, with the named profile (if any) merged over the base.
+    pub fn load(profile: Option<&str>) -> Result<Self> {
+        let exe_dir = env::current_exe()
+            .ok()
+            .and_then(|exe| exe.parent().map(PathBuf::from));
+        let candidates = ConfigCandidates {
+            env: env::var_os("SHELLM_CONFIG").map(PathBuf::from),
+            cwd: env::current_dir().ok(),
+            exe_dir,
+            config_dir: dirs::config_dir(),
+        };
+        if let Some(path) = candidates.find() {
+            return Self::load_from_file(&path, profile);
         }
 
-        // 2. Check XDG config directory
-        if let Some(config_dir) = dirs::config_dir() {
-            let path = config_dir.join("shellm").join("config.toml");
-            if path.exists() {
-                return Self::load_from_file(&path);
-            }
+        // Fall back to default configuration
+        if let Some(name) = profile {
+            bail!("profile `{name}` not found: no config file");
         }
-
-        // 3. Fall back to default configuration
         Ok(Self::default())
     }
 
-    fn load_from_file(path: &PathBuf) -> Result<Self> {
+    /// Layer the environment and then the command line over the file settings,
+    /// and fill in the defaults: defaults < config file < environment < CLI flags.
+    /// `env` looks up an environment variable.
+    pub fn resolve(
+        self,
+        env: impl Fn(&str) -> Option<String>,
+        overrides: &Overrides,
+    ) -> Result<ResolvedConfig> {
+        let Config {
+            llm,
+            mut prompt,
+            mut shell,
+            mut preference,
+            keys,
+        } = self;
+        // A template left at the default asks for JSON, which plain mode cannot read
+        if prompt.mode == PromptMode::Plain && prompt.template == DEFAULT_PROMPT_TEMPLATE {
+            prompt.template = DEFAULT_PLAIN_PROMPT_TEMPLATE.to_string();
+        }
+        let provider = llm.provider;
+        let vars = provider.env_vars();
+        let layer = |file: Option<String>, var: Option<&str>| var.and_then(&env).or(file);
+
+        let temperature = match env("SHELLM_TEMPERATURE") {
+            Some(value) => Some(
+                value
+                    .trim()
+                    .parse()
+                    .with_context(|| format!("invalid SHELLM_TEMPERATURE `{value}`"))?,
+            ),
+            None => llm.temperature,
+        };
+        let api_key = match env(vars.api_key) {
+            Some(key) => Some(key),
+            None => api_key_from_sources(
+                llm.api_key,
+                llm.api_key_file.as_deref(),
+                llm.api_key_command.as_deref(),
+            )?,
+        };
+        let model = overrides
+            .model
+            .clone()
+            .or_else(|| layer(llm.model, vars.model))
+            .or_else(|| provider.default_model().map(str::to_string))
+            // Azure picks the model from the deployment; this is only sent along
+            .or_else(|| llm.deployment.clone())
+            .unwrap_or_default();
+        let base_url = layer(llm.base_url, vars.base_url)
+            .or_else(|| provider.default_base_url().map(str::to_string))
+            .unwrap_or_default();
+
+        preference.language = overrides
+            .language
+            .clone()
+            .or_else(|| layer(preference.language, Some("SHELLM_LANGUAGE")));
+        if let Some(cwd) = &overrides.cwd {
+            shell.cwd = Some(cwd.clone());
+        }
+
+        Ok(ResolvedConfig {
+            llm: ResolvedLlm {
+                provider,
+                api_key,
+                model,
+                base_url,
+                deployment: llm.deployment,
+                api_version: llm.api_version.unwrap_or_else(|| "2024-10-21".to_string()),
+                timeout: Duration::from_secs(llm.timeout_secs.unwrap_or(120)),
+                connect_timeout: Duration::from_secs(llm.connect_timeout_secs.unwrap_or(10)),
+                stream_idle_timeout: Duration::from_secs(
+                    llm.stream_idle_timeout_secs.unwrap_or(30),
+                ),
+                max_retries: llm.max_retries.unwrap_or(3),
+                sampling: Sampling {
+                    temperature,
+                    top_p: llm.top_p,
+                    max_tokens: llm.max_tokens,
+                    reasoning_effort: llm.reasoning_effort,
+                },
+                json_mode: llm.json_mode,
+                proxy: llm.proxy,
+                no_proxy: llm.no_proxy,
+                headers: llm.headers,
+                organization: llm.organization,
+                project: llm.project,
+                show_usage: llm.show_usage,
+                fallback_model: llm.fallback_model,
+                max_reasoning_chars: llm.max_reasoning_chars.unwrap_or(100_000),
+                json_repair_attempts: llm.json_repair_attempts.unwrap_or(1),
+                max_history_turns: llm.max_history_turns,
+                compaction: llm.auto_compact.then(|| Compaction {
+                    after_turns: llm.compact_after_turns.unwrap_or(20),
+                    model: llm.compact_model,
+                }),
+                prompt_mode: prompt.mode,
+                request_reasoning: llm.request_reasoning.unwrap_or(true),
+                reasoning_effort_when_off: llm.reasoning_effort_when_off,
+            },
+            prompt,
+            shell,
+            preference,
+            keys,
+        })
+    }
+
+    fn load_from_file(path: &PathBuf, profile: Option<&str>) -> Result<Self> {
         let content = std::fs::read_to_string(path)
             .with_context(|| format!("Failed to read config file: {}", path.display()))?;
-        toml::from_str(&content)
-            .with_context(|| format!("Failed to parse config file: {}", path.display()))
+        let mut config = Self::parse(&content, profile)
+            .with_context(|| format!("Failed to parse config file: {}", path.display()))?;
+        config
+            .prompt
+            .load_template_file(path.parent().unwrap_or(Path::new("")))?;
+        Ok(config)
+    }
+
+    fn parse(content: &str, profile: Option<&str>) -> Result<Self> {
+        let mut value: toml::Table = toml::from_str(content)?;
+        let profiles = value.remove("profiles");
+
+        if let Some(name) = profile {
+            let Some(toml::Value::Table(overlay)) =
+                profiles.as_ref().and_then(|p| p.get(name)).cloned()
+            else {
+                bail!("profile `{name}` not found");
+            };
+            for (section, overrides) in overlay {
+                if !PROFILE_SECTIONS.contains(&section.as_str()) {
+                    bail!("profile `{name}` cannot override `{section}`");
+                }
+                match value.get_mut(&section) {
+                    Some(base) => merge_toml(base, overrides),
+                    None => {
+                        value.insert(section, overrides);
+                    }
+                }
+            }
+        }
+
+        Ok(toml::Value::Table(value).try_into()?)
+    }
+}
+
+/// Recursively merge `overlay` into `base`; tables merge key by key, anything else is replaced.
+fn merge_toml(base: &mut toml::Value, overlay: toml::Value) {
+    match (base, overlay) {
+        (toml::Value::Table(base), toml::Value::Table(overlay)) => {
+            for (key, value) in overlay {
+                match base.get_mut(&key) {
+                    Some(existing) => merge_toml(existing, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, overlay) => *base = overlay,
+    }
+}
+
+/// "<ID> <VERSION_ID>" from os-release content, e.g. "ubuntu 22.04" or just "arch".
+fn parse_os_release(content: &str) -> String {
+    let field = |key: &str| {
+        content.lines().find_map(|line| {
+            let value = line.trim().strip_prefix(key)?.strip_prefix('=')?;
+            let value = value.trim_matches(|c| c == '"' || c == '\'');
+            (!value.is_empty()).then_some(value)
+        })
+    };
+    match (field("ID"), field("VERSION_ID")) {
+        (Some(id), Some(version)) => format!("{id} {version}"),
+        (Some(id), None) => id.to_string(),
+        _ => String::new(),
+    }
+}
+
+/// How long a probe like `bash --version` may take before it is abandoned.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The key from `llm.api_key_command`, else `llm.api_key_file`, else the inline one.
+/// Only the winning source is read, so the command does not run needlessly.
+fn api_key_from_sources(
+    inline: Option<String>,
+    file: Option<&str>,
+    command: Option<&str>,
+) -> Result<Option<String>> {
+    let key = if let Some(command) = command {
+        let (shell, flag) = if cfg!(windows) {
+            ("cmd", "/C")
+        } else {
+            ("sh", "-c")
+        };
+        let output = Command::new(shell)
+            .args([flag, command])
+            .stderr(Stdio::inherit())
+            .output()
+            .with_context(|| format!("failed to run llm.api_key_command `{command}`"))?;
+        if !output.status.success() {
+            bail!("llm.api_key_command `{command}` failed ({})", output.status);
+        }
+        String::from_utf8(output.stdout)
+            .with_context(|| format!("llm.api_key_command `{command}` printed invalid UTF-8"))?
+    } else if let Some(path) = file {
+        std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read llm.api_key_file `{path}`"))?
+    } else {
+        return Ok(inline);
+    };
+
+    let key = key.trim_end_matches(['\r', '\n']);
+    if key.trim().is_empty() {
+        bail!("llm.api_key_command / llm.api_key_file gave an empty key");
+    }
+    Ok(Some(key.to_string()))
+}
+
+/// Run a short probe command and return its stdout, killing it if it hangs.
+fn output_with_timeout(program: &str, args: &[&str]) -> Option<String> {
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .ok()?;
+    let mut stdout = child.stdout.take()?;
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let mut out = String::new();
+        let _ = stdout.read_to_string(&mut out);
+        let _ = tx.send(out);
+    });
+    match rx.recv_timeout(PROBE_TIMEOUT) {
+        Ok(out) => {
+            let _ = child.wait();
+            Some(out)
+        }
+        Err(_) => {
+            let _ = child.kill();
+            let _ = child.wait();
+            None
+        }
     }
 }
 
+/// First version number in `--version` output, e.g. "GNU bash, version 5.2.15(1)-release" -> "5.2.15".
+fn parse_shell_version(output: &str) -> Option<String> {
+    output.lines().next()?.split_whitespace().find_map(|word| {
+        let version: String = word
+            .chars()
+            .take_while(|c| c.is_ascii_digit() || *c == '.')
+            .collect();
+        let version = version.trim_end_matches('.');
+        (version.contains('.') && version.starts_with(|c: char| c.is_ascii_digit()))
+            .then(|| version.to_string())
+    })
+}
+
+/// Substitute `{name}` placeholders in a translated message.
+fn fill(message: &str, values: &[(&str, &str)]) -> String {
+    render_prompt(message, &values.iter().copied().collect())
+}
+
+/// OpenAI organization and project ids look like "org-AbC123" and "proj_AbC123".
+fn is_openai_id(value: &str, prefix: &str) -> bool {
+    value.strip_prefix(prefix).is_some_and(|id| {
+        !id.is_empty()
+            && id
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+    })
+}
+
+/// Loose BCP 47 shape check: "en", "zh-CN", "pt_BR", "zh-Hant-TW".
+fn is_language_code(code: &str) -> bool {
+    let mut parts = code.split(['-', '_']);
+    let primary = parts.next().unwrap_or_default();
+    (2..=3).contains(&primary.len())
+        && primary.chars().all(|c| c.is_ascii_alphabetic())
+        && parts.all(|part| {
+            (1..=8).contains(&part.len()) && part.chars().all(|c| c.is_ascii_alphanumeric())
+        })
+}
+
+/// Substitute `{name}` placeholders in one pass: values are never scanned again, unknown
+/// names are left as they are, and `{{` / `}}` stand for literal braces.
 pub fn render_prompt(template: &str, vars: &HashMap<&str, &str>) -> String {
-    let mut result = template.to_string();
-    for (key, value) in vars {
-        let placeholder = format!("{{{}}}", key);
-        result = result.replace(&placeholder, value);
+    let mut result = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(i) = rest.find(['{', '}']) {
+        result.push_str(&rest[..i]);
+        let tail = &rest[i..];
+        if let Some(after) = tail.strip_prefix("{{") {
+            result.push('{');
+            rest = after;
+        } else if let Some(after) = tail.strip_prefix("}}") {
+            result.push('}');
+            rest = after;
+        } else if let Some(end) = tail.find('}')
+            && tail.starts_with('{')
+            && let Some(value) = vars.get(&tail[1..end])
+        {
+            result.push_str(value);
+            rest = &tail[end + 1..];
+        } else {
+            result.push_str(&tail[..1]);
+            rest = &tail[1..];
+        }
     }
+    result.push_str(rest);
     result
 }
 
+/// Syntax notes for shells where bash-style commands would not work.
+pub fn shell_guidance(shell: &str) -> Option<&'static str> {
+    let name = shell.to_ascii_lowercase();
+    match name.trim_end_matches(".exe") {
+        "fish" => Some(
+            "The shell is fish: set variables with `set -x NAME value` instead of `export`, \
+             use `(cmd)` instead of `$(cmd)`, and chain with `; and` / `; or` \
+             (`&&` and `||` need fish 3.0 or later).",
+        ),
+        "powershell" | "pwsh" => Some(
+            "The shell is PowerShell: prefer cmdlets such as Get-ChildItem, Select-String \
+             and Remove-Item with their named parameters (-Recurse, -Filter), pipe objects \
+             with `|`, and set variables with `$env:NAME = 'value'`.",
+        ),
+        "cmd" => Some(
+            "The shell is cmd.exe: set variables with `set NAME=value`, read them as `%NAME%`, \
+             and do not assume POSIX tools such as grep or sed are installed.",
+        ),
+        _ => None,
+    }
+}
+
+/// Render `template` and add the guidance for `{shell}`: where the template puts
+/// `{shell_guidance}`, or at the end if it does not mention it.
+pub fn system_prompt(template: &str, vars: &HashMap<&str, &str>) -> String {
+    let guidance = vars
+        .get("shell")
+        .and_then(|shell| shell_guidance(shell))
+        .unwrap_or_default();
+    if template.contains("{shell_guidance}") {
+        let mut vars = vars.clone();
+        vars.insert("shell_guidance", guidance);
+        return render_prompt(template, &vars);
+    }
+    let mut prompt = render_prompt(template, vars);
+    if !guidance.is_empty() {
+        prompt.push('\n');
+        prompt.push_str(guidance);
+    }
+    prompt
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -192,6 +1310,45 @@ mod tests {
         assert_eq!(result, "OS: Linux, Arch: x86_64, Shell: bash, Lang: zh-CN");
     }
 
+    #[test]
+    fn test_render_prompt_single_pass() {
+        let mut vars = HashMap::new();
+        vars.insert("cwd", "/tmp/{shell}");
+        vars.insert("shell", "bash");
+        // A value is not scanned again
+        assert_eq!(render_prompt("{cwd} {shell}", &vars), "/tmp/{shell} bash");
+        // Escaped braces become literal ones
+        assert_eq!(
+            render_prompt("{{shell}} is {shell}", &vars),
+            "{shell} is bash"
+        );
+        // Unknown names and JSON are left alone
+        assert_eq!(render_prompt("{nope} {shell", &vars), "{nope} {shell");
+        assert_eq!(
+            render_prompt(r#"{"command": "ls"} in {shell}"#, &vars),
+            r#"{"command": "ls"} in bash"#
+        );
+    }
+
+    #[test]
+    fn test_system_prompt_shell_guidance() {
+        let mut vars = HashMap::new();
+        vars.insert("shell", "bash");
+        assert_eq!(system_prompt("Shell: {shell}", &vars), "Shell: bash");
+
+        vars.insert("shell", "fish");
+        let prompt = system_prompt("Shell: {shell}", &vars);
+        assert!(prompt.starts_with("Shell: fish\nThe shell is fish"));
+
+        // A template that places the guidance itself gets it only there
+        let prompt = system_prompt("[{shell_guidance}] Shell: {shell}", &vars);
+        assert!(prompt.starts_with("[The shell is fish"));
+        assert!(prompt.ends_with("] Shell: fish"));
+
+        assert!(shell_guidance("pwsh.exe").is_some());
+        assert!(shell_guidance("zsh").is_none());
+    }
+
     #[test]
     fn test_render_prompt_missing_var() {
         let vars = HashMap::new();
@@ -203,9 +1360,539 @@ mod tests {
 
     #[test]
     fn test_system_info_collect() {
-        let info = SystemInfo::collect(Some("zh-CN"));
+        let info = SystemInfo::collect(Some("zh-CN"), "/bin/bash");
         assert!(!info.os.is_empty());
         assert!(!info.arch.is_empty());
         assert_eq!(info.lang, "zh-CN");
+        assert_eq!(info.shell, "bash");
+        assert_eq!(SystemInfo::shell_name("pwsh.exe"), "pwsh");
+    }
+
+    #[test]
+    fn test_date_variables_are_iso_8601() {
+        let info = SystemInfo::collect(None, "");
+        assert_eq!(info.date.len(), "2024-01-31".len());
+        assert!(info.datetime.starts_with(&info.date));
+        assert_eq!(info.datetime.as_bytes()[10], b'T');
+        assert_eq!(render_prompt("{date}", &info.to_vars()), info.date);
+    }
+
+    #[test]
+    fn test_default_config_toml() {
+        let text = default_config_toml();
+        // Commented out, it is an empty config
+        let config: Config = toml::from_str(&text).unwrap();
+        assert!(config.llm.model.is_none());
+
+        // Uncommented, every key must still parse and the template must round-trip
+        let uncommented: String = text
+            .lines()
+            .map(|line| {
+                line.strip_prefix("# ")
+                    .or(line.strip_prefix('#'))
+                    .unwrap_or(line)
+            })
+            .filter(|line| !line.starts_with("shellm configuration") && !line.starts_with("these "))
+            .collect::<Vec<_>>()
+            .join("\n");
+        let config: Config = toml::from_str(&uncommented).unwrap();
+        assert_eq!(config.prompt.template, DEFAULT_PROMPT_TEMPLATE);
+        assert_eq!(
+            config.shell.dangerous_patterns,
+            default_dangerous_patterns()
+        );
+        assert_eq!(
+            config.preference.redact_patterns(),
+            PreferenceConfig::default().redact_patterns()
+        );
+        assert_eq!(config.llm.model.as_deref(), Some("gpt-4o-mini"));
+        assert_eq!(
+            KeyBindings::from_config(&config.keys).unwrap(),
+            KeyBindings::default()
+        );
+    }
+
+    #[test]
+    fn test_write_default_config() {
+        let root = env::temp_dir().join(format!("shellm-test-{}-init", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        let path = root.join("shellm").join("config.toml");
+
+        write_default_config(&path, false, &Language::En).unwrap();
+        assert!(std::fs::read_to_string(&path).unwrap().contains("[llm]"));
+
+        std::fs::write(&path, "# mine").unwrap();
+        let err = write_default_config(&path, false, &Language::En).unwrap_err();
+        assert!(
+            err.to_string()
+                .ends_with("already exists; use --force to overwrite it")
+        );
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "# mine");
+        write_default_config(&path, true, &Language::En).unwrap();
+        assert!(std::fs::read_to_string(&path).unwrap().contains("[llm]"));
+        let _ = std::fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn test_template_file() {
+        let root = env::temp_dir().join(format!("shellm-test-{}-template", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(&root).unwrap();
+        let path = root.join("config.toml");
+        std::fs::write(root.join("prompt.txt"), "You help with {shell}.\n").unwrap();
+
+        std::fs::write(
+            &path,
+            "[prompt]\ntemplate = \"inline\"\ntemplate_file = \"prompt.txt\"",
+        )
+        .unwrap();
+        let config = Config::load_from_file(&path, None).unwrap();
+        assert_eq!(config.prompt.template, "You help with {shell}.\n");
+
+        std::fs::write(&path, "[prompt]\ntemplate_file = \"missing.txt\"").unwrap();
+        let err = Config::load_from_file(&path, None).unwrap_err();
+        assert!(format!("{err:#}").contains("prompt.template_file"));
+        let _ = std::fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn test_config_search_order() {
+        let root = env::temp_dir().join(format!("shellm-test-{}-config", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        let (cwd, exe_dir, config_dir) = (root.join("cwd"), root.join("bin"), root.join("xdg"));
+        for dir in [&cwd, &exe_dir, &config_dir.join("shellm")] {
+            std::fs::create_dir_all(dir).unwrap();
+        }
+        let env_path = root.join("custom.toml");
+        let candidates = |env: bool| ConfigCandidates {
+            env: env.then(|| env_path.clone()),
+            cwd: Some(cwd.clone()),
+            exe_dir: Some(exe_dir.clone()),
+            config_dir: Some(config_dir.clone()),
+        };
+
+        // Nothing exists yet: defaults
+        assert_eq!(candidates(true).find(), None);
+
+        let xdg_path = config_dir.join("shellm").join("config.toml");
+        std::fs::write(&xdg_path, "").unwrap();
+        assert_eq!(candidates(true).find(), Some(xdg_path));
+
+        std::fs::write(exe_dir.join("shellm.toml"), "").unwrap();
+        assert_eq!(candidates(true).find(), Some(exe_dir.join("shellm.toml")));
+
+        std::fs::write(cwd.join("shellm.toml"), "").unwrap();
+        assert_eq!(candidates(true).find(), Some(cwd.join("shellm.toml")));
+
+        // SHELLM_CONFIG only counts once the file exists
+        std::fs::write(&env_path, "").unwrap();
+        assert_eq!(candidates(true).find(), Some(env_path.clone()));
+        assert_eq!(candidates(false).find(), Some(cwd.join("shellm.toml")));
+
+        let _ = std::fs::remove_dir_all(&root);
+    }
+
+    fn resolve(toml: &str, env: &[(&str, &str)], overrides: &Overrides) -> ResolvedConfig {
+        let config: Config = toml::from_str(toml).unwrap();
+        config
+            .resolve(
+                |key| {
+                    env.iter()
+                        .find(|(k, _)| *k == key)
+                        .map(|(_, v)| v.to_string())
+                },
+                overrides,
+            )
+            .unwrap()
+    }
+
+    fn validate(toml: &str) -> Result<()> {
+        resolve(toml, &[], &Overrides::default()).validate(&Language::En)
+    }
+
+    #[test]
+    fn test_resolve_model_precedence() {
+        let none = Overrides::default();
+        assert_eq!(resolve("", &[], &none).llm.model, "gpt-4o-mini");
+        let file = "[llm]\nmodel = \"gpt-4o\"";
+        assert_eq!(resolve(file, &[], &none).llm.model, "gpt-4o");
+        let env = [("OPENAI_MODEL", "gpt-4.1")];
+        assert_eq!(resolve(file, &env, &none).llm.model, "gpt-4.1");
+        let cli = Overrides {
+            model: Some("o3".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(resolve(file, &env, &cli).llm.model, "o3");
+
+        // Each provider reads its own variables and defaults
+        let anthropic = "[llm]\nprovider = \"anthropic\"";
+        assert_eq!(
+            resolve(anthropic, &env, &none).llm.model,
+            "claude-sonnet-4-5"
+        );
+        assert_eq!(
+            resolve(anthropic, &[], &none).llm.base_url,
+            "https://api.anthropic.com/v1"
+        );
+    }
+
+    #[test]
+    fn test_api_key_from_file() {
+        let path = env::temp_dir().join(format!("shellm-test-{}-key", std::process::id()));
+        std::fs::write(&path, "sk-from-file\n").unwrap();
+        let file = format!(
+            "[llm]\napi_key = \"sk-inline\"\napi_key_file = {:?}",
+            path.display().to_string()
+        );
+        let none = Overrides::default();
+        assert_eq!(
+            resolve(&file, &[], &none).llm.api_key.as_deref(),
+            Some("sk-from-file")
+        );
+        let env = [("OPENAI_API_KEY", "sk-env")];
+        assert_eq!(
+            resolve(&file, &env, &none).llm.api_key.as_deref(),
+            Some("sk-env")
+        );
+
+        std::fs::write(&path, "\n").unwrap();
+        let config: Config = toml::from_str(&file).unwrap();
+        assert!(config.resolve(|_| None, &none).is_err());
+        std::fs::remove_file(&path).unwrap();
+        let config: Config = toml::from_str(&file).unwrap();
+        assert!(config.resolve(|_| None, &none).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_api_key_from_command() {
+        let key = api_key_from_sources(
+            Some("sk-inline".to_string()),
+            Some("/nonexistent"),
+            Some("printf 'sk-cmd\\n'"),
+        )
+        .unwrap();
+        assert_eq!(key.as_deref(), Some("sk-cmd"));
+        assert!(api_key_from_sources(None, None, Some("exit 3")).is_err());
+        assert_eq!(api_key_from_sources(None, None, None).unwrap(), None);
+    }
+
+    #[test]
+    fn test_resolve_language_precedence() {
+        let none = Overrides::default();
+        assert_eq!(resolve("", &[], &none).preference.language, None);
+        let file = "[preference]\nlanguage = \"zh-CN\"";
+        let language = |config: ResolvedConfig| config.preference.language.unwrap();
+        assert_eq!(language(resolve(file, &[], &none)), "zh-CN");
+        let env = [("SHELLM_LANGUAGE", "ja-JP")];
+        assert_eq!(language(resolve(file, &env, &none)), "ja-JP");
+        let cli = Overrides {
+            language: Some("en-US".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(language(resolve(file, &env, &cli)), "en-US");
+    }
+
+    #[test]
+    fn test_reasoning_effort_from_toml() {
+        let none = Overrides::default();
+        let config = resolve("[llm]\nreasoning_effort = \"low\"", &[], &none);
+        assert_eq!(
+            config.llm.sampling.reasoning_effort,
+            Some(ReasoningEffort::Low)
+        );
+        assert!(toml::from_str::<Config>("[llm]\nreasoning_effort = \"max\"").is_err());
+    }
+
+    #[test]
+    fn test_request_reasoning_from_toml() {
+        let none = Overrides::default();
+        assert!(resolve("", &[], &none).llm.request_reasoning);
+        let config = resolve("[llm]\nrequest_reasoning = false", &[], &none);
+        assert!(!config.llm.request_reasoning);
+        assert_eq!(config.llm.reasoning_effort_when_off, None);
+        let toml = "[llm]\nrequest_reasoning = false\nreasoning_effort_when_off = \"low\"";
+        let config = resolve(toml, &[], &none);
+        assert_eq!(
+            config.llm.reasoning_effort_when_off,
+            Some(ReasoningEffort::Low)
+        );
+    }
+
+    #[test]
+    fn test_resolve_temperature_from_env() {
+        let none = Overrides::default();
+        let config = resolve(
+            "[llm]\ntemperature = 0.5",
+            &[("SHELLM_TEMPERATURE", "0.1")],
+            &none,
+        );
+        assert_eq!(config.llm.sampling.temperature, Some(0.1));
+        let config: Config = toml::from_str("").unwrap();
+        assert!(config.resolve(|_| Some("warm".to_string()), &none).is_err());
+    }
+
+    #[test]
+    fn test_validate_accepts_defaults_and_sane_values() {
+        assert!(validate("").is_ok());
+        assert!(
+            validate(
+                "[llm]\nbase_url = \"http://localhost:11434/v1\"\ntemperature = 0.2\ntop_p = 1.0\n\
+             [preference]\nlanguage = \"zh-CN\""
+            )
+            .is_ok()
+        );
+    }
+
+    #[test]
+    fn test_validate_base_url() {
+        let err = validate("[llm]\nbase_url = \"api.openai.com/v1\"").unwrap_err();
+        assert!(err.to_string().contains("llm.base_url `api.openai.com/v1`"));
+        assert!(validate("[llm]\nbase_url = \"ftp://example.com\"").is_err());
+    }
+
+    #[test]
+    fn test_validate_proxy() {
+        for proxy in [
+            "http://proxy:8080",
+            "socks5://127.0.0.1:1080",
+            "socks5h://localhost:1080",
+        ] {
+            assert!(
+                validate(&format!("[llm]\nproxy = \"{proxy}\"")).is_ok(),
+                "{proxy}"
+            );
+        }
+        let err = validate("[llm]\nproxy = \"socks4://127.0.0.1:1080\"").unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("llm.proxy `socks4://127.0.0.1:1080`")
+        );
+        assert!(validate("[llm]\nproxy = \"127.0.0.1:1080\"").is_err());
+    }
+
+    #[test]
+    fn test_validate_headers() {
+        let headers = "[llm.headers]\nHTTP-Referer = \"https://example.com\"\nX-Title = \"shellm\"";
+        assert!(validate(headers).is_ok());
+        let err = validate("[llm.headers]\n\"X Title\" = \"shellm\"").unwrap_err();
+        assert!(err.to_string().contains("`X Title`"));
+        assert!(validate("[llm.headers]\nX-Tenant = \"a\\nb\"").is_err());
+    }
+
+    #[test]
+    fn test_validate_organization_and_project() {
+        assert!(validate("[llm]\norganization = \"org-Ab12\"\nproject = \"proj_Cd34\"").is_ok());
+        let err = validate("[llm]\norganization = \"Acme Corp\"").unwrap_err();
+        assert!(err.to_string().contains("llm.organization `Acme Corp`"));
+        assert!(validate("[llm]\nproject = \"proj_\"").is_err());
+        assert!(validate("[llm]\nproject = \"org-123\"").is_err());
+    }
+
+    #[test]
+    fn test_validate_language() {
+        let err = validate("[preference]\nlanguage = \"english please\"").unwrap_err();
+        assert!(err.to_string().contains("preference.language"));
+        assert!(validate("[preference]\nlanguage = \"pt_BR\"").is_ok());
+    }
+
+    #[test]
+    fn test_resolve_cwd_precedence() {
+        let none = Overrides::default();
+        let start_dir = |config: ResolvedConfig| config.shell.start_dir().unwrap();
+        assert_eq!(
+            start_dir(resolve("", &[], &none)),
+            env::current_dir().unwrap()
+        );
+        let file = "[shell]\ncwd = \"/srv\"";
+        assert_eq!(start_dir(resolve(file, &[], &none)), PathBuf::from("/srv"));
+        let cli = Overrides {
+            cwd: Some("/tmp".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(start_dir(resolve(file, &[], &cli)), PathBuf::from("/tmp"));
+    }
+
+    #[test]
+    fn test_validate_cwd() {
+        let dir = env::temp_dir();
+        assert!(validate(&format!("[shell]\ncwd = {:?}", dir.display().to_string())).is_ok());
+        let missing = dir.join("shellm-no-such-dir");
+        let err = validate(&format!(
+            "[shell]\ncwd = {:?}",
+            missing.display().to_string()
+        ))
+        .unwrap_err();
+        assert!(err.to_string().contains("shell.cwd"));
+    }
+
+    #[test]
+    fn test_validate_ranges() {
+        let err = validate("[llm]\ntemperature = 3.5").unwrap_err();
+        assert!(err.to_string().contains("llm.temperature = 3.5"));
+        assert!(err.to_string().contains("0-2"));
+        assert!(validate("[llm]\ntop_p = 1.5").is_err());
+        assert!(validate("[llm]\nmax_tokens = 0").is_err());
+        assert!(validate("[llm]\ntimeout_secs = 0").is_err());
+        assert!(validate("[llm]\nconnect_timeout_secs = 0").is_err());
+        assert!(validate("[llm]\nstream_idle_timeout_secs = 0").is_err());
+        assert!(validate("[llm]\nmax_reasoning_chars = 0").is_err());
+    }
+
+    #[test]
+    fn test_parse_os_release() {
+        let debian = r#"PRETTY_NAME="Debian GNU/Linux 12 (bookworm)"
+NAME="Debian GNU/Linux"
+VERSION_ID="12"
+VERSION="12 (bookworm)"
+ID=debian
+"#;
+        assert_eq!(parse_os_release(debian), "debian 12");
+        assert_eq!(
+            parse_os_release("NAME=\"Arch Linux\"\nID=arch\nBUILD_ID=rolling\n"),
+            "arch"
+        );
+        assert_eq!(
+            parse_os_release("ID_LIKE=rhel\nID='fedora'\nVERSION_ID=40"),
+            "fedora 40"
+        );
+        assert_eq!(parse_os_release(""), "");
+    }
+
+    #[test]
+    fn test_parse_shell_version() {
+        assert_eq!(
+            parse_shell_version(
+                "GNU bash, version 5.2.15(1)-release (x86_64-pc-linux-gnu)\nCopyright"
+            ),
+            Some("5.2.15".to_string())
+        );
+        assert_eq!(
+            parse_shell_version("zsh 5.9 (x86_64-apple-darwin23.0)"),
+            Some("5.9".to_string())
+        );
+        assert_eq!(
+            parse_shell_version("fish, version 3.7.1"),
+            Some("3.7.1".to_string())
+        );
+        assert_eq!(
+            parse_shell_version("3.2.57(1)-release"),
+            Some("3.2.57".to_string())
+        );
+        assert_eq!(parse_shell_version("unknown option"), None);
+    }
+
+    #[test]
+    fn test_user_variable() {
+        let user = SystemInfo::detect_user(|key| (key == "USER").then(|| "alice".to_string()));
+        let info = SystemInfo {
+            user,
+            ..SystemInfo::collect(None, "")
+        };
+        assert_eq!(render_prompt("hi {user}", &info.to_vars()), "hi alice");
+
+        let user = SystemInfo::detect_user(|key| (key == "USERNAME").then(|| "bob".to_string()));
+        assert_eq!(user, "bob");
+    }
+
+    #[test]
+    fn test_locale_to_lang() {
+        assert_eq!(SystemInfo::locale_to_lang("ja_JP.UTF-8"), "ja-JP");
+        assert_eq!(SystemInfo::locale_to_lang("zh_CN"), "zh-CN");
+        assert!(matches!(
+            crate::i18n::Language::from_code(&SystemInfo::locale_to_lang("ja_JP.UTF-8")),
+            crate::i18n::Language::Ja
+        ));
+    }
+
+    #[test]
+    fn test_provider_from_toml() {
+        let config: Config = toml::from_str("[llm]\nprovider = \"anthropic\"").unwrap();
+        assert_eq!(config.llm.provider, Provider::Anthropic);
+        let config: Config = toml::from_str("").unwrap();
+        assert_eq!(config.llm.provider, Provider::OpenAI);
+        let config: Config = toml::from_str("[llm]\nprovider = \"ollama\"").unwrap();
+        assert_eq!(config.llm.provider, Provider::Ollama);
+        let config: Config =
+            toml::from_str("[llm]\nprovider = \"azure\"\ndeployment = \"gpt4o\"").unwrap();
+        assert_eq!(config.llm.provider, Provider::Azure);
+        assert_eq!(config.llm.deployment.as_deref(), Some("gpt4o"));
+    }
+
+    #[test]
+    fn test_idle_timeout() {
+        let config: Config = toml::from_str("").unwrap();
+        assert_eq!(config.preference.idle_timeout(), None);
+        let config: Config = toml::from_str("[preference]\nidle_timeout_secs = 0").unwrap();
+        assert_eq!(config.preference.idle_timeout(), None);
+        let config: Config = toml::from_str("[preference]\nidle_timeout_secs = 600").unwrap();
+        assert_eq!(
+            config.preference.idle_timeout(),
+            Some(Duration::from_secs(600))
+        );
+    }
+
+    #[test]
+    fn test_inject_mode_from_toml() {
+        let config: Config = toml::from_str("").unwrap();
+        assert_eq!(config.preference.inject_mode, InjectMode::Prefill);
+        let config: Config = toml::from_str("[preference]\ninject_mode = \"execute\"").unwrap();
+        assert_eq!(config.preference.inject_mode, InjectMode::Execute);
+        assert!(toml::from_str::<Config>("[preference]\ninject_mode = \"run\"").is_err());
+    }
+
+    #[test]
+    fn test_plain_prompt_mode() {
+        let config = resolve("", &[], &Overrides::default());
+        assert_eq!(config.llm.prompt_mode, PromptMode::Json);
+        assert_eq!(config.prompt.template, DEFAULT_PROMPT_TEMPLATE);
+
+        let config = resolve("[prompt]\nmode = \"plain\"", &[], &Overrides::default());
+        assert_eq!(config.llm.prompt_mode, PromptMode::Plain);
+        assert_eq!(config.prompt.template, DEFAULT_PLAIN_PROMPT_TEMPLATE);
+
+        // A custom template is the user's to adapt
+        let config = resolve(
+            "[prompt]\nmode = \"plain\"\ntemplate = \"mine\"",
+            &[],
+            &Overrides::default(),
+        );
+        assert_eq!(config.prompt.template, "mine");
+    }
+
+    #[test]
+    fn test_reasoning_display_from_toml() {
+        let config: Config = toml::from_str("").unwrap();
+        assert_eq!(config.preference.reasoning, ReasoningDisplay::Collapsed);
+        let config: Config = toml::from_str("[preference]\nreasoning = \"hidden\"").unwrap();
+        assert_eq!(config.preference.reasoning, ReasoningDisplay::Hidden);
+    }
+
+    #[test]
+    fn test_profile_overrides_base() {
+        let content = r#"
+[llm]
+model = "gpt-4o"
+base_url = "https://api.openai.com/v1"
+timeout_secs = 30
+
+[profiles.home.llm]
+provider = "ollama"
+model = "llama3"
+"#;
+        let config = Config::parse(content, Some("home")).unwrap();
+        assert_eq!(config.llm.provider, Provider::Ollama);
+        assert_eq!(config.llm.model.as_deref(), Some("llama3"));
+        assert_eq!(config.llm.timeout_secs, Some(30));
+        assert_eq!(
+            config.llm.base_url.as_deref(),
+            Some("https://api.openai.com/v1")
+        );
+
+        // Without a selection the profile is ignored
+        let config = Config::parse(content, None).unwrap();
+        assert_eq!(config.llm.model.as_deref(), Some("gpt-4o"));
+
+        assert!(Config::parse(content, Some("missing")).is_err());
     }
 }
@@ -0,0 +1,160 @@
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+
+use crate::llm::ChatMessage;
+
+/// Chat history persisted as JSON between shellm runs.
+pub struct HistoryStore {
+    path: PathBuf,
+    max_entries: usize,
+}
+
+impl HistoryStore {
+    pub fn new(path: PathBuf, max_entries: usize) -> Self {
+        Self { path, max_entries }
+    }
+
+    /// `<XDG data dir>/shellm/history.json`
+    pub fn default_path() -> Option<PathBuf> {
+        dirs::data_dir().map(|dir| dir.join("shellm").join("history.json"))
+    }
+
+    pub fn load(&self) -> Result<Vec<ChatMessage>> {
+        if !self.path.exists() {
+            return Ok(Vec::new());
+        }
+        let content = fs::read_to_string(&self.path)
+            .with_context(|| format!("Failed to read history file: {}", self.path.display()))?;
+        let mut history: Vec<ChatMessage> = serde_json::from_str(&content)
+            .with_context(|| format!("Failed to parse history file: {}", self.path.display()))?;
+        self.prune(&mut history);
+        Ok(history)
+    }
+
+    pub fn save(&self, history: &[ChatMessage]) -> Result<()> {
+        let mut history = history.to_vec();
+        self.prune(&mut history);
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)
+                .with_context(|| format!("Failed to create directory: {}", dir.display()))?;
+        }
+        let content = serde_json::to_string_pretty(&history)?;
+        // Written aside and renamed over the old file, so a crash cannot truncate it
+        let temp = self.path.with_extension("json.tmp");
+        write_private(&temp, content.as_bytes())
+            .and_then(|()| fs::rename(&temp, &self.path))
+            .with_context(|| format!("Failed to write history file: {}", self.path.display()))
+    }
+
+    /// Move an unreadable history file out of the way so the next save does not
+    /// overwrite it. Returns where it went.
+    pub fn set_aside(&self) -> Result<PathBuf> {
+        let target = self.path.with_extension("json.bad");
+        fs::rename(&self.path, &target)
+            .with_context(|| format!("Failed to move history file: {}", self.path.display()))?;
+        Ok(target)
+    }
+
+    /// Drop the oldest messages beyond the cap, a whole turn at a time.
+    fn prune(&self, history: &mut Vec<ChatMessage>) {
+        if history.len() > self.max_entries {
+            let mut excess = history.len() - self.max_entries;
+            // Keep user/assistant pairs together
+            excess += excess % 2;
+            history.drain(..excess.min(history.len()));
+        }
+    }
+}
+
+/// Create or replace `path` readable by the owner only, since the history holds every
+/// question and answer.
+fn write_private(path: &Path, content: &[u8]) -> std::io::Result<()> {
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(path)?;
+    file.write_all(content)?;
+    file.sync_all()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm::Role;
+
+    fn message(role: Role, content: &str) -> ChatMessage {
+        ChatMessage {
+            role,
+            content: content.to_string(),
+        }
+    }
+
+    fn temp_store(name: &str, max_entries: usize) -> HistoryStore {
+        let dir = std::env::temp_dir().join(format!("shellm-test-{}-{name}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        HistoryStore::new(dir.join("history.json"), max_entries)
+    }
+
+    #[test]
+    fn test_missing_file_loads_empty() {
+        let store = temp_store("missing", 10);
+        assert!(store.load().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_save_and_load_roundtrip() {
+        let store = temp_store("roundtrip", 10);
+        let history = vec![
+            message(Role::User, "list files"),
+            message(Role::Assistant, "Use ls"),
+        ];
+        store.save(&history).unwrap();
+
+        let loaded = store.load().unwrap();
+        assert_eq!(loaded.len(), 2);
+        assert!(matches!(loaded[0].role, Role::User));
+        assert_eq!(loaded[1].content, "Use ls");
+        assert!(!store.path.with_extension("json.tmp").exists());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&store.path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+    }
+
+    #[test]
+    fn test_unreadable_file_is_set_aside() {
+        let store = temp_store("corrupt", 10);
+        fs::create_dir_all(store.path.parent().unwrap()).unwrap();
+        fs::write(&store.path, "[{\"role\": \"user\", \"cont").unwrap();
+        assert!(store.load().is_err());
+
+        let moved = store.set_aside().unwrap();
+        assert_eq!(
+            fs::read_to_string(&moved).unwrap(),
+            "[{\"role\": \"user\", \"cont"
+        );
+        assert!(store.load().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_prunes_oldest_turns() {
+        let store = temp_store("prune", 3);
+        let history = vec![
+            message(Role::User, "q1"),
+            message(Role::Assistant, "a1"),
+            message(Role::User, "q2"),
+            message(Role::Assistant, "a2"),
+        ];
+        store.save(&history).unwrap();
+
+        let loaded = store.load().unwrap();
+        assert_eq!(loaded.len(), 2);
+        assert_eq!(loaded[0].content, "q2");
+    }
+}
@@ -1,17 +1,72 @@
-#[derive(Debug, Clone, Copy, Default)]
+use std::collections::HashMap;
+use std::sync::OnceLock;
+
+/// Translations bundled into the binary; see the file for the format.
+const TRANSLATIONS: &str = include_str!("i18n/translations.toml");
+
+type TranslationTable = HashMap<String, HashMap<String, String>>;
+
+fn translations() -> &'static TranslationTable {
+    static TABLE: OnceLock<TranslationTable> = OnceLock::new();
+    TABLE.get_or_init(|| {
+        toml::from_str(TRANSLATIONS)
+            .unwrap_or_else(|e| panic!("i18n: invalid i18n/translations.toml: {e}"))
+    })
+}
+
+/// Check that the bundled translations parse and define every key for English.
+/// Called once at startup so a broken data file fails loudly instead of mid-session.
+pub fn validate_translations() {
+    let Some(en) = translations().get("en") else {
+        panic!("i18n: i18n/translations.toml has no [en] table");
+    };
+    for key in MessageKey::ALL {
+        if !en.contains_key(key.as_str()) {
+            panic!(
+                "i18n: i18n/translations.toml is missing `{}` for en",
+                key.as_str()
+            );
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
 pub enum Language {
     #[default]
     En,
     Zh,
+    Ja,
+    /// A language that only exists in the translations file, by its table name
+    Custom(&'static str),
 }
 
 impl Language {
-    pub fn from_str(s: &str) -> Self {
-        let s = s.to_lowercase();
-        if s.starts_with("zh") {
-            Language::Zh
-        } else {
-            Language::En
+    /// Map a language or locale code ("ja", "zh-CN", "fr_FR") to a UI language.
+    /// Unknown codes fall back to English.
+    pub fn from_code(code: &str) -> Self {
+        let code = code.to_lowercase().replace('_', "-");
+        let primary = code.split('-').next().unwrap_or(&code);
+        match primary {
+            "en" => return Language::En,
+            "zh" => return Language::Zh,
+            "ja" => return Language::Ja,
+            _ => {}
+        }
+        let table = translations();
+        [code.as_str(), primary]
+            .into_iter()
+            .find_map(|c| table.get_key_value(c))
+            .map(|(name, _)| Language::Custom(name.as_str()))
+            .unwrap_or_default()
+    }
+
+    /// Table name in the translations file
+    pub fn code(&self) -> &'static str {
+        match self {
+            Language::En => "en",
+            Language::Zh => "zh",
+            Language::Ja => "ja",
+            Language::Custom(code) => code,
         }
     }
 }
@@ -20,70 +75,596 @@ impl Language {
 pub enum MessageKey {
     WelcomeMessage,
     PromptUser,
+    PromptEditCommand,
     PromptAssistant,
     PromptCandidate,
     ThinkingProcess,
     HintToggleReasoning,
+    HintToggleExplanation,
+    HintChooseCandidate,
     ReasoningStart,
     ReasoningEnd,
     ReasoningTruncated,
+    ReasoningCapped,
     ApiKeyRequired,
+    NoModelList,
+    IdleTimeout,
+    ChatOnlyMode,
+    AzureConfigRequired,
+    InvalidBaseUrl,
+    InvalidProxy,
+    InvalidLanguage,
+    InvalidCwd,
+    InvalidHeader,
+    InvalidOpenAIId,
+    ValueOutOfRange,
     JsonParseError,
+    EmptyResponse,
+    ContentFiltered,
+    TokenUsage,
+    ReplyTiming,
+    RequestTimeout,
+    StreamStalled,
+    AuthFailed,
+    RateLimited,
+    HttpError,
+    ResponseUnreadable,
+    NetworkError,
+    RequestFailed,
+    DangerousCommand,
+    ConfirmInject,
+    CommandStaged,
+    ConfirmRun,
+    AgenticRun,
+    AgenticFollowUp,
+    AgenticLimit,
+    DoctorProvider,
+    DoctorModel,
+    DoctorDeployment,
+    DoctorBaseUrl,
+    DoctorApiKey,
+    DoctorNoApiKey,
+    DoctorProxy,
+    DoctorFallback,
+    DoctorPass,
+    DoctorFail,
+    HistoryUnreadable,
+    HistoryMovedAside,
+    HistorySaveFailed,
+    NoConfigDir,
+    ConfigExists,
+}
+
+impl MessageKey {
+    pub const ALL: &[MessageKey] = &[
+        MessageKey::WelcomeMessage,
+        MessageKey::PromptUser,
+        MessageKey::PromptEditCommand,
+        MessageKey::PromptAssistant,
+        MessageKey::PromptCandidate,
+        MessageKey::ThinkingProcess,
+        MessageKey::HintToggleReasoning,
+        MessageKey::HintToggleExplanation,
+        MessageKey::HintChooseCandidate,
+        MessageKey::ReasoningStart,
+        MessageKey::ReasoningEnd,
+        MessageKey::ReasoningTruncated,
+        MessageKey::ReasoningCapped,
+        MessageKey::ApiKeyRequired,
+        MessageKey::NoModelList,
+        MessageKey::IdleTimeout,
+        MessageKey::ChatOnlyMode,
+        MessageKey::AzureConfigRequired,
+        MessageKey::InvalidBaseUrl,
+        MessageKey::InvalidProxy,
+        MessageKey::InvalidLanguage,
+        MessageKey::InvalidCwd,
+        MessageKey::InvalidHeader,
+        MessageKey::InvalidOpenAIId,
+        MessageKey::ValueOutOfRange,
+        MessageKey::JsonParseError,
+        MessageKey::EmptyResponse,
+        MessageKey::ContentFiltered,
+        MessageKey::TokenUsage,
+        MessageKey::ReplyTiming,
+        MessageKey::RequestTimeout,
+        MessageKey::StreamStalled,
+        MessageKey::AuthFailed,
+        MessageKey::RateLimited,
+        MessageKey::HttpError,
+        MessageKey::ResponseUnreadable,
+        MessageKey::NetworkError,
+        MessageKey::RequestFailed,
+        MessageKey::DangerousCommand,
+        MessageKey::ConfirmInject,
+        MessageKey::CommandStaged,
+        MessageKey::ConfirmRun,
+        MessageKey::AgenticRun,
+        MessageKey::AgenticFollowUp,
+        MessageKey::AgenticLimit,
+        MessageKey::DoctorProvider,
+        MessageKey::DoctorModel,
+        MessageKey::DoctorDeployment,
+        MessageKey::DoctorBaseUrl,
+        MessageKey::DoctorApiKey,
+        MessageKey::DoctorNoApiKey,
+        MessageKey::DoctorProxy,
+        MessageKey::DoctorFallback,
+        MessageKey::DoctorPass,
+        MessageKey::DoctorFail,
+        MessageKey::HistoryUnreadable,
+        MessageKey::HistoryMovedAside,
+        MessageKey::HistorySaveFailed,
+        MessageKey::NoConfigDir,
+        MessageKey::ConfigExists,
+    ];
+
+    /// Key used in the translations file
+    pub fn as_str(self) -> &'static str {
+        match self {
+            MessageKey::WelcomeMessage => "welcome_message",
+            MessageKey::PromptUser => "prompt_user",
+            MessageKey::PromptEditCommand => "prompt_edit_command",
+            MessageKey::PromptAssistant => "prompt_assistant",
+            MessageKey::PromptCandidate => "prompt_candidate",
+            MessageKey::ThinkingProcess => "thinking_process",
+            MessageKey::HintToggleReasoning => "hint_toggle_reasoning",
+            MessageKey::HintToggleExplanation => "hint_toggle_explanation",
+            MessageKey::HintChooseCandidate => "hint_choose_candidate",
+            MessageKey::ReasoningStart => "reasoning_start",
+            MessageKey::ReasoningEnd => "reasoning_end",
+            MessageKey::ReasoningTruncated => "reasoning_truncated",
+            MessageKey::ReasoningCapped => "reasoning_capped",
+            MessageKey::ApiKeyRequired => "api_key_required",
+            MessageKey::NoModelList => "no_model_list",
+            MessageKey::IdleTimeout => "idle_timeout",
+            MessageKey::ChatOnlyMode => "chat_only_mode",
+            MessageKey::AzureConfigRequired => "azure_config_required",
+            MessageKey::InvalidBaseUrl => "invalid_base_url",
+            MessageKey::InvalidProxy => "invalid_proxy",
+            MessageKey::InvalidLanguage => "invalid_language",
+            MessageKey::InvalidCwd => "invalid_cwd",
+            MessageKey::InvalidHeader => "invalid_header",
+            MessageKey::InvalidOpenAIId => "invalid_openai_id",
+            MessageKey::ValueOutOfRange => "value_out_of_range",
+            MessageKey::JsonParseError => "json_parse_error",
+            MessageKey::EmptyResponse => "empty_response",
+            MessageKey::ContentFiltered => "content_filtered",
+            MessageKey::TokenUsage => "token_usage",
+            MessageKey::ReplyTiming => "reply_timing",
+            MessageKey::RequestTimeout => "request_timeout",
+            MessageKey::StreamStalled => "stream_stalled",
+            MessageKey::AuthFailed => "auth_failed",
+            MessageKey::RateLimited => "rate_limited",
+            MessageKey::HttpError => "http_error",
+            MessageKey::ResponseUnreadable => "response_unreadable",
+            MessageKey::NetworkError => "network_error",
+            MessageKey::RequestFailed => "request_failed",
+            MessageKey::DangerousCommand => "dangerous_command",
+            MessageKey::ConfirmInject => "confirm_inject",
+            MessageKey::CommandStaged => "command_staged",
+            MessageKey::ConfirmRun => "confirm_run",
+            MessageKey::AgenticRun => "agentic_run",
+            MessageKey::AgenticFollowUp => "agentic_follow_up",
+            MessageKey::AgenticLimit => "agentic_limit",
+            MessageKey::DoctorProvider => "doctor_provider",
+            MessageKey::DoctorModel => "doctor_model",
+            MessageKey::DoctorDeployment => "doctor_deployment",
+            MessageKey::DoctorBaseUrl => "doctor_base_url",
+            MessageKey::DoctorApiKey => "doctor_api_key",
+            MessageKey::DoctorNoApiKey => "doctor_no_api_key",
+            MessageKey::DoctorProxy => "doctor_proxy",
+            MessageKey::DoctorFallback => "doctor_fallback",
+            MessageKey::DoctorPass => "doctor_pass",
+            MessageKey::DoctorFail => "doctor_fail",
+            MessageKey::HistoryUnreadable => "history_unreadable",
+            MessageKey::HistoryMovedAside => "history_moved_aside",
+            MessageKey::HistorySaveFailed => "history_save_failed",
+            MessageKey::NoConfigDir => "no_config_dir",
+            MessageKey::ConfigExists => "config_exists",
+        }
+    }
 }
 
+/// Look up `key` in the translations file, falling back to the built-in strings.
 pub fn t(lang: &Language, key: MessageKey) -> &'static str {
+    translations()
+        .get(lang.code())
+        .and_then(|messages| messages.get(key.as_str()))
+        .map(String::as_str)
+        .unwrap_or_else(|| builtin(lang, key))
+}
+
+fn builtin(lang: &Language, key: MessageKey) -> &'static str {
     match (lang, key) {
+        // Languages defined only in the data file use English for missing keys
+        (Language::Custom(_), key) => builtin(&Language::En, key),
+
         // Welcome message
         (Language::En, MessageKey::WelcomeMessage) => {
-            "[LLM chat] Type your question. Ctrl+L accepts the command. Ctrl+C exits. Ctrl+R toggles reasoning."
+            "[LLM chat] Type your question. Ctrl+L accepts the command, Ctrl+E edits it first. Ctrl+C exits, Alt+C also interrupts the running command. Ctrl+R toggles reasoning. Start with ? to get an explanation instead of a command."
         }
         (Language::Zh, MessageKey::WelcomeMessage) => {
-            "[LLM chat] 输入您的问题。Ctrl+L 接受命令，Ctrl+C 退出，Ctrl+R 展开/折叠思维链。"
+            "[LLM chat] 输入您的问题。Ctrl+L 接受命令，Ctrl+E 先编辑再接受，Ctrl+C 退出，Alt+C 退出并中断正在运行的命令，Ctrl+R 展开/折叠思维链。以 ? 开头只获取解释而不生成命令。"
+        }
+        (Language::Ja, MessageKey::WelcomeMessage) => {
+            "[LLM chat] 質問を入力してください。Ctrl+L でコマンドを採用、Ctrl+E で編集してから採用、Ctrl+C で終了、Alt+C で終了して実行中のコマンドを中断、Ctrl+R で思考過程を表示/非表示。? で始めるとコマンドではなく説明のみを返します。"
         }
 
         // User input prompt
         (Language::En, MessageKey::PromptUser) => "you> ",
         (Language::Zh, MessageKey::PromptUser) => "你> ",
+        (Language::Ja, MessageKey::PromptUser) => "あなた> ",
+
+        // Prompt while editing the suggested command
+        (Language::En, MessageKey::PromptEditCommand) => "edit> ",
+        (Language::Zh, MessageKey::PromptEditCommand) => "编辑> ",
+        (Language::Ja, MessageKey::PromptEditCommand) => "編集> ",
 
         // AI response prompt
         (Language::En, MessageKey::PromptAssistant) => "assistant> ",
         (Language::Zh, MessageKey::PromptAssistant) => "助手> ",
+        (Language::Ja, MessageKey::PromptAssistant) => "アシスタント> ",
 
         // Candidate command prompt
         (Language::En, MessageKey::PromptCandidate) => "candidate: ",
         (Language::Zh, MessageKey::PromptCandidate) => "候选命令: ",
+        (Language::Ja, MessageKey::PromptCandidate) => "候補コマンド: ",
 
         // “Thinking” indicator
         (Language::En, MessageKey::ThinkingProcess) => "[Thinking] ",
         (Language::Zh, MessageKey::ThinkingProcess) => "[思考中] ",
+        (Language::Ja, MessageKey::ThinkingProcess) => "[思考中] ",
 
         // Hint for expanding/collapsing reasoning
         (Language::En, MessageKey::HintToggleReasoning) => "(Ctrl+R to expand/collapse reasoning)",
         (Language::Zh, MessageKey::HintToggleReasoning) => "(Ctrl+R 展开/折叠思维链)",
+        (Language::Ja, MessageKey::HintToggleReasoning) => "(Ctrl+R で思考過程を表示/非表示)",
+        (Language::En, MessageKey::HintToggleExplanation) => {
+            "(Ctrl+X to show/hide the explanation)"
+        }
+        (Language::Zh, MessageKey::HintToggleExplanation) => "(Ctrl+X 展开/折叠命令说明)",
+        (Language::Ja, MessageKey::HintToggleExplanation) => {
+            "(Ctrl+X でコマンドの説明を表示/非表示)"
+        }
+
+        // Several candidate commands; {n} is their count
+        (Language::En, MessageKey::HintChooseCandidate) => "(press 1-{n} to choose)",
+        (Language::Zh, MessageKey::HintChooseCandidate) => "(按 1-{n} 选择)",
+        (Language::Ja, MessageKey::HintChooseCandidate) => "(1-{n} で選択)",
 
         // Reasoning section start marker
         (Language::En, MessageKey::ReasoningStart) => "--- Reasoning ---",
         (Language::Zh, MessageKey::ReasoningStart) => "--- 思维链 ---",
+        (Language::Ja, MessageKey::ReasoningStart) => "--- 思考過程 ---",
 
         // Reasoning section end marker
         (Language::En, MessageKey::ReasoningEnd) => "--- End ---",
         (Language::Zh, MessageKey::ReasoningEnd) => "--- 结束 ---",
+        (Language::Ja, MessageKey::ReasoningEnd) => "--- 終了 ---",
 
         // Reasoning content truncated marker
-        (Language::En, MessageKey::ReasoningTruncated) => "(truncated to fit terminal height)",
-        (Language::Zh, MessageKey::ReasoningTruncated) => "（内容过长，已按终端高度截断）",
+        (Language::En, MessageKey::ReasoningTruncated) => {
+            "(truncated to fit terminal height; PageUp/PageDown scroll)"
+        }
+        (Language::Zh, MessageKey::ReasoningTruncated) => {
+            "（内容过长，已按终端高度截断；PageUp/PageDown 滚动）"
+        }
+        (Language::Ja, MessageKey::ReasoningTruncated) => {
+            "（端末の高さに合わせて省略しました。PageUp/PageDown でスクロール）"
+        }
+        (Language::En, MessageKey::ReasoningCapped) => {
+            "[earlier reasoning dropped, see llm.max_reasoning_chars]"
+        }
+        (Language::Zh, MessageKey::ReasoningCapped) => {
+            "[较早的思维链已丢弃，见 llm.max_reasoning_chars]"
+        }
+        (Language::Ja, MessageKey::ReasoningCapped) => {
+            "[以前の思考過程は破棄されました（llm.max_reasoning_chars 参照）]"
+        }
 
         // API key required error
         (Language::En, MessageKey::ApiKeyRequired) => {
-            "OPENAI_API_KEY is required (set via config file or environment variable)"
+            "API key is required (set llm.api_key in the config file, or OPENAI_API_KEY / ANTHROPIC_API_KEY)"
         }
         (Language::Zh, MessageKey::ApiKeyRequired) => {
-            "需要 OPENAI_API_KEY（请通过配置文件或环境变量设置）"
+            "需要 API 密钥（请在配置文件中设置 llm.api_key，或设置 OPENAI_API_KEY / ANTHROPIC_API_KEY）"
+        }
+        (Language::Ja, MessageKey::ApiKeyRequired) => {
+            "API キーが必要です（設定ファイルの llm.api_key、または OPENAI_API_KEY / ANTHROPIC_API_KEY を設定してください）"
+        }
+        (Language::En, MessageKey::NoModelList) => {
+            "This endpoint does not list its models; set llm.model to a name from the provider's documentation"
+        }
+        (Language::Zh, MessageKey::NoModelList) => {
+            "该端点不提供模型列表；请参考服务商文档设置 llm.model"
+        }
+        (Language::Ja, MessageKey::NoModelList) => {
+            "このエンドポイントはモデル一覧を提供していません。プロバイダーのドキュメントを参照して llm.model を設定してください"
+        }
+
+        // Session ended by preference.idle_timeout_secs
+        (Language::En, MessageKey::IdleTimeout) => "shellm: no input for {secs}s, exiting",
+        (Language::Zh, MessageKey::IdleTimeout) => "shellm：{secs} 秒无输入，已退出",
+        (Language::Ja, MessageKey::IdleTimeout) => {
+            "shellm: {secs} 秒間入力がなかったため終了します"
+        }
+
+        // The shell could not be wrapped, so only chatting over stdin/stdout is left
+        (Language::En, MessageKey::ChatOnlyMode) => {
+            "shellm: could not start the shell ({error}); chat-only mode: one question per line, Ctrl+D quits"
+        }
+        (Language::Zh, MessageKey::ChatOnlyMode) => {
+            "shellm：无法启动 shell（{error}）；进入仅对话模式：每行一个问题，Ctrl+D 退出"
+        }
+        (Language::Ja, MessageKey::ChatOnlyMode) => {
+            "shellm: シェルを起動できません（{error}）。チャット専用モード：1 行に 1 つ質問、Ctrl+D で終了"
+        }
+
+        // Azure OpenAI endpoint settings missing
+        (Language::En, MessageKey::AzureConfigRequired) => {
+            "Azure OpenAI needs llm.base_url (or AZURE_OPENAI_ENDPOINT) and llm.deployment"
+        }
+        (Language::Zh, MessageKey::AzureConfigRequired) => {
+            "Azure OpenAI 需要设置 llm.base_url（或 AZURE_OPENAI_ENDPOINT）和 llm.deployment"
+        }
+        (Language::Ja, MessageKey::AzureConfigRequired) => {
+            "Azure OpenAI には llm.base_url（または AZURE_OPENAI_ENDPOINT）と llm.deployment の設定が必要です"
+        }
+
+        // Config validation; {value}, {field} and {range} are filled in
+        (Language::En, MessageKey::InvalidBaseUrl) => {
+            "Invalid llm.base_url `{value}`: expected an http(s) URL such as https://api.openai.com/v1"
+        }
+        (Language::Zh, MessageKey::InvalidBaseUrl) => {
+            "llm.base_url `{value}` 无效：应为 http(s) 地址，例如 https://api.openai.com/v1"
+        }
+        (Language::Ja, MessageKey::InvalidBaseUrl) => {
+            "llm.base_url `{value}` が不正です：https://api.openai.com/v1 のような http(s) URL を指定してください"
+        }
+        (Language::En, MessageKey::InvalidProxy) => {
+            "Invalid llm.proxy `{value}`: expected an http://, https://, socks5:// or socks5h:// URL"
+        }
+        (Language::Zh, MessageKey::InvalidProxy) => {
+            "llm.proxy `{value}` 无效：应为 http://、https://、socks5:// 或 socks5h:// 地址"
+        }
+        (Language::Ja, MessageKey::InvalidProxy) => {
+            "llm.proxy `{value}` が不正です：http://、https://、socks5://、socks5h:// の URL を指定してください"
+        }
+        (Language::En, MessageKey::InvalidLanguage) => {
+            "Invalid preference.language `{value}`: expected a code such as en, zh-CN or ja-JP"
+        }
+        (Language::Zh, MessageKey::InvalidLanguage) => {
+            "preference.language `{value}` 无效：应为 en、zh-CN、ja-JP 这样的语言代码"
+        }
+        (Language::Ja, MessageKey::InvalidLanguage) => {
+            "preference.language `{value}` が不正です：en、zh-CN、ja-JP のような言語コードを指定してください"
+        }
+        (Language::En, MessageKey::InvalidCwd) => {
+            "Invalid shell.cwd `{value}`: not an existing directory"
+        }
+        (Language::Zh, MessageKey::InvalidCwd) => "shell.cwd `{value}` 无效：目录不存在",
+        (Language::Ja, MessageKey::InvalidCwd) => {
+            "shell.cwd `{value}` が不正です：存在するディレクトリを指定してください"
+        }
+        (Language::En, MessageKey::InvalidHeader) => {
+            "Invalid llm.headers entry `{value}`: header names cannot contain spaces or separators, and values must be single-line"
+        }
+        (Language::Zh, MessageKey::InvalidHeader) => {
+            "llm.headers 中的 `{value}` 无效：请求头名称不能包含空格或分隔符，值必须为单行"
+        }
+        (Language::Ja, MessageKey::InvalidHeader) => {
+            "llm.headers の `{value}` が不正です：ヘッダー名に空白や区切り文字は使えず、値は 1 行である必要があります"
+        }
+        (Language::En, MessageKey::InvalidOpenAIId) => {
+            "Invalid {field} `{value}`: expected an id starting with `{prefix}` (see the OpenAI dashboard settings)"
+        }
+        (Language::Zh, MessageKey::InvalidOpenAIId) => {
+            "{field} `{value}` 无效：应为以 `{prefix}` 开头的 ID（见 OpenAI 控制台设置）"
+        }
+        (Language::Ja, MessageKey::InvalidOpenAIId) => {
+            "{field} `{value}` が不正です：`{prefix}` で始まる ID を指定してください（OpenAI ダッシュボードの設定を参照）"
+        }
+        (Language::En, MessageKey::ValueOutOfRange) => {
+            "{field} = {value} is out of range (expected {range})"
+        }
+        (Language::Zh, MessageKey::ValueOutOfRange) => "{field} = {value} 超出范围（应为 {range}）",
+        (Language::Ja, MessageKey::ValueOutOfRange) => {
+            "{field} = {value} は範囲外です（{range} を指定してください）"
         }
 
         // JSON parse error
         (Language::En, MessageKey::JsonParseError) => "[JSON parse error: ",
         (Language::Zh, MessageKey::JsonParseError) => "[JSON 解析错误: ",
+        (Language::Ja, MessageKey::JsonParseError) => "[JSON 解析エラー: ",
+
+        // Empty reply
+        (Language::En, MessageKey::EmptyResponse) => {
+            "The model returned an empty response. Try rephrasing the question."
+        }
+        (Language::Zh, MessageKey::EmptyResponse) => "模型返回了空响应，请换个方式提问。",
+        (Language::Ja, MessageKey::EmptyResponse) => {
+            "モデルから空の応答が返されました。質問を言い換えてみてください。"
+        }
+        (Language::En, MessageKey::ContentFiltered) => {
+            "The response was blocked by the provider's content filter (finish reason: {reason})."
+        }
+        (Language::Zh, MessageKey::ContentFiltered) => {
+            "响应被服务商的内容过滤拦截（结束原因：{reason}）。"
+        }
+        (Language::Ja, MessageKey::ContentFiltered) => {
+            "応答はプロバイダーのコンテンツフィルターによりブロックされました（終了理由：{reason}）。"
+        }
+        (Language::En, MessageKey::TokenUsage) => {
+            "tokens: {prompt} prompt / {completion} completion"
+        }
+        (Language::Zh, MessageKey::TokenUsage) => "tokens：提示 {prompt} / 生成 {completion}",
+        (Language::Ja, MessageKey::TokenUsage) => {
+            "トークン：プロンプト {prompt} / 生成 {completion}"
+        }
+        (Language::En, MessageKey::ReplyTiming) => "took {secs}s",
+        (Language::Zh, MessageKey::ReplyTiming) => "耗时 {secs} 秒",
+        (Language::Ja, MessageKey::ReplyTiming) => "所要時間 {secs} 秒",
+
+        // LLM request timed out
+        (Language::En, MessageKey::RequestTimeout) => {
+            "The LLM request timed out (see llm.timeout_secs / llm.connect_timeout_secs)"
+        }
+        (Language::Zh, MessageKey::RequestTimeout) => {
+            "LLM 请求超时（参见 llm.timeout_secs / llm.connect_timeout_secs）"
+        }
+        (Language::Ja, MessageKey::RequestTimeout) => {
+            "LLM リクエストがタイムアウトしました（llm.timeout_secs / llm.connect_timeout_secs を参照）"
+        }
+
+        // Streamed reply stopped arriving
+        (Language::En, MessageKey::StreamStalled) => {
+            "The LLM stopped sending its reply for {secs}s (see llm.stream_idle_timeout_secs)"
+        }
+        (Language::Zh, MessageKey::StreamStalled) => {
+            "LLM 已 {secs} 秒没有继续返回内容（参见 llm.stream_idle_timeout_secs）"
+        }
+        (Language::Ja, MessageKey::StreamStalled) => {
+            "LLM の応答が {secs} 秒間途絶えました（llm.stream_idle_timeout_secs を参照）"
+        }
+
+        // Why an LLM request failed, by kind
+        (Language::En, MessageKey::AuthFailed) => {
+            "The provider rejected the credentials ({detail}); check llm.api_key"
+        }
+        (Language::Zh, MessageKey::AuthFailed) => {
+            "服务商拒绝了凭据（{detail}），请检查 llm.api_key"
+        }
+        (Language::Ja, MessageKey::AuthFailed) => {
+            "プロバイダーが認証情報を拒否しました（{detail}）。llm.api_key を確認してください"
+        }
+        (Language::En, MessageKey::RateLimited) => {
+            "The provider is rate limiting requests; try again shortly ({detail})"
+        }
+        (Language::Zh, MessageKey::RateLimited) => "服务商正在限流，请稍后再试（{detail}）",
+        (Language::Ja, MessageKey::RateLimited) => {
+            "プロバイダーがリクエストを制限しています。しばらくしてから再試行してください（{detail}）"
+        }
+        (Language::En, MessageKey::HttpError) => "The provider returned {status}: {detail}",
+        (Language::Zh, MessageKey::HttpError) => "服务商返回 {status}：{detail}",
+        (Language::Ja, MessageKey::HttpError) => "プロバイダーが {status} を返しました：{detail}",
+        (Language::En, MessageKey::ResponseUnreadable) => {
+            "Could not read the provider's response: {detail}"
+        }
+        (Language::Zh, MessageKey::ResponseUnreadable) => "无法解析服务商的响应：{detail}",
+        (Language::Ja, MessageKey::ResponseUnreadable) => {
+            "プロバイダーの応答を読み取れませんでした：{detail}"
+        }
+        (Language::En, MessageKey::NetworkError) => "Could not reach the provider: {detail}",
+        (Language::Zh, MessageKey::NetworkError) => "无法连接到服务商：{detail}",
+        (Language::Ja, MessageKey::NetworkError) => "プロバイダーに接続できませんでした：{detail}",
+
+        // LLM request failed, shown in chat mode
+        (Language::En, MessageKey::RequestFailed) => "[Error] ",
+        (Language::Zh, MessageKey::RequestFailed) => "[错误] ",
+        (Language::Ja, MessageKey::RequestFailed) => "[エラー] ",
+
+        // Accepted command matched a dangerous pattern
+        (Language::En, MessageKey::DangerousCommand) => {
+            "[Warning] This command may be destructive: "
+        }
+        (Language::Zh, MessageKey::DangerousCommand) => "[警告] 该命令可能具有破坏性: ",
+        (Language::Ja, MessageKey::DangerousCommand) => {
+            "[警告] このコマンドは破壊的な可能性があります: "
+        }
+
+        // Confirmation question before injecting a dangerous command
+        (Language::En, MessageKey::ConfirmInject) => "Inject it into the shell anyway? [y/N] ",
+        (Language::Zh, MessageKey::ConfirmInject) => "仍然将其填入 shell？[y/N] ",
+        (Language::Ja, MessageKey::ConfirmInject) => "それでもシェルに入力しますか？[y/N] ",
+
+        // Note after the stage key put the command on the shell's line
+        (Language::En, MessageKey::CommandStaged) => "(command placed on the shell prompt)",
+        (Language::Zh, MessageKey::CommandStaged) => "（命令已放到 shell 提示符上）",
+        (Language::Ja, MessageKey::CommandStaged) => {
+            "（コマンドをシェルのプロンプトに入力しました）"
+        }
+
+        // Agentic mode: confirming a command, sending its output back, stopping
+        (Language::En, MessageKey::AgenticRun) => "[Run] ",
+        (Language::Zh, MessageKey::AgenticRun) => "[执行] ",
+        (Language::Ja, MessageKey::AgenticRun) => "[実行] ",
+        (Language::En, MessageKey::ConfirmRun) => "Run it and send the output back? [y/N] ",
+        (Language::Zh, MessageKey::ConfirmRun) => "执行该命令并把输出发回给模型？[y/N] ",
+        (Language::Ja, MessageKey::ConfirmRun) => "実行して出力をモデルに送りますか？[y/N] ",
+        (Language::En, MessageKey::AgenticFollowUp) => "(sending the command's output back)",
+        (Language::Zh, MessageKey::AgenticFollowUp) => "（正在把命令输出发回给模型）",
+        (Language::Ja, MessageKey::AgenticFollowUp) => "（コマンドの出力をモデルに送っています）",
+        (Language::En, MessageKey::AgenticLimit) => {
+            "shellm: stopped after {n} commands (see preference.agentic_max_steps)"
+        }
+        (Language::Zh, MessageKey::AgenticLimit) => {
+            "shellm：已执行 {n} 条命令，停止（参见 preference.agentic_max_steps）"
+        }
+        (Language::Ja, MessageKey::AgenticLimit) => {
+            "shellm: {n} 個のコマンドを実行したので停止しました（preference.agentic_max_steps を参照）"
+        }
+        (Language::En, MessageKey::DoctorProvider) => "provider: {value}",
+        (Language::Zh, MessageKey::DoctorProvider) => "提供方：{value}",
+        (Language::Ja, MessageKey::DoctorProvider) => "プロバイダー: {value}",
+        (Language::En, MessageKey::DoctorModel) => "model:    {value}",
+        (Language::Zh, MessageKey::DoctorModel) => "模型：{value}",
+        (Language::Ja, MessageKey::DoctorModel) => "モデル: {value}",
+        (Language::En, MessageKey::DoctorDeployment) => "deployment: {value}",
+        (Language::Zh, MessageKey::DoctorDeployment) => "部署：{value}",
+        (Language::Ja, MessageKey::DoctorDeployment) => "デプロイ: {value}",
+        (Language::En, MessageKey::DoctorBaseUrl) => "base_url: {value}",
+        (Language::Zh, MessageKey::DoctorBaseUrl) => "base_url：{value}",
+        (Language::Ja, MessageKey::DoctorBaseUrl) => "base_url: {value}",
+        (Language::En, MessageKey::DoctorApiKey) => "api key:  {value}",
+        (Language::Zh, MessageKey::DoctorApiKey) => "API 密钥：{value}",
+        (Language::Ja, MessageKey::DoctorApiKey) => "API キー: {value}",
+        (Language::En, MessageKey::DoctorNoApiKey) => "(none)",
+        (Language::Zh, MessageKey::DoctorNoApiKey) => "（无）",
+        (Language::Ja, MessageKey::DoctorNoApiKey) => "（なし）",
+        (Language::En, MessageKey::DoctorProxy) => "proxy:    {value}",
+        (Language::Zh, MessageKey::DoctorProxy) => "代理：{value}",
+        (Language::Ja, MessageKey::DoctorProxy) => "プロキシ: {value}",
+        (Language::En, MessageKey::DoctorFallback) => "fallback: {value} (not checked)",
+        (Language::Zh, MessageKey::DoctorFallback) => "备用模型：{value}（未检查）",
+        (Language::Ja, MessageKey::DoctorFallback) => "フォールバック: {value}（未確認）",
+        (Language::En, MessageKey::DoctorPass) => "PASS ({secs}s)",
+        (Language::Zh, MessageKey::DoctorPass) => "通过（{secs} 秒）",
+        (Language::Ja, MessageKey::DoctorPass) => "成功（{secs} 秒）",
+        (Language::En, MessageKey::DoctorFail) => "FAIL: {value}",
+        (Language::Zh, MessageKey::DoctorFail) => "失败：{value}",
+        (Language::Ja, MessageKey::DoctorFail) => "失敗: {value}",
+        (Language::En, MessageKey::HistoryUnreadable) => {
+            "shellm: could not load the chat history ({error}); starting without it"
+        }
+        (Language::Zh, MessageKey::HistoryUnreadable) => {
+            "shellm：无法加载对话历史（{error}），将以空历史启动"
+        }
+        (Language::Ja, MessageKey::HistoryUnreadable) => {
+            "shellm: 会話履歴を読み込めません（{error}）。履歴なしで開始します"
+        }
+        (Language::En, MessageKey::HistoryMovedAside) => {
+            "shellm: the unreadable history file was moved to {path}"
+        }
+        (Language::Zh, MessageKey::HistoryMovedAside) => "shellm：无法读取的历史文件已移至 {path}",
+        (Language::Ja, MessageKey::HistoryMovedAside) => {
+            "shellm: 読み込めない履歴ファイルを {path} に移動しました"
+        }
+        (Language::En, MessageKey::HistorySaveFailed) => {
+            "[Error] Could not save the chat history: "
+        }
+        (Language::Zh, MessageKey::HistorySaveFailed) => "[错误] 无法保存对话历史：",
+        (Language::Ja, MessageKey::HistorySaveFailed) => "[エラー] 会話履歴を保存できません: ",
+        (Language::En, MessageKey::NoConfigDir) => "no config directory found",
+        (Language::Zh, MessageKey::NoConfigDir) => "找不到配置目录",
+        (Language::Ja, MessageKey::NoConfigDir) => "設定ディレクトリが見つかりません",
+        (Language::En, MessageKey::ConfigExists) => {
+            "{path} already exists; use --force to overwrite it"
+        }
+        (Language::Zh, MessageKey::ConfigExists) => "{path} 已存在；使用 --force 覆盖它",
+        (Language::Ja, MessageKey::ConfigExists) => {
+            "{path} は既に存在します。上書きするには --force を付けてください"
+        }
     }
 }
 
@@ -92,15 +673,17 @@ mod tests {
     use super::*;
 
     #[test]
-    fn test_language_from_str() {
-        assert!(matches!(Language::from_str("zh-CN"), Language::Zh));
-        assert!(matches!(Language::from_str("zh_CN"), Language::Zh));
-        assert!(matches!(Language::from_str("zh"), Language::Zh));
-        assert!(matches!(Language::from_str("ZH-CN"), Language::Zh));
-        assert!(matches!(Language::from_str("en-US"), Language::En));
-        assert!(matches!(Language::from_str("en"), Language::En));
-        assert!(matches!(Language::from_str("EN"), Language::En));
-        assert!(matches!(Language::from_str("unknown"), Language::En));
+    fn test_language_from_code() {
+        assert!(matches!(Language::from_code("zh-CN"), Language::Zh));
+        assert!(matches!(Language::from_code("zh_CN"), Language::Zh));
+        assert!(matches!(Language::from_code("zh"), Language::Zh));
+        assert!(matches!(Language::from_code("ZH-CN"), Language::Zh));
+        assert!(matches!(Language::from_code("en-US"), Language::En));
+        assert!(matches!(Language::from_code("en"), Language::En));
+        assert!(matches!(Language::from_code("EN"), Language::En));
+        assert!(matches!(Language::from_code("ja"), Language::Ja));
+        assert!(matches!(Language::from_code("ja-JP"), Language::Ja));
+        assert!(matches!(Language::from_code("unknown"), Language::En));
     }
 
     #[test]
@@ -108,5 +691,65 @@ mod tests {
         assert_eq!(t(&Language::En, MessageKey::PromptUser), "you> ");
         assert_eq!(t(&Language::Zh, MessageKey::PromptUser), "你> ");
         assert_eq!(t(&Language::Zh, MessageKey::ThinkingProcess), "[思考中] ");
+        assert_eq!(t(&Language::Ja, MessageKey::PromptUser), "あなた> ");
+    }
+
+    #[test]
+    fn test_translations_valid() {
+        validate_translations();
+    }
+
+    #[test]
+    fn test_translations_match_builtin_english() {
+        for &key in MessageKey::ALL {
+            assert_eq!(t(&Language::En, key), builtin(&Language::En, key));
+        }
+    }
+
+    #[test]
+    fn test_every_key_translated() {
+        let mut languages = vec![Language::En, Language::Zh, Language::Ja];
+        languages.extend(
+            translations()
+                .keys()
+                .map(|code| Language::from_code(code))
+                .filter(|lang| matches!(lang, Language::Custom(_))),
+        );
+        for lang in &languages {
+            for &key in MessageKey::ALL {
+                assert!(
+                    !t(lang, key).trim().is_empty(),
+                    "`{}` is empty for {}",
+                    key.as_str(),
+                    lang.code()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_all_keys_listed() {
+        // `ALL` must name every variant once; the [en] table is the other inventory
+        let listed: std::collections::HashSet<_> =
+            MessageKey::ALL.iter().map(|key| key.as_str()).collect();
+        assert_eq!(listed.len(), MessageKey::ALL.len());
+        let en = &translations()["en"];
+        for name in en.keys() {
+            assert!(
+                listed.contains(name.as_str()),
+                "`{name}` is not in MessageKey::ALL"
+            );
+        }
+    }
+
+    #[test]
+    fn test_unknown_code_falls_back_to_english() {
+        assert_eq!(Language::from_code("xx-YY"), Language::En);
+        assert_eq!(Language::from_code("ja_JP"), Language::Ja);
+        assert_eq!(Language::from_code("zh-TW").code(), "zh");
+        assert_eq!(
+            t(&Language::Custom("xx"), MessageKey::PromptUser),
+            t(&Language::En, MessageKey::PromptUser)
+        );
     }
 }
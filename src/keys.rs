@@ -0,0 +1,461 @@
+use anyhow::{Result, bail};
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+
+use crate::config::KeysConfig;
+
+/// A key plus the modifiers that must be held with it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct KeyBinding {
+    pub code: KeyCode,
+    pub modifiers: KeyModifiers,
+}
+
+impl KeyBinding {
+    const fn ctrl(c: char) -> Self {
+        Self {
+            code: KeyCode::Char(c),
+            modifiers: KeyModifiers::CONTROL,
+        }
+    }
+
+    const fn alt(c: char) -> Self {
+        Self {
+            code: KeyCode::Char(c),
+            modifiers: KeyModifiers::ALT,
+        }
+    }
+
+    /// Extra modifiers are tolerated, and letters match regardless of case.
+    pub fn matches(&self, key: &KeyEvent) -> bool {
+        let code_matches = match (self.code, key.code) {
+            (KeyCode::Char(a), KeyCode::Char(b)) => a.eq_ignore_ascii_case(&b),
+            (a, b) => a == b,
+        };
+        code_matches && key.modifiers.contains(self.modifiers)
+    }
+
+    /// Human-readable form, e.g. "Ctrl+G".
+    pub fn label(&self) -> String {
+        let mut label = String::new();
+        for (modifier, name) in [
+            (KeyModifiers::CONTROL, "Ctrl+"),
+            (KeyModifiers::ALT, "Alt+"),
+            (KeyModifiers::SHIFT, "Shift+"),
+        ] {
+            if self.modifiers.contains(modifier) {
+                label.push_str(name);
+            }
+        }
+        match self.code {
+            KeyCode::Char(' ') => label.push_str("Space"),
+            KeyCode::Char(c) => label.extend(c.to_uppercase()),
+            KeyCode::F(n) => label.push_str(&format!("F{n}")),
+            KeyCode::PageUp => label.push_str("PageUp"),
+            KeyCode::PageDown => label.push_str("PageDown"),
+            code => label.push_str(&format!("{code:?}")),
+        }
+        label
+    }
+}
+
+/// Keys for the actions that can be remapped through the `[keys]` config table.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct KeyBindings {
+    /// Leave the shell for LLM chat
+    pub enter_chat: KeyBinding,
+    /// Inject the suggested command into the shell
+    pub accept_command: KeyBinding,
+    /// Put the suggested command on the shell's line and stay in chat
+    pub stage_command: KeyBinding,
+    /// Switch between editing the suggested command and the question
+    pub edit: KeyBinding,
+    /// Expand or collapse the reasoning of the last reply
+    pub toggle_reasoning: KeyBinding,
+    /// Show or hide the explanation of the suggested command
+    pub toggle_explanation: KeyBinding,
+    /// Return to the shell without a command
+    pub exit_chat: KeyBinding,
+    /// Return to the shell and interrupt its foreground process
+    pub interrupt: KeyBinding,
+    /// Clear the screen, keeping the last reply for accepting
+    pub clear_screen: KeyBinding,
+    /// Ask the last question again, replacing the reply
+    pub regenerate: KeyBinding,
+    /// Show the reply before the one on screen
+    pub previous_reply: KeyBinding,
+    /// Show the reply after the one on screen
+    pub next_reply: KeyBinding,
+}
+
+impl Default for KeyBindings {
+    fn default() -> Self {
+        Self {
+            enter_chat: KeyBinding::ctrl('l'),
+            accept_command: KeyBinding::ctrl('l'),
+            stage_command: KeyBinding::alt('l'),
+            edit: KeyBinding::ctrl('e'),
+            toggle_reasoning: KeyBinding::ctrl('r'),
+            toggle_explanation: KeyBinding::ctrl('x'),
+            exit_chat: KeyBinding::ctrl('c'),
+            interrupt: KeyBinding::alt('c'),
+            clear_screen: KeyBinding::ctrl('k'),
+            regenerate: KeyBinding::ctrl('g'),
+            previous_reply: KeyBinding {
+                code: KeyCode::Up,
+                modifiers: KeyModifiers::ALT,
+            },
+            next_reply: KeyBinding {
+                code: KeyCode::Down,
+                modifiers: KeyModifiers::ALT,
+            },
+        }
+    }
+}
+
+impl KeyBindings {
+    pub fn from_config(config: &KeysConfig) -> Result<Self> {
+        let mut bindings = Self::default();
+        for (action, spec, binding) in [
+            ("enter_chat", &config.enter_chat, &mut bindings.enter_chat),
+            (
+                "accept_command",
+                &config.accept_command,
+                &mut bindings.accept_command,
+            ),
+            (
+                "stage_command",
+                &config.stage_command,
+                &mut bindings.stage_command,
+            ),
+            ("edit", &config.edit, &mut bindings.edit),
+            (
+                "toggle_reasoning",
+                &config.toggle_reasoning,
+                &mut bindings.toggle_reasoning,
+            ),
+            (
+                "toggle_explanation",
+                &config.toggle_explanation,
+                &mut bindings.toggle_explanation,
+            ),
+            ("exit_chat", &config.exit_chat, &mut bindings.exit_chat),
+            ("interrupt", &config.interrupt, &mut bindings.interrupt),
+            (
+                "clear_screen",
+                &config.clear_screen,
+                &mut bindings.clear_screen,
+            ),
+            ("regenerate", &config.regenerate, &mut bindings.regenerate),
+            (
+                "previous_reply",
+                &config.previous_reply,
+                &mut bindings.previous_reply,
+            ),
+            ("next_reply", &config.next_reply, &mut bindings.next_reply),
+        ] {
+            let Some(spec) = spec else {
+                continue;
+            };
+            let Some((code, modifiers)) = parse_keyspec(spec) else {
+                bail!("invalid key `{spec}` for keys.{action}");
+            };
+            *binding = KeyBinding { code, modifiers };
+        }
+        Ok(bindings)
+    }
+
+    /// Replace the default key names in a built-in message with the configured ones.
+    pub fn relabel(&self, text: &str) -> String {
+        let defaults = Self::default();
+        let replacements = [
+            (defaults.accept_command.label(), self.accept_command.label()),
+            (defaults.stage_command.label(), self.stage_command.label()),
+            (defaults.edit.label(), self.edit.label()),
+            (
+                defaults.toggle_reasoning.label(),
+                self.toggle_reasoning.label(),
+            ),
+            (
+                defaults.toggle_explanation.label(),
+                self.toggle_explanation.label(),
+            ),
+            (defaults.exit_chat.label(), self.exit_chat.label()),
+            (defaults.interrupt.label(), self.interrupt.label()),
+            (defaults.clear_screen.label(), self.clear_screen.label()),
+            (defaults.regenerate.label(), self.regenerate.label()),
+            (defaults.previous_reply.label(), self.previous_reply.label()),
+            (defaults.next_reply.label(), self.next_reply.label()),
+        ];
+
+        // Single pass, so a replacement is never replaced again
+        let mut out = String::with_capacity(text.len());
+        let mut rest = text;
+        'outer: while let Some(ch) = rest.chars().next() {
+            for (from, to) in &replacements {
+                if let Some(tail) = rest.strip_prefix(from.as_str()) {
+                    out.push_str(to);
+                    rest = tail;
+                    continue 'outer;
+                }
+            }
+            out.push(ch);
+            rest = &rest[ch.len_utf8()..];
+        }
+        out
+    }
+}
+
+/// Parse a key specification such as `"ctrl+g"`, `"alt+enter"` or `"f2"`.
+pub fn parse_keyspec(spec: &str) -> Option<(KeyCode, KeyModifiers)> {
+    let spec = spec.trim().to_ascii_lowercase();
+    let mut parts: Vec<&str> = spec.split('+').map(str::trim).collect();
+    let key = parts.pop()?;
+
+    let mut modifiers = KeyModifiers::NONE;
+    for part in parts {
+        modifiers |= match part {
+            "ctrl" | "control" => KeyModifiers::CONTROL,
+            "alt" | "meta" | "option" => KeyModifiers::ALT,
+            "shift" => KeyModifiers::SHIFT,
+            _ => return None,
+        };
+    }
+
+    let code = match key {
+        "enter" | "return" => KeyCode::Enter,
+        "esc" | "escape" => KeyCode::Esc,
+        "tab" => KeyCode::Tab,
+        "space" => KeyCode::Char(' '),
+        "backspace" => KeyCode::Backspace,
+        "delete" | "del" => KeyCode::Delete,
+        "insert" | "ins" => KeyCode::Insert,
+        "home" => KeyCode::Home,
+        "end" => KeyCode::End,
+        "pageup" => KeyCode::PageUp,
+        "pagedown" => KeyCode::PageDown,
+        "up" => KeyCode::Up,
+        "down" => KeyCode::Down,
+        "left" => KeyCode::Left,
+        "right" => KeyCode::Right,
+        _ => {
+            let mut chars = key.chars();
+            match (chars.next(), chars.next()) {
+                (Some(c), None) => KeyCode::Char(c),
+                (Some('f'), Some(_)) => match key[1..].parse() {
+                    Ok(n @ 1..=24) => KeyCode::F(n),
+                    _ => return None,
+                },
+                _ => return None,
+            }
+        }
+    };
+    Some((code, modifiers))
+}
+
+/// The bytes a terminal sends to the shell for `key`, or `None` for keys it has no
+/// encoding for here. With `application_cursor` (DECCKM) the arrows, Home and End
+/// use SS3 (`ESC O A`) instead of CSI (`ESC [ A`). Keypad keys arrive as their plain
+/// characters and are sent that way, as from a keypad in numeric mode.
+pub fn key_to_bytes(key: &KeyEvent, application_cursor: bool) -> Option<Vec<u8>> {
+    if application_cursor {
+        let last = match key.code {
+            KeyCode::Up => Some(b'A'),
+            KeyCode::Down => Some(b'B'),
+            KeyCode::Right => Some(b'C'),
+            KeyCode::Left => Some(b'D'),
+            KeyCode::Home => Some(b'H'),
+            KeyCode::End => Some(b'F'),
+            _ => None,
+        };
+        if let Some(last) = last {
+            return Some(vec![0x1b, b'O', last]);
+        }
+    }
+    let bytes = match key.code {
+        KeyCode::Char(c) => {
+            if key.modifiers.contains(KeyModifiers::CONTROL) {
+                vec![(c.to_ascii_lowercase() as u8) & 0x1f]
+            } else {
+                let mut buf = [0u8; 4];
+                let s = c.encode_utf8(&mut buf).as_bytes();
+                if key.modifiers.contains(KeyModifiers::ALT) {
+                    [&[0x1b], s].concat()
+                } else {
+                    s.to_vec()
+                }
+            }
+        }
+        KeyCode::Enter => b"\r".to_vec(),
+        KeyCode::Backspace => vec![0x7f],
+        KeyCode::Tab => b"\t".to_vec(),
+        KeyCode::Esc => vec![0x1b],
+        KeyCode::Up => b"\x1b[A".to_vec(),
+        KeyCode::Down => b"\x1b[B".to_vec(),
+        KeyCode::Right => b"\x1b[C".to_vec(),
+        KeyCode::Left => b"\x1b[D".to_vec(),
+        KeyCode::Home => b"\x1b[H".to_vec(),
+        KeyCode::End => b"\x1b[F".to_vec(),
+        KeyCode::PageUp => b"\x1b[5~".to_vec(),
+        KeyCode::PageDown => b"\x1b[6~".to_vec(),
+        KeyCode::Delete => b"\x1b[3~".to_vec(),
+        KeyCode::Insert => b"\x1b[2~".to_vec(),
+        KeyCode::F(n) => {
+            let seq: &[u8] = match n {
+                1 => b"\x1bOP",
+                2 => b"\x1bOQ",
+                3 => b"\x1bOR",
+                4 => b"\x1bOS",
+                5 => b"\x1b[15~",
+                6 => b"\x1b[17~",
+                7 => b"\x1b[18~",
+                8 => b"\x1b[19~",
+                9 => b"\x1b[20~",
+                10 => b"\x1b[21~",
+                11 => b"\x1b[23~",
+                12 => b"\x1b[24~",
+                _ => return None,
+            };
+            seq.to_vec()
+        }
+        _ => return None,
+    };
+    Some(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_key_to_bytes() {
+        let key = |code, modifiers| key_to_bytes(&KeyEvent::new(code, modifiers), false);
+        assert_eq!(
+            key(KeyCode::Char('a'), KeyModifiers::NONE),
+            Some(b"a".to_vec())
+        );
+        assert_eq!(
+            key(KeyCode::Char('C'), KeyModifiers::CONTROL),
+            Some(vec![0x03])
+        );
+        assert_eq!(
+            key(KeyCode::Char('b'), KeyModifiers::ALT),
+            Some(b"\x1bb".to_vec())
+        );
+        assert_eq!(
+            key(KeyCode::Char('é'), KeyModifiers::NONE),
+            Some("é".as_bytes().to_vec())
+        );
+        assert_eq!(
+            key(KeyCode::Enter, KeyModifiers::NONE),
+            Some(b"\r".to_vec())
+        );
+        assert_eq!(
+            key(KeyCode::Up, KeyModifiers::NONE),
+            Some(b"\x1b[A".to_vec())
+        );
+        assert_eq!(
+            key(KeyCode::F(5), KeyModifiers::NONE),
+            Some(b"\x1b[15~".to_vec())
+        );
+        assert_eq!(key(KeyCode::F(13), KeyModifiers::NONE), None);
+        assert_eq!(key(KeyCode::CapsLock, KeyModifiers::NONE), None);
+    }
+
+    #[test]
+    fn test_key_to_bytes_application_cursor() {
+        let key = |code, application| key_to_bytes(&KeyEvent::from(code), application);
+        assert_eq!(key(KeyCode::Up, true), Some(b"\x1bOA".to_vec()));
+        assert_eq!(key(KeyCode::Left, true), Some(b"\x1bOD".to_vec()));
+        assert_eq!(key(KeyCode::Home, true), Some(b"\x1bOH".to_vec()));
+        assert_eq!(key(KeyCode::End, true), Some(b"\x1bOF".to_vec()));
+        assert_eq!(key(KeyCode::Up, false), Some(b"\x1b[A".to_vec()));
+        // Other keys do not depend on the mode
+        assert_eq!(key(KeyCode::PageUp, true), Some(b"\x1b[5~".to_vec()));
+        assert_eq!(key(KeyCode::Char('k'), true), Some(b"k".to_vec()));
+    }
+
+    #[test]
+    fn test_parse_keyspec_modifiers() {
+        assert_eq!(
+            parse_keyspec("ctrl+g"),
+            Some((KeyCode::Char('g'), KeyModifiers::CONTROL))
+        );
+        assert_eq!(
+            parse_keyspec(" Ctrl + Shift + X "),
+            Some((
+                KeyCode::Char('x'),
+                KeyModifiers::CONTROL | KeyModifiers::SHIFT
+            ))
+        );
+        assert_eq!(
+            parse_keyspec("alt+enter"),
+            Some((KeyCode::Enter, KeyModifiers::ALT))
+        );
+        assert_eq!(parse_keyspec("hyper+g"), None);
+        assert_eq!(parse_keyspec("ctrl+"), None);
+        assert_eq!(parse_keyspec(""), None);
+    }
+
+    #[test]
+    fn test_parse_keyspec_named_keys() {
+        assert_eq!(
+            parse_keyspec("f2"),
+            Some((KeyCode::F(2), KeyModifiers::NONE))
+        );
+        assert_eq!(
+            parse_keyspec("F12"),
+            Some((KeyCode::F(12), KeyModifiers::NONE))
+        );
+        assert_eq!(parse_keyspec("f25"), None);
+        assert_eq!(
+            parse_keyspec("esc"),
+            Some((KeyCode::Esc, KeyModifiers::NONE))
+        );
+        assert_eq!(
+            parse_keyspec("ctrl+space"),
+            Some((KeyCode::Char(' '), KeyModifiers::CONTROL))
+        );
+        assert_eq!(
+            parse_keyspec("pagedown"),
+            Some((KeyCode::PageDown, KeyModifiers::NONE))
+        );
+        assert_eq!(parse_keyspec("enterr"), None);
+    }
+
+    #[test]
+    fn test_binding_matches() {
+        let binding = KeyBinding::ctrl('g');
+        assert!(binding.matches(&KeyEvent::new(KeyCode::Char('g'), KeyModifiers::CONTROL)));
+        assert!(binding.matches(&KeyEvent::new(
+            KeyCode::Char('G'),
+            KeyModifiers::CONTROL | KeyModifiers::SHIFT
+        )));
+        assert!(!binding.matches(&KeyEvent::new(KeyCode::Char('g'), KeyModifiers::NONE)));
+        assert!(!binding.matches(&KeyEvent::new(KeyCode::Char('l'), KeyModifiers::CONTROL)));
+    }
+
+    #[test]
+    fn test_from_config_and_relabel() {
+        let config = KeysConfig {
+            accept_command: Some("ctrl+r".to_string()),
+            toggle_reasoning: Some("f2".to_string()),
+            edit: Some("alt+e".to_string()),
+            ..Default::default()
+        };
+        let keys = KeyBindings::from_config(&config).unwrap();
+        assert_eq!(keys.enter_chat, KeyBinding::ctrl('l'));
+        assert_eq!(keys.accept_command, KeyBinding::ctrl('r'));
+        assert_eq!(keys.edit, KeyBinding::alt('e'));
+        assert_eq!(keys.interrupt, KeyBinding::alt('c'));
+        assert_eq!(
+            keys.relabel("Ctrl+L accepts, Ctrl+E edits, Ctrl+R toggles, Ctrl+C exits"),
+            "Ctrl+R accepts, Alt+E edits, F2 toggles, Ctrl+C exits"
+        );
+
+        let config = KeysConfig {
+            exit_chat: Some("ctrl+nope".to_string()),
+            ..Default::default()
+        };
+        assert!(KeyBindings::from_config(&config).is_err());
+    }
+}
@@ -0,0 +1,523 @@
+use std::io::BufReader;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use anyhow::{Result, bail};
+use reqwest::StatusCode;
+use reqwest::blocking::{Client, RequestBuilder, Response};
+use serde::{Deserialize, Serialize};
+
+use super::reply::{
+    AnswerStream, empty_reply, parse_plain_reply, parse_reply, push_capped, sanitize_stream_text,
+};
+use super::{
+    COMPACT_PROMPT, ChatMessage, ChatReply, ChatSink, ClientOptions, Compaction, IdleTimeoutReader,
+    LLMClient, LlmError, NoopSink, PromptMode, Role, Sampling, SseEvents, build_http_client,
+    check_status, compacted_history, compaction_split, log_response, parse_event, recent_turns,
+    send_with_retry, stream_error, summary_transcript,
+};
+use crate::i18n::{Language, MessageKey, t};
+
+const ANTHROPIC_VERSION: &str = "2023-06-01";
+const DEFAULT_MAX_TOKENS: u32 = 4096;
+/// Tokens extended thinking may use; the smallest budget the API accepts.
+const THINKING_BUDGET_TOKENS: u32 = 1024;
+
+pub struct AnthropicClient {
+    api_key: String,
+    model: String,
+    base_url: String,
+    client: Client,
+    lang: Language,
+    max_retries: u32,
+    stream_idle_timeout: Duration,
+    headers: Vec<(String, String)>,
+    sampling: Sampling,
+    max_reasoning_chars: usize,
+    max_history_turns: Option<usize>,
+    compaction: Option<Compaction>,
+    prompt_mode: PromptMode,
+    request_reasoning: bool,
+    /// Set once the model has rejected the `thinking` parameter
+    thinking_unsupported: AtomicBool,
+}
+
+impl AnthropicClient {
+    pub fn new(options: ClientOptions) -> Result<Self> {
+        let client = build_http_client(&options)?;
+        let ClientOptions {
+            api_key,
+            model,
+            base_url,
+            lang,
+            max_retries,
+            stream_idle_timeout,
+            sampling,
+            headers,
+            max_reasoning_chars,
+            max_history_turns,
+            compaction,
+            prompt_mode,
+            request_reasoning,
+            ..
+        } = options;
+        Ok(Self {
+            api_key,
+            model,
+            base_url,
+            client,
+            lang,
+            max_retries,
+            stream_idle_timeout,
+            headers,
+            sampling,
+            max_reasoning_chars,
+            max_history_turns,
+            compaction,
+            prompt_mode,
+            request_reasoning,
+            thinking_unsupported: AtomicBool::new(false),
+        })
+    }
+
+    /// Extended thinking for a request allowed `max_tokens`, when reasoning is wanted.
+    /// The API refuses it together with a custom temperature or top_p, and its budget
+    /// has to fit below `max_tokens`.
+    fn thinking(&self, max_tokens: u32) -> Option<Thinking> {
+        let wanted = self.request_reasoning
+            && self.sampling.temperature.is_none()
+            && self.sampling.top_p.is_none()
+            && max_tokens > THINKING_BUDGET_TOKENS
+            && !self.thinking_unsupported.load(Ordering::Relaxed);
+        wanted.then_some(Thinking {
+            kind: "enabled",
+            budget_tokens: THINKING_BUDGET_TOKENS,
+        })
+    }
+
+    fn build_request(&self, endpoint: &str, req: &MessagesRequest) -> RequestBuilder {
+        let mut request = self
+            .client
+            .post(endpoint)
+            .header("x-api-key", &self.api_key)
+            .header("anthropic-version", ANTHROPIC_VERSION)
+            .json(req);
+        for (name, value) in &self.headers {
+            request = request.header(name, value);
+        }
+        request
+    }
+
+    /// Send `req`, asking again without extended thinking if the model has none.
+    fn send(&self, req: &mut MessagesRequest, cancel: &AtomicBool) -> Result<Response, LlmError> {
+        let endpoint = format!("{}/messages", self.base_url);
+        if crate::log::enabled()
+            && let Ok(body) = serde_json::to_string_pretty(&req)
+        {
+            crate::log::record(
+                &format!("request {endpoint}"),
+                &crate::log::redact(&body, &self.api_key),
+            );
+        }
+        loop {
+            let resp = send_with_retry(
+                || self.build_request(&endpoint, req),
+                self.max_retries,
+                "failed to call Anthropic",
+                cancel,
+            )?;
+            if req.thinking.is_none() || resp.status() != StatusCode::BAD_REQUEST {
+                return check_status(resp);
+            }
+            let status = resp.status();
+            let body = resp.text().unwrap_or_default();
+            if !body.contains("thinking") {
+                return Err(LlmError::from_status(status, &body));
+            }
+            // Older models have no extended thinking; ask without it from now on
+            self.thinking_unsupported.store(true, Ordering::Relaxed);
+            req.thinking = None;
+        }
+    }
+
+    /// Read a streamed message, passing thinking and answer text on as it arrives.
+    fn read_stream(
+        &self,
+        resp: Response,
+        sink: &mut dyn ChatSink,
+        cancel: &AtomicBool,
+    ) -> Result<Streamed, LlmError> {
+        let reader = BufReader::new(IdleTimeoutReader::new(
+            resp,
+            self.stream_idle_timeout,
+            cancel,
+        ));
+        let mut streamed = Streamed::default();
+        let mut answer_stream = AnswerStream::default();
+
+        // The `event:` lines duplicate the `type` field of the data payload
+        for data in SseEvents::new(reader) {
+            if cancel.load(Ordering::Relaxed) {
+                return Err(LlmError::Cancelled);
+            }
+            let data = data.map_err(stream_error)?;
+
+            match parse_event::<StreamEvent>(&data) {
+                Some(StreamEvent::ContentBlockDelta { delta }) => match delta {
+                    BlockDelta::ThinkingDelta { .. } if !self.request_reasoning => {}
+                    BlockDelta::ThinkingDelta { thinking } => {
+                        let thinking = sanitize_stream_text(&thinking);
+                        push_capped(
+                            &mut streamed.reasoning,
+                            &thinking,
+                            self.max_reasoning_chars,
+                            t(&self.lang, MessageKey::ReasoningCapped),
+                        );
+                        sink.on_reasoning(&thinking);
+                    }
+                    BlockDelta::TextDelta { text } => {
+                        streamed.content.push_str(&text);
+                        if self.prompt_mode == PromptMode::Plain {
+                            sink.on_answer(&text);
+                        } else if let Some(answer) = answer_stream.update(&streamed.content) {
+                            sink.on_answer(&answer);
+                        }
+                    }
+                    BlockDelta::Other => {}
+                },
+                Some(StreamEvent::MessageDelta { delta }) => {
+                    if delta.stop_reason.is_some() {
+                        streamed.stop_reason = delta.stop_reason;
+                    }
+                }
+                Some(StreamEvent::MessageStop) => break,
+                Some(StreamEvent::Error { error }) => {
+                    return Err(LlmError::from_status(error.status(), &error.message));
+                }
+                Some(StreamEvent::Other) | None => {}
+            }
+        }
+        Ok(streamed)
+    }
+}
+
+/// What a streamed message added up to.
+#[derive(Default)]
+struct Streamed {
+    content: String,
+    reasoning: String,
+    stop_reason: Option<String>,
+}
+
+#[derive(Serialize)]
+struct MessagesRequest<'a> {
+    model: &'a str,
+    max_tokens: u32,
+    system: &'a str,
+    messages: Vec<serde_json::Value>,
+    stream: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    thinking: Option<Thinking>,
+}
+
+#[derive(Serialize)]
+struct Thinking {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    budget_tokens: u32,
+}
+
+// Streaming events; only the ones we care about are modelled
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum StreamEvent {
+    ContentBlockDelta {
+        delta: BlockDelta,
+    },
+    MessageDelta {
+        delta: MessageDeltaBody,
+    },
+    MessageStop,
+    Error {
+        error: StreamError,
+    },
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum BlockDelta {
+    TextDelta {
+        text: String,
+    },
+    ThinkingDelta {
+        thinking: String,
+    },
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Deserialize)]
+struct MessageDeltaBody {
+    #[serde(default)]
+    stop_reason: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct StreamError {
+    #[serde(rename = "type", default)]
+    kind: String,
+    message: String,
+}
+
+impl StreamError {
+    /// The status Anthropic documents for this error type when it is not streaming.
+    fn status(&self) -> StatusCode {
+        match self.kind.as_str() {
+            "invalid_request_error" => StatusCode::BAD_REQUEST,
+            "authentication_error" => StatusCode::UNAUTHORIZED,
+            "permission_error" => StatusCode::FORBIDDEN,
+            "not_found_error" => StatusCode::NOT_FOUND,
+            "request_too_large" => StatusCode::PAYLOAD_TOO_LARGE,
+            "rate_limit_error" => StatusCode::TOO_MANY_REQUESTS,
+            // 529 has no name in the HTTP registry
+            "overloaded_error" => {
+                StatusCode::from_u16(529).unwrap_or(StatusCode::SERVICE_UNAVAILABLE)
+            }
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+impl LLMClient for AnthropicClient {
+    fn chat(
+        &self,
+        system_prompt: &str,
+        history: &[ChatMessage],
+        user_input: &str,
+        sink: &mut dyn ChatSink,
+        cancel: &AtomicBool,
+    ) -> Result<ChatReply, LlmError> {
+        let history = recent_turns(history, self.max_history_turns);
+        let mut payload: Vec<serde_json::Value> = Vec::with_capacity(history.len() + 1);
+        for m in history {
+            let role = match m.role {
+                Role::User => "user",
+                Role::Assistant => "assistant",
+            };
+            payload.push(serde_json::json!({ "role": role, "content": m.content }));
+        }
+        payload.push(serde_json::json!({"role": "user", "content": user_input}));
+
+        // The Messages API requires max_tokens, so fall back to a default
+        let max_tokens = self.sampling.max_tokens.unwrap_or(DEFAULT_MAX_TOKENS);
+        let mut req = MessagesRequest {
+            model: &self.model,
+            max_tokens,
+            system: system_prompt,
+            messages: payload,
+            stream: true,
+            temperature: self.sampling.temperature,
+            top_p: self.sampling.top_p,
+            thinking: self.thinking(max_tokens),
+        };
+        let resp = self.send(&mut req, cancel)?;
+        let streamed = self.read_stream(resp, sink, cancel)?;
+
+        log_response(&streamed.content, &streamed.reasoning);
+        if streamed.content.trim().is_empty() {
+            return Ok(empty_reply(
+                streamed.reasoning,
+                streamed.stop_reason.as_deref(),
+                &self.lang,
+            ));
+        }
+        Ok(match self.prompt_mode {
+            PromptMode::Json => parse_reply(streamed.content, streamed.reasoning, &self.lang),
+            PromptMode::Plain => parse_plain_reply(streamed.content, streamed.reasoning),
+        })
+    }
+
+    fn compact_history(
+        &self,
+        history: &[ChatMessage],
+        cancel: &AtomicBool,
+    ) -> Result<Vec<ChatMessage>> {
+        let Some(compaction) = &self.compaction else {
+            return Ok(history.to_vec());
+        };
+        let Some((older, recent)) = compaction_split(history, compaction.after_turns) else {
+            return Ok(history.to_vec());
+        };
+        let mut req = MessagesRequest {
+            model: compaction.model.as_deref().unwrap_or(&self.model),
+            max_tokens: DEFAULT_MAX_TOKENS,
+            system: COMPACT_PROMPT,
+            messages: vec![
+                serde_json::json!({ "role": "user", "content": summary_transcript(older) }),
+            ],
+            stream: true,
+            temperature: None,
+            top_p: None,
+            thinking: None,
+        };
+        let resp = self.send(&mut req, cancel)?;
+        let streamed = self.read_stream(resp, &mut NoopSink, cancel)?;
+        crate::log::record("summary", &streamed.content);
+        let summary = streamed.content.trim();
+        if summary.is_empty() {
+            bail!("the summary of the earlier conversation came back empty");
+        }
+        Ok(compacted_history(summary, recent))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm::tests::{client_options, mock_server};
+
+    #[test]
+    fn test_parse_text_and_thinking_deltas() {
+        let text = r#"{"type":"content_block_delta","index":1,"delta":{"type":"text_delta","text":"{\"command\""}}"#;
+        let thinking = r#"{"type":"content_block_delta","index":0,"delta":{"type":"thinking_delta","thinking":"hmm"}}"#;
+        assert!(matches!(
+            serde_json::from_str::<StreamEvent>(text).unwrap(),
+            StreamEvent::ContentBlockDelta { delta: BlockDelta::TextDelta { text } } if text == "{\"command\""
+        ));
+        assert!(matches!(
+            serde_json::from_str::<StreamEvent>(thinking).unwrap(),
+            StreamEvent::ContentBlockDelta { delta: BlockDelta::ThinkingDelta { thinking } } if thinking == "hmm"
+        ));
+    }
+
+    /// Collects the reasoning a reply streams.
+    #[derive(Default)]
+    struct ReasoningSink(String);
+
+    impl ChatSink for ReasoningSink {
+        fn on_reasoning(&mut self, text: &str) {
+            self.0.push_str(text);
+        }
+    }
+
+    fn thinking_reply() -> String {
+        [
+            r#"{"type":"content_block_delta","index":0,"delta":{"type":"thinking_delta","thinking":"hmm"}}"#,
+            r#"{"type":"content_block_delta","index":1,"delta":{"type":"text_delta","text":"{\"answer\":\"ok\",\"command\":\"ls\"}"}}"#,
+            r#"{"type":"message_stop"}"#,
+        ]
+        .iter()
+        .map(|data| format!("event: x\ndata: {data}\n\n"))
+        .collect()
+    }
+
+    /// Chat once with `options` and return the request body and the streamed reasoning.
+    fn chat_once(options: impl FnOnce(&mut ClientOptions)) -> (serde_json::Value, String) {
+        let (base_url, bodies) = mock_server(vec![thinking_reply()]);
+        let mut opts = client_options(&base_url);
+        options(&mut opts);
+        let client = AnthropicClient::new(opts).unwrap();
+        let mut sink = ReasoningSink::default();
+        let reply = client
+            .chat(
+                "system",
+                &[],
+                "list files",
+                &mut sink,
+                &AtomicBool::new(false),
+            )
+            .unwrap();
+        assert_eq!(reply.suggested_commands, ["ls"]);
+        let body = serde_json::from_str(&bodies.recv().unwrap()).unwrap();
+        (body, sink.0)
+    }
+
+    #[test]
+    fn test_extended_thinking_request() {
+        let (body, reasoning) = chat_once(|_| {});
+        assert_eq!(body["thinking"]["type"], "enabled");
+        assert_eq!(body["thinking"]["budget_tokens"], THINKING_BUDGET_TOKENS);
+        assert_eq!(reasoning, "hmm");
+
+        // Not requested, and any that still arrives is not passed on
+        let (body, reasoning) = chat_once(|opts| opts.request_reasoning = false);
+        assert!(body.get("thinking").is_none());
+        assert!(reasoning.is_empty());
+
+        // The API refuses thinking with a custom temperature or a tight token limit
+        let (body, _) = chat_once(|opts| opts.sampling.temperature = Some(0.2));
+        assert!(body.get("thinking").is_none());
+        let (body, _) = chat_once(|opts| opts.sampling.max_tokens = Some(THINKING_BUDGET_TOKENS));
+        assert!(body.get("thinking").is_none());
+    }
+
+    #[test]
+    fn test_compact_history() {
+        let history: Vec<ChatMessage> = (1..=3)
+            .flat_map(|i| {
+                [
+                    ChatMessage {
+                        role: Role::User,
+                        content: format!("q{i}"),
+                    },
+                    ChatMessage {
+                        role: Role::Assistant,
+                        content: format!("a{i}"),
+                    },
+                ]
+            })
+            .collect();
+        let summary = [
+            r#"{"type":"content_block_delta","index":0,"delta":{"type":"text_delta","text":"listed files"}}"#,
+            r#"{"type":"message_stop"}"#,
+        ]
+        .iter()
+        .map(|data| format!("event: x\ndata: {data}\n\n"))
+        .collect();
+        let (base_url, bodies) = mock_server(vec![summary]);
+        let mut opts = client_options(&base_url);
+        opts.compaction = Some(Compaction {
+            after_turns: 2,
+            model: Some("small".to_string()),
+        });
+        let client = AnthropicClient::new(opts).unwrap();
+
+        // Cancelled before it starts: nothing is sent
+        let err = client
+            .compact_history(&history, &AtomicBool::new(true))
+            .unwrap_err();
+        assert!(matches!(err.downcast_ref(), Some(LlmError::Cancelled)));
+
+        let compacted = client
+            .compact_history(&history, &AtomicBool::new(false))
+            .unwrap();
+        assert_eq!(compacted.len(), 4);
+        assert!(compacted[0].content.ends_with("listed files"));
+        assert_eq!(compacted[2].content, "q3");
+        let body: serde_json::Value = serde_json::from_str(&bodies.recv().unwrap()).unwrap();
+        assert_eq!(body["model"], "small");
+        assert_eq!(body["system"], COMPACT_PROMPT);
+        assert!(body.get("thinking").is_none());
+        assert!(bodies.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_parse_unrelated_events() {
+        let ping = r#"{"type":"ping"}"#;
+        let stop = r#"{"type":"message_stop"}"#;
+        assert!(matches!(
+            serde_json::from_str::<StreamEvent>(ping).unwrap(),
+            StreamEvent::Other
+        ));
+        assert!(matches!(
+            serde_json::from_str::<StreamEvent>(stop).unwrap(),
+            StreamEvent::MessageStop
+        ));
+    }
+}
@@ -0,0 +1,276 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use anyhow::Result;
+use reqwest::StatusCode;
+
+use super::{ChatMessage, ChatReply, ChatSink, LLMClient, LlmError};
+
+/// Wraps any client and retries a failed turn once against a second one, typically the
+/// same provider with `llm.fallback_model`. Only failures another model could get past
+/// are retried (see `worth_falling_back`); cancellation never is.
+pub struct FallbackClient {
+    primary: Box<dyn LLMClient>,
+    fallback: Box<dyn LLMClient>,
+    /// Named in the log when the primary attempt fails
+    fallback_model: String,
+}
+
+impl FallbackClient {
+    pub fn new(
+        primary: Box<dyn LLMClient>,
+        fallback: Box<dyn LLMClient>,
+        fallback_model: String,
+    ) -> Self {
+        Self {
+            primary,
+            fallback,
+            fallback_model,
+        }
+    }
+}
+
+impl LLMClient for FallbackClient {
+    fn chat(
+        &self,
+        system_prompt: &str,
+        history: &[ChatMessage],
+        user_input: &str,
+        sink: &mut dyn ChatSink,
+        cancel: &AtomicBool,
+    ) -> Result<ChatReply, LlmError> {
+        let first = match self
+            .primary
+            .chat(system_prompt, history, user_input, sink, cancel)
+        {
+            Ok(reply) => return Ok(reply),
+            Err(e @ LlmError::Cancelled) => return Err(e),
+            Err(e) if cancel.load(Ordering::Relaxed) => return Err(e),
+            Err(e) if !worth_falling_back(&e) => return Err(e),
+            Err(e) => e,
+        };
+        crate::log::record(
+            &format!("falling back to {}", self.fallback_model),
+            &first.to_string(),
+        );
+
+        // The primary may have failed mid-stream; its partial text must not run into
+        // the fallback's
+        sink.on_restart();
+
+        // When both fail, the fallback's error is the one the user can still act on;
+        // the primary's is in the log
+        self.fallback
+            .chat(system_prompt, history, user_input, sink, cancel)
+    }
+
+    fn compact_history(
+        &self,
+        history: &[ChatMessage],
+        cancel: &AtomicBool,
+    ) -> Result<Vec<ChatMessage>> {
+        self.primary.compact_history(history, cancel)
+    }
+
+    fn list_models(&self) -> Result<Vec<String>> {
+        self.primary.list_models()
+    }
+}
+
+/// Whether the primary failed in a way the fallback model may not: timeouts, dropped
+/// connections, rate limits and server errors. A rejected key, a missing model or a
+/// malformed request would fail the same way again.
+fn worth_falling_back(err: &LlmError) -> bool {
+    match err {
+        LlmError::Timeout { .. } | LlmError::Network(_) | LlmError::RateLimited { .. } => true,
+        LlmError::Http { status, .. } => {
+            status.is_server_error() || *status == StatusCode::TOO_MANY_REQUESTS
+        }
+        LlmError::Auth { .. } | LlmError::Parse(_) | LlmError::Cancelled => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm::{NoopSink, Role};
+
+    struct Failing;
+
+    impl LLMClient for Failing {
+        fn chat(
+            &self,
+            _: &str,
+            _: &[ChatMessage],
+            _: &str,
+            _: &mut dyn ChatSink,
+            _: &AtomicBool,
+        ) -> Result<ChatReply, LlmError> {
+            Err(LlmError::Http {
+                status: StatusCode::SERVICE_UNAVAILABLE,
+                message: "overloaded".to_string(),
+            })
+        }
+    }
+
+    struct Echo;
+
+    impl LLMClient for Echo {
+        fn chat(
+            &self,
+            _: &str,
+            history: &[ChatMessage],
+            user_input: &str,
+            sink: &mut dyn ChatSink,
+            _: &AtomicBool,
+        ) -> Result<ChatReply, LlmError> {
+            let text = format!("{} {user_input}", history.len());
+            sink.on_answer(&text);
+            Ok(ChatReply {
+                text,
+                suggested_commands: Vec::new(),
+                reasoning: None,
+                explanation: None,
+                usage: None,
+            })
+        }
+    }
+
+    /// Streams part of an answer, then fails with `error`
+    struct BreaksOff(fn() -> LlmError);
+
+    impl LLMClient for BreaksOff {
+        fn chat(
+            &self,
+            _: &str,
+            _: &[ChatMessage],
+            _: &str,
+            sink: &mut dyn ChatSink,
+            _: &AtomicBool,
+        ) -> Result<ChatReply, LlmError> {
+            sink.on_reasoning("thinking about");
+            sink.on_answer("half an ans");
+            Err(self.0())
+        }
+    }
+
+    /// What a preview would show: the text streamed since the last restart
+    #[derive(Default)]
+    struct Preview {
+        reasoning: String,
+        answer: String,
+    }
+
+    impl ChatSink for Preview {
+        fn on_reasoning(&mut self, text: &str) {
+            self.reasoning.push_str(text);
+        }
+
+        fn on_answer(&mut self, text: &str) {
+            self.answer.push_str(text);
+        }
+
+        fn on_restart(&mut self) {
+            self.reasoning.clear();
+            self.answer.clear();
+        }
+    }
+
+    fn chat(client: &FallbackClient, cancel: &AtomicBool) -> Result<ChatReply, LlmError> {
+        let history = vec![ChatMessage {
+            role: Role::User,
+            content: "earlier".to_string(),
+        }];
+        client.chat("sys", &history, "hi", &mut NoopSink, cancel)
+    }
+
+    #[test]
+    fn test_fallback_after_failure() {
+        let client = FallbackClient::new(Box::new(Failing), Box::new(Echo), "small".to_string());
+        let reply = chat(&client, &AtomicBool::new(false)).unwrap();
+        assert_eq!(reply.text, "1 hi");
+
+        let client = FallbackClient::new(Box::new(Failing), Box::new(Failing), "small".to_string());
+        let err = chat(&client, &AtomicBool::new(false)).unwrap_err();
+        assert!(matches!(
+            err,
+            LlmError::Http {
+                status: StatusCode::SERVICE_UNAVAILABLE,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn test_no_fallback_when_cancelled() {
+        let client = FallbackClient::new(Box::new(Failing), Box::new(Echo), "small".to_string());
+        assert!(chat(&client, &AtomicBool::new(true)).is_err());
+    }
+
+    #[test]
+    fn test_fallback_restarts_the_stream() {
+        let client = FallbackClient::new(
+            Box::new(BreaksOff(|| {
+                LlmError::Network("connection reset".to_string())
+            })),
+            Box::new(Echo),
+            "small".to_string(),
+        );
+        let mut preview = Preview::default();
+        let reply = client
+            .chat("sys", &[], "hi", &mut preview, &AtomicBool::new(false))
+            .unwrap();
+        assert_eq!(reply.text, "0 hi");
+        assert_eq!(preview.reasoning, "");
+        assert_eq!(preview.answer, "0 hi");
+    }
+
+    #[test]
+    fn test_no_fallback_for_permanent_errors() {
+        let permanent: [fn() -> LlmError; 3] = [
+            || LlmError::Auth {
+                status: StatusCode::UNAUTHORIZED,
+                message: "bad key".to_string(),
+            },
+            || LlmError::Http {
+                status: StatusCode::NOT_FOUND,
+                message: "no such model".to_string(),
+            },
+            || LlmError::Parse("not JSON".to_string()),
+        ];
+        for error in permanent {
+            let client = FallbackClient::new(
+                Box::new(BreaksOff(error)),
+                Box::new(Echo),
+                "small".to_string(),
+            );
+            let mut preview = Preview::default();
+            assert!(
+                client
+                    .chat("sys", &[], "hi", &mut preview, &AtomicBool::new(false))
+                    .is_err()
+            );
+            // Nothing was restarted, so the partial answer stays for the error to replace
+            assert_eq!(preview.answer, "half an ans");
+        }
+
+        let transient: [fn() -> LlmError; 4] = [
+            || LlmError::Timeout { idle: None },
+            || LlmError::RateLimited {
+                message: "slow down".to_string(),
+            },
+            || LlmError::Http {
+                status: StatusCode::BAD_GATEWAY,
+                message: "bad gateway".to_string(),
+            },
+            || LlmError::Network("connection reset".to_string()),
+        ];
+        for error in transient {
+            let client = FallbackClient::new(
+                Box::new(BreaksOff(error)),
+                Box::new(Echo),
+                "small".to_string(),
+            );
+            assert!(chat(&client, &AtomicBool::new(false)).is_ok());
+        }
+    }
+}
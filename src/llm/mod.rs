@@ -1,14 +1,30 @@
+pub mod anthropic;
+pub mod fallback;
 pub mod openai;
+mod reply;
 
-use anyhow::Result;
+use std::fmt;
+use std::io::{self, BufRead, Lines, Read};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-#[derive(Clone, Copy, Debug)]
+use anyhow::{Context, Result};
+use reqwest::StatusCode;
+use reqwest::blocking::{Client, RequestBuilder, Response};
+use serde::{Deserialize, Serialize};
+
+use crate::i18n::{Language, MessageKey, t};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum Role {
     User,
     Assistant,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct ChatMessage {
     pub role: Role,
     pub content: String,
@@ -17,15 +33,952 @@ pub struct ChatMessage {
 #[derive(Clone, Debug)]
 pub struct ChatReply {
     pub text: String,
-    pub suggested_command: Option<String>,
+    /// Candidate commands, best first; empty when the model suggested none
+    pub suggested_commands: Vec<String>,
     pub reasoning: Option<String>,
+    /// Longer description of the suggested command, shown on demand
+    pub explanation: Option<String>,
+    /// Token counts, when the backend reported them
+    pub usage: Option<Usage>,
+}
+
+/// Token counts for one turn, as reported in the final chunk of a stream.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+pub struct Usage {
+    pub prompt_tokens: u32,
+    pub completion_tokens: u32,
+}
+
+impl ChatReply {
+    /// The primary suggestion.
+    pub fn suggested_command(&self) -> Option<&str> {
+        self.suggested_commands.first().map(String::as_str)
+    }
+}
+
+/// Settings shared by every provider client.
+#[derive(Clone, Debug)]
+pub struct ClientOptions {
+    pub api_key: String,
+    pub model: String,
+    pub base_url: String,
+    pub lang: Language,
+    /// Whole-request timeout, including reading the streamed body
+    pub timeout: Duration,
+    pub connect_timeout: Duration,
+    /// Longest wait for the next piece of a streamed reply
+    pub stream_idle_timeout: Duration,
+    /// How many times a 429/5xx response is retried before giving up
+    pub max_retries: u32,
+    pub sampling: Sampling,
+    pub json_mode: JsonMode,
+    /// Route requests through this proxy instead of the environment's
+    pub proxy: Option<String>,
+    /// Comma-separated hosts that bypass `proxy`
+    pub no_proxy: Option<String>,
+    /// Extra headers added to every request (checked by config validation)
+    pub headers: Vec<(String, String)>,
+    /// `OpenAI-Organization` / `OpenAI-Project` headers, for OpenAI only
+    pub organization: Option<String>,
+    pub project: Option<String>,
+    /// Request `stream_options.include_usage` so the last chunk carries token counts
+    pub show_usage: bool,
+    /// Only the tail of longer reasoning is kept
+    pub max_reasoning_chars: usize,
+    /// Follow-up requests asking to fix a reply that is not valid JSON (OpenAI-compatible only)
+    pub json_repair_attempts: u32,
+    /// Question/answer pairs of history sent per request; `None` sends all
+    pub max_history_turns: Option<usize>,
+    /// Summarize old history when it gets long; `None` never does
+    pub compaction: Option<Compaction>,
+    pub prompt_mode: PromptMode,
+    /// Ask for the model's reasoning; when false the provider is asked not to reason
+    /// where it can be, whatever still streams is dropped and `on_reasoning` never fires
+    pub request_reasoning: bool,
+    /// `reasoning_effort` for OpenAI-compatible requests while `request_reasoning` is
+    /// off; they are sent unchanged when unset
+    pub reasoning_effort_when_off: Option<ReasoningEffort>,
+}
+
+/// When and with which model old history is summarized (`llm.auto_compact`).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Compaction {
+    /// History with more question/answer pairs than this is compacted
+    pub after_turns: usize,
+    /// Model that writes the summary; the chat model when unset
+    pub model: Option<String>,
+}
+
+/// Whether OpenAI-compatible requests ask for `response_format: json_object`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JsonMode {
+    /// Send it, and drop it for the rest of the session if the backend rejects it
+    #[default]
+    Auto,
+    On,
+    /// Never send it; the JSON is recovered from the markdown fence instead
+    Off,
+}
+
+/// How the model is asked to shape its reply (`prompt.mode`).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PromptMode {
+    /// A JSON object with the commands, answer and explanation
+    #[default]
+    Json,
+    /// Plain text with the command in a fenced code block, for models that
+    /// struggle to produce valid JSON
+    Plain,
+}
+
+/// Optional sampling parameters; unset fields are left out of the request.
+#[derive(Clone, Copy, Debug, Default, Serialize)]
+pub struct Sampling {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<u32>,
+    /// How hard a reasoning model thinks before answering (OpenAI-compatible only)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reasoning_effort: Option<ReasoningEffort>,
+}
+
+/// `reasoning_effort` for OpenAI o-series and other reasoning models.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReasoningEffort {
+    /// The least reasoning, for models that support it (e.g. gpt-5)
+    Minimal,
+    Low,
+    Medium,
+    High,
+}
+
+/// Receives a reply while it streams in. Every method does nothing by default, so a
+/// caller implements only the signals it shows.
+pub trait ChatSink {
+    /// A piece of the model's reasoning
+    fn on_reasoning(&mut self, _text: &str) {}
+    /// A piece of the answer text, as far as it can be read from the partial JSON
+    fn on_answer(&mut self, _text: &str) {}
+    /// Token counts, once the backend reports them
+    fn on_usage(&mut self, _usage: Usage) {}
+    /// Everything streamed so far belongs to an abandoned attempt and is about to be
+    /// streamed again from the start
+    fn on_restart(&mut self) {}
 }
 
+/// A sink for callers that only want the finished reply.
+pub struct NoopSink;
+
+impl ChatSink for NoopSink {}
+
 pub trait LLMClient: Send + Sync {
     fn chat(
         &self,
+        system_prompt: &str,
         history: &[ChatMessage],
         user_input: &str,
-        on_reasoning: &mut dyn FnMut(&str),
-    ) -> Result<ChatReply>;
+        sink: &mut dyn ChatSink,
+        cancel: &AtomicBool,
+    ) -> Result<ChatReply, LlmError>;
+
+    /// `history` with its oldest turns replaced by a summary once it is long enough, as
+    /// configured. Unchanged by default and for clients that cannot summarize. Raising
+    /// `cancel` abandons the summary like it does a reply.
+    fn compact_history(
+        &self,
+        history: &[ChatMessage],
+        _cancel: &AtomicBool,
+    ) -> Result<Vec<ChatMessage>> {
+        Ok(history.to_vec())
+    }
+
+    /// Model names the backend offers, for `shellm models`. Empty when it cannot tell.
+    fn list_models(&self) -> Result<Vec<String>> {
+        Ok(Vec::new())
+    }
+}
+
+/// Why `LLMClient::chat` failed. `anyhow` takes it through `?` like any error, and
+/// `downcast_ref` gets it back for callers that handle the cases differently.
+#[derive(Debug)]
+pub enum LlmError {
+    /// The provider rejected the credentials (401/403)
+    Auth { status: StatusCode, message: String },
+    /// Still rate limited (429) after the retries ran out
+    RateLimited { message: String },
+    /// The request timed out, or the stream sent nothing for `idle`
+    Timeout { idle: Option<Duration> },
+    /// Any other error status
+    Http { status: StatusCode, message: String },
+    /// The response body could not be decoded
+    Parse(String),
+    /// The provider could not be reached, or the connection broke
+    Network(String),
+    /// `cancel` was raised while the request was in flight
+    Cancelled,
+}
+
+impl LlmError {
+    /// The error classified by its HTTP status; `body` is the response body.
+    fn from_status(status: StatusCode, body: &str) -> Self {
+        let message = error_message(body).unwrap_or_else(|| status.to_string());
+        match status {
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => Self::Auth { status, message },
+            StatusCode::TOO_MANY_REQUESTS => Self::RateLimited { message },
+            _ => Self::Http { status, message },
+        }
+    }
+
+    /// The message shown to the user in `lang`.
+    pub fn localized(&self, lang: &Language) -> String {
+        match self {
+            Self::Auth { message, .. } => {
+                t(lang, MessageKey::AuthFailed).replace("{detail}", message)
+            }
+            Self::RateLimited { message } => {
+                t(lang, MessageKey::RateLimited).replace("{detail}", message)
+            }
+            Self::Timeout { idle: Some(idle) } => {
+                t(lang, MessageKey::StreamStalled).replace("{secs}", &idle.as_secs().to_string())
+            }
+            Self::Timeout { idle: None } => t(lang, MessageKey::RequestTimeout).to_string(),
+            Self::Http { status, message } => t(lang, MessageKey::HttpError)
+                .replace("{status}", &status.to_string())
+                .replace("{detail}", message),
+            Self::Parse(message) => {
+                t(lang, MessageKey::ResponseUnreadable).replace("{detail}", message)
+            }
+            Self::Network(message) => {
+                t(lang, MessageKey::NetworkError).replace("{detail}", message)
+            }
+            Self::Cancelled => self.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for LlmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Auth { status, message } => {
+                write!(f, "authentication failed ({status}): {message}")
+            }
+            Self::RateLimited { message } => write!(f, "rate limited: {message}"),
+            Self::Timeout { idle: Some(idle) } => {
+                write!(f, "stream stalled: no data for {}s", idle.as_secs())
+            }
+            Self::Timeout { idle: None } => f.write_str("request timed out"),
+            Self::Http { status, message } => write!(f, "error status {status}: {message}"),
+            Self::Parse(message) => write!(f, "unreadable response: {message}"),
+            Self::Network(message) => f.write_str(message),
+            Self::Cancelled => f.write_str("request cancelled"),
+        }
+    }
+}
+
+impl std::error::Error for LlmError {}
+
+/// The `error.message` of an OpenAI- or Anthropic-style error body, else the body itself.
+fn error_message(body: &str) -> Option<String> {
+    #[derive(Deserialize)]
+    struct ErrorBody {
+        error: ErrorDetail,
+    }
+    #[derive(Deserialize)]
+    struct ErrorDetail {
+        message: String,
+    }
+
+    let body = body.trim();
+    let message = serde_json::from_str::<ErrorBody>(body)
+        .map(|parsed| parsed.error.message)
+        .unwrap_or_else(|_| body.to_string());
+    Some(message).filter(|message| !message.is_empty())
+}
+
+/// `resp` when its status is a success, else the error it stands for.
+fn check_status(resp: Response) -> Result<Response, LlmError> {
+    let status = resp.status();
+    if status.is_success() {
+        return Ok(resp);
+    }
+    Err(LlmError::from_status(
+        status,
+        &resp.text().unwrap_or_default(),
+    ))
+}
+
+/// The tail of `history` holding its last `max_turns` question/answer pairs, cut
+/// before a question so a pair is never split.
+fn recent_turns(history: &[ChatMessage], max_turns: Option<usize>) -> &[ChatMessage] {
+    let Some(max_turns) = max_turns else {
+        return history;
+    };
+    let mut questions = 0;
+    let mut start = history.len();
+    for (idx, message) in history.iter().enumerate().rev() {
+        if message.role == Role::User {
+            if questions == max_turns {
+                break;
+            }
+            questions += 1;
+            start = idx;
+        }
+    }
+    &history[start..]
+}
+
+/// System prompt of the request that summarizes old history.
+const COMPACT_PROMPT: &str = "Summarize this conversation between a user and a shell \
+command assistant in one short paragraph. Keep the facts, paths, file names and commands \
+that later questions may refer to. Reply with the summary as plain text.";
+
+/// How the summary is put back into the history: as a question/answer pair, so the
+/// history keeps alternating and trimming and saving it still work turn by turn.
+const SUMMARY_PREFIX: &str = "Summary of our earlier conversation:\n";
+const SUMMARY_ACK: &str = "Understood.";
+
+/// For a history with more than `after_turns` question/answer pairs, the older part
+/// to summarize and the most recent `after_turns / 2` pairs kept as they are.
+fn compaction_split(
+    history: &[ChatMessage],
+    after_turns: usize,
+) -> Option<(&[ChatMessage], &[ChatMessage])> {
+    let turns = history.iter().filter(|m| m.role == Role::User).count();
+    if turns <= after_turns {
+        return None;
+    }
+    let recent = recent_turns(history, Some(after_turns / 2));
+    Some(history.split_at(history.len() - recent.len()))
+}
+
+/// The older turns as text for `COMPACT_PROMPT`.
+fn summary_transcript(older: &[ChatMessage]) -> String {
+    older
+        .iter()
+        .map(|m| match m.role {
+            Role::User => format!("User: {}", m.content),
+            Role::Assistant => format!("Assistant: {}", m.content),
+        })
+        .collect::<Vec<_>>()
+        .join("\n\n")
+}
+
+/// `summary` standing in for the older turns, followed by the `recent` ones.
+fn compacted_history(summary: &str, recent: &[ChatMessage]) -> Vec<ChatMessage> {
+    let mut compacted = vec![
+        ChatMessage {
+            role: Role::User,
+            content: format!("{SUMMARY_PREFIX}{summary}"),
+        },
+        ChatMessage {
+            role: Role::Assistant,
+            content: SUMMARY_ACK.to_string(),
+        },
+    ];
+    compacted.extend_from_slice(recent);
+    compacted
+}
+
+fn build_http_client(options: &ClientOptions) -> Result<Client> {
+    let mut builder = Client::builder()
+        .timeout(options.timeout)
+        .connect_timeout(options.connect_timeout);
+    // Without an explicit proxy, reqwest still honors HTTP(S)_PROXY / NO_PROXY
+    if let Some(url) = &options.proxy {
+        let proxy = reqwest::Proxy::all(url)
+            .with_context(|| format!("invalid llm.proxy `{url}`"))?
+            .no_proxy(
+                options
+                    .no_proxy
+                    .as_deref()
+                    .and_then(reqwest::NoProxy::from_string),
+            );
+        builder = builder.proxy(proxy);
+    }
+    Ok(builder.build()?)
+}
+
+fn request_error(err: reqwest::Error, context: &'static str) -> LlmError {
+    if err.is_timeout() {
+        LlmError::Timeout { idle: None }
+    } else if err.is_decode() {
+        LlmError::Parse(format!("{:#}", anyhow::Error::new(err)))
+    } else {
+        LlmError::Network(format!("{:#}", anyhow::Error::new(err).context(context)))
+    }
+}
+
+const RETRY_BASE_DELAY: Duration = Duration::from_millis(500);
+const RETRY_MAX_DELAY: Duration = Duration::from_secs(8);
+const RETRY_AFTER_CAP: Duration = Duration::from_secs(60);
+/// How often a wait for the provider looks at the cancel flag.
+const CANCEL_POLL: Duration = Duration::from_millis(50);
+
+fn is_retryable(status: StatusCode) -> bool {
+    matches!(status.as_u16(), 429 | 500 | 502 | 503)
+}
+
+/// Delay before retry number `attempt` (0-based): the server's Retry-After when given,
+/// otherwise exponential backoff with up to 50% random jitter.
+fn retry_delay(attempt: u32, retry_after: Option<Duration>) -> Duration {
+    if let Some(delay) = retry_after {
+        return delay.min(RETRY_AFTER_CAP);
+    }
+    let backoff = RETRY_BASE_DELAY
+        .saturating_mul(2u32.saturating_pow(attempt))
+        .min(RETRY_MAX_DELAY);
+    backoff + jitter(backoff / 2)
+}
+
+fn jitter(max: Duration) -> Duration {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.subsec_nanos())
+        .unwrap_or(0);
+    max.mul_f64(f64::from(nanos % 1000) / 1000.0)
+}
+
+fn retry_after(resp: &Response) -> Option<Duration> {
+    // Only the delta-seconds form is supported; HTTP dates fall back to backoff
+    resp.headers()
+        .get(reqwest::header::RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .trim()
+        .parse::<u64>()
+        .ok()
+        .map(Duration::from_secs)
+}
+
+/// Send the request built by `build`, re-issuing it from scratch on transient statuses.
+/// Retries happen before any of the body is read, so no partial output leaks out.
+/// Raising `cancel` ends the wait between attempts.
+fn send_with_retry(
+    build: impl Fn() -> RequestBuilder,
+    max_retries: u32,
+    context: &'static str,
+    cancel: &AtomicBool,
+) -> Result<Response, LlmError> {
+    let mut attempt = 0;
+    loop {
+        if cancel.load(Ordering::Relaxed) {
+            return Err(LlmError::Cancelled);
+        }
+        let resp = build().send().map_err(|e| request_error(e, context))?;
+        if attempt < max_retries && is_retryable(resp.status()) {
+            sleep_unless_cancelled(retry_delay(attempt, retry_after(&resp)), cancel);
+            attempt += 1;
+            continue;
+        }
+        return Ok(resp);
+    }
+}
+
+/// Sleep for `delay` in short slices, returning early once `cancel` is raised.
+fn sleep_unless_cancelled(delay: Duration, cancel: &AtomicBool) {
+    let deadline = Instant::now() + delay;
+    while !cancel.load(Ordering::Relaxed) {
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            break;
+        }
+        thread::sleep(left.min(CANCEL_POLL));
+    }
+}
+
+/// A streamed body read on its own thread, so a connection that stops sending without
+/// closing fails after `idle` instead of blocking the chat forever, and raising
+/// `cancel` ends a read right away. The blocking client has no per-read timeout of
+/// its own.
+struct IdleTimeoutReader<'a> {
+    chunks: Receiver<io::Result<Vec<u8>>>,
+    pending: Vec<u8>,
+    offset: usize,
+    idle: Duration,
+    cancel: &'a AtomicBool,
+}
+
+impl<'a> IdleTimeoutReader<'a> {
+    fn new(mut inner: impl Read + Send + 'static, idle: Duration, cancel: &'a AtomicBool) -> Self {
+        let (tx, chunks) = mpsc::channel();
+        thread::spawn(move || {
+            let mut buf = [0u8; 8 * 1024];
+            loop {
+                let chunk = match inner.read(&mut buf) {
+                    Ok(0) => break,
+                    Ok(n) => Ok(buf[..n].to_vec()),
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) => Err(e),
+                };
+                let failed = chunk.is_err();
+                // The receiver is gone once the reply is finished or abandoned
+                if tx.send(chunk).is_err() || failed {
+                    break;
+                }
+            }
+        });
+        Self {
+            chunks,
+            pending: Vec::new(),
+            offset: 0,
+            idle,
+            cancel,
+        }
+    }
+}
+
+impl Read for IdleTimeoutReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let deadline = Instant::now() + self.idle;
+        while self.offset == self.pending.len() {
+            // Not `Interrupted`: `BufRead` retries those instead of giving up
+            if self.cancel.load(Ordering::Relaxed) {
+                return Err(io::Error::other(StreamCancelled));
+            }
+            let left = deadline.saturating_duration_since(Instant::now());
+            match self.chunks.recv_timeout(left.min(CANCEL_POLL)) {
+                Ok(chunk) => {
+                    self.pending = chunk?;
+                    self.offset = 0;
+                }
+                Err(RecvTimeoutError::Disconnected) => return Ok(0),
+                Err(RecvTimeoutError::Timeout) if left <= CANCEL_POLL => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        StreamStalled(self.idle),
+                    ));
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+            }
+        }
+        let n = buf.len().min(self.pending.len() - self.offset);
+        buf[..n].copy_from_slice(&self.pending[self.offset..self.offset + n]);
+        self.offset += n;
+        Ok(n)
+    }
+}
+
+/// The data of each event in a server-sent event stream. Gateways differ in the
+/// details, so `data:` is accepted with or without the space after it, comment lines
+/// (`: keep-alive`) and other fields are skipped, and the `data:` lines of one event
+/// are joined with newlines. An event ends at a blank line or at the end of the stream.
+struct SseEvents<R> {
+    lines: Lines<R>,
+}
+
+impl<R: BufRead> SseEvents<R> {
+    fn new(reader: R) -> Self {
+        Self {
+            lines: reader.lines(),
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for SseEvents<R> {
+    type Item = io::Result<String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut data: Option<String> = None;
+        loop {
+            let line = match self.lines.next() {
+                Some(Ok(line)) => line,
+                Some(Err(e)) => return Some(Err(e)),
+                None => return data.map(Ok),
+            };
+            if line.is_empty() {
+                if data.is_some() {
+                    return data.map(Ok);
+                }
+                continue;
+            }
+            let Some(value) = line.strip_prefix("data") else {
+                continue;
+            };
+            // `database: ...` is some other field, not data
+            let value = match value.strip_prefix(':') {
+                Some(value) => value.strip_prefix(' ').unwrap_or(value),
+                None if value.is_empty() => "",
+                None => continue,
+            };
+            match &mut data {
+                Some(data) => {
+                    data.push('\n');
+                    data.push_str(value);
+                }
+                None => data = Some(value.to_string()),
+            }
+        }
+    }
+}
+
+/// Parse the JSON payload of an SSE event. A payload spread over several `data:` lines
+/// is joined with newlines, which is only whitespace to JSON unless a gateway split it
+/// inside a string; raw newlines are never valid there, so they are dropped and the
+/// parse retried.
+fn parse_event<T: serde::de::DeserializeOwned>(data: &str) -> Option<T> {
+    serde_json::from_str(data)
+        .ok()
+        .or_else(|| serde_json::from_str(&data.replace('\n', "")).ok())
+}
+
+/// Set on the error `IdleTimeoutReader` returns when the stream went quiet.
+#[derive(Debug)]
+struct StreamStalled(Duration);
+
+impl fmt::Display for StreamStalled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "no data for {}s", self.0.as_secs())
+    }
+}
+
+impl std::error::Error for StreamStalled {}
+
+/// Set on the error `IdleTimeoutReader` returns once `cancel` was raised.
+#[derive(Debug)]
+struct StreamCancelled;
+
+impl fmt::Display for StreamCancelled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("cancelled while waiting for data")
+    }
+}
+
+impl std::error::Error for StreamCancelled {}
+
+fn stream_error(err: io::Error) -> LlmError {
+    let inner = err.get_ref();
+    if let Some(StreamStalled(idle)) = inner.and_then(|e| e.downcast_ref()) {
+        LlmError::Timeout { idle: Some(*idle) }
+    } else if inner.is_some_and(|e| e.is::<StreamCancelled>()) {
+        LlmError::Cancelled
+    } else if err.kind() == io::ErrorKind::TimedOut {
+        LlmError::Timeout { idle: None }
+    } else {
+        LlmError::Network(format!("failed to read line from stream: {err}"))
+    }
+}
+
+/// Record the raw streamed output in the debug log, before it is parsed.
+fn log_response(content: &str, reasoning: &str) {
+    if !reasoning.is_empty() {
+        crate::log::record("reasoning", reasoning);
+    }
+    crate::log::record("response", content);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::net::TcpListener;
+
+    /// Options for a test client talking to `base_url`.
+    pub(crate) fn client_options(base_url: &str) -> ClientOptions {
+        ClientOptions {
+            api_key: "key".to_string(),
+            model: "gpt-4o".to_string(),
+            base_url: base_url.to_string(),
+            lang: Language::En,
+            timeout: std::time::Duration::from_secs(1),
+            connect_timeout: std::time::Duration::from_secs(1),
+            stream_idle_timeout: std::time::Duration::from_secs(1),
+            max_retries: 0,
+            sampling: Sampling::default(),
+            json_mode: JsonMode::Auto,
+            proxy: None,
+            no_proxy: None,
+            headers: Vec::new(),
+            organization: None,
+            project: None,
+            show_usage: false,
+            max_reasoning_chars: 100_000,
+            json_repair_attempts: 1,
+            max_history_turns: None,
+            compaction: None,
+            prompt_mode: PromptMode::Json,
+            request_reasoning: true,
+            reasoning_effort_when_off: None,
+        }
+    }
+    /// A local server for client tests. It answers one request for each of `replies`
+    /// with that text as an event stream, or as it is when it is a whole HTTP response,
+    /// and passes on the body of every request.
+    pub(crate) fn mock_server(replies: Vec<String>) -> (String, Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        let (tx, bodies) = mpsc::channel();
+        thread::spawn(move || {
+            for reply in replies {
+                let Ok((mut stream, _)) = listener.accept() else {
+                    return;
+                };
+                let mut reader = io::BufReader::new(stream.try_clone().unwrap());
+                let mut length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line.trim().is_empty() {
+                        break;
+                    }
+                    if let Some((name, value)) = line.split_once(':')
+                        && name.eq_ignore_ascii_case("content-length")
+                    {
+                        length = value.trim().parse().unwrap();
+                    }
+                }
+                let mut body = vec![0; length];
+                reader.read_exact(&mut body).unwrap();
+                let _ = tx.send(String::from_utf8(body).unwrap());
+                let response = if reply.starts_with("HTTP/") {
+                    reply
+                } else {
+                    format!(
+                        "HTTP/1.1 200 OK\r\ncontent-type: text/event-stream\r\n\
+                         content-length: {}\r\nconnection: close\r\n\r\n{reply}",
+                        reply.len()
+                    )
+                };
+                let _ = stream.write_all(response.as_bytes());
+            }
+        });
+        (base_url, bodies)
+    }
+
+    /// Hands out `data`, then blocks like a connection that stays open but silent.
+    struct StallingReader(Option<Vec<u8>>);
+
+    impl Read for StallingReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match self.0.take() {
+                Some(data) => {
+                    buf[..data.len()].copy_from_slice(&data);
+                    Ok(data.len())
+                }
+                None => {
+                    thread::sleep(Duration::from_secs(5));
+                    Ok(0)
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_idle_timeout_reader() {
+        let cancel = AtomicBool::new(false);
+        let mut reader =
+            IdleTimeoutReader::new(&b"data: a\ndata: b\n"[..], Duration::from_secs(5), &cancel);
+        let mut text = String::new();
+        reader.read_to_string(&mut text).unwrap();
+        assert_eq!(text, "data: a\ndata: b\n");
+
+        let stalled = StallingReader(Some(b"data: a\n".to_vec()));
+        let mut reader = IdleTimeoutReader::new(stalled, Duration::from_millis(50), &cancel);
+        let mut buf = [0u8; 64];
+        assert_eq!(reader.read(&mut buf).unwrap(), 8);
+        let err = reader.read(&mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        let message = stream_error(err).localized(&Language::En);
+        assert!(message.contains("stream_idle_timeout_secs"), "{message}");
+    }
+
+    #[test]
+    fn test_idle_timeout_reader_cancel() {
+        let cancel = AtomicBool::new(false);
+        let mut reader =
+            IdleTimeoutReader::new(StallingReader(None), Duration::from_secs(5), &cancel);
+        let started = Instant::now();
+        thread::scope(|s| {
+            s.spawn(|| {
+                thread::sleep(Duration::from_millis(100));
+                cancel.store(true, Ordering::Relaxed);
+            });
+            let err = reader.read(&mut [0u8; 64]).unwrap_err();
+            assert!(matches!(stream_error(err), LlmError::Cancelled));
+        });
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn test_send_with_retry_cancel() {
+        let busy = "HTTP/1.1 503 Service Unavailable\r\nretry-after: 30\r\n\
+                    content-length: 0\r\nconnection: close\r\n\r\n";
+        let (base_url, bodies) = mock_server(vec![busy.to_string(), busy.to_string()]);
+        let client = Client::new();
+        let build = || client.post(&base_url).body("{}");
+
+        // Cancelled during the Retry-After wait: no second attempt
+        let cancel = AtomicBool::new(false);
+        let started = Instant::now();
+        thread::scope(|s| {
+            s.spawn(|| {
+                thread::sleep(Duration::from_millis(100));
+                cancel.store(true, Ordering::Relaxed);
+            });
+            let err = send_with_retry(build, 3, "test", &cancel).unwrap_err();
+            assert!(matches!(err, LlmError::Cancelled));
+        });
+        assert!(started.elapsed() < Duration::from_secs(2));
+        assert_eq!(bodies.try_iter().count(), 1);
+
+        // Already cancelled: nothing is sent
+        let err = send_with_retry(build, 3, "test", &cancel).unwrap_err();
+        assert!(matches!(err, LlmError::Cancelled));
+        assert_eq!(bodies.try_iter().count(), 0);
+    }
+
+    #[test]
+    fn test_error_from_status() {
+        let body = r#"{"error": {"message": "Incorrect API key provided", "type": "invalid_request_error"}}"#;
+        let err = LlmError::from_status(StatusCode::UNAUTHORIZED, body);
+        assert!(
+            matches!(&err, LlmError::Auth { message, .. } if message == "Incorrect API key provided")
+        );
+        assert_eq!(
+            err.localized(&Language::En),
+            "The provider rejected the credentials (Incorrect API key provided); check llm.api_key"
+        );
+
+        let err = LlmError::from_status(StatusCode::TOO_MANY_REQUESTS, "slow down\n");
+        assert!(matches!(&err, LlmError::RateLimited { message } if message == "slow down"));
+        let err = LlmError::from_status(StatusCode::BAD_GATEWAY, "");
+        assert_eq!(
+            err.to_string(),
+            "error status 502 Bad Gateway: 502 Bad Gateway"
+        );
+    }
+
+    #[test]
+    fn test_recent_turns() {
+        let message = |role, content: &str| ChatMessage {
+            role,
+            content: content.to_string(),
+        };
+        let history = vec![
+            message(Role::User, "q1"),
+            message(Role::Assistant, "a1"),
+            message(Role::User, "q2"),
+            message(Role::Assistant, "a2"),
+            message(Role::User, "q3"),
+            message(Role::Assistant, "a3"),
+        ];
+        let contents = |max| -> Vec<&str> {
+            recent_turns(&history, max)
+                .iter()
+                .map(|m| m.content.as_str())
+                .collect()
+        };
+        assert_eq!(contents(None).len(), 6);
+        assert_eq!(contents(Some(2)), ["q2", "a2", "q3", "a3"]);
+        assert_eq!(contents(Some(1)), ["q3", "a3"]);
+        assert!(contents(Some(0)).is_empty());
+        assert_eq!(contents(Some(9)).len(), 6);
+
+        // An answer left without its question (e.g. a trimmed saved history) is dropped
+        assert_eq!(
+            recent_turns(&history[1..], Some(9))
+                .iter()
+                .map(|m| m.content.as_str())
+                .collect::<Vec<_>>(),
+            ["q2", "a2", "q3", "a3"]
+        );
+    }
+
+    #[test]
+    fn test_compaction() {
+        let history: Vec<ChatMessage> = (1..=5)
+            .flat_map(|i| {
+                [
+                    ChatMessage {
+                        role: Role::User,
+                        content: format!("q{i}"),
+                    },
+                    ChatMessage {
+                        role: Role::Assistant,
+                        content: format!("a{i}"),
+                    },
+                ]
+            })
+            .collect();
+        assert!(compaction_split(&history, 5).is_none());
+
+        let (older, recent) = compaction_split(&history, 4).unwrap();
+        assert_eq!(older.len(), 6);
+        assert_eq!(recent[0].content, "q4");
+        assert_eq!(summary_transcript(&older[..2]), "User: q1\n\nAssistant: a1");
+
+        let compacted = compacted_history("listed files", recent);
+        assert_eq!(compacted.len(), 6);
+        assert_eq!(compacted[0].role, Role::User);
+        assert!(compacted[0].content.ends_with("listed files"));
+        assert_eq!(compacted[1].role, Role::Assistant);
+        assert_eq!(compacted[2].content, "q4");
+        // A compacted history is short again, summary included
+        assert!(compaction_split(&compacted, 4).is_none());
+    }
+
+    #[test]
+    fn test_sse_events() {
+        let stream = "data: {\"a\":1}\n\ndata:{\"b\":2}\n\n: keep-alive\n\n\
+                      event: ping\ndata: x\ndata:y\nid: 3\n\ndata: [DONE]\n";
+        let events: Vec<String> = SseEvents::new(stream.as_bytes())
+            .collect::<io::Result<_>>()
+            .unwrap();
+        assert_eq!(events, ["{\"a\":1}", "{\"b\":2}", "x\ny", "[DONE]"]);
+
+        // CRLF line endings, and only the first space after the colon is dropped
+        let events: Vec<String> = SseEvents::new("data:  a\r\n\r\n".as_bytes())
+            .collect::<io::Result<_>>()
+            .unwrap();
+        assert_eq!(events, [" a"]);
+    }
+
+    #[test]
+    fn test_parse_event_split_json() {
+        let stream =
+            "data: {\"role\":\ndata: \"assistant\"}\n\ndata: {\"role\":\"assis\ndata:tant\"}\n\n";
+        let events: Vec<serde_json::Value> = SseEvents::new(stream.as_bytes())
+            .map(|data| parse_event(&data.unwrap()).unwrap())
+            .collect();
+        assert_eq!(events.len(), 2);
+        assert!(events.iter().all(|e| e["role"] == "assistant"));
+        assert!(parse_event::<serde_json::Value>("{\"role\":").is_none());
+    }
+
+    #[test]
+    fn test_is_retryable() {
+        assert!(is_retryable(StatusCode::TOO_MANY_REQUESTS));
+        assert!(is_retryable(StatusCode::BAD_GATEWAY));
+        assert!(!is_retryable(StatusCode::BAD_REQUEST));
+        assert!(!is_retryable(StatusCode::UNAUTHORIZED));
+        assert!(!is_retryable(StatusCode::GATEWAY_TIMEOUT));
+    }
+
+    #[test]
+    fn test_retry_delay_honors_retry_after() {
+        assert_eq!(
+            retry_delay(0, Some(Duration::from_secs(3))),
+            Duration::from_secs(3)
+        );
+        assert_eq!(
+            retry_delay(0, Some(Duration::from_secs(600))),
+            RETRY_AFTER_CAP
+        );
+    }
+
+    #[test]
+    fn test_retry_delay_backoff_bounds() {
+        for attempt in 0..6 {
+            let base = RETRY_BASE_DELAY
+                .saturating_mul(2u32.pow(attempt))
+                .min(RETRY_MAX_DELAY);
+            let delay = retry_delay(attempt, None);
+            assert!(delay >= base && delay <= base + base / 2);
+        }
+    }
 }
@@ -1,48 +1,346 @@
-use std::io::{BufRead, BufReader};
+use std::io::BufReader;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
 
-use anyhow::{Context, Result};
-use reqwest::blocking::Client;
+use anyhow::{Context, Result, bail};
+use reqwest::StatusCode;
+use reqwest::blocking::{Client, RequestBuilder, Response};
 use serde::{Deserialize, Serialize};
 
-use super::{ChatMessage, ChatReply, LLMClient, Role};
+use super::reply::{
+    AnswerStream, empty_reply, is_reply_json, parse_plain_reply, parse_reply, push_capped,
+    sanitize_stream_text,
+};
+use super::{
+    COMPACT_PROMPT, ChatMessage, ChatReply, ChatSink, ClientOptions, Compaction, IdleTimeoutReader,
+    JsonMode, LLMClient, LlmError, NoopSink, PromptMode, ReasoningEffort, Role, Sampling,
+    SseEvents, Usage, build_http_client, check_status, compacted_history, compaction_split,
+    log_response, parse_event, recent_turns, send_with_retry, stream_error, summary_transcript,
+};
 use crate::i18n::{Language, MessageKey, t};
 
+/// Sent after a reply whose JSON could not be parsed, along with that reply.
+const JSON_REPAIR_PROMPT: &str = "Your last reply was not valid JSON. Reply again with only the \
+JSON object described in the instructions, with no other text.";
+
 pub struct OpenAIClient {
+    endpoint: Endpoint,
     api_key: String,
     model: String,
     base_url: String,
     client: Client,
-    system_prompt: String,
     lang: Language,
+    max_retries: u32,
+    stream_idle_timeout: Duration,
+    headers: Vec<(String, String)>,
+    organization: Option<String>,
+    project: Option<String>,
+    sampling: Sampling,
+    json_mode: JsonMode,
+    show_usage: bool,
+    max_reasoning_chars: usize,
+    max_history_turns: Option<usize>,
+    compaction: Option<Compaction>,
+    prompt_mode: PromptMode,
+    request_reasoning: bool,
+    /// `reasoning_effort` sent instead of the configured one while `request_reasoning` is off
+    reasoning_effort_when_off: Option<ReasoningEffort>,
+    /// Follow-up requests allowed for replies whose JSON does not parse
+    json_repair_attempts: u32,
+    /// Set once the backend has rejected `response_format` in auto mode
+    json_mode_unsupported: AtomicBool,
 }
 
 impl OpenAIClient {
-    pub fn new(
-        api_key: String,
-        model: String,
-        base_url: String,
-        system_prompt: String,
-        lang: Language,
-    ) -> Result<Self> {
-        let client = Client::builder().build()?;
+    pub fn new(options: ClientOptions) -> Result<Self> {
+        let client = build_http_client(&options)?;
+        let ClientOptions {
+            api_key,
+            model,
+            base_url,
+            lang,
+            max_retries,
+            stream_idle_timeout,
+            sampling,
+            json_mode,
+            headers,
+            organization,
+            project,
+            show_usage,
+            max_reasoning_chars,
+            max_history_turns,
+            compaction,
+            prompt_mode,
+            request_reasoning,
+            reasoning_effort_when_off,
+            json_repair_attempts,
+            ..
+        } = options;
         Ok(Self {
+            endpoint: Endpoint::OpenAI,
             api_key,
             model,
             base_url,
             client,
-            system_prompt,
             lang,
+            max_retries,
+            stream_idle_timeout,
+            headers,
+            organization,
+            project,
+            sampling,
+            json_mode,
+            show_usage,
+            max_reasoning_chars,
+            max_history_turns,
+            compaction,
+            prompt_mode,
+            request_reasoning,
+            reasoning_effort_when_off,
+            json_repair_attempts,
+            json_mode_unsupported: AtomicBool::new(false),
         })
     }
+
+    /// A client for Ollama's OpenAI-compatible endpoint, which also takes `think`.
+    pub fn ollama(options: ClientOptions) -> Result<Self> {
+        let mut client = Self::new(options)?;
+        client.endpoint = Endpoint::Ollama;
+        Ok(client)
+    }
+
+    /// A client for an Azure OpenAI deployment; `base_url` is the resource endpoint.
+    pub fn azure(options: ClientOptions, deployment: String, api_version: String) -> Result<Self> {
+        let mut client = Self::new(options)?;
+        client.endpoint = Endpoint::Azure {
+            deployment,
+            api_version,
+        };
+        Ok(client)
+    }
+
+    fn chat_url(&self) -> String {
+        match &self.endpoint {
+            Endpoint::OpenAI | Endpoint::Ollama => format!("{}/chat/completions", self.base_url),
+            Endpoint::Azure {
+                deployment,
+                api_version,
+            } => format!(
+                "{}/openai/deployments/{deployment}/chat/completions?api-version={api_version}",
+                self.base_url.trim_end_matches('/')
+            ),
+        }
+    }
+
+    fn send(&self, req: &OaiRequest, cancel: &AtomicBool) -> Result<Response, LlmError> {
+        let endpoint = self.chat_url();
+        if crate::log::enabled()
+            && let Ok(body) = serde_json::to_string_pretty(req)
+        {
+            crate::log::record(
+                &format!("request {endpoint}"),
+                &crate::log::redact(&body, &self.api_key),
+            );
+        }
+        send_with_retry(
+            || self.build_request(&endpoint, req),
+            self.max_retries,
+            "failed to call OpenAI",
+            cancel,
+        )
+    }
+
+    fn build_request(&self, endpoint: &str, req: &OaiRequest) -> RequestBuilder {
+        self.authorize(self.client.post(endpoint).json(req))
+    }
+
+    /// Add the configured headers and the credentials to `request`.
+    fn authorize(&self, mut request: RequestBuilder) -> RequestBuilder {
+        for (name, value) in &self.headers {
+            request = request.header(name, value);
+        }
+        // Only sent when configured, so the key's default organization/project applies otherwise
+        if let Some(organization) = &self.organization {
+            request = request.header("OpenAI-Organization", organization);
+        }
+        if let Some(project) = &self.project {
+            request = request.header("OpenAI-Project", project);
+        }
+        match self.endpoint {
+            Endpoint::Azure { .. } => request.header("api-key", &self.api_key),
+            // Keyless local servers (e.g. Ollama) reject an empty bearer token
+            Endpoint::OpenAI | Endpoint::Ollama if self.api_key.is_empty() => request,
+            Endpoint::OpenAI | Endpoint::Ollama => request.bearer_auth(&self.api_key),
+        }
+    }
+
+    /// Send `req`, dropping `response_format` for good in auto mode if the backend
+    /// rejects it.
+    fn send_checked(
+        &self,
+        req: &mut OaiRequest,
+        cancel: &AtomicBool,
+    ) -> Result<Response, LlmError> {
+        loop {
+            let resp = self.send(req, cancel)?;
+            if resp.status() != StatusCode::BAD_REQUEST
+                || self.json_mode != JsonMode::Auto
+                || req.response_format.is_none()
+            {
+                return check_status(resp);
+            }
+            let status = resp.status();
+            let body = resp.text().unwrap_or_default();
+            if !rejects_response_format(&body) {
+                return Err(LlmError::from_status(status, &body));
+            }
+            // Fall back to plain output; extract_json still finds the fenced JSON
+            self.json_mode_unsupported.store(true, Ordering::Relaxed);
+            req.response_format = None;
+        }
+    }
+
+    /// The sampling parameters and Ollama's `think` for a chat request. With
+    /// `request_reasoning` off, Ollama is told not to think; OpenAI and Azure have no
+    /// such switch and get `reasoning_effort_when_off` when it is set.
+    fn reasoning_params(&self) -> (Sampling, Option<bool>) {
+        let mut sampling = self.sampling;
+        if self.request_reasoning {
+            return (sampling, None);
+        }
+        match self.endpoint {
+            Endpoint::Ollama => (sampling, Some(false)),
+            Endpoint::OpenAI | Endpoint::Azure { .. } => {
+                if let Some(effort) = self.reasoning_effort_when_off {
+                    sampling.reasoning_effort = Some(effort);
+                }
+                (sampling, None)
+            }
+        }
+    }
+
+    /// Read a streamed completion, passing reasoning and answer text on as it arrives.
+    fn read_stream(
+        &self,
+        resp: Response,
+        sink: &mut dyn ChatSink,
+        cancel: &AtomicBool,
+    ) -> Result<Streamed, LlmError> {
+        // Use BufReader to read streaming responses line by line
+        let reader = BufReader::new(IdleTimeoutReader::new(
+            resp,
+            self.stream_idle_timeout,
+            cancel,
+        ));
+        let mut streamed = Streamed::default();
+        let mut answer_stream = AnswerStream::default();
+
+        for data in SseEvents::new(reader) {
+            if cancel.load(Ordering::Relaxed) {
+                return Err(LlmError::Cancelled);
+            }
+            let data = data.map_err(stream_error)?;
+
+            // Stream end marker
+            if data == "[DONE]" {
+                break;
+            }
+
+            // Parse JSON chunk
+            let Some(chunk) = parse_event::<StreamChunk>(&data) else {
+                continue;
+            };
+            // The usage chunk has an empty `choices`
+            if let Some(usage) = chunk.usage {
+                sink.on_usage(usage);
+                streamed.usage = Some(usage);
+            }
+            if let Some(choice) = chunk.choices.first() {
+                // Handle reasoning content
+                if let Some(reasoning) = choice.delta.reasoning()
+                    && self.request_reasoning
+                {
+                    let reasoning = sanitize_stream_text(reasoning);
+                    push_capped(
+                        &mut streamed.reasoning,
+                        &reasoning,
+                        self.max_reasoning_chars,
+                        t(&self.lang, MessageKey::ReasoningCapped),
+                    );
+                    sink.on_reasoning(&reasoning);
+                }
+
+                // Accumulate standard content
+                if let Some(content) = &choice.delta.content {
+                    streamed.content.push_str(content);
+                    if self.prompt_mode == PromptMode::Plain {
+                        sink.on_answer(content);
+                    } else if let Some(answer) = answer_stream.update(&streamed.content) {
+                        sink.on_answer(&answer);
+                    }
+                }
+
+                if let Some(reason) = &choice.finish_reason {
+                    streamed.finish_reason = Some(reason.clone());
+                }
+            }
+        }
+        Ok(streamed)
+    }
+
+    fn use_response_format(&self) -> bool {
+        if self.prompt_mode == PromptMode::Plain {
+            return false;
+        }
+        match self.json_mode {
+            JsonMode::On => true,
+            JsonMode::Off => false,
+            JsonMode::Auto => !self.json_mode_unsupported.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// URL shape and authentication of the chat completions endpoint.
+enum Endpoint {
+    /// `{base_url}/chat/completions` with a bearer token
+    OpenAI,
+    /// Like `OpenAI`; the server also understands `think`
+    Ollama,
+    /// `{base_url}/openai/deployments/{deployment}/chat/completions` with an `api-key` header
+    Azure {
+        deployment: String,
+        api_version: String,
+    },
+}
+
+/// Whether a 400 body complains about `response_format` rather than something else
+/// (bad model name, context length, ...), which retrying would not fix.
+fn rejects_response_format(body: &str) -> bool {
+    let body = body.to_ascii_lowercase();
+    ["response_format", "response format", "json_object"]
+        .iter()
+        .any(|needle| body.contains(needle))
 }
 
 #[derive(Serialize)]
 struct OaiRequest<'a> {
     model: &'a str,
     messages: Vec<serde_json::Value>,
-    #[serde(rename = "response_format")]
-    response_format: ResponseFormat<'a>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    response_format: Option<ResponseFormat<'a>>,
     stream: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stream_options: Option<StreamOptions>,
+    #[serde(flatten)]
+    sampling: Sampling,
+    /// Ollama only; `false` turns thinking off for models that support it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    think: Option<bool>,
+}
+
+#[derive(Serialize)]
+struct StreamOptions {
+    include_usage: bool,
 }
 
 #[derive(Serialize)]
@@ -51,60 +349,88 @@ struct ResponseFormat<'a> {
     kind: &'a str,
 }
 
-#[derive(Deserialize)]
-struct JsonPayload {
-    command: Option<String>,
-    answer: Option<String>,
-    note: Option<String>,
-    explanation: Option<String>,
-    message: Option<String>,
-}
-
 // Data structures for streaming responses
 #[derive(Deserialize)]
 struct StreamChunk {
+    // Some servers (e.g. Ollama) send chunks without `choices`
+    #[serde(default)]
     choices: Vec<StreamChoice>,
+    // Only in the final chunk, and only when `include_usage` was requested
+    #[serde(default)]
+    usage: Option<Usage>,
 }
 
 #[derive(Deserialize)]
 struct StreamChoice {
+    // The final chunk may carry only a finish reason
+    #[serde(default)]
     delta: StreamDelta,
+    #[serde(default)]
+    finish_reason: Option<String>,
+}
+
+/// What one streamed completion produced.
+#[derive(Default)]
+struct Streamed {
+    content: String,
+    reasoning: String,
+    finish_reason: Option<String>,
+    usage: Option<Usage>,
+}
+
+/// `GET /models` response; only the ids are used.
+#[derive(Deserialize)]
+struct ModelList {
+    data: Vec<ModelEntry>,
 }
 
 #[derive(Deserialize)]
+struct ModelEntry {
+    id: String,
+}
+
+/// Sorted model ids from a `/models` body; anything unexpected gives none.
+fn parse_model_list(body: &str) -> Vec<String> {
+    let mut ids: Vec<String> = serde_json::from_str::<ModelList>(body)
+        .map(|list| list.data.into_iter().map(|model| model.id).collect())
+        .unwrap_or_default();
+    ids.sort();
+    ids
+}
+
+#[derive(Deserialize, Default)]
 struct StreamDelta {
+    /// DeepSeek-style reasoning
     #[serde(default)]
     reasoning_content: Option<String>,
+    /// The same under the name newer OpenAI-compatible servers use
+    #[serde(default)]
+    reasoning: Option<String>,
     #[serde(default)]
     content: Option<String>,
 }
 
-fn extract_json(content: &str) -> &str {
-    let trimmed = content.trim();
-    if let Some(start) = trimmed.find("```json") {
-        let json_start = start + 7;
-        if let Some(end) = trimmed[json_start..].find("```") {
-            return trimmed[json_start..json_start + end].trim();
-        }
-    }
-    if let Some(start) = trimmed.find("```") {
-        let json_start = start + 3;
-        if let Some(end) = trimmed[json_start..].find("```") {
-            return trimmed[json_start..json_start + end].trim();
-        }
+impl StreamDelta {
+    /// Reasoning text under either name; a server sending both gets `reasoning_content`.
+    fn reasoning(&self) -> Option<&str> {
+        self.reasoning_content
+            .as_deref()
+            .or(self.reasoning.as_deref())
     }
-    trimmed
 }
 
 impl LLMClient for OpenAIClient {
     fn chat(
         &self,
+        system_prompt: &str,
         history: &[ChatMessage],
         user_input: &str,
-        on_reasoning: &mut dyn FnMut(&str),
-    ) -> Result<ChatReply> {
+        sink: &mut dyn ChatSink,
+        cancel: &AtomicBool,
+    ) -> Result<ChatReply, LlmError> {
+        let history = recent_turns(history, self.max_history_turns);
         let mut payload: Vec<serde_json::Value> = Vec::with_capacity(history.len() + 2);
-        payload.push(serde_json::json!({ "role": "system", "content": self.system_prompt }));
+        payload.push(serde_json::json!({ "role": "system", "content": system_prompt }));
         for m in history {
             let role = match m.role {
                 Role::User => "user",
@@ -114,154 +440,463 @@ impl LLMClient for OpenAIClient {
         }
         payload.push(serde_json::json!({"role": "user", "content": user_input}));
 
-        let req = OaiRequest {
+        let (sampling, think) = self.reasoning_params();
+        let mut req = OaiRequest {
             model: &self.model,
             messages: payload,
-            response_format: ResponseFormat {
+            response_format: self.use_response_format().then_some(ResponseFormat {
                 kind: "json_object",
-            },
+            }),
             stream: true,
+            stream_options: self.show_usage.then_some(StreamOptions {
+                include_usage: true,
+            }),
+            sampling,
+            think,
+        };
+
+        // Malformed JSON is sent back with a request to fix it, a bounded number of times
+        let mut repairs_left = match self.prompt_mode {
+            PromptMode::Json => self.json_repair_attempts,
+            PromptMode::Plain => 0,
         };
+        let mut repairing = false;
+        let streamed = loop {
+            let resp = self.send_checked(&mut req, cancel)?;
+            // Only the first attempt is previewed; a repair would be appended to the
+            // malformed text already on screen
+            let target: &mut dyn ChatSink = if repairing { &mut NoopSink } else { &mut *sink };
+            let streamed = self.read_stream(resp, target, cancel)?;
+            if repairs_left == 0
+                || streamed.content.trim().is_empty()
+                || is_reply_json(&streamed.content)
+            {
+                break streamed;
+            }
+            repairs_left -= 1;
+            repairing = true;
+            crate::log::record("json repair", &streamed.content);
+            req.messages
+                .push(serde_json::json!({"role": "assistant", "content": streamed.content}));
+            req.messages
+                .push(serde_json::json!({"role": "user", "content": JSON_REPAIR_PROMPT}));
+        };
+        let Streamed {
+            content: accumulated_content,
+            reasoning: accumulated_reasoning,
+            finish_reason,
+            usage,
+        } = streamed;
+
+        log_response(&accumulated_content, &accumulated_reasoning);
+        let mut reply = if accumulated_content.trim().is_empty() {
+            empty_reply(accumulated_reasoning, finish_reason.as_deref(), &self.lang)
+        } else if self.prompt_mode == PromptMode::Plain {
+            parse_plain_reply(accumulated_content, accumulated_reasoning)
+        } else {
+            parse_reply(accumulated_content, accumulated_reasoning, &self.lang)
+        };
+        reply.usage = usage;
+        Ok(reply)
+    }
+
+    fn compact_history(
+        &self,
+        history: &[ChatMessage],
+        cancel: &AtomicBool,
+    ) -> Result<Vec<ChatMessage>> {
+        let Some(compaction) = &self.compaction else {
+            return Ok(history.to_vec());
+        };
+        let Some((older, recent)) = compaction_split(history, compaction.after_turns) else {
+            return Ok(history.to_vec());
+        };
+        let req = OaiRequest {
+            model: compaction.model.as_deref().unwrap_or(&self.model),
+            messages: vec![
+                serde_json::json!({ "role": "system", "content": COMPACT_PROMPT }),
+                serde_json::json!({ "role": "user", "content": summary_transcript(older) }),
+            ],
+            response_format: None,
+            stream: true,
+            stream_options: None,
+            sampling: Sampling::default(),
+            think: None,
+        };
+        let resp = check_status(self.send(&req, cancel)?)?;
+        let streamed = self.read_stream(resp, &mut NoopSink, cancel)?;
+        crate::log::record("summary", &streamed.content);
+        let summary = streamed.content.trim();
+        if summary.is_empty() {
+            bail!("the summary of the earlier conversation came back empty");
+        }
+        Ok(compacted_history(summary, recent))
+    }
 
-        let endpoint = format!("{}/chat/completions", self.base_url);
+    fn list_models(&self) -> Result<Vec<String>> {
+        // Azure lists base models here, not the deployments requests are made with
+        if let Endpoint::Azure { .. } = self.endpoint {
+            return Ok(Vec::new());
+        }
         let resp = self
-            .client
-            .post(&endpoint)
-            .bearer_auth(&self.api_key)
-            .json(&req)
+            .authorize(self.client.get(format!("{}/models", self.base_url)))
             .send()
-            .context("failed to call OpenAI")?
+            .context("failed to list models")?;
+        // Some OpenAI-compatible servers simply do not have the endpoint
+        if matches!(
+            resp.status(),
+            StatusCode::NOT_FOUND | StatusCode::METHOD_NOT_ALLOWED | StatusCode::NOT_IMPLEMENTED
+        ) {
+            return Ok(Vec::new());
+        }
+        let body = resp
             .error_for_status()
-            .context("OpenAI returned error status")?;
+            .context("failed to list models")?
+            .text()
+            .context("failed to list models")?;
+        Ok(parse_model_list(&body))
+    }
+}
 
-        // Use BufReader to read streaming responses line by line
-        let reader = BufReader::new(resp);
-        let mut accumulated_content = String::new();
-        let mut accumulated_reasoning = String::new();
-
-        for line in reader.lines() {
-            let line = line.context("failed to read line from stream")?;
-            
-            // SSE format: data lines start with "data: "
-            if let Some(data) = line.strip_prefix("data: ") {
-                // Stream end marker
-                if data == "[DONE]" {
-                    break;
-                }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm::tests::{client_options, mock_server};
 
-                // Parse JSON chunk
-                if let Ok(chunk) = serde_json::from_str::<StreamChunk>(data) {
-                    if let Some(choice) = chunk.choices.first() {
-                        // Handle reasoning content
-                        if let Some(reasoning) = &choice.delta.reasoning_content {
-                            accumulated_reasoning.push_str(reasoning);
-                            on_reasoning(reasoning);
-                        }
-                        
-                        // Accumulate standard content
-                        if let Some(content) = &choice.delta.content {
-                            accumulated_content.push_str(content);
-                        }
-                    }
-                }
+    #[test]
+    fn test_stream_chunk_without_choices() {
+        let chunk: StreamChunk = serde_json::from_str(r#"{"id":"x","model":"llama3"}"#).unwrap();
+        assert!(chunk.choices.is_empty());
+    }
+
+    #[test]
+    fn test_stream_chunk_finish_reason_only() {
+        let chunk: StreamChunk =
+            serde_json::from_str(r#"{"choices":[{"index":0,"finish_reason":"content_filter"}]}"#)
+                .unwrap();
+        assert_eq!(
+            chunk.choices[0].finish_reason.as_deref(),
+            Some("content_filter")
+        );
+        assert!(chunk.choices[0].delta.content.is_none());
+    }
+
+    #[test]
+    fn test_sampling_only_serialized_when_set() {
+        let mut req = OaiRequest {
+            model: "gpt-4o-mini",
+            messages: Vec::new(),
+            response_format: None,
+            stream: true,
+            stream_options: None,
+            sampling: Sampling::default(),
+            think: None,
+        };
+        let json = serde_json::to_value(&req).unwrap();
+        assert!(json.get("response_format").is_none());
+        assert!(json.get("temperature").is_none());
+        assert!(json.get("max_tokens").is_none());
+        assert!(json.get("reasoning_effort").is_none());
+
+        req.sampling.temperature = Some(0.1);
+        req.sampling.max_tokens = Some(256);
+        req.sampling.reasoning_effort = Some(ReasoningEffort::High);
+        let json = serde_json::to_value(&req).unwrap();
+        assert!((json["temperature"].as_f64().unwrap() - 0.1).abs() < 1e-6);
+        assert_eq!(json["max_tokens"], 256);
+        assert_eq!(json["reasoning_effort"], "high");
+        assert!(json.get("top_p").is_none());
+        assert!(json.get("stream_options").is_none());
+    }
+
+    #[test]
+    fn test_reasoning_delta_shapes() {
+        let accumulate = |chunks: &[&str]| {
+            let mut reasoning = String::new();
+            for chunk in chunks {
+                let chunk: StreamChunk = serde_json::from_str(chunk).unwrap();
+                reasoning.push_str(chunk.choices[0].delta.reasoning().unwrap_or(""));
             }
+            reasoning
+        };
+        let deepseek = accumulate(&[
+            r#"{"choices":[{"delta":{"reasoning_content":"List "}}]}"#,
+            r#"{"choices":[{"delta":{"reasoning_content":"files."}}]}"#,
+        ]);
+        let newer = accumulate(&[
+            r#"{"choices":[{"delta":{"reasoning":"List "}}]}"#,
+            r#"{"choices":[{"delta":{"reasoning":"files.","content":null}}]}"#,
+        ]);
+        assert_eq!(deepseek, "List files.");
+        assert_eq!(newer, deepseek);
+
+        let both =
+            accumulate(&[r#"{"choices":[{"delta":{"reasoning_content":"a","reasoning":"a"}}]}"#]);
+        assert_eq!(both, "a");
+    }
+
+    #[test]
+    fn test_usage_chunk() {
+        let chunk: StreamChunk = serde_json::from_str(
+            r#"{"choices":[],"usage":{"prompt_tokens":120,"completion_tokens":34,"total_tokens":154}}"#,
+        )
+        .unwrap();
+        let usage = chunk.usage.unwrap();
+        assert_eq!((usage.prompt_tokens, usage.completion_tokens), (120, 34));
+        assert!(chunk.choices.is_empty());
+    }
+
+    /// Records what a chat reply streamed in.
+    #[derive(Default)]
+    struct RecordingSink {
+        reasoning: String,
+        answers: Vec<String>,
+    }
+
+    impl ChatSink for RecordingSink {
+        fn on_reasoning(&mut self, text: &str) {
+            self.reasoning.push_str(text);
         }
 
-        let suggested_command;
-        let display_text;
-
-        let json_str = extract_json(&accumulated_content);
-        match serde_json::from_str::<JsonPayload>(json_str) {
-            Ok(json) => {
-                suggested_command = json.command.clone();
-                display_text = json
-                    .answer
-                    .or(json.note)
-                    .or(json.explanation)
-                    .or(json.message)
-                    .unwrap_or_default();
-            }
-            Err(e) => {
-                suggested_command = None;
-                let error_prefix = t(&self.lang, MessageKey::JsonParseError);
-                display_text = format!("{}{}]\n{}", error_prefix, e, accumulated_content);
-            }
+        fn on_answer(&mut self, text: &str) {
+            self.answers.push(text.to_string());
         }
+    }
 
-        Ok(ChatReply {
-            text: if display_text.is_empty() {
-                accumulated_content
-            } else {
-                display_text
-            },
-            suggested_command,
-            reasoning: if accumulated_reasoning.is_empty() {
-                None
-            } else {
-                Some(accumulated_reasoning)
-            },
-        })
+    fn sse(chunks: &[serde_json::Value]) -> String {
+        let mut body: String = chunks
+            .iter()
+            .map(|chunk| format!("data: {chunk}\n\n"))
+            .collect();
+        body.push_str("data: [DONE]\n\n");
+        body
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    /// A reply streaming some reasoning and then `content`.
+    fn reply_stream(content: &str) -> String {
+        sse(&[
+            serde_json::json!({"choices": [{"delta": {"reasoning_content": "thinking"}}]}),
+            serde_json::json!({"choices": [{"delta": {"content": content}}]}),
+        ])
+    }
 
     #[test]
-    fn test_extract_json_with_json_fence() {
-        let input = r#"```json
-{"command": "ls -la", "answer": "list files"}
-```"#;
-        let result = extract_json(input);
-        assert_eq!(result, r#"{"command": "ls -la", "answer": "list files"}"#);
+    fn test_request_reasoning_off() {
+        let answer = r#"{"answer":"ok","command":"ls"}"#;
+        let chat = |client: &OpenAIClient, sink: &mut RecordingSink| {
+            client
+                .chat("system", &[], "list files", sink, &AtomicBool::new(false))
+                .unwrap()
+        };
+
+        // On, nothing extra is sent and the reasoning comes through
+        let (base_url, bodies) = mock_server(vec![reply_stream(answer)]);
+        let client = OpenAIClient::new(client_options(&base_url)).unwrap();
+        let mut sink = RecordingSink::default();
+        assert_eq!(
+            chat(&client, &mut sink).reasoning.as_deref(),
+            Some("thinking")
+        );
+        assert_eq!(sink.reasoning, "thinking");
+        let body: serde_json::Value = serde_json::from_str(&bodies.recv().unwrap()).unwrap();
+        assert!(body.get("reasoning_effort").is_none());
+        assert!(body.get("think").is_none());
+
+        // Off, OpenAI has no switch: the request is unchanged and streamed reasoning is dropped
+        let (base_url, bodies) = mock_server(vec![reply_stream(answer)]);
+        let mut opts = client_options(&base_url);
+        opts.request_reasoning = false;
+        opts.sampling.reasoning_effort = Some(ReasoningEffort::High);
+        let client = OpenAIClient::new(opts).unwrap();
+        let mut sink = RecordingSink::default();
+        let reply = chat(&client, &mut sink);
+        assert_eq!(reply.suggested_commands, ["ls"]);
+        assert!(reply.reasoning.is_none_or(|reasoning| reasoning.is_empty()));
+        assert!(sink.reasoning.is_empty());
+        let body: serde_json::Value = serde_json::from_str(&bodies.recv().unwrap()).unwrap();
+        assert_eq!(body["reasoning_effort"], "high");
+        assert!(body.get("think").is_none());
+
+        // reasoning_effort_when_off replaces the configured effort
+        let (base_url, bodies) = mock_server(vec![reply_stream(answer)]);
+        let mut opts = client_options(&base_url);
+        opts.request_reasoning = false;
+        opts.sampling.reasoning_effort = Some(ReasoningEffort::High);
+        opts.reasoning_effort_when_off = Some(ReasoningEffort::Low);
+        chat(
+            &OpenAIClient::new(opts).unwrap(),
+            &mut RecordingSink::default(),
+        );
+        let body: serde_json::Value = serde_json::from_str(&bodies.recv().unwrap()).unwrap();
+        assert_eq!(body["reasoning_effort"], "low");
+
+        // A rejected effort is an error; it is not dropped to let the model reason at will
+        let error = r#"{"error":{"message":"Unsupported value: 'reasoning_effort' does not support 'minimal' with this model."}}"#;
+        let rejected = format!(
+            "HTTP/1.1 400 Bad Request\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{error}",
+            error.len()
+        );
+        let (base_url, bodies) = mock_server(vec![rejected.clone(), rejected]);
+        let mut opts = client_options(&base_url);
+        opts.request_reasoning = false;
+        opts.reasoning_effort_when_off = Some(ReasoningEffort::Minimal);
+        let client = OpenAIClient::new(opts).unwrap();
+        for _ in 0..2 {
+            let cancel = AtomicBool::new(false);
+            let result = client.chat("system", &[], "list files", &mut NoopSink, &cancel);
+            assert!(
+                matches!(
+                    result,
+                    Err(LlmError::Http {
+                        status: StatusCode::BAD_REQUEST,
+                        ..
+                    })
+                ),
+                "{result:?}"
+            );
+            let body: serde_json::Value = serde_json::from_str(&bodies.recv().unwrap()).unwrap();
+            assert_eq!(body["reasoning_effort"], "minimal");
+        }
+        assert!(bodies.try_recv().is_err());
+
+        // Ollama gets its own switch instead
+        let (base_url, bodies) = mock_server(vec![reply_stream(answer)]);
+        let mut opts = client_options(&base_url);
+        opts.request_reasoning = false;
+        chat(
+            &OpenAIClient::ollama(opts).unwrap(),
+            &mut RecordingSink::default(),
+        );
+        let body: serde_json::Value = serde_json::from_str(&bodies.recv().unwrap()).unwrap();
+        assert_eq!(body["think"], false);
+        assert!(body.get("reasoning_effort").is_none());
+    }
+
+    #[test]
+    fn test_json_repair_round() {
+        let malformed = r#"{"answer": "Listing files", "command": "ls -la""#;
+        let valid = r#"{"answer":"List files","command":"ls -la"}"#;
+        let replies = vec![reply_stream(malformed), reply_stream(valid)];
+        let (base_url, bodies) = mock_server(replies);
+        let client = OpenAIClient::new(client_options(&base_url)).unwrap();
+        let mut sink = RecordingSink::default();
+        let reply = client
+            .chat(
+                "system",
+                &[],
+                "list files",
+                &mut sink,
+                &AtomicBool::new(false),
+            )
+            .unwrap();
+        assert_eq!(reply.text, "List files");
+        assert_eq!(reply.suggested_commands, ["ls -la"]);
+
+        // One repair round: the malformed reply goes back with the request to fix it
+        let sent: Vec<serde_json::Value> = bodies
+            .iter()
+            .take(2)
+            .map(|body| serde_json::from_str(&body).unwrap())
+            .collect();
+        assert_eq!(sent[0]["messages"].as_array().unwrap().len(), 2);
+        let messages = sent[1]["messages"].as_array().unwrap();
+        assert_eq!(messages.len(), 4);
+        assert_eq!(messages[2]["content"], malformed);
+        assert_eq!(messages[3]["content"], JSON_REPAIR_PROMPT);
+
+        // Only the first attempt reached the preview
+        assert_eq!(sink.reasoning, "thinking");
+        assert!(!sink.answers.is_empty());
+        assert!(
+            sink.answers
+                .iter()
+                .all(|answer| answer.starts_with("Listing"))
+        );
     }
 
     #[test]
-    fn test_extract_json_with_generic_fence() {
-        let input = r#"```
-{"command": "pwd", "answer": "print working directory"}
-```"#;
-        let result = extract_json(input);
-        assert_eq!(result, r#"{"command": "pwd", "answer": "print working directory"}"#);
+    fn test_chat_url() {
+        let client = OpenAIClient::new(client_options("https://api.openai.com/v1")).unwrap();
+        assert_eq!(
+            client.chat_url(),
+            "https://api.openai.com/v1/chat/completions"
+        );
+
+        let client = OpenAIClient::azure(
+            client_options("https://myres.openai.azure.com/"),
+            "gpt4o-prod".to_string(),
+            "2024-10-21".to_string(),
+        )
+        .unwrap();
+        assert_eq!(
+            client.chat_url(),
+            "https://myres.openai.azure.com/openai/deployments/gpt4o-prod/chat/completions?api-version=2024-10-21"
+        );
     }
 
     #[test]
-    fn test_extract_json_plain() {
-        let input = r#"{"command": "echo hello", "answer": "prints hello"}"#;
-        let result = extract_json(input);
-        assert_eq!(result, r#"{"command": "echo hello", "answer": "prints hello"}"#);
+    fn test_parse_model_list() {
+        let body = r#"{"object":"list","data":[{"id":"gpt-4o","object":"model"},{"id":"gpt-4o-mini"},{"id":"dall-e-3"}]}"#;
+        assert_eq!(
+            parse_model_list(body),
+            vec!["dall-e-3", "gpt-4o", "gpt-4o-mini"]
+        );
+        assert!(parse_model_list("<html>not found</html>").is_empty());
     }
 
     #[test]
-    fn test_extract_json_with_whitespace() {
-        let input = r#"
-```json
-{
-    "command": "du -sh ~",
-    "answer": "查看主目录占用空间"
-}
-```
-"#;
-        let result = extract_json(input);
-        assert!(result.contains(r#""command": "du -sh ~""#));
+    fn test_organization_and_project_headers() {
+        let req = OaiRequest {
+            model: "gpt-4o",
+            messages: Vec::new(),
+            response_format: None,
+            stream: true,
+            stream_options: None,
+            sampling: Sampling::default(),
+            think: None,
+        };
+        let endpoint = "https://api.openai.com/v1/chat/completions";
+
+        let client = OpenAIClient::new(client_options("https://api.openai.com/v1")).unwrap();
+        let request = client.build_request(endpoint, &req).build().unwrap();
+        assert!(request.headers().get("OpenAI-Organization").is_none());
+        assert!(request.headers().get("OpenAI-Project").is_none());
+
+        let mut opts = client_options("https://api.openai.com/v1");
+        opts.organization = Some("org-abc".to_string());
+        opts.project = Some("proj_xyz".to_string());
+        let client = OpenAIClient::new(opts).unwrap();
+        let request = client.build_request(endpoint, &req).build().unwrap();
+        assert_eq!(request.headers()["OpenAI-Organization"], "org-abc");
+        assert_eq!(request.headers()["OpenAI-Project"], "proj_xyz");
     }
 
     #[test]
-    fn test_extract_json_with_text_before_fence() {
-        let input = r#"Here is your command:
-```json
-{"command": "cat /etc/passwd", "answer": "view passwd file"}
-```"#;
-        let result = extract_json(input);
-        assert_eq!(result, r#"{"command": "cat /etc/passwd", "answer": "view passwd file"}"#);
+    fn test_proxy_option() {
+        let mut opts = client_options("https://api.openai.com/v1");
+        opts.proxy = Some("http://127.0.0.1:8080".to_string());
+        opts.no_proxy = Some("localhost,.internal".to_string());
+        assert!(OpenAIClient::new(opts).is_ok());
+
+        let mut opts = client_options("https://api.openai.com/v1");
+        opts.proxy = Some("http://[::1".to_string());
+        let err = OpenAIClient::new(opts).err().unwrap();
+        assert!(err.to_string().contains("llm.proxy"));
     }
 
     #[test]
-    fn test_extract_json_unclosed_fence() {
-        let input = r#"```json
-{"command": "ls"}"#;
-        let result = extract_json(input);
-        assert_eq!(result, input.trim());
+    fn test_rejects_response_format() {
+        assert!(rejects_response_format(
+            r#"{"error":{"message":"Unrecognized request argument supplied: response_format"}}"#
+        ));
+        assert!(rejects_response_format(
+            r#"{"error":"'json_object' is not supported by this model"}"#
+        ));
+        assert!(!rejects_response_format(
+            r#"{"error":{"message":"This model's maximum context length is 8192 tokens"}}"#
+        ));
+        assert!(!rejects_response_format(
+            r#"{"error":"model 'gpt-5' not found"}"#
+        ));
     }
 }
@@ -0,0 +1,532 @@
+use serde::Deserialize;
+
+use super::ChatReply;
+use crate::i18n::{Language, MessageKey, t};
+
+#[derive(Deserialize)]
+struct JsonPayload {
+    command: Option<String>,
+    /// Ranked candidates, best first; takes precedence over `command`
+    #[serde(default)]
+    commands: Vec<String>,
+    answer: Option<String>,
+    note: Option<String>,
+    explanation: Option<String>,
+    message: Option<String>,
+}
+
+pub(crate) fn extract_json(content: &str) -> &str {
+    let trimmed = content.trim();
+    if let Some(start) = trimmed.find("```json") {
+        let json_start = start + 7;
+        if let Some(end) = trimmed[json_start..].find("```") {
+            return trimmed[json_start..json_start + end].trim();
+        }
+    }
+    if let Some(start) = trimmed.find("```") {
+        let json_start = start + 3;
+        if let Some(end) = trimmed[json_start..].find("```") {
+            return trimmed[json_start..json_start + end].trim();
+        }
+    }
+    trimmed
+}
+
+/// Split a plain-text reply into the contents of its first fenced code block, the
+/// command, and the text around it, the answer. A fence left open runs to the end.
+pub(crate) fn extract_command_block(content: &str) -> (Option<String>, String) {
+    let Some(start) = content.find("```") else {
+        return (None, content.trim().to_string());
+    };
+    let before = &content[..start];
+    // The rest of the opening line is the language tag, if any
+    let block = &content[start + 3..];
+    let block = block.find('\n').map_or("", |eol| &block[eol + 1..]);
+    let (command, after) = match block.find("```") {
+        Some(end) => (&block[..end], &block[end + 3..]),
+        None => (block, ""),
+    };
+    let command = command.trim();
+    let answer = [before.trim(), after.trim()]
+        .into_iter()
+        .filter(|part| !part.is_empty())
+        .collect::<Vec<_>>()
+        .join("\n");
+    ((!command.is_empty()).then(|| command.to_string()), answer)
+}
+
+/// Append streamed reasoning to `buf`, keeping only the last `max_chars` characters
+/// behind `marker` once there are more, so a chatty model cannot grow it without bound.
+pub(crate) fn push_capped(buf: &mut String, text: &str, max_chars: usize, marker: &str) {
+    buf.push_str(text);
+    let body_start = if !marker.is_empty() && buf.starts_with(marker) {
+        marker.len() + 1
+    } else {
+        0
+    };
+    let body = buf.get(body_start..).unwrap_or_default();
+    // A byte count bounds the character count, so short text needs no counting
+    if body.len() <= max_chars {
+        return;
+    }
+    let chars = body.chars().count();
+    if chars <= max_chars {
+        return;
+    }
+    let cut = body
+        .char_indices()
+        .nth(chars - max_chars)
+        .map_or(body.len(), |(i, _)| i);
+    *buf = format!("{marker}\n{}", &body[cut..]);
+}
+
+/// Drop escape sequences (SGR colors, cursor movement, OSC titles) and other control
+/// characters from streamed model text; newlines and tabs are kept.
+pub(crate) fn sanitize_stream_text(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\n' | '\t' => out.push(c),
+            '\x1b' => match chars.next() {
+                // CSI: parameters and intermediates up to a final byte in @..~
+                Some('[') => {
+                    for c in chars.by_ref() {
+                        if ('@'..='~').contains(&c) {
+                            break;
+                        }
+                    }
+                }
+                // OSC: terminated by BEL or ESC \
+                Some(']') => {
+                    while let Some(c) = chars.next() {
+                        if c == '\x07' {
+                            break;
+                        }
+                        if c == '\x1b' {
+                            chars.next_if_eq(&'\\');
+                            break;
+                        }
+                    }
+                }
+                // Two-character sequences such as ESC c
+                _ => {}
+            },
+            c if c.is_control() => {}
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// Pulls the `answer` string out of JSON that is still being streamed, so it can be
+/// shown before the reply is complete. Anything it cannot make sense of yet is ignored.
+#[derive(Default)]
+pub(crate) struct AnswerStream {
+    /// Characters of the decoded answer already handed out
+    emitted: usize,
+}
+
+impl AnswerStream {
+    /// Given the content accumulated so far, return the answer text that is new since the last call.
+    pub(crate) fn update(&mut self, content: &str) -> Option<String> {
+        let answer = partial_answer(content)?;
+        let new: String = answer.chars().skip(self.emitted).collect();
+        if new.is_empty() {
+            return None;
+        }
+        self.emitted += new.chars().count();
+        Some(new)
+    }
+}
+
+/// Decode as much of the `"answer"` string value as has arrived, stopping at the closing
+/// quote or at an escape sequence that is still incomplete.
+fn partial_answer(content: &str) -> Option<String> {
+    let mut search = 0;
+    let value = loop {
+        let pos = search + content[search..].find("\"answer\"")?;
+        search = pos + 1;
+        // Skip an escaped occurrence inside another string value
+        if content[..pos].ends_with('\\') {
+            continue;
+        }
+        let rest = content[pos + "\"answer\"".len()..].trim_start();
+        let Some(rest) = rest.strip_prefix(':') else {
+            continue;
+        };
+        break rest.trim_start().strip_prefix('"')?;
+    };
+
+    let mut out = String::new();
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => break,
+            '\\' => {
+                let decoded = match chars.next() {
+                    Some('n') => '\n',
+                    Some('t') => '\t',
+                    Some('r') => '\r',
+                    Some('b') => '\u{8}',
+                    Some('f') => '\u{c}',
+                    Some('u') => {
+                        let hex: String = chars.by_ref().take(4).collect();
+                        match u32::from_str_radix(&hex, 16).ok().and_then(char::from_u32) {
+                            Some(c) if hex.len() == 4 => c,
+                            // Incomplete, or half of a surrogate pair
+                            _ => break,
+                        }
+                    }
+                    Some(other) => other,
+                    None => break,
+                };
+                out.push(decoded);
+            }
+            c => out.push(c),
+        }
+    }
+    Some(out)
+}
+
+/// The reply for a stream that ended without any content, which some backends do when
+/// a content filter kicks in. `finish_reason` is whatever the provider reported last.
+pub(crate) fn empty_reply(
+    reasoning: String,
+    finish_reason: Option<&str>,
+    lang: &Language,
+) -> ChatReply {
+    let text = match finish_reason {
+        Some(reason @ ("content_filter" | "refusal")) => {
+            t(lang, MessageKey::ContentFiltered).replace("{reason}", reason)
+        }
+        _ => t(lang, MessageKey::EmptyResponse).to_string(),
+    };
+    ChatReply {
+        text,
+        suggested_commands: Vec::new(),
+        explanation: None,
+        reasoning: if reasoning.is_empty() {
+            None
+        } else {
+            Some(reasoning)
+        },
+        usage: None,
+    }
+}
+
+/// Whether `content` holds a reply object `parse_reply` can read.
+pub(crate) fn is_reply_json(content: &str) -> bool {
+    serde_json::from_str::<JsonPayload>(extract_json(content)).is_ok()
+}
+
+/// Turn the accumulated model output into a `ChatReply`, shared by all providers.
+pub(crate) fn parse_reply(content: String, reasoning: String, lang: &Language) -> ChatReply {
+    let suggested_commands;
+    let display_text;
+    let mut explanation = None;
+
+    let json_str = extract_json(&content);
+    match serde_json::from_str::<JsonPayload>(json_str) {
+        Ok(json) => {
+            let commands = if json.commands.is_empty() {
+                json.command.into_iter().collect()
+            } else {
+                json.commands
+            };
+            suggested_commands = commands
+                .into_iter()
+                .filter(|cmd| !cmd.trim().is_empty())
+                .collect();
+            explanation = json.explanation.filter(|text| !text.trim().is_empty());
+            display_text = json
+                .answer
+                .or(json.note)
+                .or(json.message)
+                .unwrap_or_default();
+        }
+        Err(e) => {
+            suggested_commands = Vec::new();
+            let error_prefix = t(lang, MessageKey::JsonParseError);
+            display_text = format!("{}{}]\n{}", error_prefix, e, content);
+        }
+    }
+
+    ChatReply {
+        // The explanation stays separate, so a reply with only that has no answer line
+        text: if display_text.is_empty() && explanation.is_none() {
+            content
+        } else {
+            display_text
+        },
+        suggested_commands,
+        explanation,
+        reasoning: if reasoning.is_empty() {
+            None
+        } else {
+            Some(reasoning)
+        },
+        usage: None,
+    }
+}
+
+/// `parse_reply` for `prompt.mode = "plain"`, which never fails: a reply without a
+/// code block is all answer.
+pub(crate) fn parse_plain_reply(content: String, reasoning: String) -> ChatReply {
+    let (command, answer) = extract_command_block(&content);
+    ChatReply {
+        text: answer,
+        suggested_commands: command.into_iter().collect(),
+        explanation: None,
+        reasoning: if reasoning.is_empty() {
+            None
+        } else {
+            Some(reasoning)
+        },
+        usage: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_extract_json_with_json_fence() {
+        let input = r#"```json
+{"command": "ls -la", "answer": "list files"}
+```"#;
+        let result = extract_json(input);
+        assert_eq!(result, r#"{"command": "ls -la", "answer": "list files"}"#);
+    }
+
+    #[test]
+    fn test_extract_json_with_generic_fence() {
+        let input = r#"```
+{"command": "pwd", "answer": "print working directory"}
+```"#;
+        let result = extract_json(input);
+        assert_eq!(
+            result,
+            r#"{"command": "pwd", "answer": "print working directory"}"#
+        );
+    }
+
+    #[test]
+    fn test_extract_json_plain() {
+        let input = r#"{"command": "echo hello", "answer": "prints hello"}"#;
+        let result = extract_json(input);
+        assert_eq!(
+            result,
+            r#"{"command": "echo hello", "answer": "prints hello"}"#
+        );
+    }
+
+    #[test]
+    fn test_extract_json_with_whitespace() {
+        let input = r#"
+```json
+{
+    "command": "du -sh ~",
+    "answer": "查看主目录占用空间"
+}
+```
+"#;
+        let result = extract_json(input);
+        assert!(result.contains(r#""command": "du -sh ~""#));
+    }
+
+    #[test]
+    fn test_extract_json_with_text_before_fence() {
+        let input = r#"Here is your command:
+```json
+{"command": "cat /etc/passwd", "answer": "view passwd file"}
+```"#;
+        let result = extract_json(input);
+        assert_eq!(
+            result,
+            r#"{"command": "cat /etc/passwd", "answer": "view passwd file"}"#
+        );
+    }
+
+    #[test]
+    fn test_extract_json_unclosed_fence() {
+        let input = r#"```json
+{"command": "ls"}"#;
+        let result = extract_json(input);
+        assert_eq!(result, input.trim());
+    }
+
+    #[test]
+    fn test_extract_command_block() {
+        let (command, answer) =
+            extract_command_block("Lists all files.\n```bash\nls -la\n```\nIncludes hidden ones.");
+        assert_eq!(command.as_deref(), Some("ls -la"));
+        assert_eq!(answer, "Lists all files.\nIncludes hidden ones.");
+
+        let (command, answer) = extract_command_block("```\ndu -sh ~\n```");
+        assert_eq!(command.as_deref(), Some("du -sh ~"));
+        assert_eq!(answer, "");
+
+        // Only the first block is the command; a fence left open runs to the end
+        let (command, _) = extract_command_block("```sh\npwd\n```\n```sh\nls\n```");
+        assert_eq!(command.as_deref(), Some("pwd"));
+        let (command, _) = extract_command_block("```sh\ngit status\n");
+        assert_eq!(command.as_deref(), Some("git status"));
+
+        let (command, answer) = extract_command_block("  Which directory do you mean?\n");
+        assert_eq!(command, None);
+        assert_eq!(answer, "Which directory do you mean?");
+        assert_eq!(extract_command_block("```bash\n\n```").0, None);
+    }
+
+    #[test]
+    fn test_parse_plain_reply() {
+        let reply = parse_plain_reply("```bash\npwd\n```\nwhere am I".to_string(), String::new());
+        assert_eq!(reply.suggested_command(), Some("pwd"));
+        assert_eq!(reply.text, "where am I");
+        assert!(reply.reasoning.is_none());
+    }
+
+    #[test]
+    fn test_parse_reply_falls_back_to_note() {
+        let reply = parse_reply(
+            r#"{"command": "pwd", "note": "where am I"}"#.to_string(),
+            String::new(),
+            &Language::En,
+        );
+        assert_eq!(reply.suggested_command(), Some("pwd"));
+        assert_eq!(reply.text, "where am I");
+        assert!(reply.reasoning.is_none());
+    }
+
+    #[test]
+    fn test_parse_reply_commands_array() {
+        let reply = parse_reply(
+            r#"{"commands": ["ls -la", "", "ls -lah"], "command": "ls", "answer": "list"}"#
+                .to_string(),
+            String::new(),
+            &Language::En,
+        );
+        assert_eq!(reply.suggested_commands, vec!["ls -la", "ls -lah"]);
+        assert_eq!(reply.suggested_command(), Some("ls -la"));
+
+        let reply = parse_reply(
+            r#"{"command": "  ", "answer": "no command needed"}"#.to_string(),
+            String::new(),
+            &Language::En,
+        );
+        assert!(reply.suggested_commands.is_empty());
+        assert_eq!(reply.suggested_command(), None);
+    }
+
+    #[test]
+    fn test_parse_reply_keeps_explanation_separate() {
+        let reply = parse_reply(
+            r#"{"command": "du -sh .", "answer": "size of this directory", "explanation": "-s sums, -h is human-readable"}"#
+                .to_string(),
+            String::new(),
+            &Language::En,
+        );
+        assert_eq!(reply.text, "size of this directory");
+        assert_eq!(
+            reply.explanation.as_deref(),
+            Some("-s sums, -h is human-readable")
+        );
+
+        let reply = parse_reply(
+            r#"{"command": "ls", "explanation": "lists files"}"#.to_string(),
+            String::new(),
+            &Language::En,
+        );
+        assert_eq!(reply.text, "");
+        assert_eq!(reply.explanation.as_deref(), Some("lists files"));
+    }
+
+    #[test]
+    fn test_is_reply_json() {
+        assert!(is_reply_json(
+            "```json\n{\"command\": \"ls\", \"answer\": \"list\"}\n```"
+        ));
+        assert!(is_reply_json("{\"answer\": \"no command needed\"}"));
+        assert!(!is_reply_json("```json\n{\"command\": \"ls\",}\n```"));
+        assert!(!is_reply_json("Sure! Run ls to list the files."));
+    }
+
+    #[test]
+    fn test_push_capped() {
+        let mut buf = String::new();
+        push_capped(&mut buf, "abc", 5, "[cut]");
+        push_capped(&mut buf, "de", 5, "[cut]");
+        assert_eq!(buf, "abcde");
+        push_capped(&mut buf, "f", 5, "[cut]");
+        assert_eq!(buf, "[cut]\nbcdef");
+        push_capped(&mut buf, "gh", 5, "[cut]");
+        assert_eq!(buf, "[cut]\ndefgh");
+
+        // Counted in characters, not bytes
+        let mut buf = String::new();
+        push_capped(&mut buf, "思考中", 3, "[cut]");
+        assert_eq!(buf, "思考中");
+        push_capped(&mut buf, "。", 3, "[cut]");
+        assert_eq!(buf, "[cut]\n考中。");
+    }
+
+    #[test]
+    fn test_sanitize_stream_text() {
+        assert_eq!(
+            sanitize_stream_text("\x1b[31mred\x1b[0m and \x1b[1;32mgreen\x1b[m"),
+            "red and green"
+        );
+        assert_eq!(sanitize_stream_text("a\x07b\x00c\rd\u{9b}e\x7f"), "abcde");
+        assert_eq!(
+            sanitize_stream_text("\x1b]0;title\x07step 1\n\tstep 2"),
+            "step 1\n\tstep 2"
+        );
+        assert_eq!(sanitize_stream_text("思考 ─ done"), "思考 ─ done");
+    }
+
+    #[test]
+    fn test_empty_reply() {
+        let reply = empty_reply(String::new(), Some("stop"), &Language::En);
+        assert_eq!(reply.text, t(&Language::En, MessageKey::EmptyResponse));
+        assert_eq!(reply.suggested_command(), None);
+
+        let reply = empty_reply("hmm".to_string(), Some("content_filter"), &Language::En);
+        assert!(reply.text.contains("content_filter"));
+        assert_eq!(reply.reasoning.as_deref(), Some("hmm"));
+    }
+
+    #[test]
+    fn test_answer_stream_emits_increments() {
+        let mut stream = AnswerStream::default();
+        let full = r#"```json
+{"command": "ls", "answer": "list \"all\" files"}
+```"#;
+        let mut collected = String::new();
+        for end in 0..=full.len() {
+            if !full.is_char_boundary(end) {
+                continue;
+            }
+            if let Some(chunk) = stream.update(&full[..end]) {
+                collected.push_str(&chunk);
+            }
+        }
+        assert_eq!(collected, "list \"all\" files");
+    }
+
+    #[test]
+    fn test_answer_stream_ignores_partial_and_invalid_json() {
+        let mut stream = AnswerStream::default();
+        assert_eq!(stream.update(r#"{"command": "echo \"answer\": x""#), None);
+        assert_eq!(stream.update(r#"{"answ"#), None);
+        assert_eq!(
+            stream.update(r#"{"answer": "caf\u00"#).as_deref(),
+            Some("caf")
+        );
+        assert_eq!(
+            stream.update(r#"{"answer": "caf\u00e9"#).as_deref(),
+            Some("é")
+        );
+    }
+}
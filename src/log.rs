@@ -0,0 +1,80 @@
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::sync::{Mutex, OnceLock};
+
+use anyhow::{Context, Result};
+use chrono::Local;
+
+/// Opt-in transcript of requests and replies, for attaching to bug reports.
+static LOG_FILE: OnceLock<Mutex<File>> = OnceLock::new();
+
+/// Start appending to `path`; until this is called every `record` is a no-op.
+pub fn init(path: &Path) -> Result<()> {
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("Failed to open log file: {}", path.display()))?;
+    let _ = LOG_FILE.set(Mutex::new(file));
+    Ok(())
+}
+
+/// Lets callers skip building a log entry nobody will read.
+pub fn enabled() -> bool {
+    LOG_FILE.get().is_some()
+}
+
+/// Append a titled entry; write errors are ignored so logging never breaks a chat.
+pub fn record(title: &str, body: &str) {
+    let Some(file) = LOG_FILE.get() else {
+        return;
+    };
+    let Ok(mut file) = file.lock() else {
+        return;
+    };
+    let timestamp = Local::now().format("%Y-%m-%dT%H:%M:%S%:z");
+    let _ = writeln!(file, "=== {timestamp} {title} ===\n{body}\n");
+}
+
+/// Mask every occurrence of `secret` (e.g. the API key) in `text`.
+pub fn redact(text: &str, secret: &str) -> String {
+    if secret.is_empty() {
+        text.to_string()
+    } else {
+        text.replace(secret, "[REDACTED]")
+    }
+}
+
+/// `secret` with all but its first and last few characters hidden, for showing which
+/// key is in use.
+pub fn mask(secret: &str) -> String {
+    let chars: Vec<char> = secret.chars().collect();
+    if chars.len() < 12 {
+        return "*".repeat(chars.len());
+    }
+    let head: String = chars[..3].iter().collect();
+    let tail: String = chars[chars.len() - 4..].iter().collect();
+    format!("{head}...{tail}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_redact() {
+        assert_eq!(
+            redact(r#"{"key":"sk-123","q":"hi"}"#, "sk-123"),
+            r#"{"key":"[REDACTED]","q":"hi"}"#
+        );
+        assert_eq!(redact("nothing to hide", ""), "nothing to hide");
+    }
+
+    #[test]
+    fn test_mask() {
+        assert_eq!(mask("sk-proj-abcdefgh1234"), "sk-...1234");
+        assert_eq!(mask("short"), "*****");
+        assert_eq!(mask(""), "");
+    }
+}
@@ -1,75 +1,476 @@
 mod chat;
+mod cli;
 mod config;
+mod history;
 mod i18n;
+mod keys;
 mod llm;
+mod log;
 mod pty;
+mod safety;
+mod style;
+mod terminal;
 
 use std::env;
-use std::time::Duration;
+use std::io::{self, BufRead, IsTerminal, Write};
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
+use std::time::{Duration, Instant};
 
-use anyhow::{Context, Result};
-use crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
-use crossterm::terminal::{disable_raw_mode, enable_raw_mode};
+use anyhow::{Context, Result, bail};
+use crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use regex::Regex;
 
-use crate::chat::chat_mode;
-use crate::config::{Config, SystemInfo, render_prompt};
+use crate::chat::{
+    ChatOptions, ChatOutcome, agentic_follow_up, chat_mode, confirm_dangerous, confirm_run,
+};
+use crate::cli::{Command, USAGE};
+use crate::config::{
+    Config, PreferenceConfig, Provider, ResolvedLlm, ShellConfig, SystemInfo, system_prompt,
+};
+use crate::history::HistoryStore;
 use crate::i18n::{Language, MessageKey, t};
-use crate::llm::LLMClient;
+use crate::keys::{KeyBindings, key_to_bytes};
+use crate::llm::anthropic::AnthropicClient;
+use crate::llm::fallback::FallbackClient;
 use crate::llm::openai::OpenAIClient;
-use crate::pty::PtySession;
+use crate::llm::{ChatMessage, ClientOptions, LLMClient, LlmError, NoopSink, Role};
+use crate::pty::{InjectMode, PtySession};
+use crate::safety::{compile_patterns, is_dangerous, redact};
+use crate::terminal::{BracketedPasteGuard, RawModeGuard, TitleGuard, install_panic_hook};
 
-fn main() -> Result<()> {
-    let config = Config::load()?;
-    let sys_info = SystemInfo::collect(config.preference.language.as_deref());
+fn main() -> Result<ExitCode> {
+    i18n::validate_translations();
+    let cli = cli::parse(env::args().skip(1))?;
+    match cli.command {
+        Command::Help => {
+            println!("{USAGE}");
+            return Ok(ExitCode::SUCCESS);
+        }
+        Command::Version => {
+            let shell = pty::resolve_shell(&ShellConfig::default(), |key| env::var(key).ok());
+            println!("{}", cli::version_info(&shell));
+            return Ok(ExitCode::SUCCESS);
+        }
+        Command::ConfigInit { force } => {
+            let lang = config::startup_language();
+            let path = config::default_config_path().context(t(&lang, MessageKey::NoConfigDir))?;
+            config::write_default_config(&path, force, &lang)?;
+            println!("{}", path.display());
+            return Ok(ExitCode::SUCCESS);
+        }
+        _ => {}
+    }
+
+    let profile = cli.profile.or_else(|| env::var("SHELLM_PROFILE").ok());
+    let config =
+        Config::load(profile.as_deref())?.resolve(|key| env::var(key).ok(), &cli.overrides)?;
+    // SHELLM_LOG wins over preference.log_file so a one-off run can be traced
+    if let Some(path) = env::var_os("SHELLM_LOG")
+        .filter(|path| !path.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| config.preference.log_file.as_ref().map(PathBuf::from))
+    {
+        log::init(&path)?;
+    }
+    let shell = pty::resolve_shell(&config.shell, |key| env::var(key).ok());
+    let mut sys_info = SystemInfo::collect(config.preference.language.as_deref(), &shell);
+    if let Some(cwd) = config.shell.cwd.as_ref().filter(|dir| !dir.is_empty()) {
+        sys_info.cwd = cwd.clone();
+    }
+
+    // Configured language, else LANG (e.g. ja_JP.UTF-8 -> ja-JP)
+    let ui_lang = Language::from_code(&sys_info.lang);
+    config.validate(&ui_lang)?;
+
+    if let Command::Doctor = cli.command {
+        let prompt = system_prompt(&config.prompt.template, &sys_info.to_vars());
+        return doctor(config.llm, &prompt, ui_lang);
+    }
 
-    let ui_lang = config
+    let llm = build_client(config.llm, ui_lang)?;
+    if let Command::Models = cli.command {
+        let models = llm.list_models()?;
+        if models.is_empty() {
+            eprintln!("{}", t(&ui_lang, MessageKey::NoModelList));
+        }
+        for model in models {
+            println!("{model}");
+        }
+        return Ok(ExitCode::SUCCESS);
+    }
+    if let Command::Ask(question) = &cli.command {
+        let prompt = system_prompt(&config.prompt.template, &sys_info.to_vars());
+        return ask(llm.as_ref(), &prompt, question);
+    }
+
+    let dangerous_patterns = compile_patterns(&config.shell.dangerous_patterns, "dangerous")?;
+
+    let session = if cli.no_pty {
+        None
+    } else {
+        match start_session(&shell, &config.shell) {
+            Ok(session) => Some(session),
+            Err(e) => {
+                let warning = t(&ui_lang, MessageKey::ChatOnlyMode);
+                eprintln!("{}", warning.replace("{error}", &format!("{e:#}")));
+                None
+            }
+        }
+    };
+    let Some(mut session) = session else {
+        let prompt = system_prompt(&config.prompt.template, &sys_info.to_vars());
+        return chat_only_loop(llm.as_ref(), &prompt, &ui_lang).map(|()| ExitCode::SUCCESS);
+    };
+
+    let history_store = config
         .preference
-        .language
+        .persist_history
+        .then(HistoryStore::default_path)
+        .flatten()
+        .map(|path| HistoryStore::new(path, config.preference.history_max_entries()));
+    let history = match &history_store {
+        Some(store) => load_history(store, &ui_lang),
+        None => Vec::new(),
+    };
+
+    install_panic_hook();
+    let raw_mode = RawModeGuard::enable()?;
+
+    let chat_options = ChatOptions {
+        include_terminal_context: config.prompt.include_terminal_context,
+        history_store,
+        color: config.preference.color.resolve(),
+        prompt_template: config.prompt.template,
+        system_info: sys_info,
+        keys: KeyBindings::from_config(&config.keys)?,
+        reasoning: config.preference.reasoning,
+        reasoning_wrap: config.preference.reasoning_wrap,
+        show_timing: config.preference.show_timing,
+        show_welcome: config.preference.show_welcome.unwrap_or(true),
+        welcome_text: config.preference.welcome_text.clone(),
+        redact_patterns: compile_patterns(&config.preference.redact_patterns(), "redact")?,
+    };
+    let paste_guard = BracketedPasteGuard::enable();
+    let title_guard = match config
+        .preference
+        .title
         .as_deref()
-        .map(Language::from_str)
-        .unwrap_or_default();
-
-    let system_prompt = render_prompt(&config.prompt.template, &sys_info.to_vars());
-
-    let api_key = config
-        .llm
-        .api_key
-        .or_else(|| env::var("OPENAI_API_KEY").ok())
-        .context(t(&ui_lang, MessageKey::ApiKeyRequired))?;
-    let model = config
-        .llm
-        .model
-        .unwrap_or_else(|| env::var("OPENAI_MODEL").unwrap_or_else(|_| "gpt-4o-mini".to_string()));
-    let base_url = config.llm.base_url.unwrap_or_else(|| {
-        env::var("OPENAI_BASE_URL").unwrap_or_else(|_| "https://api.openai.com/v1".to_string())
-    });
+        .filter(|title| !title.is_empty())
+    {
+        Some(title) => Some(TitleGuard::set(title)?),
+        None => None,
+    };
+    let res = run_event_loop(
+        &mut session,
+        llm,
+        &chat_options,
+        history,
+        ui_lang,
+        &dangerous_patterns,
+        &config.preference,
+    );
+    if let Some(title_guard) = title_guard {
+        title_guard.restore(session.title().as_deref());
+    }
+    drop(paste_guard);
+    drop(raw_mode);
+    res.map(|()| ExitCode::SUCCESS)
+}
 
-    let llm: Box<dyn LLMClient> = Box::new(OpenAIClient::new(
-        api_key,
-        model,
-        base_url,
+/// The saved chat history. One that cannot be read is reported, moved aside so it is
+/// not overwritten, and replaced by an empty history instead of stopping shellm.
+fn load_history(store: &HistoryStore, lang: &Language) -> Vec<ChatMessage> {
+    let error = match store.load() {
+        Ok(history) => return history,
+        Err(e) => format!("{e:#}"),
+    };
+    log::record("history load failed", &error);
+    eprintln!(
+        "{}",
+        t(lang, MessageKey::HistoryUnreadable).replace("{error}", &error)
+    );
+    match store.set_aside() {
+        Ok(path) => {
+            let path = path.display().to_string();
+            eprintln!(
+                "{}",
+                t(lang, MessageKey::HistoryMovedAside).replace("{path}", &path)
+            );
+        }
+        Err(e) => log::record("history not moved", &format!("{e:#}")),
+    }
+    Vec::new()
+}
+
+/// One-shot mode: print the suggested command without a PTY or raw mode, so the
+/// output can be piped or captured. Exits non-zero when no command was produced.
+fn ask(llm: &dyn LLMClient, system_prompt: &str, question: &str) -> Result<ExitCode> {
+    let reply = llm.chat(
         system_prompt,
-        ui_lang,
-    )?);
+        &[],
+        question,
+        &mut NoopSink,
+        &AtomicBool::new(false),
+    )?;
+    log::record("reply", &format!("{reply:#?}"));
+    match reply.suggested_command() {
+        Some(cmd) => {
+            println!("{cmd}");
+            Ok(ExitCode::SUCCESS)
+        }
+        None => {
+            println!("{}", reply.text);
+            Ok(ExitCode::FAILURE)
+        }
+    }
+}
+
+/// Sent by `doctor`; any reply at all counts as a pass.
+const DOCTOR_QUESTION: &str = "Reply with a one-word greeting.";
 
-    let mut session = PtySession::new(config.shell.path.as_deref())?;
+/// `shellm doctor`: show the resolved provider settings, then send one short question
+/// through the same client and request path as chat. Exits non-zero on failure.
+fn doctor(mut llm: ResolvedLlm, system_prompt: &str, lang: Language) -> Result<ExitCode> {
+    let line = |key, value: &str| println!("{}", t(&lang, key).replace("{value}", value));
+    line(
+        MessageKey::DoctorProvider,
+        &format!("{:?}", llm.provider).to_ascii_lowercase(),
+    );
+    line(MessageKey::DoctorModel, &llm.model);
+    if let Some(deployment) = &llm.deployment {
+        line(MessageKey::DoctorDeployment, deployment);
+    }
+    line(MessageKey::DoctorBaseUrl, &llm.base_url);
+    let api_key = match llm.api_key.as_deref() {
+        Some(key) => log::mask(key),
+        None => t(&lang, MessageKey::DoctorNoApiKey).to_string(),
+    };
+    line(MessageKey::DoctorApiKey, &api_key);
+    if let Some(proxy) = &llm.proxy {
+        line(MessageKey::DoctorProxy, proxy);
+    }
+    // A working fallback would hide a failing primary model, so only the primary is tried
+    if let Some(model) = llm.fallback_model.take() {
+        line(MessageKey::DoctorFallback, &model);
+    }
+
+    let started = Instant::now();
+    let result = build_client(llm, lang).and_then(|client| {
+        let reply = client.chat(
+            system_prompt,
+            &[],
+            DOCTOR_QUESTION,
+            &mut NoopSink,
+            &AtomicBool::new(false),
+        )?;
+        Ok(reply)
+    });
+    match result {
+        Ok(reply) => {
+            log::record("reply", &format!("{reply:#?}"));
+            let secs = format!("{:.1}", started.elapsed().as_secs_f64());
+            println!(
+                "{}",
+                t(&lang, MessageKey::DoctorPass).replace("{secs}", &secs)
+            );
+            Ok(ExitCode::SUCCESS)
+        }
+        Err(e) => {
+            let detail = match e.downcast_ref::<LlmError>() {
+                Some(error) => error.localized(&lang),
+                None => format!("{e:#}"),
+            };
+            line(MessageKey::DoctorFail, &detail);
+            Ok(ExitCode::FAILURE)
+        }
+    }
+}
+
+/// Spawn the shell for the normal wrapped mode. Needs a terminal on stdin for raw mode.
+fn start_session(shell: &str, config: &ShellConfig) -> Result<PtySession> {
+    if !io::stdin().is_terminal() {
+        bail!("stdin is not a terminal");
+    }
+    let session = PtySession::new(shell, config)?;
     session.spawn_output_relay()?;
+    Ok(session)
+}
+
+/// Chat without a shell: each line of stdin is a question, and the suggested command
+/// goes to stdout (the reply text when there is none), so the output can be piped.
+/// Earlier turns are kept as context, as in chat mode.
+fn chat_only_loop(llm: &dyn LLMClient, system_prompt: &str, lang: &Language) -> Result<()> {
+    let stdin = io::stdin();
+    let interactive = stdin.is_terminal();
+    let mut history = Vec::new();
+    let mut line = String::new();
+    loop {
+        if interactive {
+            eprint!("{}", t(lang, MessageKey::PromptUser));
+            io::stderr().flush().ok();
+        }
+        line.clear();
+        if stdin.lock().read_line(&mut line)? == 0 {
+            break;
+        }
+        let question = line.trim();
+        if question.is_empty() {
+            continue;
+        }
+        let reply = match llm.chat(
+            system_prompt,
+            &history,
+            question,
+            &mut NoopSink,
+            &AtomicBool::new(false),
+        ) {
+            Ok(reply) => reply,
+            // Piped input has nobody to retry, so the failure becomes the exit status
+            Err(e) if !interactive => return Err(e.into()),
+            Err(e) => {
+                eprintln!(
+                    "{}{}",
+                    t(lang, MessageKey::RequestFailed),
+                    e.localized(lang)
+                );
+                continue;
+            }
+        };
+        log::record("reply", &format!("{reply:#?}"));
+        match reply.suggested_command() {
+            Some(cmd) => {
+                if interactive && !reply.text.is_empty() {
+                    eprintln!("{}", reply.text);
+                }
+                println!("{cmd}");
+            }
+            None => println!("{}", reply.text),
+        }
+        history.push(ChatMessage {
+            role: Role::User,
+            content: question.to_string(),
+        });
+        history.push(ChatMessage {
+            role: Role::Assistant,
+            content: reply.text,
+        });
+    }
+    Ok(())
+}
+
+fn build_client(llm: ResolvedLlm, lang: Language) -> Result<Arc<dyn LLMClient>> {
+    let api_key = match llm.provider {
+        // Local servers usually run without authentication, so the key is optional
+        Provider::Ollama => llm.api_key.unwrap_or_default(),
+        _ => llm.api_key.context(t(&lang, MessageKey::ApiKeyRequired))?,
+    };
+    if llm.provider == Provider::Azure && llm.base_url.is_empty() {
+        bail!(t(&lang, MessageKey::AzureConfigRequired));
+    }
+
+    let options = ClientOptions {
+        api_key,
+        model: llm.model,
+        base_url: llm.base_url,
+        lang,
+        timeout: llm.timeout,
+        connect_timeout: llm.connect_timeout,
+        stream_idle_timeout: llm.stream_idle_timeout,
+        max_retries: llm.max_retries,
+        sampling: llm.sampling,
+        json_mode: llm.json_mode,
+        proxy: llm.proxy,
+        no_proxy: llm.no_proxy,
+        headers: llm.headers.into_iter().collect(),
+        organization: llm.organization,
+        project: llm.project,
+        show_usage: llm.show_usage,
+        max_reasoning_chars: llm.max_reasoning_chars,
+        json_repair_attempts: llm.json_repair_attempts,
+        max_history_turns: llm.max_history_turns,
+        compaction: llm.compaction,
+        prompt_mode: llm.prompt_mode,
+        request_reasoning: llm.request_reasoning,
+        reasoning_effort_when_off: llm.reasoning_effort_when_off,
+    };
 
-    enable_raw_mode().context("failed to enter raw mode")?;
-    let res = run_event_loop(&mut session, llm, ui_lang);
-    disable_raw_mode().ok();
-    res
+    let primary = connect(
+        llm.provider,
+        options.clone(),
+        llm.deployment,
+        &llm.api_version,
+    )?;
+    let client: Arc<dyn LLMClient> = match llm.fallback_model {
+        Some(model) => {
+            // Azure picks the model through the deployment, so the fallback names one
+            let fallback_options = ClientOptions {
+                model: model.clone(),
+                ..options
+            };
+            let fallback = connect(
+                llm.provider,
+                fallback_options,
+                Some(model.clone()),
+                &llm.api_version,
+            )?;
+            Arc::new(FallbackClient::new(primary, fallback, model))
+        }
+        None => Arc::from(primary),
+    };
+    Ok(client)
+}
+
+/// A client for one model; `deployment` is only used by Azure.
+fn connect(
+    provider: Provider,
+    options: ClientOptions,
+    deployment: Option<String>,
+    api_version: &str,
+) -> Result<Box<dyn LLMClient>> {
+    let client: Box<dyn LLMClient> = match provider {
+        Provider::OpenAI => Box::new(OpenAIClient::new(options)?),
+        Provider::Ollama => Box::new(OpenAIClient::ollama(options)?),
+        Provider::Azure => {
+            let deployment =
+                deployment.context(t(&options.lang, MessageKey::AzureConfigRequired))?;
+            Box::new(OpenAIClient::azure(
+                options,
+                deployment,
+                api_version.to_string(),
+            )?)
+        }
+        Provider::Anthropic => Box::new(AnthropicClient::new(options)?),
+    };
+    Ok(client)
 }
 
 fn run_event_loop(
     session: &mut PtySession,
-    llm: Box<dyn LLMClient>,
+    llm: Arc<dyn LLMClient>,
+    chat_options: &ChatOptions,
+    mut history: Vec<ChatMessage>,
     lang: Language,
+    dangerous_patterns: &[Regex],
+    preference: &PreferenceConfig,
 ) -> Result<()> {
+    let idle_timeout = preference.idle_timeout();
+    // Only keystrokes and pastes count; shell output never keeps the session alive
+    let mut last_input = Instant::now();
     loop {
         if session.child_exited() {
             break;
         }
+        if let Some(timeout) = idle_timeout
+            && last_input.elapsed() >= timeout
+        {
+            let message =
+                t(&lang, MessageKey::IdleTimeout).replace("{secs}", &timeout.as_secs().to_string());
+            print!("\r\n{message}\r\n");
+            break;
+        }
 
         if event::poll(Duration::from_millis(100))? {
             match event::read()? {
@@ -77,23 +478,32 @@ fn run_event_loop(
                     if key.kind == KeyEventKind::Release {
                         continue;
                     }
+                    last_input = Instant::now();
 
-                    // Ctrl+L enters LLM chat mode
-                    if key.code == KeyCode::Char('l')
-                        && key.modifiers.contains(KeyModifiers::CONTROL)
-                    {
-                        let cmd = chat_mode(llm.as_ref(), &lang)?;
-                        session.write(b"\r")?;
-                        if let Some(cmd) = cmd {
-                            session.write(cmd.as_bytes())?;
+                    // Ctrl+L (or the configured key) enters LLM chat mode, except over a
+                    // full-screen program, which gets the key instead (Ctrl+L redraws most)
+                    if chat_options.keys.enter_chat.matches(&key) && !session.in_alt_screen() {
+                        enter_chat(
+                            session,
+                            &llm,
+                            chat_options,
+                            &mut history,
+                            &lang,
+                            dangerous_patterns,
+                            preference,
+                        )?;
+                        last_input = Instant::now();
+                        if session.child_exited() {
+                            break;
                         }
                         continue;
                     }
 
-                    handle_key_event(session, key)?;
+                    handle_key_event(session, &key)?;
                 }
                 Event::Paste(text) => {
-                    session.write(text.as_bytes())?;
+                    last_input = Instant::now();
+                    session.paste(&text)?;
                 }
                 Event::Resize(cols, rows) => {
                     session.resize(cols, rows);
@@ -106,59 +516,110 @@ fn run_event_loop(
     Ok(())
 }
 
-fn handle_key_event(
+/// One visit to chat mode. In agentic mode an accepted command is run after confirmation
+/// and its output sent back, until the model suggests nothing more or the step limit.
+fn enter_chat(
     session: &mut PtySession,
-    key: crossterm::event::KeyEvent,
+    llm: &Arc<dyn LLMClient>,
+    chat_options: &ChatOptions,
+    history: &mut Vec<ChatMessage>,
+    lang: &Language,
+    dangerous_patterns: &[Regex],
+    preference: &PreferenceConfig,
 ) -> Result<()> {
-    match key.code {
-        KeyCode::Char(c) => {
-            if key.modifiers.contains(KeyModifiers::CONTROL) {
-                let ctrl_char = (c.to_ascii_lowercase() as u8) & 0x1f;
-                session.write(&[ctrl_char])?;
-            } else if key.modifiers.contains(KeyModifiers::ALT) {
-                session.write(&[0x1b])?;
-                let mut buf = [0u8; 4];
-                let s = c.encode_utf8(&mut buf);
-                session.write(s.as_bytes())?;
-            } else {
-                let mut buf = [0u8; 4];
-                let s = c.encode_utf8(&mut buf);
-                session.write(s.as_bytes())?;
+    let mut follow_up = None;
+    let mut steps = 0;
+    loop {
+        let outcome = chat_mode(llm, session, chat_options, history, lang, follow_up.take())?;
+        if session.child_exited() {
+            return Ok(());
+        }
+        // The command replaces whatever is on the prompt; Enter only follows
+        // in execute mode, so nothing half-typed gets run first
+        let declined = ChatOutcome::Exit.shell_input(preference.inject_mode);
+        match outcome {
+            ChatOutcome::Accept(cmd) if preference.agentic => {
+                let dangerous = is_dangerous(&cmd, dangerous_patterns);
+                if !confirm_run(&cmd, dangerous, lang, chat_options.color)? {
+                    return session.write(&declined);
+                }
+                let mark = session.output_mark();
+                session.inject(&cmd, InjectMode::Execute)?;
+                if !wait_for_command(session)? || session.child_exited() {
+                    return Ok(());
+                }
+                steps += 1;
+                if steps >= preference.agentic_max_steps() {
+                    let message =
+                        t(lang, MessageKey::AgenticLimit).replace("{n}", &steps.to_string());
+                    print!("\r\n{message}\r\n");
+                    return session.write(&declined);
+                }
+                let output = redact(&session.output_since(mark), &chat_options.redact_patterns);
+                follow_up = Some(agentic_follow_up(&cmd, &output));
             }
+            ChatOutcome::Accept(cmd)
+                if is_dangerous(&cmd, dangerous_patterns)
+                    && !confirm_dangerous(&cmd, lang, chat_options.color)? =>
+            {
+                // A command staged earlier may be on the line, so it is cleared too
+                return session.write(&declined);
+            }
+            outcome => return session.write(&outcome.shell_input(preference.inject_mode)),
         }
-        KeyCode::Enter => session.write(b"\r")?,
-        KeyCode::Backspace => session.write(&[0x7f])?,
-        KeyCode::Tab => session.write(b"\t")?,
-        KeyCode::Esc => session.write(&[0x1b])?,
-        KeyCode::Up => session.write(b"\x1b[A")?,
-        KeyCode::Down => session.write(b"\x1b[B")?,
-        KeyCode::Right => session.write(b"\x1b[C")?,
-        KeyCode::Left => session.write(b"\x1b[D")?,
-        KeyCode::Home => session.write(b"\x1b[H")?,
-        KeyCode::End => session.write(b"\x1b[F")?,
-        KeyCode::PageUp => session.write(b"\x1b[5~")?,
-        KeyCode::PageDown => session.write(b"\x1b[6~")?,
-        KeyCode::Delete => session.write(b"\x1b[3~")?,
-        KeyCode::Insert => session.write(b"\x1b[2~")?,
-        KeyCode::F(n) => {
-            let seq = match n {
-                1 => b"\x1bOP".as_slice(),
-                2 => b"\x1bOQ".as_slice(),
-                3 => b"\x1bOR".as_slice(),
-                4 => b"\x1bOS".as_slice(),
-                5 => b"\x1b[15~".as_slice(),
-                6 => b"\x1b[17~".as_slice(),
-                7 => b"\x1b[18~".as_slice(),
-                8 => b"\x1b[19~".as_slice(),
-                9 => b"\x1b[20~".as_slice(),
-                10 => b"\x1b[21~".as_slice(),
-                11 => b"\x1b[23~".as_slice(),
-                12 => b"\x1b[24~".as_slice(),
-                _ => return Ok(()),
-            };
-            session.write(seq)?;
+    }
+}
+
+/// Output has to stop for this long before a finished command counts as done.
+const COMMAND_SETTLE: Duration = Duration::from_millis(300);
+/// Without a way to see the foreground process, a pause this long counts as done.
+const COMMAND_QUIET: Duration = Duration::from_millis(1500);
+
+/// Relay input while an agentic command runs, until it finishes; false when the user
+/// interrupted it with Ctrl+C, which also ends the agentic run.
+fn wait_for_command(session: &mut PtySession) -> Result<bool> {
+    let mut last_mark = session.output_mark();
+    let mut last_output = Instant::now();
+    let mut started = false;
+    loop {
+        if session.child_exited() {
+            return Ok(false);
         }
-        _ => {}
+        let mark = session.output_mark();
+        if mark != last_mark {
+            last_mark = mark;
+            last_output = Instant::now();
+        }
+        let quiet = last_output.elapsed();
+        match session.command_running() {
+            Some(true) => started = true,
+            Some(false) if started && quiet >= COMMAND_SETTLE => return Ok(true),
+            _ if quiet >= COMMAND_QUIET => return Ok(true),
+            _ => {}
+        }
+
+        if event::poll(Duration::from_millis(50))? {
+            match event::read()? {
+                Event::Key(key) if key.kind == KeyEventKind::Release => {}
+                Event::Key(key) => {
+                    handle_key_event(session, &key)?;
+                    if key.code == KeyCode::Char('c')
+                        && key.modifiers.contains(KeyModifiers::CONTROL)
+                    {
+                        return Ok(false);
+                    }
+                }
+                Event::Paste(text) => session.paste(&text)?,
+                Event::Resize(cols, rows) => session.resize(cols, rows),
+                _ => {}
+            }
+        }
+    }
+}
+
+fn handle_key_event(session: &mut PtySession, key: &KeyEvent) -> Result<()> {
+    if let Some(bytes) = key_to_bytes(key, session.application_cursor()) {
+        session.write(&bytes)?;
     }
     Ok(())
-}
\ No newline at end of file
+}
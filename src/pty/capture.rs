@@ -0,0 +1,97 @@
+use std::collections::VecDeque;
+
+/// Bounded buffer holding the most recent plain-text output of the shell.
+pub struct OutputCapture {
+    buf: VecDeque<u8>,
+    capacity: usize,
+    /// Bytes pushed since the start, including those dropped since
+    total: u64,
+}
+
+impl OutputCapture {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            buf: VecDeque::with_capacity(capacity),
+            capacity,
+            total: 0,
+        }
+    }
+
+    pub fn push(&mut self, bytes: &[u8]) {
+        self.total += bytes.len() as u64;
+        let bytes = &bytes[bytes.len().saturating_sub(self.capacity)..];
+        let overflow = (self.buf.len() + bytes.len()).saturating_sub(self.capacity);
+        self.buf.drain(..overflow);
+        self.buf.extend(bytes);
+    }
+
+    /// The captured output as text, with line endings normalized and control bytes dropped.
+    pub fn text(&self) -> String {
+        self.text_since(0)
+    }
+
+    /// Position of the end of the output so far, for `text_since`.
+    pub fn mark(&self) -> u64 {
+        self.total
+    }
+
+    /// Like `text`, but only what was pushed after `mark`, as far as it is still kept.
+    pub fn text_since(&self, mark: u64) -> String {
+        let new = self.total.saturating_sub(mark).min(self.buf.len() as u64) as usize;
+        let (head, tail) = self.buf.as_slices();
+        let bytes = [head, tail].concat();
+        let bytes = &bytes[bytes.len() - new..];
+        // Dropping old bytes may have cut a multi-byte char in half
+        let start = bytes
+            .iter()
+            .position(|b| !(0x80..0xc0).contains(b))
+            .unwrap_or(bytes.len());
+        let raw = String::from_utf8_lossy(&bytes[start..]).into_owned();
+        raw.replace("\r\n", "\n")
+            .chars()
+            .filter(|c| !c.is_control() || *c == '\n' || *c == '\t')
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_keeps_only_the_tail() {
+        let mut capture = OutputCapture::new(8);
+        capture.push(b"hello ");
+        capture.push(b"world");
+        assert_eq!(capture.text(), "lo world");
+        capture.push(b"0123456789");
+        assert_eq!(capture.text(), "23456789");
+    }
+
+    #[test]
+    fn test_text_since_mark() {
+        let mut capture = OutputCapture::new(8);
+        capture.push(b"$ ls");
+        let mark = capture.mark();
+        capture.push(b"\r\nfoo");
+        assert_eq!(capture.text_since(mark), "\nfoo");
+        // Only the part still kept comes back
+        capture.push(b"0123456789");
+        assert_eq!(capture.text_since(mark), "23456789");
+        assert_eq!(capture.text_since(capture.mark()), "");
+    }
+
+    #[test]
+    fn test_normalizes_line_endings() {
+        let mut capture = OutputCapture::new(64);
+        capture.push(b"$ ls\r\nfoo\r\nbar\x07\r\n");
+        assert_eq!(capture.text(), "$ ls\nfoo\nbar\n");
+    }
+
+    #[test]
+    fn test_skips_split_multibyte_char() {
+        let mut capture = OutputCapture::new(4);
+        capture.push("你好".as_bytes());
+        assert_eq!(capture.text(), "好");
+    }
+}
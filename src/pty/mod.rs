@@ -1,28 +1,62 @@
+mod capture;
 mod responder;
 
+use capture::OutputCapture;
 use responder::VtResponder;
-use std::env;
+use std::collections::HashMap;
 use std::io::{Read, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
 use std::thread;
 
 use anyhow::{Context, Result};
 use portable_pty::{CommandBuilder, MasterPty, PtyPair, PtySize, native_pty_system};
+use serde::Deserialize;
+
+use crate::config::ShellConfig;
 
 pub type PtyWriter = Arc<Mutex<Box<dyn Write + Send>>>;
 
+/// `TERM` for the shell when none is set.
+const DEFAULT_TERM: &str = "xterm-256color";
+
+/// How much recent shell output is kept for the LLM context.
+const CAPTURE_CAPACITY: usize = 8 * 1024;
+
+/// What happens to an accepted command once it is typed into the shell.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum InjectMode {
+    /// Leave it on the prompt for review; Enter runs it
+    #[default]
+    Prefill,
+    /// Press Enter right away
+    Execute,
+}
+
 pub struct PtySession {
     pub master: Box<dyn MasterPty + Send>,
     pub child: Box<dyn portable_pty::Child + Send + Sync>,
     pub writer: PtyWriter,
+    capture: Arc<Mutex<OutputCapture>>,
+    /// The shell's working directory, kept current through OSC 7
+    cwd: Arc<Mutex<PathBuf>>,
+    /// Whether the shell has bracketed paste switched on
+    bracketed_paste: Arc<AtomicBool>,
+    /// Whether a full-screen program owns the display
+    alt_screen: Arc<AtomicBool>,
+    /// Whether the child wants arrow keys in application mode (DECCKM)
+    application_cursor: Arc<AtomicBool>,
+    /// Window title the shell or a program last set
+    title: Arc<Mutex<Option<String>>>,
+    /// Whether the relay keeps the shell's output off the screen
+    muted: Arc<AtomicBool>,
 }
 
 impl PtySession {
-    
-    pub fn new(shell_path: Option<&str>) -> Result<Self> {
-        let shell = shell_path
-            .map(|s| s.to_string())
-            .unwrap_or_else(detect_shell);
+    /// Spawn `shell` (see `resolve_shell`) with the options in `config`.
+    pub fn new(shell: &str, config: &ShellConfig) -> Result<Self> {
         let (cols, rows) = crossterm::terminal::size().unwrap_or((120, 32));
 
         let pty_system = native_pty_system();
@@ -36,14 +70,14 @@ impl PtySession {
             .context("failed to open pty")?;
 
         let PtyPair { master, slave } = pair;
-        let current_dir = env::current_dir().context("failed to get current directory")?;
+        let current_dir = config.start_dir()?;
 
-        let mut cmd = CommandBuilder::new(&shell);
-        cmd.cwd(current_dir);
+        let mut cmd = CommandBuilder::new(shell);
+        cmd.args(shell_args(shell, config.login, config.args.as_deref()));
+        apply_env(&mut cmd, &config.env);
+        cmd.cwd(&current_dir);
 
-        let child = slave
-            .spawn_command(cmd)
-            .context("failed to spawn shell")?;
+        let child = slave.spawn_command(cmd).context("failed to spawn shell")?;
 
         let writer = master.take_writer().context("failed to take pty writer")?;
         let writer: PtyWriter = Arc::new(Mutex::new(writer));
@@ -52,6 +86,13 @@ impl PtySession {
             master,
             child,
             writer,
+            capture: Arc::new(Mutex::new(OutputCapture::new(CAPTURE_CAPACITY))),
+            cwd: Arc::new(Mutex::new(current_dir)),
+            bracketed_paste: Arc::new(AtomicBool::new(false)),
+            alt_screen: Arc::new(AtomicBool::new(false)),
+            application_cursor: Arc::new(AtomicBool::new(false)),
+            title: Arc::new(Mutex::new(None)),
+            muted: Arc::new(AtomicBool::new(false)),
         })
     }
 
@@ -61,6 +102,13 @@ impl PtySession {
             .try_clone_reader()
             .context("failed to clone pty reader")?;
         let writer_for_responder = self.writer.clone();
+        let capture = self.capture.clone();
+        let cwd = self.cwd.clone();
+        let bracketed_paste = self.bracketed_paste.clone();
+        let alt_screen = self.alt_screen.clone();
+        let application_cursor = self.application_cursor.clone();
+        let title = self.title.clone();
+        let muted = self.muted.clone();
 
         thread::spawn(move || {
             let mut stdout = std::io::stdout();
@@ -74,8 +122,27 @@ impl PtySession {
                         let filtered = responder.process(&buf[..n], |resp| {
                             let _ = write_bytes(&writer_for_responder, resp);
                         });
-                        let _ = stdout.write_all(&filtered);
-                        let _ = stdout.flush();
+                        if !muted.load(Ordering::Relaxed) {
+                            let _ = stdout.write_all(&filtered);
+                            let _ = stdout.flush();
+                        }
+                        if let Ok(mut capture) = capture.lock() {
+                            capture.push(&responder.take_text());
+                        }
+                        if let Some(dir) = responder.take_cwd()
+                            && let Ok(mut cwd) = cwd.lock()
+                        {
+                            *cwd = dir;
+                        }
+                        bracketed_paste.store(responder.bracketed_paste(), Ordering::Relaxed);
+                        alt_screen.store(responder.in_alt_screen(), Ordering::Relaxed);
+                        application_cursor.store(responder.application_cursor(), Ordering::Relaxed);
+                        if let Some(latest) = responder.title()
+                            && let Ok(mut title) = title.lock()
+                            && title.as_deref() != Some(latest)
+                        {
+                            *title = Some(latest.to_string());
+                        }
                     }
                     Err(_) => break,
                 }
@@ -97,6 +164,71 @@ impl PtySession {
             .unwrap_or(false)
     }
 
+    /// Recent shell output as plain text, escape sequences stripped.
+    pub fn recent_output(&self) -> String {
+        self.capture
+            .lock()
+            .map(|capture| capture.text())
+            .unwrap_or_default()
+    }
+
+    /// Position in the shell's output, for `output_since`.
+    pub fn output_mark(&self) -> u64 {
+        self.capture
+            .lock()
+            .map(|capture| capture.mark())
+            .unwrap_or(0)
+    }
+
+    /// Shell output after `mark` as plain text, as far as the capture still holds it.
+    pub fn output_since(&self, mark: u64) -> String {
+        self.capture
+            .lock()
+            .map(|capture| capture.text_since(mark))
+            .unwrap_or_default()
+    }
+
+    /// Whether a program started from the shell holds the terminal, i.e. the foreground
+    /// process group is not the shell's. `None` where that cannot be told.
+    pub fn command_running(&self) -> Option<bool> {
+        #[cfg(unix)]
+        {
+            let leader = self.master.process_group_leader()?;
+            let shell = self.child.process_id()?;
+            Some(u32::try_from(leader).ok() != Some(shell))
+        }
+        #[cfg(not(unix))]
+        None
+    }
+
+    /// Whether a full-screen program (vim, less, htop, ...) is on the alternate screen,
+    /// where chat output would be drawn over it.
+    pub fn in_alt_screen(&self) -> bool {
+        self.alt_screen.load(Ordering::Relaxed)
+    }
+
+    /// Whether the child switched on application cursor keys, as vim and less do, and so
+    /// expects arrows as `ESC O A` instead of `ESC [ A`.
+    pub fn application_cursor(&self) -> bool {
+        self.application_cursor.load(Ordering::Relaxed)
+    }
+
+    /// Drop the shell's output instead of drawing it, e.g. its echo of a command staged
+    /// while chat mode owns the screen. It is still captured and parsed.
+    pub fn mute_output(&self, muted: bool) {
+        self.muted.store(muted, Ordering::Relaxed);
+    }
+
+    /// The window title last set through OSC 0/2 from inside the session.
+    pub fn title(&self) -> Option<String> {
+        self.title.lock().ok().and_then(|title| title.clone())
+    }
+
+    /// The shell's working directory as last reported via OSC 7, or the one it started in.
+    pub fn current_dir(&self) -> PathBuf {
+        self.cwd.lock().map(|cwd| cwd.clone()).unwrap_or_default()
+    }
+
     pub fn resize(&self, cols: u16, rows: u16) {
         let _ = self.master.resize(PtySize {
             rows,
@@ -109,32 +241,106 @@ impl PtySession {
     pub fn write(&self, bytes: &[u8]) -> Result<()> {
         write_bytes(&self.writer, bytes)
     }
+
+    /// Type an accepted command onto the shell's prompt, running it with `Execute`.
+    pub fn inject(&self, cmd: &str, mode: InjectMode) -> Result<()> {
+        self.write(&inject_command(cmd, mode == InjectMode::Execute))
+    }
+
+    /// Forward pasted text, wrapped in the bracketed-paste markers when the shell asked
+    /// for them so it can tell a paste from typing.
+    pub fn paste(&self, text: &str) -> Result<()> {
+        self.write(&paste_bytes(
+            text,
+            self.bracketed_paste.load(Ordering::Relaxed),
+        ))
+    }
+
+    /// Send Ctrl+C (ETX), which the terminal line discipline turns into SIGINT for
+    /// the foreground process group.
+    pub fn interrupt(&self) -> Result<()> {
+        self.write(&[0x03])
+    }
 }
 
-fn detect_shell() -> String {
-    #[cfg(target_os = "windows")]
-    {
-        detect_windows_shell()
+/// The bytes that replace the shell's current input line with `cmd`: Ctrl+U clears
+/// whatever was typed, then the command follows, and Enter only when `execute`.
+pub fn inject_command(cmd: &str, execute: bool) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(cmd.len() + 2);
+    bytes.push(0x15);
+    bytes.extend_from_slice(cmd.as_bytes());
+    if execute {
+        bytes.push(b'\r');
     }
+    bytes
+}
 
-    #[cfg(not(target_os = "windows"))]
-    {
-        detect_unix_shell()
+/// `text` as the shell should receive it from a paste.
+fn paste_bytes(text: &str, bracketed: bool) -> Vec<u8> {
+    if !bracketed {
+        return text.as_bytes().to_vec();
     }
+    let mut bytes = Vec::with_capacity(text.len() + 12);
+    bytes.extend_from_slice(b"\x1b[200~");
+    // A stray end marker inside the paste would end it early and run the rest
+    bytes.extend_from_slice(text.replace("\x1b[201~", "").as_bytes());
+    bytes.extend_from_slice(b"\x1b[201~");
+    bytes
 }
 
-#[cfg(target_os = "windows")]
-fn detect_windows_shell() -> String {
-    if env::var("PSModulePath").is_ok() {
-        "powershell.exe".to_string()
-    } else {
-        "cmd.exe".to_string()
+/// The arguments to start `shell` with: the configured `args` if any, otherwise `-l` when
+/// a login shell is wanted and the shell understands it.
+fn shell_args(shell: &str, login: Option<bool>, args: Option<&[String]>) -> Vec<String> {
+    if let Some(args) = args {
+        return args.to_vec();
+    }
+    if !login.unwrap_or(cfg!(target_os = "macos")) {
+        return Vec::new();
+    }
+    let name = std::path::Path::new(shell)
+        .file_stem()
+        .and_then(|name| name.to_str())
+        .unwrap_or(shell);
+    match name {
+        "bash" | "zsh" | "fish" | "sh" | "ksh" | "dash" => vec!["-l".to_string()],
+        // PowerShell and cmd have no login mode
+        _ => Vec::new(),
     }
 }
 
-#[cfg(not(target_os = "windows"))]
-fn detect_unix_shell() -> String {
-    env::var("SHELL").unwrap_or_else(|_| "/bin/bash".to_string())
+/// Set the configured `shell.env` variables, and a `TERM` if neither they nor the
+/// inherited environment have one, since full-screen programs need it.
+fn apply_env(cmd: &mut CommandBuilder, env: &HashMap<String, String>) {
+    for (key, value) in env {
+        cmd.env(key, value);
+    }
+    if cmd.get_env("TERM").is_none_or(|term| term.is_empty()) {
+        cmd.env("TERM", DEFAULT_TERM);
+    }
+}
+
+/// The shell to spawn: `shell.path` if set, otherwise detected from the environment
+/// ($SHELL on Unix, PowerShell or cmd on Windows).
+pub fn resolve_shell(config: &ShellConfig, env: impl Fn(&str) -> Option<String>) -> String {
+    if let Some(path) = config
+        .path
+        .as_deref()
+        .map(str::trim)
+        .filter(|p| !p.is_empty())
+    {
+        return path.to_string();
+    }
+    if cfg!(target_os = "windows") {
+        if env("PSModulePath").is_some() {
+            "powershell.exe".to_string()
+        } else {
+            "cmd.exe".to_string()
+        }
+    } else {
+        env("SHELL")
+            .filter(|shell| !shell.is_empty())
+            .unwrap_or_else(|| "/bin/bash".to_string())
+    }
 }
 
 fn write_bytes(writer: &PtyWriter, bytes: &[u8]) -> Result<()> {
@@ -144,4 +350,80 @@ fn write_bytes(writer: &PtyWriter, bytes: &[u8]) -> Result<()> {
     w.write_all(bytes)?;
     w.flush()?;
     Ok(())
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_inject_command() {
+        assert_eq!(inject_command("ls -la", false), b"\x15ls -la");
+        assert_eq!(inject_command("ls -la", true), b"\x15ls -la\r");
+    }
+
+    #[test]
+    fn test_paste_bytes() {
+        assert_eq!(paste_bytes("ls\n", false), b"ls\n");
+        assert_eq!(paste_bytes("ls\n", true), b"\x1b[200~ls\n\x1b[201~");
+        assert_eq!(
+            paste_bytes("a\x1b[201~rm x\n", true),
+            b"\x1b[200~arm x\n\x1b[201~"
+        );
+    }
+
+    #[test]
+    fn test_shell_args() {
+        assert_eq!(shell_args("/bin/bash", Some(true), None), vec!["-l"]);
+        assert_eq!(
+            shell_args("/usr/local/bin/fish", Some(true), None),
+            vec!["-l"]
+        );
+        assert!(shell_args("/bin/zsh", Some(false), None).is_empty());
+        assert!(shell_args("powershell.exe", Some(true), None).is_empty());
+
+        let args = vec!["--norc".to_string()];
+        assert_eq!(
+            shell_args("/bin/bash", Some(true), Some(&args)),
+            vec!["--norc"]
+        );
+
+        let default = shell_args("/bin/zsh", None, None);
+        assert_eq!(default.is_empty(), !cfg!(target_os = "macos"));
+    }
+
+    #[test]
+    fn test_apply_env() {
+        let mut cmd = CommandBuilder::new("/bin/sh");
+        cmd.env_remove("TERM");
+        let env = HashMap::from([("PROMPT".to_string(), "> ".to_string())]);
+        apply_env(&mut cmd, &env);
+        assert_eq!(cmd.get_env("PROMPT").unwrap(), "> ");
+        assert_eq!(cmd.get_env("TERM").unwrap(), DEFAULT_TERM);
+
+        let env = HashMap::from([("TERM".to_string(), "screen".to_string())]);
+        apply_env(&mut cmd, &env);
+        assert_eq!(cmd.get_env("TERM").unwrap(), "screen");
+    }
+
+    #[test]
+    fn test_resolve_shell() {
+        let env = |key: &str| (key == "SHELL").then(|| "/usr/bin/zsh".to_string());
+        let configured = ShellConfig {
+            path: Some("/usr/local/bin/fish".to_string()),
+            ..ShellConfig::default()
+        };
+        assert_eq!(resolve_shell(&configured, env), "/usr/local/bin/fish");
+
+        if !cfg!(target_os = "windows") {
+            let blank = ShellConfig {
+                path: Some(" ".to_string()),
+                ..ShellConfig::default()
+            };
+            assert_eq!(resolve_shell(&blank, env), "/usr/bin/zsh");
+            assert_eq!(
+                resolve_shell(&ShellConfig::default(), |_| None),
+                "/bin/bash"
+            );
+        }
+    }
+}
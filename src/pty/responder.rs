@@ -1,16 +1,69 @@
+use std::path::PathBuf;
+
 use anyhow::Result;
 
+/// DECSET modes that switch to the alternate screen: xterm's 1049 and the older 1047 and 47.
+const ALT_SCREEN_MODES: [&[u8]; 3] = [b"1049", b"1047", b"47"];
+
 pub struct VtResponder {
     pending: Vec<u8>,
+    /// Printable output seen since the last `take_text`, without escape sequences
+    text: Vec<u8>,
+    /// Working directory last reported by the shell through OSC 7, until taken
+    cwd: Option<PathBuf>,
+    /// Whether the shell asked for bracketed paste (DECSET 2004)
+    bracketed_paste: bool,
+    /// Whether a full-screen program has switched to the alternate screen
+    alt_screen: bool,
+    /// Whether the child asked for application cursor keys (DECCKM, DECSET 1)
+    application_cursor: bool,
+    /// Window title last set through OSC 0 or 2
+    title: Option<String>,
 }
 
 impl VtResponder {
     pub fn new() -> Self {
         Self {
             pending: Vec::new(),
+            text: Vec::new(),
+            cwd: None,
+            bracketed_paste: false,
+            alt_screen: false,
+            application_cursor: false,
+            title: None,
         }
     }
 
+    /// Drain the plain text (escape sequences removed) collected by `process`.
+    pub fn take_text(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.text)
+    }
+
+    /// The directory from the latest OSC 7 report, if one arrived since the last call.
+    pub fn take_cwd(&mut self) -> Option<PathBuf> {
+        self.cwd.take()
+    }
+
+    /// Whether pastes should reach the shell wrapped in `ESC [200~` / `ESC [201~`.
+    pub fn bracketed_paste(&self) -> bool {
+        self.bracketed_paste
+    }
+
+    /// Whether the child is drawing on the alternate screen (vim, less, htop, ...).
+    pub fn in_alt_screen(&self) -> bool {
+        self.alt_screen
+    }
+
+    /// Whether arrow keys should reach the child as `ESC O A` rather than `ESC [ A`.
+    pub fn application_cursor(&self) -> bool {
+        self.application_cursor
+    }
+
+    /// The latest window title the child set, if it ever set one.
+    pub fn title(&self) -> Option<&str> {
+        self.title.as_deref()
+    }
+
     pub fn process(&mut self, chunk: &[u8], mut on_response: impl FnMut(&[u8])) -> Vec<u8> {
         self.pending.extend_from_slice(chunk);
         let mut out: Vec<u8> = Vec::with_capacity(chunk.len());
@@ -19,6 +72,7 @@ impl VtResponder {
         while i < self.pending.len() {
             if self.pending[i] != 0x1b {
                 out.push(self.pending[i]);
+                self.text.push(self.pending[i]);
                 i += 1;
                 continue;
             }
@@ -46,6 +100,34 @@ impl VtResponder {
                     } else if seq == b"\x1b[c" {
                         // DA1 (Primary Device Attributes)
                         on_response(b"\x1b[?1;0c");
+                    } else if seq == b"\x1b[>c" || seq == b"\x1b[>0c" {
+                        // DA2 (Secondary Device Attributes): VT100-class, xterm patch 276
+                        on_response(b"\x1b[>0;276;0c");
+                    } else if seq == b"\x1b[>q" || seq == b"\x1b[>0q" {
+                        // XTVERSION
+                        on_response(&xtversion_response());
+                    } else if let Some((enabled, modes)) = private_modes(seq) {
+                        if modes.contains(&&b"2004"[..]) {
+                            self.bracketed_paste = enabled;
+                        }
+                        if modes.iter().any(|mode| ALT_SCREEN_MODES.contains(mode)) {
+                            self.alt_screen = enabled;
+                        }
+                        if modes.contains(&&b"1"[..]) {
+                            self.application_cursor = enabled;
+                        }
+                        // shellm keeps bracketed paste on in the outer terminal for the whole
+                        // session, so the shell's own switch is only recorded
+                        if modes != [b"2004"] {
+                            out.extend_from_slice(seq);
+                        }
+                    } else if let Some(mode) = decrqm_mode(seq) {
+                        // DECRQM: report the private mode as not recognized rather than
+                        // forwarding it, since the outer terminal's reply would reach our stdin
+                        let mut resp = b"\x1b[?".to_vec();
+                        resp.extend_from_slice(mode);
+                        resp.extend_from_slice(b";0$y");
+                        on_response(&resp);
                     } else {
                         out.extend_from_slice(seq);
                     }
@@ -55,6 +137,24 @@ impl VtResponder {
                     let Some(end) = parse_osc_end(&self.pending, i + 2) else {
                         break;
                     };
+                    // Payload excludes the BEL or ESC \ terminator
+                    let payload_end = if self.pending[end] == 0x07 {
+                        end
+                    } else {
+                        end - 1
+                    };
+                    let payload = &self.pending[i + 2..payload_end];
+                    if let Some(uri) = payload.strip_prefix(b"7;")
+                        && let Some(path) = parse_file_uri(uri)
+                    {
+                        self.cwd = Some(path);
+                    } else if let Some(title) = payload
+                        .strip_prefix(b"0;")
+                        .or_else(|| payload.strip_prefix(b"2;"))
+                    {
+                        self.title = Some(String::from_utf8_lossy(title).into_owned());
+                    }
+                    // Forwarded unchanged; the outer terminal may track it too
                     out.extend_from_slice(&self.pending[i..=end]);
                     i = end + 1;
                 }
@@ -65,6 +165,10 @@ impl VtResponder {
                     out.extend_from_slice(&self.pending[i..=end]);
                     i = end + 1;
                 }
+                // DECKPAM / DECKPNM are dropped: the outer terminal stays in numeric
+                // keypad mode, since crossterm cannot parse its application sequences
+                // and keypad keys would vanish
+                b'=' | b'>' => i += 2,
                 _ => {
                     let Some(end) = parse_esc(&self.pending, i + 1) else {
                         break;
@@ -100,26 +204,70 @@ impl Default for VtResponder {
 
 fn cursor_position_response() -> Vec<u8> {
     if let Ok((col, row)) = crossterm::cursor::position() {
-        format!(
-            "\x1b[{};{}R",
-            row.saturating_add(1),
-            col.saturating_add(1)
-        )
-        .into_bytes()
+        format!("\x1b[{};{}R", row.saturating_add(1), col.saturating_add(1)).into_bytes()
     } else {
         b"\x1b[1;1R".to_vec()
     }
 }
 
-// CSI: ESC [ ... <final> (0x40..0x7E)
-fn parse_csi_end(buf: &[u8], start: usize) -> Option<usize> {
-    for idx in start..buf.len() {
-        let b = buf[idx];
-        if (0x40..=0x7e).contains(&b) {
-            return Some(idx);
+fn xtversion_response() -> Vec<u8> {
+    format!("\x1bP>|shellm({})\x1b\\", env!("CARGO_PKG_VERSION")).into_bytes()
+}
+
+/// For a DECSET (`ESC [ ? ... h`) or DECRST (`... l`) sequence, whether it sets or
+/// resets, and the private modes it names.
+fn private_modes(seq: &[u8]) -> Option<(bool, Vec<&[u8]>)> {
+    let params = seq.strip_prefix(b"\x1b[?")?;
+    let (last, params) = params.split_last()?;
+    let enabled = match last {
+        b'h' => true,
+        b'l' => false,
+        _ => return None,
+    };
+    let modes: Vec<&[u8]> = params.split(|b| *b == b';').collect();
+    modes
+        .iter()
+        .all(|mode| !mode.is_empty() && mode.iter().all(u8::is_ascii_digit))
+        .then_some((enabled, modes))
+}
+
+/// Mode number of a DECRQM private mode query, `ESC [ ? <mode> $ p`.
+fn decrqm_mode(seq: &[u8]) -> Option<&[u8]> {
+    let mode = seq.strip_prefix(b"\x1b[?")?.strip_suffix(b"$p")?;
+    (!mode.is_empty() && mode.iter().all(u8::is_ascii_digit)).then_some(mode)
+}
+
+/// Path of a `file://host/path` URI as sent in OSC 7, percent-decoded.
+fn parse_file_uri(uri: &[u8]) -> Option<PathBuf> {
+    let rest = uri.strip_prefix(b"file://")?;
+    // Skip the host part
+    let path = &rest[rest.iter().position(|b| *b == b'/')?..];
+
+    let mut decoded = Vec::with_capacity(path.len());
+    let mut i = 0;
+    while i < path.len() {
+        if path[i] == b'%'
+            && let Some(byte) = path
+                .get(i + 1..i + 3)
+                .and_then(|hex| std::str::from_utf8(hex).ok())
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+        {
+            decoded.push(byte);
+            i += 3;
+        } else {
+            decoded.push(path[i]);
+            i += 1;
         }
     }
-    None
+    String::from_utf8(decoded).ok().map(PathBuf::from)
+}
+
+// CSI: ESC [ ... <final> (0x40..0x7E)
+fn parse_csi_end(buf: &[u8], start: usize) -> Option<usize> {
+    buf.iter()
+        .skip(start)
+        .position(|b| (0x40..=0x7e).contains(b))
+        .map(|pos| start + pos)
 }
 
 // OSC: ESC ] ... BEL | ST(ESC \)
@@ -128,10 +276,8 @@ fn parse_osc_end(buf: &[u8], start: usize) -> Option<usize> {
     while i < buf.len() {
         match buf[i] {
             0x07 => return Some(i), // BEL
-            0x1b => {
-                if i + 1 < buf.len() && buf[i + 1] == b'\\' {
-                    return Some(i + 1); // ST
-                }
+            0x1b if i + 1 < buf.len() && buf[i + 1] == b'\\' => {
+                return Some(i + 1); // ST
             }
             _ => {}
         }
@@ -165,3 +311,132 @@ fn parse_esc(buf: &[u8], start: usize) -> Option<usize> {
     }
     None
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(input: &[u8]) -> (Vec<u8>, Vec<Vec<u8>>) {
+        let mut responder = VtResponder::new();
+        let mut responses = Vec::new();
+        let out = responder.process(input, |resp| responses.push(resp.to_vec()));
+        (out, responses)
+    }
+
+    #[test]
+    fn test_secondary_device_attributes() {
+        let (out, responses) = run(b"a\x1b[>cb\x1b[>0c");
+        assert_eq!(out, b"ab");
+        assert_eq!(responses, vec![b"\x1b[>0;276;0c".to_vec(); 2]);
+    }
+
+    #[test]
+    fn test_xtversion() {
+        let (out, responses) = run(b"\x1b[>0qx");
+        assert_eq!(out, b"x");
+        let expected = format!("\x1bP>|shellm({})\x1b\\", env!("CARGO_PKG_VERSION"));
+        assert_eq!(responses, vec![expected.into_bytes()]);
+    }
+
+    #[test]
+    fn test_other_sequences_pass_through() {
+        let (out, responses) = run(b"\x1b[31mred\x1b[0m");
+        assert_eq!(out, b"\x1b[31mred\x1b[0m");
+        assert!(responses.is_empty());
+    }
+
+    #[test]
+    fn test_decrqm_split_across_chunks() {
+        let mut responder = VtResponder::new();
+        let mut responses = Vec::new();
+        let mut out = responder.process(b"ok\x1b[?20", |resp| responses.push(resp.to_vec()));
+        assert!(responses.is_empty());
+        out.extend(responder.process(b"04$pdone", |resp| responses.push(resp.to_vec())));
+        assert_eq!(out, b"okdone");
+        assert_eq!(responses, vec![b"\x1b[?2004;0$y".to_vec()]);
+    }
+
+    #[test]
+    fn test_bracketed_paste_mode_is_tracked() {
+        let mut responder = VtResponder::new();
+        assert!(!responder.bracketed_paste());
+        let out = responder.process(b"\x1b[?2004h$ ", |_| {});
+        assert_eq!(out, b"$ ");
+        assert!(responder.bracketed_paste());
+        responder.process(b"\x1b[?2004l", |_| {});
+        assert!(!responder.bracketed_paste());
+
+        // Combined with other modes, the sequence still reaches the terminal
+        let out = responder.process(b"\x1b[?1;2004h", |_| {});
+        assert_eq!(out, b"\x1b[?1;2004h");
+        assert!(responder.bracketed_paste());
+        responder.process(b"\x1b[?20040h", |_| {});
+        assert!(responder.bracketed_paste());
+    }
+
+    #[test]
+    fn test_alt_screen_is_tracked_and_forwarded() {
+        let mut responder = VtResponder::new();
+        assert!(!responder.in_alt_screen());
+        let out = responder.process(b"\x1b[?1049h\x1b[H", |_| {});
+        assert_eq!(out, b"\x1b[?1049h\x1b[H");
+        assert!(responder.in_alt_screen());
+        responder.process(b"\x1b[?1049l", |_| {});
+        assert!(!responder.in_alt_screen());
+
+        responder.process(b"\x1b[?1;47h", |_| {});
+        assert!(responder.in_alt_screen());
+        responder.process(b"\x1b[?47l", |_| {});
+        assert!(!responder.in_alt_screen());
+    }
+
+    #[test]
+    fn test_application_cursor_is_tracked_and_forwarded() {
+        let mut responder = VtResponder::new();
+        assert!(!responder.application_cursor());
+        let out = responder.process(b"\x1b[?1h", |_| {});
+        assert_eq!(out, b"\x1b[?1h");
+        assert!(responder.application_cursor());
+        responder.process(b"\x1b[?1049;1l", |_| {});
+        assert!(!responder.application_cursor());
+        responder.process(b"\x1b[?12h", |_| {});
+        assert!(!responder.application_cursor());
+    }
+
+    #[test]
+    fn test_keypad_mode_is_not_forwarded() {
+        let mut responder = VtResponder::new();
+        // less and vim switch it on together with the cursor keys
+        let out = responder.process(b"\x1b[?1h\x1b=~", |_| {});
+        assert_eq!(out, b"\x1b[?1h~");
+        let out = responder.process(b"\x1b>$ ", |_| {});
+        assert_eq!(out, b"$ ");
+    }
+
+    #[test]
+    fn test_osc_title_is_tracked_and_forwarded() {
+        let mut responder = VtResponder::new();
+        assert_eq!(responder.title(), None);
+        let out = responder.process(b"\x1b]0;vim notes.txt\x07", |_| {});
+        assert_eq!(out, b"\x1b]0;vim notes.txt\x07");
+        assert_eq!(responder.title(), Some("vim notes.txt"));
+        responder.process(b"\x1b]2;me@host: ~\x1b\\", |_| {});
+        assert_eq!(responder.title(), Some("me@host: ~"));
+        // Icon name only
+        responder.process(b"\x1b]1;icon\x07", |_| {});
+        assert_eq!(responder.title(), Some("me@host: ~"));
+    }
+
+    #[test]
+    fn test_osc7_tracks_cwd_and_is_forwarded() {
+        let mut responder = VtResponder::new();
+        let input = b"\x1b]7;file://host/home/me/my%20dir\x1b\\$ ";
+        let out = responder.process(input, |_| {});
+        assert_eq!(out, input);
+        assert_eq!(responder.take_cwd(), Some(PathBuf::from("/home/me/my dir")));
+        assert_eq!(responder.take_cwd(), None);
+
+        responder.process(b"\x1b]7;file:///tmp\x07", |_| {});
+        assert_eq!(responder.take_cwd(), Some(PathBuf::from("/tmp")));
+    }
+}
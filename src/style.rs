@@ -0,0 +1,86 @@
+use std::env;
+use std::io::{self, IsTerminal};
+
+use serde::Deserialize;
+
+/// Whether shellm's own output uses ANSI colors.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ColorMode {
+    /// Color unless `NO_COLOR` is set or stdout is not a terminal
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorMode {
+    /// Settle `Auto` into `Always` or `Never`; done once at startup.
+    pub fn resolve(self) -> Self {
+        match self {
+            ColorMode::Auto => {
+                let no_color = env::var_os("NO_COLOR").is_some_and(|v| !v.is_empty());
+                if no_color || !io::stdout().is_terminal() {
+                    ColorMode::Never
+                } else {
+                    ColorMode::Always
+                }
+            }
+            mode => mode,
+        }
+    }
+
+    pub fn enabled(self) -> bool {
+        self != ColorMode::Never
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Style {
+    Dim,
+    Bold,
+    Reverse,
+    Red,
+    Yellow,
+}
+
+impl Style {
+    fn codes(self) -> (&'static str, &'static str) {
+        match self {
+            Style::Dim => ("\x1b[90m", "\x1b[0m"),
+            Style::Bold => ("\x1b[1m", "\x1b[0m"),
+            Style::Reverse => ("\x1b[7m", "\x1b[27m"),
+            Style::Red => ("\x1b[31m", "\x1b[0m"),
+            Style::Yellow => ("\x1b[33m", "\x1b[0m"),
+        }
+    }
+}
+
+/// Wrap `text` in the SGR codes for `style`, or return it bare when colors are off.
+pub fn style(text: &str, style: Style, mode: ColorMode) -> String {
+    if !mode.enabled() {
+        return text.to_string();
+    }
+    let (start, end) = style.codes();
+    format!("{start}{text}{end}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_style_never_returns_bare_text() {
+        assert_eq!(style("hint", Style::Dim, ColorMode::Never), "hint");
+        assert_eq!(
+            style("hint", Style::Dim, ColorMode::Always),
+            "\x1b[90mhint\x1b[0m"
+        );
+    }
+
+    #[test]
+    fn test_resolve_keeps_explicit_modes() {
+        assert_eq!(ColorMode::Always.resolve(), ColorMode::Always);
+        assert_eq!(ColorMode::Never.resolve(), ColorMode::Never);
+    }
+}
@@ -0,0 +1,108 @@
+use std::io::{self, Write};
+use std::panic;
+
+use anyhow::{Context, Result};
+use crossterm::event::{DisableBracketedPaste, EnableBracketedPaste};
+use crossterm::execute;
+use crossterm::terminal::{disable_raw_mode, enable_raw_mode};
+
+/// Keeps the terminal in raw mode for as long as it lives.
+pub struct RawModeGuard;
+
+impl RawModeGuard {
+    pub fn enable() -> Result<Self> {
+        enable_raw_mode().context("failed to enter raw mode")?;
+        Ok(Self)
+    }
+}
+
+impl Drop for RawModeGuard {
+    fn drop(&mut self) {
+        let _ = disable_raw_mode();
+    }
+}
+
+/// Keeps bracketed paste on in the outer terminal for as long as it lives, so pastes
+/// arrive as one `Event::Paste` both in chat and while the shell has the terminal.
+pub struct BracketedPasteGuard;
+
+impl BracketedPasteGuard {
+    pub fn enable() -> Result<Self> {
+        let mut stdout = io::stdout();
+        execute!(stdout, EnableBracketedPaste)?;
+        Ok(Self)
+    }
+}
+
+impl Drop for BracketedPasteGuard {
+    fn drop(&mut self) {
+        let mut stdout = io::stdout();
+        let _ = execute!(stdout, DisableBracketedPaste);
+    }
+}
+
+/// Sets shellm's window title for the session. The terminal's own title is saved on
+/// its title stack (XTWINOPS 22/23) and comes back when the guard goes away.
+pub struct TitleGuard {
+    active: bool,
+}
+
+impl TitleGuard {
+    pub fn set(title: &str) -> Result<Self> {
+        let mut stdout = io::stdout();
+        write!(stdout, "\x1b[22;0t{}", osc_title(title))?;
+        stdout.flush()?;
+        Ok(Self { active: true })
+    }
+
+    /// Bring back the title from before shellm started, then the last one set inside
+    /// the session if there was one, so the tab keeps showing what ran last.
+    pub fn restore(mut self, last: Option<&str>) {
+        let mut stdout = io::stdout();
+        let _ = write!(stdout, "\x1b[23;0t");
+        if let Some(last) = last {
+            let _ = write!(stdout, "{}", osc_title(last));
+        }
+        let _ = stdout.flush();
+        self.active = false;
+    }
+}
+
+impl Drop for TitleGuard {
+    fn drop(&mut self) {
+        if self.active {
+            let mut stdout = io::stdout();
+            let _ = write!(stdout, "\x1b[23;0t");
+            let _ = stdout.flush();
+        }
+    }
+}
+
+/// OSC 2 setting the window title, with control characters that would end it early dropped.
+fn osc_title(title: &str) -> String {
+    let title: String = title.chars().filter(|c| !c.is_control()).collect();
+    format!("\x1b]2;{title}\x07")
+}
+
+/// Put the terminal back to normal before the default hook prints a panic, so a crash
+/// leaves a usable shell and a readable message instead of raw-mode garbage.
+pub fn install_panic_hook() {
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        let mut stdout = io::stdout();
+        let _ = execute!(stdout, DisableBracketedPaste);
+        let _ = disable_raw_mode();
+        default_hook(info);
+    }));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_osc_title() {
+        assert_eq!(osc_title("shellm"), "\x1b]2;shellm\x07");
+        assert_eq!(osc_title("a\x07b\x1b]c"), "\x1b]2;ab]c\x07");
+    }
+}